@@ -0,0 +1,33 @@
+use std::{env, process::Command};
+
+fn main() {
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+
+    let git_commit = command_output("git", &["rev-parse", "--short", "HEAD"]);
+    println!(
+        "cargo:rustc-env=RDIR_GIT_COMMIT={}",
+        git_commit.as_deref().unwrap_or("unknown")
+    );
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = command_output(&rustc, &["--version"]);
+    println!(
+        "cargo:rustc-env=RDIR_RUSTC_VERSION={}",
+        rustc_version.as_deref().unwrap_or("unknown")
+    );
+
+    let mut features = env::vars()
+        .filter_map(|(k, _)| k.strip_prefix("CARGO_FEATURE_").map(|f| f.to_lowercase()))
+        .collect::<Vec<_>>();
+    features.sort();
+    println!("cargo:rustc-env=RDIR_FEATURES={}", features.join(","));
+}
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_string())
+}
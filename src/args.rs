@@ -1,10 +1,27 @@
-use std::{fs::canonicalize, net::SocketAddrV4, path::PathBuf};
+use std::{
+    ffi::OsString,
+    fs::canonicalize,
+    net::{Ipv4Addr, SocketAddrV4},
+    num::{NonZeroU32, NonZeroU64, NonZeroUsize},
+    path::PathBuf,
+};
 
-use clap::{Parser, Subcommand, ValueHint};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum, ValueHint, error::ErrorKind};
 use derive_more::IsVariant;
 use smol::io;
 
-use crate::common::shares::{CommonShareName, ShareName};
+use crate::{
+    common::{
+        Cipher, LogLevel, MAX_BANNER_LEN, MountOptions, ShareOptions, StatusExposure,
+        shares::{
+            CommonShareName, FullShareName, Namespace, RemoteDirPath, RemotePeerAddr, ShareName,
+        },
+    },
+    server::{ERROR_LOGS_PREFIX, LOGS_PREFIX, NETWORK_PORT},
+};
+
+/// Env var overriding `--log-level`
+const LOG_OVERRIDE_ENV: &str = "RDIR_LOG";
 
 #[derive(Parser, Debug)]
 #[command(version, about)]
@@ -23,81 +40,522 @@ pub struct Args {
         value_parser=tmpdir_parser,
     )]
     pub tmp_dir: PathBuf,
+    /// File with `FULL_SHARE_NAME [MOUNT_PATH] [PIN_KEY]` lines of remote shares
+    /// to mount when the server starts, and again whenever their connection is
+    /// lost
+    #[arg(
+        env = "RDIR_AUTOMOUNT",
+        global = true,
+        long = "automount",
+        value_hint = ValueHint::FilePath
+    )]
+    pub automount: Option<PathBuf>,
+    /// Level the server starts logging at, change it at runtime with
+    /// `rdir log-level`. Release builds only log up to info. RDIR_LOG
+    /// overrides it
+    #[arg(default_value = "info", global = true, long = "log-level")]
+    pub log_level: LogLevel,
+    /// Name of the daily log files in the logs dir, the date is appended
+    #[arg(
+        default_value = LOGS_PREFIX,
+        env = "RDIR_LOG_PREFIX",
+        global = true,
+        long = "log-prefix"
+    )]
+    pub log_prefix: String,
+    /// Name of the daily log files that only get warnings and errors
+    #[arg(
+        default_value = ERROR_LOGS_PREFIX,
+        env = "RDIR_ERROR_LOG_PREFIX",
+        global = true,
+        long = "error-log-prefix"
+    )]
+    pub error_log_prefix: String,
+    /// Dotenv style file with env vars to load, defaults to ./rdir.env
+    #[arg(global = true, long = "env-file", value_hint = ValueHint::FilePath)]
+    pub env_file: Option<PathBuf>,
     /// Server TCP bind socket
     #[arg(env = "RDIR_TCP_SOCKET", global = true, long = "tcp-socket")]
     pub tcp_socket: Option<SocketAddrV4>,
+    /// Prefix of the names peers see the shares of this server under, like
+    /// `team-a:photos` for the share `photos`. Local commands use the name
+    /// without it
+    #[arg(env = "RDIR_NAMESPACE", global = true, long = "namespace")]
+    pub namespace: Option<Namespace>,
     /// Server UDP bind socket
     #[arg(env = "RDIR_UDP_SOCKET", global = true, long = "udp-socket")]
     pub udp_socket: Option<SocketAddrV4>,
+    /// Seconds of inactivity after which a peer stream is closed
+    #[arg(
+        default_value_t = 30,
+        env = "RDIR_STREAM_IDLE_TIMEOUT",
+        global = true,
+        long = "stream-idle-timeout"
+    )]
+    pub stream_idle_timeout: u64,
+    /// Seconds a peer has to send a whole request once it started sending it
+    #[arg(
+        default_value_t = 60,
+        env = "RDIR_REQUEST_TIMEOUT",
+        global = true,
+        long = "request-timeout"
+    )]
+    pub request_timeout: u64,
+    /// Seconds a peer has to accept a connection and finish the handshake
+    #[arg(
+        default_value = "5",
+        env = "RDIR_CONNECT_TIMEOUT",
+        global = true,
+        long = "connect-timeout"
+    )]
+    pub connect_timeout: NonZeroU64,
+    /// Seconds a transfer has to run before its progress gets logged
+    #[arg(
+        default_value_t = 30,
+        env = "RDIR_TRANSFER_LOG_AFTER",
+        global = true,
+        long = "transfer-log-after"
+    )]
+    pub transfer_log_after: u64,
+    /// Seconds between the progress logs of a long transfer
+    #[arg(
+        default_value = "10",
+        env = "RDIR_TRANSFER_LOG_INTERVAL",
+        global = true,
+        long = "transfer-log-interval"
+    )]
+    pub transfer_log_interval: NonZeroU64,
+    /// Requests a peer may send per second before it gets disconnected
+    #[arg(
+        default_value = "1000",
+        env = "RDIR_PEER_MAX_REQUESTS",
+        global = true,
+        long = "peer-max-requests"
+    )]
+    pub peer_max_requests: NonZeroU32,
+    /// Bytes of requests a peer may send per second before it gets disconnected
+    #[arg(
+        default_value = "16777216",
+        env = "RDIR_PEER_MAX_REQUEST_BYTES",
+        global = true,
+        long = "peer-max-request-bytes"
+    )]
+    pub peer_max_request_bytes: NonZeroU64,
+    /// Refuse to start unless peer sessions use ephemeral keys
+    #[arg(
+        env = "RDIR_REQUIRE_FORWARD_SECRECY",
+        global = true,
+        long = "require-forward-secrecy"
+    )]
+    pub require_forward_secrecy: bool,
+    /// Also listen for peers on this host on an abstract Unix socket, they
+    /// skip TCP then. Peers running as another user still authenticate with
+    /// Noise, and only use it once their key of this server is pinned
+    #[arg(
+        env = "RDIR_SAME_HOST_SOCKET",
+        global = true,
+        long = "same-host-socket"
+    )]
+    pub same_host_socket: bool,
+    /// Number of directories read concurrently when walking a share
+    #[arg(
+        default_value = "4",
+        env = "RDIR_WALK_CONCURRENCY",
+        global = true,
+        long = "walk-concurrency"
+    )]
+    pub walk_concurrency: NonZeroUsize,
+    /// Number of file reads served at once, further reads wait
+    #[arg(
+        default_value = "16",
+        env = "RDIR_MAX_CONCURRENT_READS",
+        global = true,
+        long = "max-concurrent-reads"
+    )]
+    pub max_concurrent_reads: NonZeroUsize,
+    /// Number of file reads served at once to a single peer
+    #[arg(
+        default_value = "4",
+        env = "RDIR_MAX_CONCURRENT_READS_PER_PEER",
+        global = true,
+        long = "max-concurrent-reads-per-peer"
+    )]
+    pub max_concurrent_reads_per_peer: NonZeroUsize,
+    /// What peers asking for the status of this daemon get to see
+    #[arg(
+        default_value = "shares",
+        env = "RDIR_EXPOSE_STATUS",
+        global = true,
+        long = "expose-status"
+    )]
+    pub expose_status: StatusExposure,
+    /// Cipher of the peer sessions, picked from the CPU features when unset.
+    /// When set, peers proposing another cipher are refused, otherwise
+    /// sessions opened by peers use whichever cipher they pick
+    #[arg(env = "RDIR_CIPHER", global = true, long = "cipher")]
+    pub cipher: Option<Cipher>,
+    /// Total upload rate in bytes per second, split evenly between the peers
+    /// connected at the moment
+    #[arg(env = "RDIR_FAIR_BANDWIDTH", global = true, long = "fair-bandwidth")]
+    pub fair_bandwidth: Option<NonZeroU64>,
+    /// Bytes of read ahead buffers all peer connections may hold together,
+    /// connections opened once it is spent read without one. Unlimited when unset
+    #[arg(
+        env = "RDIR_IO_BUFFER_BUDGET",
+        global = true,
+        long = "io-buffer-budget"
+    )]
+    pub io_buffer_budget: Option<usize>,
+    /// Answer debug commands, they expose internals of the server
+    #[arg(env = "RDIR_ALLOW_DEBUG", global = true, long = "allow-debug")]
+    pub allow_debug: bool,
+    /// Pin the keys of peers that a peer whose key is pinned already vouches
+    /// for, see `rdir connect introduce`. Introduced keys never replace pinned ones
+    #[arg(
+        env = "RDIR_ACCEPT_INTRODUCTIONS",
+        global = true,
+        long = "accept-introductions"
+    )]
+    pub accept_introductions: bool,
+    /// Confine the server with Landlock to reading the shares it starts with
+    /// and writing the tmp dir and the mount paths of automounts. Shares cant
+    /// be added later, default mount paths cant be created and hooks cant
+    /// reach paths outside of those. Builds with FUSE mounts refuse it,
+    /// Landlock denies mounting
+    #[arg(env = "RDIR_SANDBOX", global = true, long = "sandbox")]
+    pub sandbox: bool,
 }
 
 impl Args {
+    /// Parses the args, exiting on combinations clap cant reject by itself
+    pub fn parse_checked() -> Self {
+        Self::parse()
+            .checked()
+            .and_then(|args| args.with_log_override(std::env::var_os(LOG_OVERRIDE_ENV)))
+            .unwrap_or_else(|err| err.exit())
+    }
+
+    /// `value` of [`LOG_OVERRIDE_ENV`] wins over `--log-level`, like `RUST_LOG`
+    /// does for other programs
+    fn with_log_override(mut self, value: Option<OsString>) -> Result<Self, clap::Error> {
+        let Some(value) = value else {
+            return Ok(self);
+        };
+        let value = value.to_string_lossy();
+        self.log_level = LogLevel::from_str(&value, true).map_err(|_| {
+            let levels: Vec<_> = LogLevel::value_variants()
+                .iter()
+                .filter_map(|level| Some(level.to_possible_value()?.get_name().to_string()))
+                .collect();
+            let message = format!(
+                "Invalid {LOG_OVERRIDE_ENV} \"{value}\", expected one of {}",
+                levels.join(", ")
+            );
+            Self::command().error(ErrorKind::InvalidValue, message)
+        })?;
+        Ok(self)
+    }
+
+    fn checked(mut self) -> Result<Self, clap::Error> {
+        if let Command::Share {
+            command: ShareCommand::Share { paths, name, .. },
+        } = &mut self.command
+        {
+            // `share <dir> <name>` names the share, a second value with a `/`
+            // or naming an existing dir is another dir instead
+            if let [_, second] = paths.as_slice()
+                && !second.to_string_lossy().contains('/')
+                && !second.is_dir()
+            {
+                if name.is_some() {
+                    return Err(Self::command().error(
+                        ErrorKind::ArgumentConflict,
+                        "The name of the share was given both as an argument and with --name",
+                    ));
+                }
+                let second = paths.pop().unwrap_or_default();
+                let second = second.to_string_lossy().parse().map_err(|err| {
+                    Self::command().error(ErrorKind::ValueValidation, format!("{err}"))
+                })?;
+                *name = Some(second);
+            }
+            if name.is_some() && paths.len() > 1 {
+                return Err(Self::command().error(
+                    ErrorKind::ArgumentConflict,
+                    "--name can only be used when sharing a single dir",
+                ));
+            }
+            for path in paths.iter_mut() {
+                *path = canonicalize(&*path).map_err(|err| {
+                    let message = format!("Invalid dir \"{}\": {err}", path.display());
+                    Self::command().error(ErrorKind::ValueValidation, message)
+                })?;
+            }
+        }
+        #[cfg(feature = "fuse")]
+        if self.sandbox {
+            return Err(Self::command().error(
+                ErrorKind::ArgumentConflict,
+                "--sandbox cant be used in builds with FUSE mounts, Landlock denies mounting",
+            ));
+        }
+        Ok(self)
+    }
+
+    /// Socket the server listens on for peers
+    pub fn tcp_socket_or_default(&self) -> SocketAddrV4 {
+        self.tcp_socket
+            .unwrap_or(SocketAddrV4::new(Ipv4Addr::LOCALHOST, NETWORK_PORT))
+    }
+
     pub fn expects_active_server(&self) -> bool {
         match &self.command {
-            Command::Connect { .. } | Command::Discover => true,
+            Command::Connect {
+                command: ConnectCommand::Reconnect { .. },
+            } => false,
+            Command::Connect { .. } | Command::Discover { .. } => true,
             Command::Share { command } => match command {
                 ShareCommand::Remove { .. } | ShareCommand::Share { .. } => true,
-                ShareCommand::Ls => false,
+                ShareCommand::Exists { .. }
+                | ShareCommand::Ls { .. }
+                | ShareCommand::Repath { .. }
+                | ShareCommand::Size { .. }
+                | ShareCommand::Validate { .. } => false,
             },
-            Command::Kill | Command::Ls => false,
+            Command::Config { .. }
+            | Command::Debug { .. }
+            | Command::Kill
+            | Command::LogLevel { .. }
+            | Command::Ls
+            | Command::Peer { .. }
+            | Command::PeerOnly { .. }
+            | Command::RotateKey { .. }
+            | Command::Selftest { .. }
+            | Command::Transfers
+            | Command::Version { .. } => false,
         }
     }
 }
 
 #[derive(Debug, IsVariant, Subcommand)]
 pub enum Command {
+    /// Print the configuration of the running server after flags, env vars and
+    /// env files are applied
+    Config {
+        /// Print what this client resolved instead, without asking the server
+        #[arg(long)]
+        resolved: bool,
+    },
     /// manage remote shares
     #[command(short_flag = 'C', alias = "c")]
     Connect {
         #[command(subcommand)]
         command: ConnectCommand,
     },
+    /// Inspect internals of the running server
+    #[command(hide = true)]
+    Debug {
+        #[command(subcommand)]
+        command: DebugCommand,
+    },
     /// Discover shares in the local network
     #[command(short_flag = 'D', alias = "d")]
-    Discover,
+    Discover {
+        /// Print as JSON
+        #[arg(long)]
+        json: bool,
+    },
     /// Kill the server, lets ongoing operations finish
     #[command(short_flag = 'K', alias = "k")]
     Kill,
+    /// Change the log level of the running server, prints the previous one.
+    /// Release builds only log up to info
+    LogLevel {
+        #[arg()]
+        level: LogLevel,
+    },
     /// List shares and the status of the server
     #[command(short_flag = 'L', alias = "l")]
     Ls,
+    /// Manage the peers connected to this daemon
+    #[command(short_flag = 'P', alias = "p")]
+    Peer {
+        #[command(subcommand)]
+        command: PeerCommand,
+    },
+    /// Serve the shares from a config file to peers, without the local IPC
+    /// socket. The shares can only be changed by restarting
+    #[command(long_flag = "peer-only")]
+    PeerOnly {
+        /// File with `NAME=PATH` lines of the shares to serve
+        #[arg(
+            env = "RDIR_SHARES_CONFIG",
+            long = "shares-config",
+            value_hint = ValueHint::FilePath
+        )]
+        shares_config: PathBuf,
+    },
+    /// Replace the static key peers know this daemon by, the server uses the
+    /// new one once restarted
+    RotateKey {
+        /// Rotate even though peers that pinned the current key refuse this
+        /// daemon unless they connect within the grace period
+        #[arg(long)]
+        force: bool,
+        /// How long peers that pinned the current key still get it, along with
+        /// the new key they pin instead, like `12h` or `7d`
+        #[arg(long, default_value = "7d", value_parser = duration_secs_parser)]
+        grace: u64,
+    },
     /// manage Shares
     #[command(short_flag = 'S', alias = "s")]
     Share {
         #[command(subcommand)]
         command: ShareCommand,
     },
+    /// Check this host can serve and mount shares, without a running server
+    Selftest {
+        #[command(subcommand)]
+        command: SelftestCommand,
+    },
+    /// List file transfers in progress with their progress
+    Transfers,
+    /// Print build information of the client and the running server
+    Version {
+        /// Print as JSON
+        #[arg(long)]
+        json: bool,
+    },
 }
 
 #[derive(Debug, IsVariant, Subcommand)]
 pub enum ConnectCommand {
-    /// List used remote shares
+    /// List used remote shares, or a dir of a remote share without mounting it
     #[command(short_flag = 'l', alias = "l")]
-    Ls,
+    Ls {
+        /// Dir to list as <IP>/<NAME> or <IP>/<NAME>/<PATH>
+        #[arg()]
+        dir: Option<RemoteDirPath>,
+        /// Print the listed dir as JSON
+        #[arg(long, requires = "dir")]
+        json: bool,
+    },
     /// Mount a new remote share
     #[command(short_flag = 'm', alias = "m")]
     Mount {
         /// Name of the remote share. If address is omitted, tries to search the local network
         #[arg()]
         name: ShareName,
-        /// Path to a dir to mount the share
+        /// Path to a dir to mount the share, defaults to ~/rdir/<suggested name>
+        /// if the share suggests one
         #[arg(value_hint=ValueHint::DirPath, value_parser=existing_path_parser)]
-        path: PathBuf,
+        path: Option<PathBuf>,
+        #[command(flatten)]
+        options: MountOptions,
+    },
+    /// Retry a mount that is waiting to reconnect right away
+    Reconnect {
+        /// Full name of the remote share
+        #[arg()]
+        name: FullShareName,
+    },
+    /// Show the public status of a remote daemon
+    Status {
+        /// Address of the remote daemon
+        #[arg()]
+        addr: RemotePeerAddr,
     },
     /// Unmount a remote share
     #[command(short_flag = 'u', alias = "u")]
     Unmount {
-        /// Name of the remote share, if ambiguous specify as <IP>:<NAME>
+        /// Name of the remote share, if ambiguous specify as <IP>/<NAME>
         #[arg()]
         name: ShareName,
     },
+    /// Pin the key a remote daemon has now, replacing the pinned one
+    Trust {
+        /// Address of the remote daemon
+        #[arg()]
+        addr: RemotePeerAddr,
+    },
+    /// Forget the pinned key of a remote daemon, the next connection pins a new one
+    Untrust {
+        /// Address of the remote daemon
+        #[arg()]
+        addr: RemotePeerAddr,
+    },
+    /// List a dir of a mounted remote share
+    #[command(short_flag = 'b', alias = "b")]
+    Browse {
+        /// Name of the remote share, if ambiguous specify as <IP>/<NAME>
+        #[arg()]
+        name: ShareName,
+        /// Path of the dir inside the share, defaults to its root
+        #[arg()]
+        path: Option<PathBuf>,
+    },
+    /// Vouch for the pinned key of a remote daemon to the owner of a mounted
+    /// share, which pins it as well if it runs with `--accept-introductions`
+    Introduce {
+        /// Name of the mounted remote share, if ambiguous specify as <IP>/<NAME>
+        #[arg()]
+        name: ShareName,
+        /// Address of the remote daemon to vouch for
+        #[arg()]
+        addr: RemotePeerAddr,
+        /// How long the introduction is valid, like `12h` or `7d`
+        #[arg(long, default_value = "7d", value_parser = duration_secs_parser)]
+        ttl: u64,
+    },
+}
+
+#[derive(Debug, IsVariant, Subcommand)]
+pub enum SelftestCommand {
+    /// Mount a share of this process over loopback TCP, then list it and read
+    /// a file through the mount, printing how long each step took
+    Mount,
+}
+
+#[derive(Debug, IsVariant, Subcommand)]
+pub enum DebugCommand {
+    /// Print the whole internal state, needs a server started with `--allow-debug`
+    Dump,
+}
+
+#[derive(Debug, IsVariant, Subcommand)]
+pub enum PeerCommand {
+    /// Drop every connection and mount of a host, whichever port it uses
+    Disconnect {
+        /// IP of the host
+        #[arg()]
+        addr: Ipv4Addr,
+    },
 }
 
 #[derive(Debug, IsVariant, Subcommand)]
 pub enum ShareCommand {
+    /// Exit with 0 if a share exists, 1 otherwise
+    Exists {
+        /// Name of the share
+        #[arg()]
+        name: CommonShareName,
+        /// Print the result as JSON
+        #[arg(long)]
+        json: bool,
+    },
     /// List shares
     #[command(short_flag = 'l', alias = "l")]
-    Ls,
+    Ls {
+        /// Only list shares whose directory exists
+        #[arg(long, conflicts_with = "unavailable_only")]
+        available_only: bool,
+        /// Only list shares whose directory is missing
+        #[arg(long)]
+        unavailable_only: bool,
+        /// Only list shares nobody is connected to
+        #[arg(long)]
+        idle: bool,
+    },
     /// Remove a share
     #[command(short_flag = 'r', alias = "r")]
     Remove {
@@ -105,15 +563,66 @@ pub enum ShareCommand {
         #[arg()]
         name: CommonShareName,
     },
-    /// create a new Share
-    #[command(short_flag = 's', alias = "s")]
-    Share {
-        /// Path to dir a share
+    /// Point a share at another dir
+    Repath {
+        /// Name of the share
+        #[arg()]
+        name: CommonShareName,
+        /// New dir of the share
         #[arg(value_hint=ValueHint::DirPath, value_parser=existing_path_parser)]
         path: PathBuf,
-        /// Name of the share, defaults to the name of the shared dir
+        /// Only update if the share still points at this dir, fails otherwise.
+        /// The dir doesnt have to exist anymore
+        #[arg(long = "expected-current")]
+        expected_current: Option<PathBuf>,
+        /// Refuse to move the share inside or around another share instead of
+        /// only warning about it
+        #[arg(long = "no-overlap")]
+        no_overlap: bool,
+        /// Move the share even if the dir is sensitive like /etc or ~/.ssh
+        #[arg(long = "force")]
+        force: bool,
+    },
+    /// Show the total size of a share
+    Size {
+        /// Name of the share
         #[arg()]
+        name: CommonShareName,
+    },
+    /// create a new Share
+    #[command(short_flag = 's', alias = "s")]
+    Share {
+        /// Dir to share, optionally followed by the name of the share. Several
+        /// dirs each become their own share named after the dir, a second
+        /// value is only taken as a dir if it contains a `/` or names an
+        /// existing dir
+        #[arg(
+            num_args = 1..,
+            required = true,
+            value_name = "PATH [NAME]",
+            value_hint = ValueHint::DirPath
+        )]
+        paths: Vec<PathBuf>,
+        /// Name of the share, defaults to the name of the shared dir. Only
+        /// allowed when sharing a single dir
+        #[arg(long = "name", short = 'n')]
         name: Option<CommonShareName>,
+        #[command(flatten)]
+        options: ShareOptions,
+        /// Refuse to share a dir inside or around another share instead of
+        /// only warning about it
+        #[arg(long = "no-overlap")]
+        no_overlap: bool,
+        /// Share the dir even if it is sensitive like /etc or ~/.ssh
+        #[arg(long = "force")]
+        force: bool,
+    },
+    /// Check a shares config for `peer-only` without starting anything, exits
+    /// with 1 if it has problems
+    Validate {
+        /// Path to the shares config
+        #[arg(value_hint = ValueHint::FilePath)]
+        file: PathBuf,
     },
 }
 
@@ -126,6 +635,152 @@ fn tmpdir_parser(s: &str) -> Result<PathBuf, &'static str> {
     Ok(path)
 }
 
+pub fn mount_suggestion_parser(s: &str) -> Result<String, &'static str> {
+    if s.is_empty() || s == "." || s == ".." || s.contains('/') {
+        return Err("Mount suggestion has to be a plain dir name");
+    }
+    Ok(s.to_string())
+}
+
+pub fn banner_parser(s: &str) -> Result<String, String> {
+    match s.len() > MAX_BANNER_LEN {
+        true => Err(format!("Banner cant be longer than {MAX_BANNER_LEN} bytes")),
+        false => Ok(s.to_string()),
+    }
+}
+
+pub fn glob_parser(s: &str) -> Result<String, String> {
+    glob::Pattern::new(s)
+        .map(|_| s.to_string())
+        .map_err(|err| format!("Invalid glob \"{s}\": {err}"))
+}
+
+/// Parses a duration like `90`, `90s`, `30m`, `1h` or `2d` into seconds
+pub fn duration_secs_parser(s: &str) -> Result<u64, String> {
+    let (number, multiplier) = match s.char_indices().last() {
+        Some((i, 's')) => (&s[..i], 1),
+        Some((i, 'm')) => (&s[..i], 60),
+        Some((i, 'h')) => (&s[..i], 60 * 60),
+        Some((i, 'd')) => (&s[..i], 24 * 60 * 60),
+        _ => (s, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("Could not parse \"{s}\" as a duration"))
+}
+
 fn existing_path_parser(s: &str) -> io::Result<PathBuf> {
     canonicalize(s)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duration_parse() {
+        assert_eq!(duration_secs_parser("90"), Ok(90));
+        assert_eq!(duration_secs_parser("90s"), Ok(90));
+        assert_eq!(duration_secs_parser("30m"), Ok(30 * 60));
+        assert_eq!(duration_secs_parser("1h"), Ok(60 * 60));
+        assert_eq!(duration_secs_parser("2d"), Ok(2 * 24 * 60 * 60));
+        assert!(duration_secs_parser("").is_err());
+        assert!(duration_secs_parser("h").is_err());
+        assert!(duration_secs_parser("1w").is_err());
+    }
+
+    #[test]
+    fn repath_expects_a_missing_dir() {
+        let args = Args::try_parse_from([
+            "rdir",
+            "share",
+            "repath",
+            "A",
+            "/",
+            "--expected-current",
+            "/nonexistent/old",
+        ])
+        .unwrap();
+        let Command::Share {
+            command: ShareCommand::Repath {
+                expected_current, ..
+            },
+        } = args.command
+        else {
+            panic!("Parsed {:?}", args.command);
+        };
+        assert_eq!(expected_current, Some(PathBuf::from("/nonexistent/old")));
+    }
+
+    #[test]
+    fn share_takes_a_positional_name() {
+        let shared = |argv: &[&str]| -> Result<_, clap::Error> {
+            let args = Args::try_parse_from(["rdir", "share", "share"].iter().chain(argv))?;
+            match args.checked()?.command {
+                Command::Share {
+                    command: ShareCommand::Share { paths, name, .. },
+                } => Ok((paths, name.map(|name| name.to_string()))),
+                command => panic!("Parsed {command:?}"),
+            }
+        };
+        let root = PathBuf::from("/");
+        let tmp = canonicalize("/tmp").unwrap();
+
+        assert_eq!(
+            shared(&["/", "A"]).unwrap(),
+            (vec![root.clone()], Some("A".into()))
+        );
+        assert_eq!(
+            shared(&["/", "-n", "A"]).unwrap(),
+            (vec![root.clone()], Some("A".into()))
+        );
+        assert_eq!(
+            shared(&["/", "/tmp"]).unwrap(),
+            (vec![root.clone(), tmp.clone()], None)
+        );
+        assert_eq!(
+            shared(&["/", "/tmp", "/"]).unwrap().0,
+            [root.clone(), tmp, root]
+        );
+        // Relative to the package root the tests run in
+        let relative = ["src", "tests"].map(|dir| canonicalize(dir).unwrap());
+        assert_eq!(
+            shared(&["src", "tests"]).unwrap(),
+            (relative.to_vec(), None)
+        );
+        assert_eq!(
+            shared(&["src", "A"]).unwrap(),
+            (vec![relative[0].clone()], Some("A".into()))
+        );
+        let conflict = |argv: &[&str]| shared(argv).unwrap_err().kind();
+        assert_eq!(
+            conflict(&["/", "A", "-n", "B"]),
+            ErrorKind::ArgumentConflict
+        );
+        assert_eq!(
+            conflict(&["/", "/tmp", "-n", "B"]),
+            ErrorKind::ArgumentConflict
+        );
+        assert_eq!(conflict(&["/nonexistent", "A"]), ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn log_level_is_validated() {
+        let args = Args::try_parse_from(["rdir", "ls"]).unwrap();
+        assert_eq!(args.log_level, LogLevel::Info);
+        let args = Args::try_parse_from(["rdir", "--log-level", "trace", "ls"]).unwrap();
+        assert_eq!(args.log_level, LogLevel::Trace);
+        let err = Args::try_parse_from(["rdir", "--log-level", "verbose", "ls"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidValue);
+
+        // The env var overrides the arg instead of being a default for it
+        let args = args.with_log_override(Some("Debug".into())).unwrap();
+        assert_eq!(args.log_level, LogLevel::Debug);
+        let args = args.with_log_override(None).unwrap();
+        assert_eq!(args.log_level, LogLevel::Debug);
+        let err = args.with_log_override(Some("loud".into())).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidValue);
+    }
+}
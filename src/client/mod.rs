@@ -1,14 +1,18 @@
-use std::time::Duration;
+use std::{fs, path::Path, time::Duration};
 
-use anyhow::{Context, Result as AnyResult};
+use anyhow::{Context, Result as AnyResult, bail};
 use backoff::{ExponentialBackoffBuilder, backoff::Backoff};
 use bitcode::{decode, encode};
 use smol::{LocalExecutor, Timer, io, net::unix::UnixStream};
 
 use crate::{
-    args::Args,
-    common::{ClientMessage, ServerResponse, framing::FramedStream},
-    server::SOCKET_NAME,
+    args::{Args, Command, ConnectCommand, ShareCommand},
+    common::{
+        ClientEnvelope, ClientMessage, ConfigDto, DirEntryDto, ServerResponse,
+        framing::FramedStream,
+        version::{BuildInfo, versions_json},
+    },
+    server::{SOCKET_NAME, shares_config},
 };
 
 pub struct Client<'a> {
@@ -29,10 +33,33 @@ impl Client<'_> {
     }
 
     async fn main(&self, args: Args, maybe_sock: Option<UnixStream>) -> AnyResult<()> {
+        if let Command::Version { json } = args.command {
+            return version(json, maybe_sock).await;
+        }
+        if let Command::Share {
+            command: ShareCommand::Validate { file },
+        } = &args.command
+        {
+            return validate_shares_config(file);
+        }
+        if let Command::Config { resolved: true } = args.command {
+            print!("{}", ConfigDto::new(&args, None));
+            return Ok(());
+        }
+
         let sock = match (maybe_sock, args.expects_active_server()) {
             (Some(val), _) => val,
             (None, false) => {
-                println!("Server is down");
+                if let Some(json) = share_exists_json(&args) {
+                    share_exists(false, json);
+                }
+                match args.tmp_dir.exists() {
+                    true => println!("Server is down"),
+                    false => println!(
+                        "Server is down, no rdir daemon has run with the tmpdir {} yet",
+                        args.tmp_dir.to_string_lossy()
+                    ),
+                }
                 return Ok(());
             }
             (None, true) => try_connect(&args).await.context(
@@ -40,11 +67,44 @@ impl Client<'_> {
             )?,
         };
         let mut stream = FramedStream::new(sock);
-        stream.write(&encode(&ClientMessage::from(&args))).await?;
+        let message = ClientMessage::try_from(&args)?;
+        stream
+            .write(&encode(&ClientEnvelope::from(&message)))
+            .await?;
         let resp: ServerResponse = decode(&stream.read().await?)?;
         match resp {
+            ServerResponse::Bool(exists) => {
+                share_exists(exists, share_exists_json(&args).unwrap_or_default());
+                Ok(())
+            }
             ServerResponse::Err(err) => Err(anyhow::Error::from(err)),
-            resp @ _ => {
+            ServerResponse::PartialOk(ref outcomes) => {
+                print!("{resp}");
+                let failed = outcomes.iter().filter(|outcome| outcome.result.is_err());
+                match failed.count() {
+                    0 => Ok(()),
+                    failed => bail!("{failed} of {} dirs failed to be shared", outcomes.len()),
+                }
+            }
+            ServerResponse::Discovered(discovered)
+                if matches!(args.command, Command::Discover { json: true }) =>
+            {
+                println!("{}", discovered.to_json());
+                Ok(())
+            }
+            ServerResponse::DirEntries(entries)
+                if matches!(
+                    args.command,
+                    Command::Connect {
+                        command: ConnectCommand::Ls { json: true, .. }
+                    }
+                ) =>
+            {
+                let entries: Vec<_> = entries.iter().map(DirEntryDto::to_json).collect();
+                println!("[{}]", entries.join(","));
+                Ok(())
+            }
+            resp => {
                 print!("{}", resp);
                 Ok(())
             }
@@ -52,6 +112,73 @@ impl Client<'_> {
     }
 }
 
+/// Returns whether to print JSON if the command is `share exists`
+fn share_exists_json(args: &Args) -> Option<bool> {
+    match &args.command {
+        Command::Share {
+            command: ShareCommand::Exists { json, .. },
+        } => Some(*json),
+        _ => None,
+    }
+}
+
+/// Reports the result of `share exists` through the exit code
+fn share_exists(exists: bool, json: bool) {
+    if json {
+        println!(r#"{{"exists":{exists}}}"#);
+    }
+    if !exists {
+        std::process::exit(1);
+    }
+}
+
+/// Reports every problem of a shares config, without involving the server
+fn validate_shares_config(path: &Path) -> AnyResult<()> {
+    let content = fs::read_to_string(path).context(format!(
+        "Failed to read the shares config at: {}",
+        path.to_string_lossy()
+    ))?;
+    let (shares, problems) = shares_config::check(&content);
+    for problem in &problems {
+        println!("{problem}");
+    }
+    match problems.len() {
+        0 => {
+            println!("{} shares, no problems found", shares.len());
+            Ok(())
+        }
+        n => bail!("Found {n} problems in {}", path.to_string_lossy()),
+    }
+}
+
+/// Prints the build info of this binary and of the running server if there is one
+async fn version(json: bool, maybe_sock: Option<UnixStream>) -> AnyResult<()> {
+    let client = BuildInfo::current();
+    let server = match maybe_sock {
+        Some(sock) => {
+            let mut stream = FramedStream::new(sock);
+            let envelope = ClientEnvelope::from(&ClientMessage::Version);
+            stream.write(&encode(&envelope)).await?;
+            match decode(&stream.read().await?)? {
+                ServerResponse::Version(info) => Some(info),
+                _ => None,
+            }
+        }
+        None => None,
+    };
+
+    if json {
+        println!("{}", versions_json(&client, server.as_ref()));
+    } else {
+        println!("client: {client}");
+        match server {
+            Some(server) => println!("server: {server}"),
+            None => println!("server: not running"),
+        }
+    }
+    Ok(())
+}
+
 /// Tries to connect to the newly spawned server
 async fn try_connect(args: &Args) -> io::Result<UnixStream> {
     let mut backoff = ExponentialBackoffBuilder::new()
@@ -0,0 +1,29 @@
+use std::time::Duration;
+
+use bitcode::{Decode, Encode};
+
+use crate::common::shares::CommonShareName;
+
+/// How long `rdir discover` collects answers
+pub const DISCOVERY_WINDOW: Duration = Duration::from_secs(2);
+/// Most peers one discovery keeps, so a flooded network cant exhaust memory
+pub const MAX_DISCOVERED_PEERS: usize = 256;
+/// Most share names one announcement carries, keeps it within a datagram
+pub const MAX_ANNOUNCED_SHARES: usize = 512;
+/// Biggest datagram read, bigger ones are truncated and fail to decode
+pub const MAX_PACKET_SIZE: usize = 64 * 1024;
+
+/// Datagram daemons exchange over UDP to find each other. `sender` is picked
+/// randomly by every daemon on start, so it can ignore its own broadcasts
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub enum DiscoveryPacket {
+    /// Asks every daemon that hears it to answer with an `Announce`
+    Query { sender: u64 },
+    /// Shares of a daemon listening for peers on `port`, broadcast
+    /// periodically and sent as the answer to a `Query`
+    Announce {
+        sender: u64,
+        port: u16,
+        shares: Vec<CommonShareName>,
+    },
+}
@@ -1,32 +1,63 @@
 use derive_more::{Constructor, From};
-use smol::io::{self, AsyncRead, AsyncReadExt, AsyncWrite};
+use smol::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 type PrefixType = u16;
 const PREFIX_LEN: usize = (PrefixType::BITS / 8) as usize;
 pub const MAX_FRAME_SIZE: usize = PrefixType::MAX as usize;
+/// Upper bound of a message reassembled from multiple frames
+pub const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
 
 #[derive(Constructor, From)]
 pub struct FramedStream<S: Unpin>(S);
 
 impl<S: AsyncWrite + Unpin> FramedStream<S> {
+    /// Writes `buf` as one message. Messages that dont fit into a single frame
+    /// are split, every frame of `MAX_FRAME_SIZE` bytes is followed by another
+    /// one and the message ends with the first shorter (possibly empty) frame.
+    /// The message is flushed, so the stream can be dropped right after
     pub async fn write(&mut self, buf: &[u8]) -> io::Result<()> {
-        let len = buf.len();
-        assert!(len <= MAX_FRAME_SIZE);
-        let prefix = (len as PrefixType).to_be_bytes();
-        let chain = prefix.chain(buf);
-        io::copy(chain, &mut self.0).await?;
-        Ok(())
+        if buf.len() > MAX_MESSAGE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Message exceeds the maximum message size",
+            ));
+        }
+
+        let mut rest = buf;
+        loop {
+            let len = rest.len().min(MAX_FRAME_SIZE);
+            let (frame, tail) = rest.split_at(len);
+            let prefix = (len as PrefixType).to_be_bytes();
+            self.0.write_all(&prefix).await?;
+            self.0.write_all(frame).await?;
+            if len < MAX_FRAME_SIZE {
+                return self.0.flush().await;
+            }
+            rest = tail;
+        }
     }
 }
 
 impl<S: AsyncRead + Unpin> FramedStream<S> {
     pub async fn read(&mut self) -> io::Result<Vec<u8>> {
-        let mut prefix_buf = [0; PREFIX_LEN];
-        self.0.read_exact(&mut prefix_buf).await?;
-        let len = PrefixType::from_be_bytes(prefix_buf);
-        let mut buf = vec![0; len as usize];
-        self.0.read_exact(&mut buf).await?;
-        Ok(buf)
+        let mut buf = Vec::new();
+        loop {
+            let mut prefix_buf = [0; PREFIX_LEN];
+            self.0.read_exact(&mut prefix_buf).await?;
+            let len = PrefixType::from_be_bytes(prefix_buf) as usize;
+            let start = buf.len();
+            if start + len > MAX_MESSAGE_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Message exceeds the maximum message size",
+                ));
+            }
+            buf.resize(start + len, 0);
+            self.0.read_exact(&mut buf[start..]).await?;
+            if len < MAX_FRAME_SIZE {
+                return Ok(buf);
+            }
+        }
     }
 }
 
@@ -58,4 +89,47 @@ mod tests {
         };
         assert_eq!(read_buf, (0..10).collect::<Vec<u8>>());
     }
+
+    fn round_trip(payload: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::<u8>::new();
+        block_on(FramedStream(&mut buf).write(payload)).unwrap();
+        let mut reader = FramedStream(buf.as_slice());
+        let read_buf = block_on(reader.read()).unwrap();
+        assert!(reader.0.is_empty());
+        read_buf
+    }
+
+    #[test]
+    fn framed_stream_splits_large_messages() {
+        let payload = (0..MAX_FRAME_SIZE * 3 + 100)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        assert_eq!(round_trip(&payload), payload);
+
+        let payload = vec![7; MAX_FRAME_SIZE * 2];
+        assert_eq!(round_trip(&payload), payload);
+        assert_eq!(round_trip(&[]), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn message_survives_dropping_the_stream() {
+        let (local, remote) = smol::net::unix::UnixStream::pair().unwrap();
+        block_on(async {
+            // Buffered writes only reach the socket once flushed
+            let mut writer = FramedStream(io::BufWriter::new(local));
+            writer.write(b"response").await.unwrap();
+            drop(writer);
+            let read_buf = FramedStream(remote).read().await.unwrap();
+            assert_eq!(read_buf, b"response");
+        });
+    }
+
+    #[test]
+    fn framed_stream_rejects_oversized_messages() {
+        let mut buf = Vec::<u8>::new();
+        let payload = vec![0; MAX_MESSAGE_SIZE + 1];
+        let err = block_on(FramedStream(&mut buf).write(&payload)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(buf.is_empty());
+    }
 }
@@ -1,23 +1,48 @@
-use std::{collections::BTreeMap, fmt, net::SocketAddrV4};
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    net::{Ipv4Addr, SocketAddrV4},
+    path::Path,
+    time::Instant,
+};
 
-use bitcode::{Decode, Encode};
+use bitcode::{Decode, Encode, decode, encode};
 use derive_more::{Display, Error, From, IsVariant};
+use tracing::level_filters::LevelFilter;
 
 use crate::{
-    args::{Args, ConnectCommand, ShareCommand},
-    common::shares::{CommonShareName, CommonShareNameParseError, RemotePeerAddr, ShareName},
+    args::{
+        Args, ConnectCommand, DebugCommand, PeerCommand, ShareCommand, banner_parser,
+        duration_secs_parser, glob_parser, mount_suggestion_parser,
+    },
+    common::{
+        shares::{
+            CommonShareName, CommonShareNameParseError, FullShareName, RemoteDirPath,
+            RemotePeerAddr, ShareName,
+        },
+        trust::NotPinnedError,
+        version::{BuildInfo, PROTOCOL_VERSION, json_string},
+    },
     server::{
-        ConnectToRemoteShareError, ProtocolError,
-        net::NoiseStreamError,
+        ConnectToRemoteShareError, DebugDisabledError, DiscoveryDisabledError,
+        InvalidMountPathError, ListRemoteDirError, PeerStatusError, ProtocolError,
+        RefusedSensitivePathError, SandboxedError, ServerBusyError,
+        fuse::FuseUnavailableError,
+        messages::PeerResponseError,
+        net::{NoiseStreamError, PublicKey},
         state::{
-            PeerId, RemoteShare, RepeatedPeerError, RepeatedRemoteShareError, RepeatedShare, Share,
-            ShareDoesntExistError,
+            AmbiguousShareNameError, NoSuchRemoteShareError, PeerId, RemoteShare,
+            RepeatedPeerError, RepeatedRemoteShareError, RepeatedShare, Share,
+            ShareDoesntExistError, SharePathOverlapError, UpdateSharePathError,
         },
     },
 };
 
+pub mod discovery;
 pub mod framing;
 pub mod shares;
+pub mod trust;
+pub mod version;
 
 #[derive(Encode, Decode, Clone, Debug, IsVariant)]
 pub enum ClientMessage {
@@ -26,90 +51,735 @@ pub enum ClientMessage {
     Kill,
     Ls,
     Ping,
+    SetLogLevel(LogLevel),
     Share(ShareMessage),
+    ShareExists { name: CommonShareName },
+    Version,
+    ReconnectMount { name: FullShareName },
+    Config,
+    DebugDump,
+    Transfers,
+    DisconnectPeer { addr: Ipv4Addr },
+}
+
+impl ClientMessage {
+    /// Stable name of the command, sent along so that a daemon can name commands it doesnt know
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Config => "config",
+            Self::Connect(ConnectMessage::Browse { .. }) => "connect browse",
+            Self::Connect(ConnectMessage::Introduce { .. }) => "connect introduce",
+            Self::Connect(ConnectMessage::Ls) => "connect ls",
+            Self::Connect(ConnectMessage::LsRemote { .. }) => "connect ls remote",
+            Self::Connect(ConnectMessage::Mount { .. }) => "connect mount",
+            Self::Connect(ConnectMessage::Status { .. }) => "connect status",
+            Self::Connect(ConnectMessage::Trust { .. }) => "connect trust",
+            Self::Connect(ConnectMessage::Unmount { .. }) => "connect unmount",
+            Self::Connect(ConnectMessage::Untrust { .. }) => "connect untrust",
+            Self::DebugDump => "debug dump",
+            Self::Discover => "discover",
+            Self::DisconnectPeer { .. } => "peer disconnect",
+            Self::Kill => "kill",
+            Self::Ls => "ls",
+            Self::Ping => "ping",
+            Self::ReconnectMount { .. } => "connect reconnect",
+            Self::SetLogLevel(_) => "log-level",
+            Self::Share(ShareMessage::Ls { .. }) => "share ls",
+            Self::Share(ShareMessage::Remove { .. }) => "share remove",
+            Self::Share(ShareMessage::Repath { .. }) => "share repath",
+            Self::Share(ShareMessage::Size { .. }) => "share size",
+            Self::Share(ShareMessage::Share { .. }) => "share share",
+            Self::Share(ShareMessage::ShareMany { .. }) => "share share many",
+            Self::ShareExists { .. } => "share exists",
+            Self::Transfers => "transfers",
+            Self::Version => "version",
+        }
+    }
+}
+
+/// What a client actually sends, the tag stays readable even when the message isnt
+#[derive(Encode, Decode, Clone, Debug)]
+pub struct ClientEnvelope {
+    /// [`PROTOCOL_VERSION`] of the client
+    pub version: u16,
+    pub kind: String,
+    pub message: Vec<u8>,
+}
+
+impl From<&ClientMessage> for ClientEnvelope {
+    fn from(value: &ClientMessage) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            kind: value.kind().to_string(),
+            message: encode(value),
+        }
+    }
+}
+
+impl TryFrom<ClientEnvelope> for ClientMessage {
+    type Error = UnknownCommandError;
+
+    fn try_from(value: ClientEnvelope) -> Result<Self, Self::Error> {
+        // A message of another version could decode as a different one
+        if value.version != PROTOCOL_VERSION {
+            return Err(UnknownCommandError { kind: value.kind });
+        }
+        match decode::<ClientMessage>(&value.message) {
+            Ok(message) if message.kind() == value.kind => Ok(message),
+            _ => Err(UnknownCommandError { kind: value.kind }),
+        }
+    }
+}
+
+#[derive(Encode, Decode, Clone, Debug, Display, Error, PartialEq, Eq)]
+#[display(
+    "The server doesnt know the `{kind}` command, it might be older than this client. Restart it with `rdir kill`"
+)]
+pub struct UnknownCommandError {
+    pub kind: String,
+}
+
+/// Command handled without a server, so it has no message to send
+#[derive(Clone, Debug, Display, Error, PartialEq, Eq)]
+#[display("`{command}` doesnt talk to a server")]
+pub struct LocalCommandError {
+    pub command: &'static str,
 }
 
-impl From<&Args> for ClientMessage {
-    fn from(value: &Args) -> Self {
-        match &value.command {
-            crate::args::Command::Connect { command } => Self::Connect(command.into()),
-            crate::args::Command::Discover => Self::Discover,
+impl TryFrom<&Args> for ClientMessage {
+    type Error = LocalCommandError;
+
+    fn try_from(value: &Args) -> Result<Self, Self::Error> {
+        Ok(match &value.command {
+            crate::args::Command::Config { .. } => Self::Config,
+            crate::args::Command::Connect { command } => command.into(),
+            crate::args::Command::Debug {
+                command: DebugCommand::Dump,
+            } => Self::DebugDump,
+            crate::args::Command::Discover { .. } => Self::Discover,
             crate::args::Command::Kill => Self::Kill,
+            crate::args::Command::LogLevel { level } => Self::SetLogLevel(*level),
             crate::args::Command::Ls => Self::Ls,
-            crate::args::Command::Share { command } => Self::Share(command.into()),
-        }
+            crate::args::Command::Peer {
+                command: PeerCommand::Disconnect { addr },
+            } => Self::DisconnectPeer { addr: *addr },
+            crate::args::Command::PeerOnly { .. } => {
+                return Err(LocalCommandError {
+                    command: "peer-only",
+                });
+            }
+            crate::args::Command::RotateKey { .. } => {
+                return Err(LocalCommandError {
+                    command: "rotate-key",
+                });
+            }
+            crate::args::Command::Selftest { .. } => {
+                return Err(LocalCommandError {
+                    command: "selftest",
+                });
+            }
+            crate::args::Command::Share { command } => command.try_into()?,
+            crate::args::Command::Transfers => Self::Transfers,
+            crate::args::Command::Version { .. } => Self::Version,
+        })
     }
 }
 
 #[derive(Encode, Decode, Clone, Debug, IsVariant)]
 pub enum ConnectMessage {
     Ls,
-    Mount { path: String, name: ShareName },
-    Unmount { name: ShareName },
+    Mount {
+        path: Option<String>,
+        name: ShareName,
+        options: MountOptions,
+    },
+    Unmount {
+        name: ShareName,
+    },
+    Status {
+        addr: RemotePeerAddr,
+    },
+    /// Lists a dir of a share that isnt mounted, over a connection of its own
+    LsRemote {
+        dir: RemoteDirPath,
+    },
+    Trust {
+        addr: RemotePeerAddr,
+    },
+    Untrust {
+        addr: RemotePeerAddr,
+    },
+    Browse {
+        name: ShareName,
+        path: Option<String>,
+    },
+    /// Vouches for the pinned key of `addr` to the owner of a mounted share,
+    /// the introduction expires after `ttl` seconds
+    Introduce {
+        name: ShareName,
+        addr: RemotePeerAddr,
+        ttl: u64,
+    },
 }
 
-impl From<&ConnectCommand> for ConnectMessage {
+impl From<&ConnectCommand> for ClientMessage {
     fn from(value: &ConnectCommand) -> Self {
-        match &value {
-            ConnectCommand::Ls => Self::Ls,
-            ConnectCommand::Mount { name, path } => Self::Mount {
-                path: path.to_string_lossy().to_string(),
+        let message = match &value {
+            ConnectCommand::Ls { dir: None, .. } => ConnectMessage::Ls,
+            ConnectCommand::Ls { dir: Some(dir), .. } => {
+                ConnectMessage::LsRemote { dir: dir.clone() }
+            }
+            ConnectCommand::Mount {
+                name,
+                path,
+                options,
+            } => ConnectMessage::Mount {
+                path: path.as_ref().map(|p| p.to_string_lossy().to_string()),
                 name: name.clone(),
+                options: *options,
             },
-            ConnectCommand::Unmount { name } => Self::Unmount { name: name.clone() },
-        }
+            ConnectCommand::Reconnect { name } => {
+                return Self::ReconnectMount { name: name.clone() };
+            }
+            ConnectCommand::Status { addr } => ConnectMessage::Status { addr: addr.clone() },
+            ConnectCommand::Unmount { name } => ConnectMessage::Unmount { name: name.clone() },
+            ConnectCommand::Trust { addr } => ConnectMessage::Trust { addr: addr.clone() },
+            ConnectCommand::Untrust { addr } => ConnectMessage::Untrust { addr: addr.clone() },
+            ConnectCommand::Browse { name, path } => ConnectMessage::Browse {
+                name: name.clone(),
+                path: path.as_ref().map(|p| p.to_string_lossy().to_string()),
+            },
+            ConnectCommand::Introduce { name, addr, ttl } => ConnectMessage::Introduce {
+                name: name.clone(),
+                addr: addr.clone(),
+                ttl: *ttl,
+            },
+        };
+        Self::Connect(message)
     }
 }
 
 #[derive(Encode, Decode, Clone, Debug, IsVariant)]
 pub enum ShareMessage {
-    Ls,
+    Ls {
+        availability: Option<ShareAvailability>,
+        idle_only: bool,
+    },
     Remove {
         name: CommonShareName,
     },
+    Size {
+        name: CommonShareName,
+    },
     Share {
         path: String,
         name: Option<CommonShareName>,
+        options: ShareOptions,
+        no_overlap: bool,
+        force: bool,
+    },
+    Repath {
+        name: CommonShareName,
+        path: String,
+        expected_current: Option<String>,
+        no_overlap: bool,
+        force: bool,
+    },
+    /// Shares named after their dirs, each one succeeds or fails on its own
+    ShareMany {
+        paths: Vec<String>,
+        options: ShareOptions,
+        no_overlap: bool,
+        force: bool,
     },
 }
 
-impl From<&ShareCommand> for ShareMessage {
-    fn from(value: &ShareCommand) -> Self {
-        match &value {
-            ShareCommand::Ls => Self::Ls,
-            ShareCommand::Remove { name } => Self::Remove { name: name.clone() },
-            ShareCommand::Share { path, name } => Self::Share {
-                path: path.to_string_lossy().to_string(),
+impl TryFrom<&ShareCommand> for ClientMessage {
+    type Error = LocalCommandError;
+
+    fn try_from(value: &ShareCommand) -> Result<Self, Self::Error> {
+        let message = match &value {
+            ShareCommand::Exists { name, .. } => {
+                return Ok(Self::ShareExists { name: name.clone() });
+            }
+            ShareCommand::Ls {
+                available_only,
+                unavailable_only,
+                idle,
+            } => ShareMessage::Ls {
+                availability: match (available_only, unavailable_only) {
+                    (true, _) => Some(ShareAvailability::Available),
+                    (_, true) => Some(ShareAvailability::Unavailable),
+                    _ => None,
+                },
+                idle_only: *idle,
+            },
+            ShareCommand::Remove { name } => ShareMessage::Remove { name: name.clone() },
+            ShareCommand::Repath {
+                name,
+                path,
+                expected_current,
+                no_overlap,
+                force,
+            } => ShareMessage::Repath {
                 name: name.clone(),
+                path: path.to_string_lossy().to_string(),
+                expected_current: expected_current
+                    .as_ref()
+                    .map(|p| p.to_string_lossy().to_string()),
+                no_overlap: *no_overlap,
+                force: *force,
             },
+            ShareCommand::Size { name } => ShareMessage::Size { name: name.clone() },
+            ShareCommand::Validate { .. } => {
+                return Err(LocalCommandError {
+                    command: "share validate",
+                });
+            }
+            ShareCommand::Share {
+                paths,
+                name,
+                options,
+                no_overlap,
+                force,
+            } => match paths.as_slice() {
+                [path] => ShareMessage::Share {
+                    path: path.to_string_lossy().to_string(),
+                    name: name.clone(),
+                    options: options.clone(),
+                    no_overlap: *no_overlap,
+                    force: *force,
+                },
+                paths => ShareMessage::ShareMany {
+                    paths: paths
+                        .iter()
+                        .map(|path| path.to_string_lossy().to_string())
+                        .collect(),
+                    options: options.clone(),
+                    no_overlap: *no_overlap,
+                    force: *force,
+                },
+            },
+        };
+        Ok(Self::Share(message))
+    }
+}
+
+/// Longest banner of a share in bytes, longer ones from peers are cut
+pub const MAX_BANNER_LEN: usize = 1024;
+
+/// Settings of a share chosen by its owner
+#[derive(clap::Args, Encode, Decode, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShareOptions {
+    /// Name of the dir mounters should use when they dont specify a path
+    #[arg(long = "suggest-mount", value_parser = mount_suggestion_parser)]
+    pub suggested_mount: Option<String>,
+    /// Remove the share after this long, e.g. 90s, 30m, 1h or 2d
+    #[arg(long = "expires-in", value_parser = duration_secs_parser)]
+    pub expires_in: Option<u64>,
+    /// Match paths requested by peers regardless of case, for clients on
+    /// case insensitive filesystems. Makes lookups slower
+    #[arg(long = "case-insensitive")]
+    pub case_insensitive: bool,
+    /// Show mounters the same modification time for every file
+    #[arg(long = "hide-mtime")]
+    pub hide_mtime: bool,
+    /// Show mounters default permissions instead of the real ones
+    #[arg(long = "hide-mode")]
+    pub hide_mode: bool,
+    /// Shell command run whenever a peer connects to the share, it gets the
+    /// share name and the peer address as `$1` and `$2`
+    #[arg(long = "on-connect")]
+    pub on_connect: Option<String>,
+    /// Like `--on-connect`, run whenever a peer disconnects from the share
+    #[arg(long = "on-disconnect")]
+    pub on_disconnect: Option<String>,
+    /// Only show peers paths matching this glob, matched from the share root
+    /// like `docs` or `src/*.rs`. Can be repeated
+    #[arg(long = "include", value_parser = glob_parser)]
+    pub include: Vec<String>,
+    /// Hide paths matching this glob from peers, a pattern without `/` like
+    /// `.git` matches at any depth. Can be repeated
+    #[arg(long = "exclude", value_parser = glob_parser)]
+    pub exclude: Vec<String>,
+    /// Let mounters read the extended attributes of files
+    #[arg(long = "xattrs")]
+    pub xattrs: bool,
+    /// Tell mounters the content hash of files, so identical files like in
+    /// backups are downloaded once. Files get hashed on first request
+    #[arg(long = "dedup")]
+    pub dedup: bool,
+    /// Message shown to everyone mounting the share, like terms or contact info
+    #[arg(long = "banner", value_parser = banner_parser)]
+    pub banner: Option<String>,
+}
+
+/// Settings of a mount chosen by the mounter
+#[derive(clap::Args, Encode, Decode, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MountOptions {
+    /// Show every file as owned by this uid, e.g. `$(id -u)` when the owners
+    /// on the remote dont exist locally
+    #[arg(long = "map-uid")]
+    pub map_uid: Option<u32>,
+    /// Show every file as owned by this gid
+    #[arg(long = "map-gid")]
+    pub map_gid: Option<u32>,
+    /// Only mount if the peer proves to have this static key, given in hex or
+    /// base64. Checked again whenever the mount reconnects
+    #[arg(long = "pin-key", value_parser = trust::parse_key)]
+    pub pin_key: Option<PublicKey>,
+}
+
+/// How much of its status a daemon tells peers that ask for it
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatusExposure {
+    /// Refuse to tell anything
+    None,
+    /// Names of the shares
+    Shares,
+    /// Names of the shares and the number of connected peers
+    Full,
+}
+
+/// AEAD cipher protecting peer sessions
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Display, PartialEq, Eq)]
+pub enum Cipher {
+    /// Fastest with hardware AES support
+    #[value(name = "aesgcm")]
+    #[display("AESGCM")]
+    AesGcm,
+    /// Fast and constant time without hardware support
+    #[value(name = "chachapoly")]
+    #[display("ChaChaPoly")]
+    ChaChaPoly,
+}
+
+/// Why the server is going away
+#[derive(Encode, Decode, Clone, Copy, Debug, Display, PartialEq, Eq)]
+pub enum ShutdownReason {
+    #[display("killed by a client")]
+    Kill,
+    #[display("nothing left to serve")]
+    Idle,
+}
+
+/// What happened to one dir of `share share` with several dirs
+#[derive(Encode, Decode, Clone, Debug)]
+pub struct ShareOutcomeDto {
+    pub path: String,
+    pub result: Result<CommonShareName, ServerErrorDto>,
+}
+
+impl fmt::Display for ShareOutcomeDto {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.result {
+            Ok(name) => write!(f, "{}: shared as {name}", self.path),
+            Err(err) => write!(
+                f,
+                "{}: error: {:?}",
+                self.path,
+                anyhow::Error::from(err.clone())
+            ),
         }
     }
 }
 
+/// File transfer in progress between this daemon and a peer
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct TransferDto {
+    pub share: String,
+    pub path: String,
+    pub peer: SocketAddrV4,
+    pub done: u64,
+    pub total: u64,
+    /// Average bytes per second since the transfer started
+    pub rate: u64,
+}
+
+impl fmt::Display for TransferDto {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{} with {}: {}/{} bytes, {} B/s",
+            self.share, self.path, self.peer, self.done, self.total, self.rate
+        )
+    }
+}
+
+/// Public status of a remote daemon, filtered by its owner
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct PeerStatusDto {
+    pub shares: Vec<CommonShareName>,
+    pub peers: Option<u32>,
+}
+
+impl fmt::Display for PeerStatusDto {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "shares:")?;
+        for name in &self.shares {
+            writeln!(f, "  {name}")?;
+        }
+        if let Some(peers) = self.peers {
+            writeln!(f, "peers: {peers}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Entry of a dir in a remote share
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct DirEntryDto {
+    pub name: String,
+    pub kind: DirEntryKind,
+    pub size: u64,
+    /// Seconds since the unix epoch, 0 if the share hides it
+    pub mtime: u64,
+}
+
+impl DirEntryDto {
+    pub fn to_json(&self) -> String {
+        let kind = match self.kind {
+            DirEntryKind::File => "file",
+            DirEntryKind::Dir => "dir",
+            DirEntryKind::Symlink => "symlink",
+        };
+        format!(
+            r#"{{"name":{},"kind":"{kind}","size":{},"mtime":{}}}"#,
+            json_string(&self.name),
+            self.size,
+            self.mtime,
+        )
+    }
+}
+
+impl fmt::Display for DirEntryDto {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {:>12} {}", self.kind, self.size, self.name)
+    }
+}
+
+#[derive(Encode, Decode, Clone, Copy, Debug, Display, PartialEq, Eq, IsVariant)]
+pub enum DirEntryKind {
+    #[display("-")]
+    File,
+    #[display("d")]
+    Dir,
+    #[display("l")]
+    Symlink,
+}
+
+/// Shares advertised by the daemons found in the local network
+#[derive(Encode, Decode, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DiscoveredDto {
+    pub peers: Vec<DiscoveredPeerDto>,
+}
+
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct DiscoveredPeerDto {
+    pub addr: SocketAddrV4,
+    /// Round trip time of the discovery request
+    pub rtt_ms: u32,
+    pub shares: Vec<CommonShareName>,
+}
+
+impl DiscoveredDto {
+    /// Whether more than one peer advertises a share with this name, so
+    /// connecting to it needs `<IP>/<NAME>`
+    pub fn is_ambiguous(&self, name: &CommonShareName) -> bool {
+        self.peers
+            .iter()
+            .filter(|peer| peer.shares.contains(name))
+            .count()
+            > 1
+    }
+
+    /// Address of the only peer advertising a share with this name
+    pub fn owner(
+        &self,
+        name: &CommonShareName,
+    ) -> Result<Option<SocketAddrV4>, AmbiguousShareNameError> {
+        let mut owners = self.peers.iter().filter(|peer| peer.shares.contains(name));
+        match (owners.next(), owners.next()) {
+            (Some(peer), None) => Ok(Some(peer.addr)),
+            (None, _) => Ok(None),
+            (Some(_), Some(_)) => Err(AmbiguousShareNameError { name: name.clone() }),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let peers = self
+            .peers
+            .iter()
+            .map(|peer| {
+                let shares = peer
+                    .shares
+                    .iter()
+                    .map(|name| {
+                        format!(
+                            r#"{{"name":{},"ambiguous":{}}}"#,
+                            json_string(&name.to_string()),
+                            self.is_ambiguous(name)
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join(",");
+                format!(
+                    r#"{{"addr":{},"rtt_ms":{},"shares":[{shares}]}}"#,
+                    json_string(&peer.addr.to_string()),
+                    peer.rtt_ms,
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(r#"{{"peers":[{peers}]}}"#)
+    }
+}
+
+impl fmt::Display for DiscoveredDto {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.peers.is_empty() {
+            return writeln!(f, "No peers found");
+        }
+        let mut peers: Vec<_> = self.peers.iter().collect();
+        peers.sort_by_key(|peer| peer.addr);
+        let mut any_ambiguous = false;
+        for peer in peers {
+            writeln!(f, "{} ({} ms)", peer.addr, peer.rtt_ms)?;
+            for name in &peer.shares {
+                match self.is_ambiguous(name) {
+                    true => {
+                        any_ambiguous = true;
+                        writeln!(f, "  {name} *")?;
+                    }
+                    false => writeln!(f, "  {name}")?,
+                }
+            }
+        }
+        if any_ambiguous {
+            writeln!(f, "* offered by several peers, connect as <IP>/<NAME>")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(clap::ValueEnum, Encode, Decode, Clone, Copy, Debug, Display, PartialEq, Eq)]
+#[display(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(value: LogLevel) -> Self {
+        match value {
+            LogLevel::Off => Self::OFF,
+            LogLevel::Error => Self::ERROR,
+            LogLevel::Warn => Self::WARN,
+            LogLevel::Info => Self::INFO,
+            LogLevel::Debug => Self::DEBUG,
+            LogLevel::Trace => Self::TRACE,
+        }
+    }
+}
+
+impl From<LevelFilter> for LogLevel {
+    fn from(value: LevelFilter) -> Self {
+        match value {
+            LevelFilter::ERROR => Self::Error,
+            LevelFilter::WARN => Self::Warn,
+            LevelFilter::INFO => Self::Info,
+            LevelFilter::DEBUG => Self::Debug,
+            LevelFilter::TRACE => Self::Trace,
+            _ => Self::Off,
+        }
+    }
+}
+
+#[derive(Encode, Decode, Clone, Copy, Debug, IsVariant, PartialEq, Eq)]
+pub enum ShareAvailability {
+    Available,
+    Unavailable,
+}
+
+impl ShareAvailability {
+    pub fn matches(self, share: &Share) -> bool {
+        self.is_available() == share.is_available()
+    }
+}
+
 #[derive(Encode, Decode, Clone, Debug, From, IsVariant)]
 pub enum ServerResponse {
+    Bool(bool),
     Err(ServerErrorDto),
+    LogLevel {
+        previous: LogLevel,
+    },
     LsMountedShares(RemoteSharesDto),
     LsShares(SharesDto),
     Ok,
     Pong,
+    ShareSize {
+        name: CommonShareName,
+        bytes: u64,
+    },
     Status {
         peers: PeersDto,
         remote_shares: RemoteSharesDto,
         shares: SharesDto,
     },
+    Version(BuildInfo),
+    Config(ConfigDto),
+    PeerStatus(PeerStatusDto),
+    /// Dir of a remote share listed by `rdir connect ls` or `rdir connect
+    /// browse`, sorted by name
+    DirEntries(Vec<DirEntryDto>),
+    /// The command succeeded, but something about it looks wrong
+    Warning(String),
+    /// Sent instead of the response when the server shuts down first
+    ShuttingDown {
+        reason: ShutdownReason,
+    },
+    #[from(skip)]
+    DebugDump(String),
+    Transfers(Vec<TransferDto>),
+    /// Outcome of every part of a batch, some could have failed
+    PartialOk(Vec<ShareOutcomeDto>),
+    Discovered(DiscoveredDto),
+    /// Number of peers dropped by `rdir peer disconnect`
+    Disconnected {
+        peers: u32,
+    },
+    /// Key pinned by `rdir connect trust`, in hex
+    #[from(skip)]
+    Trusted {
+        key: String,
+    },
+    /// The share got mounted, along with the banner of its owner
+    Mounted {
+        banner: Option<String>,
+    },
 }
 
 impl fmt::Display for ServerResponse {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            ServerResponse::Bool(_) => Ok(()),
+            ServerResponse::Config(config) => write!(f, "{config}"),
+            ServerResponse::DebugDump(dump) => writeln!(f, "{dump}"),
             ServerResponse::Err(err) => {
                 writeln!(f, "error: {:?}", anyhow::Error::from(err.clone()))
             }
+            ServerResponse::LogLevel { previous } => writeln!(f, "previous level: {previous}"),
             ServerResponse::LsMountedShares(remote_shares_dto) => write!(f, "{remote_shares_dto}"),
             ServerResponse::LsShares(shares_dto) => write!(f, "{shares_dto}"),
             ServerResponse::Ok => Ok(()),
+            ServerResponse::PeerStatus(status) => write!(f, "{status}"),
             ServerResponse::Pong => Ok(()),
+            ServerResponse::ShareSize { name, bytes } => writeln!(f, "{name}: {bytes} bytes"),
             ServerResponse::Status {
                 peers,
                 remote_shares,
@@ -119,6 +789,40 @@ impl fmt::Display for ServerResponse {
                 writeln!(f, "{remote_shares}")?;
                 writeln!(f, "{shares}")
             }
+            ServerResponse::Version(build_info) => writeln!(f, "{build_info}"),
+            ServerResponse::DirEntries(entries) if entries.is_empty() => {
+                writeln!(f, "Directory is empty")
+            }
+            ServerResponse::DirEntries(entries) => {
+                for entry in entries {
+                    writeln!(f, "{entry}")?;
+                }
+                Ok(())
+            }
+            ServerResponse::Warning(warning) => writeln!(f, "warning: {warning}"),
+            ServerResponse::ShuttingDown { reason } => writeln!(f, "daemon exiting: {reason}"),
+            ServerResponse::PartialOk(outcomes) => {
+                for outcome in outcomes {
+                    writeln!(f, "{outcome}")?;
+                }
+                Ok(())
+            }
+            ServerResponse::Discovered(discovered) => write!(f, "{discovered}"),
+            ServerResponse::Disconnected { peers } => writeln!(f, "Disconnected {peers} peers"),
+            ServerResponse::Trusted { key } => writeln!(f, "Pinned the key {key}"),
+            ServerResponse::Mounted { banner: None } => Ok(()),
+            ServerResponse::Mounted {
+                banner: Some(banner),
+            } => writeln!(f, "{banner}"),
+            ServerResponse::Transfers(transfers) if transfers.is_empty() => {
+                writeln!(f, "No transfers in progress")
+            }
+            ServerResponse::Transfers(transfers) => {
+                for transfer in transfers {
+                    writeln!(f, "{transfer}")?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -175,10 +879,128 @@ impl fmt::Display for RemoteShareDto {
     }
 }
 
+/// Settings a process runs with, once flags, env vars and env files are applied
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct ConfigDto {
+    /// Value of every global flag, by the long name of the flag
+    pub settings: Vec<(String, String)>,
+}
+
+impl ConfigDto {
+    /// `log_level` is the level the server runs with now, it can change at
+    /// runtime. The level in `args` is reported without one
+    pub fn new(args: &Args, log_level: Option<LogLevel>) -> Self {
+        let unset = || "-".to_string();
+        let path = |path: &Path| path.to_string_lossy().to_string();
+        let settings = [
+            ("tmpdir", path(&args.tmp_dir)),
+            (
+                "automount",
+                args.automount.as_deref().map_or_else(unset, path),
+            ),
+            ("log-level", log_level.unwrap_or(args.log_level).to_string()),
+            ("log-prefix", args.log_prefix.clone()),
+            ("error-log-prefix", args.error_log_prefix.clone()),
+            (
+                "env-file",
+                args.env_file.as_deref().map_or_else(unset, path),
+            ),
+            ("tcp-socket", args.tcp_socket_or_default().to_string()),
+            (
+                "namespace",
+                args.namespace
+                    .as_ref()
+                    .map_or_else(unset, ToString::to_string),
+            ),
+            (
+                "udp-socket",
+                args.udp_socket.map_or_else(unset, |s| s.to_string()),
+            ),
+            (
+                "stream-idle-timeout",
+                format!("{}s", args.stream_idle_timeout),
+            ),
+            ("request-timeout", format!("{}s", args.request_timeout)),
+            ("connect-timeout", format!("{}s", args.connect_timeout)),
+            (
+                "transfer-log-after",
+                format!("{}s", args.transfer_log_after),
+            ),
+            (
+                "transfer-log-interval",
+                format!("{}s", args.transfer_log_interval),
+            ),
+            ("peer-max-requests", args.peer_max_requests.to_string()),
+            (
+                "peer-max-request-bytes",
+                args.peer_max_request_bytes.to_string(),
+            ),
+            (
+                "require-forward-secrecy",
+                args.require_forward_secrecy.to_string(),
+            ),
+            ("same-host-socket", args.same_host_socket.to_string()),
+            ("walk-concurrency", args.walk_concurrency.to_string()),
+            (
+                "max-concurrent-reads",
+                args.max_concurrent_reads.to_string(),
+            ),
+            (
+                "max-concurrent-reads-per-peer",
+                args.max_concurrent_reads_per_peer.to_string(),
+            ),
+            ("expose-status", value_name(args.expose_status)),
+            ("cipher", args.cipher.map_or_else(unset, value_name)),
+            (
+                "fair-bandwidth",
+                args.fair_bandwidth.map_or_else(unset, |b| b.to_string()),
+            ),
+            (
+                "io-buffer-budget",
+                args.io_buffer_budget.map_or_else(unset, |b| b.to_string()),
+            ),
+            ("allow-debug", args.allow_debug.to_string()),
+            (
+                "accept-introductions",
+                args.accept_introductions.to_string(),
+            ),
+            ("sandbox", args.sandbox.to_string()),
+        ];
+        Self {
+            settings: settings
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+        }
+    }
+}
+
+/// Name a flag takes `value` by
+fn value_name(value: impl clap::ValueEnum) -> String {
+    value
+        .to_possible_value()
+        .map(|value| value.get_name().to_string())
+        .unwrap_or_default()
+}
+
+impl fmt::Display for ConfigDto {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, value) in &self.settings {
+            writeln!(f, "{name}: {value}")?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Encode, Decode, Clone, Debug)]
 pub struct ShareDto {
     pub name: CommonShareName,
     pub path: String,
+    pub available: bool,
+    /// Seconds left until the share expires
+    pub expires_in: Option<u64>,
+    /// Mounted remote share this share lies in
+    pub reexports: Option<FullShareName>,
     pub participants: Vec<PeerId>,
 }
 
@@ -187,6 +1009,11 @@ impl From<&Share> for ShareDto {
         Self {
             name: value.name.clone(),
             path: value.path.to_string_lossy().to_string(),
+            available: value.is_available(),
+            expires_in: value
+                .expires_at
+                .map(|at| at.saturating_duration_since(Instant::now()).as_secs()),
+            reexports: None,
             participants: value.participants.iter().cloned().collect(),
         }
     }
@@ -196,6 +1023,15 @@ impl fmt::Display for ShareDto {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "  {}:", self.name)?;
         writeln!(f, "    path: {}", self.path)?;
+        if !self.available {
+            writeln!(f, "    unavailable: directory is missing")?;
+        }
+        if let Some(expires_in) = self.expires_in {
+            writeln!(f, "    expires in: {expires_in}s")?;
+        }
+        if let Some(remote) = &self.reexports {
+            writeln!(f, "    re-exports: {remote}")?;
+        }
         write!(
             f,
             "    participants: {}",
@@ -241,13 +1077,38 @@ impl fmt::Display for SharesDto {
 #[derive(Debug, Display, Error, From, IsVariant)]
 #[display("Server encountered an error while processing the command")]
 pub enum ServerError {
+    AmbiguousShareName(AmbiguousShareNameError),
+    #[display("Failed to browse the share: {_0}")]
+    Browse(PeerResponseError),
+    #[display("Peer refused the introduction: {_0}")]
+    #[from(skip)]
+    Introduce(PeerResponseError),
     #[display("Specified share name is invalid")]
     CommonShareNameParse(CommonShareNameParseError),
     ConnectToRemoteShare(ConnectToRemoteShareError),
+    DebugDisabled(DebugDisabledError),
+    DiscoveryDisabled(DiscoveryDisabledError),
+    FuseUnavailable(FuseUnavailableError),
+    #[display("Path has no dir name to name the share after, give the share a name")]
     InvalidShareName,
+    #[display("Failed to read the shared directory")]
+    Io(io::Error),
+    ListRemoteDir(ListRemoteDirError),
+    #[display("Failed to change the log level")]
+    LogLevel(tracing_subscriber::reload::Error),
+    NoSuchRemoteShare(NoSuchRemoteShareError),
+    NotPinned(NotPinnedError),
     PeerIo(NoiseStreamError),
+    PeerStatus(PeerStatusError),
+    Protocol(ProtocolError),
+    RefusedSensitivePath(RefusedSensitivePathError),
     RepeatedShare(RepeatedShare),
+    Sandboxed(SandboxedError),
+    ServerBusy(ServerBusyError),
     ShareDoesntExit(ShareDoesntExistError),
+    SharePathOverlap(SharePathOverlapError),
+    UnknownCommand(UnknownCommandError),
+    UpdateSharePath(UpdateSharePathError),
 }
 
 #[derive(Encode, Decode, Clone, Debug, Display, Error, From, IsVariant)]
@@ -255,22 +1116,73 @@ pub enum ServerErrorDto {
     #[display("Specified share name is invalid")]
     CommonShareNameParse(CommonShareNameParseError),
     ConnectToRemoteShare(ConnectToRemoteShareErrorDto),
+    FuseUnavailable(#[error(ignore)] FuseUnavailableError),
+    #[display("Path has no dir name to name the share after, give the share a name")]
     InvalidShareName,
+    #[display("{_0}")]
+    Io(#[error(ignore)] String),
+    #[display("{_0}")]
+    #[from(skip)]
+    LogLevel(#[error(ignore)] String),
     #[display("Error while communicating with a peer")]
     PeerIo(FramedErrorDto),
     RepeatedShare(#[error(ignore)] RepeatedShare),
     ShareDoesntExit(#[error(ignore)] ShareDoesntExistError),
+    UnknownCommand(#[error(ignore)] UnknownCommandError),
+    NoSuchRemoteShare(#[error(ignore)] NoSuchRemoteShareError),
+    #[display("{_0}")]
+    #[from(skip)]
+    PeerStatus(#[error(ignore)] String),
+    UpdateSharePath(#[error(ignore)] UpdateSharePathError),
+    #[display("{_0}")]
+    #[from(skip)]
+    ListRemoteDir(#[error(ignore)] String),
+    SharePathOverlap(#[error(ignore)] SharePathOverlapError),
+    DebugDisabled(#[error(ignore)] DebugDisabledError),
+    Protocol(#[error(ignore)] ProtocolError),
+    RefusedSensitivePath(#[error(ignore)] RefusedSensitivePathError),
+    DiscoveryDisabled(#[error(ignore)] DiscoveryDisabledError),
+    AmbiguousShareName(#[error(ignore)] AmbiguousShareNameError),
+    ServerBusy(#[error(ignore)] ServerBusyError),
+    #[display("Failed to browse the share: {_0}")]
+    Browse(#[error(ignore)] PeerResponseError),
+    Sandboxed(#[error(ignore)] SandboxedError),
+    #[display("Peer refused the introduction: {_0}")]
+    #[from(skip)]
+    Introduce(#[error(ignore)] PeerResponseError),
+    NotPinned(#[error(ignore)] NotPinnedError),
 }
 
 impl From<ServerError> for ServerErrorDto {
     fn from(value: ServerError) -> Self {
         match value {
+            ServerError::AmbiguousShareName(err) => Self::AmbiguousShareName(err),
+            ServerError::Browse(err) => Self::Browse(err),
             ServerError::CommonShareNameParse(err) => Self::CommonShareNameParse(err),
             ServerError::ConnectToRemoteShare(err) => Self::ConnectToRemoteShare(err.into()),
-            ServerError::InvalidShareName => todo!(),
+            ServerError::DebugDisabled(err) => Self::DebugDisabled(err),
+            ServerError::DiscoveryDisabled(err) => Self::DiscoveryDisabled(err),
+            ServerError::FuseUnavailable(err) => Self::FuseUnavailable(err),
+            ServerError::Introduce(err) => Self::Introduce(err),
+            ServerError::InvalidShareName => Self::InvalidShareName,
+            ServerError::Io(err) => Self::Io(anyhow::Error::from(err).to_string()),
+            ServerError::ListRemoteDir(err) => {
+                Self::ListRemoteDir(anyhow::Error::from(err).to_string())
+            }
+            ServerError::LogLevel(err) => Self::LogLevel(anyhow::Error::from(err).to_string()),
+            ServerError::NoSuchRemoteShare(err) => Self::NoSuchRemoteShare(err),
+            ServerError::NotPinned(err) => Self::NotPinned(err),
             ServerError::PeerIo(err) => Self::PeerIo(err.into()),
+            ServerError::PeerStatus(err) => Self::PeerStatus(anyhow::Error::from(err).to_string()),
+            ServerError::Protocol(err) => Self::Protocol(err),
+            ServerError::RefusedSensitivePath(err) => Self::RefusedSensitivePath(err),
             ServerError::RepeatedShare(err) => Self::RepeatedShare(err),
+            ServerError::Sandboxed(err) => Self::Sandboxed(err),
+            ServerError::ServerBusy(err) => Self::ServerBusy(err),
             ServerError::ShareDoesntExit(err) => Self::ShareDoesntExit(err),
+            ServerError::SharePathOverlap(err) => Self::SharePathOverlap(err),
+            ServerError::UnknownCommand(err) => Self::UnknownCommand(err),
+            ServerError::UpdateSharePath(err) => Self::UpdateSharePath(err),
         }
     }
 }
@@ -282,6 +1194,8 @@ pub enum FramedErrorDto {
     Crypto(#[error(ignore)] String),
     #[display("{_0}")]
     Io(#[error(ignore)] String),
+    #[display("Peer is unreachable: {_0}")]
+    PeerUnreachable(#[error(ignore)] String),
 }
 
 impl From<NoiseStreamError> for FramedErrorDto {
@@ -289,28 +1203,149 @@ impl From<NoiseStreamError> for FramedErrorDto {
         match value {
             NoiseStreamError::Io(err) => Self::Io(anyhow::Error::from(err).to_string()),
             NoiseStreamError::Crypto(err) => Self::Crypto(anyhow::Error::from(err).to_string()),
+            NoiseStreamError::PeerUnreachable(err) => {
+                Self::PeerUnreachable(anyhow::Error::from(err).to_string())
+            }
+            err @ (NoiseStreamError::UnsupportedCipher(_)
+            | NoiseStreamError::CipherMismatch { .. }
+            | NoiseStreamError::ProtocolVersion(_)) => Self::Crypto(err.to_string()),
         }
     }
 }
 
 #[derive(Encode, Decode, Clone, Debug, Display, Error, IsVariant)]
 pub enum ConnectToRemoteShareErrorDto {
-    #[display("{_0}")]
-    Io(#[error(ignore)] String),
+    #[display("{category}: {message}")]
+    Io {
+        category: ConnectionErrorCategory,
+        message: String,
+    },
     ShareDoesntExist(ShareDoesntExistError),
     RepeatedRemoteShare(RepeatedRemoteShareError),
     RepeatedPeer(RepeatedPeerError),
     ProtocolError(ProtocolError),
+    #[display("No mount path was given and the share doesnt suggest one")]
+    NoMountPath,
+    #[display("Peer closed the connection before answering, it might be unstable")]
+    PeerClosedDuringHandshake,
+    InvalidMountPath(InvalidMountPathError),
+}
+
+/// Coarse cause of a failed connection, lets clients react without parsing messages
+#[derive(Encode, Decode, Clone, Copy, Debug, Display, PartialEq, Eq, IsVariant)]
+pub enum ConnectionErrorCategory {
+    #[display("Peer is unreachable")]
+    Unreachable,
+    #[display("Handshake failed")]
+    HandshakeFailed,
+    #[display("Peer is not trusted")]
+    Untrusted,
+    #[display("Peer broke the protocol")]
+    Protocol,
+    #[display("Timed out")]
+    Timeout,
+    #[display("IO error")]
+    Io,
 }
 
 impl From<ConnectToRemoteShareError> for ConnectToRemoteShareErrorDto {
     fn from(value: ConnectToRemoteShareError) -> Self {
         match value {
-            ConnectToRemoteShareError::Io(err) => Self::Io(anyhow::Error::from(err).to_string()),
+            ConnectToRemoteShareError::Io(err) => Self::Io {
+                category: err.category(),
+                message: anyhow::Error::from(err).to_string(),
+            },
             ConnectToRemoteShareError::ShareDoesntExist(err) => Self::ShareDoesntExist(err),
             ConnectToRemoteShareError::RepeatedRemoteShare(err) => Self::RepeatedRemoteShare(err),
             ConnectToRemoteShareError::RepeatedPeer(err) => Self::RepeatedPeer(err),
             ConnectToRemoteShareError::ProtocolError(err) => Self::ProtocolError(err),
+            ConnectToRemoteShareError::NoMountPath => Self::NoMountPath,
+            ConnectToRemoteShareError::PeerClosedDuringHandshake => Self::PeerClosedDuringHandshake,
+            ConnectToRemoteShareError::InvalidMountPath(err) => Self::InvalidMountPath(err),
+            ConnectToRemoteShareError::KeyMismatch(err) => Self::Io {
+                category: ConnectionErrorCategory::Untrusted,
+                message: err.to_string(),
+            },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use clap::{CommandFactory, Parser};
+
+    use super::*;
+
+    #[test]
+    fn local_commands_have_no_message() {
+        let message = |args: &[&str]| ClientMessage::try_from(&Args::parse_from(args));
+        assert!(matches!(
+            message(&["rdir", "connect", "reconnect", "127.0.0.1/A"]),
+            Ok(ClientMessage::ReconnectMount { .. })
+        ));
+        assert!(matches!(
+            message(&["rdir", "share", "exists", "A"]),
+            Ok(ClientMessage::ShareExists { .. })
+        ));
+        let validate = message(&["rdir", "share", "validate", "/shares"]);
+        assert_eq!(validate.unwrap_err().command, "share validate");
+        let peer_only = message(&["rdir", "peer-only", "--shares-config", "/shares"]);
+        assert_eq!(peer_only.unwrap_err().command, "peer-only");
+        assert!(message(&["rdir", "selftest", "mount"]).is_err());
+        assert!(message(&["rdir", "rotate-key", "--force"]).is_err());
+    }
+
+    #[test]
+    fn config_lists_every_global_flag() {
+        let command = Args::command();
+        let flags: Vec<_> = command
+            .get_arguments()
+            .filter(|arg| arg.is_global_set())
+            .filter_map(|arg| arg.get_long())
+            .collect();
+        let config = ConfigDto::new(&Args::parse_from(["rdir", "ls"]), None);
+        let names: Vec<_> = config.settings.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, flags);
+    }
+
+    #[test]
+    fn discovered_groups_shares_by_peer() {
+        let discovered = DiscoveredDto {
+            peers: vec![
+                DiscoveredPeerDto {
+                    addr: "192.168.1.20:4242".parse().unwrap(),
+                    rtt_ms: 12,
+                    shares: vec!["photos".parse().unwrap()],
+                },
+                DiscoveredPeerDto {
+                    addr: "192.168.1.10:4242".parse().unwrap(),
+                    rtt_ms: 3,
+                    shares: vec!["docs".parse().unwrap(), "photos".parse().unwrap()],
+                },
+            ],
+        };
+        assert_eq!(
+            ServerResponse::Discovered(discovered.clone()).to_string(),
+            "\
+192.168.1.10:4242 (3 ms)
+  docs
+  photos *
+192.168.1.20:4242 (12 ms)
+  photos *
+* offered by several peers, connect as <IP>/<NAME>
+"
+        );
+        assert_eq!(
+            discovered.to_json(),
+            r#"{"peers":[{"addr":"192.168.1.20:4242","rtt_ms":12,"shares":[{"name":"photos","ambiguous":true}]},{"addr":"192.168.1.10:4242","rtt_ms":3,"shares":[{"name":"docs","ambiguous":false},{"name":"photos","ambiguous":true}]}]}"#
+        );
+
+        let owner = |name: &str| discovered.owner(&name.parse().unwrap());
+        assert_eq!(
+            owner("docs"),
+            Ok(Some("192.168.1.10:4242".parse().unwrap()))
+        );
+        assert!(owner("photos").is_err());
+        assert_eq!(owner("music"), Ok(None));
+    }
+}
@@ -9,6 +9,9 @@ use derive_more::{AsRef, Display, Error, From, IsVariant};
 use crate::server::NETWORK_PORT;
 
 pub const MAX_SHARE_NAME_LENGTH: usize = 60;
+pub const MAX_NAMESPACE_LENGTH: usize = 20;
+/// Separates the namespace of a server from the name of its share on the wire
+pub const NAMESPACE_SEPARATOR: char = ':';
 
 #[derive(
     Encode, Decode, Clone, Debug, Display, From, IsVariant, PartialEq, Eq, PartialOrd, Ord,
@@ -67,6 +70,31 @@ pub enum FullShareNameParseError {
     NoSeparator,
 }
 
+/// Dir of a remote share, `<IP>/<NAME>` for its root or `<IP>/<NAME>/<PATH>`
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct RemoteDirPath {
+    pub share: FullShareName,
+    /// Relative to the root of the share, empty for the root itself
+    pub path: String,
+}
+
+impl FromStr for RemoteDirPath {
+    type Err = FullShareNameParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (raw_addr, rest) = s.split_once('/').ok_or(Self::Err::NoSeparator)?;
+        let (raw_name, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let share = FullShareName {
+            addr: RemotePeerAddr::from_str(raw_addr)?,
+            name: CommonShareName::from_str(raw_name)?,
+        };
+        Ok(Self {
+            share,
+            path: path.to_string(),
+        })
+    }
+}
+
 #[derive(Encode, Decode, Clone, Debug, Display, PartialEq, Eq, PartialOrd, Ord)]
 #[display("{addr}{}", port.as_ref()
     .map(|p| format!(":{}", p))
@@ -112,6 +140,15 @@ pub enum RemotePeerAddrParseError {
     PortNumber(#[error(ignore)] String),
 }
 
+impl From<SocketAddrV4> for RemotePeerAddr {
+    fn from(val: SocketAddrV4) -> Self {
+        Self {
+            addr: *val.ip(),
+            port: (val.port() != NETWORK_PORT).then_some(val.port()),
+        }
+    }
+}
+
 impl From<RemotePeerAddr> for SocketAddrV4 {
     fn from(val: RemotePeerAddr) -> Self {
         SocketAddrV4::new(val.addr, val.port.unwrap_or(NETWORK_PORT))
@@ -131,7 +168,17 @@ impl FromStr for CommonShareName {
     type Err = CommonShareNameParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.len() > MAX_SHARE_NAME_LENGTH {
+        // The namespace doesnt count, so every local name can be mounted. A
+        // separator always ends one, so no name is mistaken for a namespaced one
+        let local = match s.split_once(NAMESPACE_SEPARATOR) {
+            Some((namespace, local)) if namespace.parse::<Namespace>().is_ok() => local,
+            Some(_) => return Err(Self::Err::StraySeparator),
+            None => s,
+        };
+        if local.contains(NAMESPACE_SEPARATOR) {
+            return Err(Self::Err::StraySeparator);
+        }
+        if local.len() > MAX_SHARE_NAME_LENGTH {
             return Err(Self::Err::NameTooLong);
         }
 
@@ -139,10 +186,91 @@ impl FromStr for CommonShareName {
     }
 }
 
+impl CommonShareName {
+    /// Name of a share of this server, which gets its namespace from
+    /// `--namespace` rather than from the name
+    pub fn local(s: &str) -> Result<Self, CommonShareNameParseError> {
+        let name: Self = s.parse()?;
+        match split_namespace(&name.0) {
+            Some(_) => Err(CommonShareNameParseError::Namespaced),
+            None => Ok(name),
+        }
+    }
+
+    /// Name without the namespace of the server sharing it, what a mounter
+    /// calls the share
+    pub fn without_namespace(&self) -> Self {
+        match split_namespace(&self.0) {
+            Some((_, local)) => Self(local.to_string()),
+            None => self.clone(),
+        }
+    }
+}
+
 #[derive(Encode, Decode, Clone, Debug, Display, Error, IsVariant, PartialEq, Eq)]
 pub enum CommonShareNameParseError {
     #[display("Name of a share cannot exceed {MAX_SHARE_NAME_LENGTH} characters")]
     NameTooLong,
+    #[display("Name of a share can only contain '{NAMESPACE_SEPARATOR}' right after a namespace")]
+    StraySeparator,
+    #[display("Name of a local share cannot have a namespace, use --namespace instead")]
+    Namespaced,
+}
+
+/// Prefix a server gives the names of its shares on the wire, so shares of
+/// many users on one discovery domain dont collide
+#[derive(Clone, Debug, Display, PartialEq, Eq)]
+pub struct Namespace(String);
+
+impl FromStr for Namespace {
+    type Err = NamespaceParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err(Self::Err::Empty);
+        }
+        if s.len() > MAX_NAMESPACE_LENGTH {
+            return Err(Self::Err::TooLong);
+        }
+        let invalid = s
+            .chars()
+            .find(|c| !c.is_ascii_alphanumeric() && !matches!(c, '-' | '_'));
+        match invalid {
+            Some(c) => Err(Self::Err::InvalidCharacter(c)),
+            None => Ok(Self(s.to_string())),
+        }
+    }
+}
+
+impl Namespace {
+    /// Name a local share goes by on the wire
+    pub fn prefix(&self, name: &CommonShareName) -> CommonShareName {
+        CommonShareName(format!("{}{NAMESPACE_SEPARATOR}{}", self.0, name.0))
+    }
+
+    /// Local share a name from the wire refers to, `None` unless it is in
+    /// this namespace
+    pub fn strip(&self, name: &CommonShareName) -> Option<CommonShareName> {
+        let (namespace, local) = split_namespace(&name.0)?;
+        (namespace == self.0).then(|| CommonShareName(local.to_string()))
+    }
+}
+
+#[derive(Clone, Debug, Display, Error, IsVariant, PartialEq, Eq)]
+pub enum NamespaceParseError {
+    #[display("Namespace cannot be empty")]
+    Empty,
+    #[display("Namespace cannot exceed {MAX_NAMESPACE_LENGTH} characters")]
+    TooLong,
+    #[display("Namespace can only contain ascii letters, digits, '-' and '_', not {_0:?}")]
+    InvalidCharacter(#[error(ignore)] char),
+}
+
+/// Splits off the namespace, if the part before the separator is a valid one
+fn split_namespace(name: &str) -> Option<(&str, &str)> {
+    let (namespace, local) = name.split_once(NAMESPACE_SEPARATOR)?;
+    namespace.parse::<Namespace>().ok()?;
+    Some((namespace, local))
 }
 
 #[cfg(test)]
@@ -160,6 +288,45 @@ mod tests {
         );
     }
 
+    #[test]
+    fn namespace_parse() {
+        let namespace = Namespace::from_str("team-A_1").unwrap();
+        let name = CommonShareName::from_str("photos").unwrap();
+        let prefixed = namespace.prefix(&name);
+        assert_eq!(prefixed.to_string(), "team-A_1:photos");
+        assert_eq!(namespace.strip(&prefixed), Some(name.clone()));
+        assert_eq!(namespace.strip(&name), None);
+        assert_eq!(prefixed.without_namespace(), name);
+        // A separator has to end a namespace
+        for odd in ["a b:photos", ":photos", "team:a:photos"] {
+            assert!(
+                CommonShareName::from_str(odd)
+                    .unwrap_err()
+                    .is_stray_separator(),
+                "{odd}"
+            );
+        }
+        assert!(CommonShareName::local("photos").is_ok());
+        assert!(
+            CommonShareName::local("team:photos")
+                .unwrap_err()
+                .is_namespaced()
+        );
+
+        let long = format!("team:{}", "A".repeat(MAX_SHARE_NAME_LENGTH));
+        assert!(CommonShareName::from_str(&long).is_ok());
+        assert!(Namespace::from_str("").unwrap_err().is_empty());
+        assert!(
+            Namespace::from_str(&"a".repeat(MAX_NAMESPACE_LENGTH + 1))
+                .unwrap_err()
+                .is_too_long()
+        );
+        assert_eq!(
+            Namespace::from_str("team:a").unwrap_err(),
+            NamespaceParseError::InvalidCharacter(':')
+        );
+    }
+
     #[test]
     fn full_share_name_parse() {
         let name = FullShareName::from_str("1.2.3.4/Example").unwrap();
@@ -195,6 +362,32 @@ mod tests {
         );
     }
 
+    #[test]
+    fn remote_dir_path_parse() {
+        let dir = RemoteDirPath::from_str("1.2.3.4/Example").unwrap();
+        assert_eq!(
+            dir.share,
+            FullShareName::from_str("1.2.3.4/Example").unwrap()
+        );
+        assert_eq!(dir.path, "");
+
+        let dir = RemoteDirPath::from_str("1.2.3.4:1234/Example/photos/2024").unwrap();
+        assert_eq!(dir.share.addr.port, Some(1234));
+        assert_eq!(dir.path, "photos/2024");
+
+        assert!(
+            RemoteDirPath::from_str("Example")
+                .unwrap_err()
+                .is_no_separator()
+        );
+        let too_long = format!("1.2.3.4/{}/photos", "A".repeat(MAX_SHARE_NAME_LENGTH + 1));
+        assert!(
+            RemoteDirPath::from_str(&too_long)
+                .unwrap_err()
+                .is_invalid_common_share_name()
+        );
+    }
+
     #[test]
     fn share_name_parse() {
         assert!(ShareName::from_str("Example").unwrap().is_common());
@@ -0,0 +1,235 @@
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use bitcode::{Decode, Encode};
+use derive_more::{Display, Error};
+use nix::fcntl::{Flock, FlockArg};
+
+use crate::{
+    common::shares::RemotePeerAddr,
+    server::{content_hash::to_hex, net::PublicKey},
+};
+
+/// File under the tmp dir with the pinned keys of peers
+pub const TRUST_STORE_NAME: &str = "known_peers";
+
+/// Static keys of peers, pinned on the first connection so a peer
+/// impersonated later on is caught. Every line is `<ADDR> <HEX KEY>`
+#[derive(Debug)]
+pub struct TrustStore {
+    path: PathBuf,
+}
+
+/// Outcome of comparing the key of a peer against the pinned one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyCheck {
+    /// First contact, the key is pinned from now on
+    Recorded,
+    Matches,
+    Mismatch,
+}
+
+#[derive(Encode, Decode, Clone, Debug, Display, Error)]
+#[display(
+    "Key of {addr} differs from the pinned one, someone could be impersonating it. If the peer \
+     changed its key, run `rdir connect trust {addr}`"
+)]
+pub struct KeyMismatchError {
+    pub addr: RemotePeerAddr,
+}
+
+#[derive(Encode, Decode, Clone, Debug, Display, Error)]
+#[display(
+    "No key of {addr} is pinned, mount one of its shares or run `rdir connect trust {addr}` first"
+)]
+pub struct NotPinnedError {
+    pub addr: RemotePeerAddr,
+}
+
+impl TrustStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Compares `key` against the pinned one, pinning it if there is none
+    pub fn check(&self, addr: &RemotePeerAddr, key: &PublicKey) -> io::Result<KeyCheck> {
+        self.update(|keys| match keys.get(addr) {
+            Some(stored) if stored == key => KeyCheck::Matches,
+            Some(_) => KeyCheck::Mismatch,
+            None => {
+                keys.insert(addr.clone(), *key);
+                KeyCheck::Recorded
+            }
+        })
+    }
+
+    /// Key pinned for the peer, if any
+    pub fn pinned(&self, addr: &RemotePeerAddr) -> io::Result<Option<PublicKey>> {
+        self.update(|keys| keys.get(addr).copied())
+    }
+
+    /// Whether `key` is pinned for any peer
+    pub fn is_pinned(&self, key: &PublicKey) -> io::Result<bool> {
+        self.update(|keys| keys.values().any(|pinned| pinned == key))
+    }
+
+    /// Pins `key`, replacing the previous key of the peer
+    pub fn trust(&self, addr: &RemotePeerAddr, key: &PublicKey) -> io::Result<()> {
+        self.update(|keys| {
+            keys.insert(addr.clone(), *key);
+        })
+    }
+
+    /// Forgets the key of the peer, the next connection pins a new one.
+    /// Returns if there was any
+    pub fn untrust(&self, addr: &RemotePeerAddr) -> io::Result<bool> {
+        self.update(|keys| keys.remove(addr).is_some())
+    }
+
+    /// Runs `f` on the stored keys while holding a lock on the file, so
+    /// several servers sharing the tmp dir dont lose each others changes
+    fn update<R>(
+        &self,
+        f: impl FnOnce(&mut BTreeMap<RemotePeerAddr, PublicKey>) -> R,
+    ) -> io::Result<R> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(&self.path)?;
+        let mut file = Flock::lock(file, FlockArg::LockExclusive).map_err(|(_, err)| err)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let mut keys = parse(&contents, &self.path)?;
+        let before = keys.clone();
+        let result = f(&mut keys);
+        if keys != before {
+            write(&mut file, &keys)?;
+        }
+        Ok(result)
+    }
+}
+
+fn parse(contents: &str, path: &Path) -> io::Result<BTreeMap<RemotePeerAddr, PublicKey>> {
+    let invalid = |line: &str| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid line in {}: {line}", path.to_string_lossy()),
+        )
+    };
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let (addr, key) = line.split_once(' ').ok_or_else(|| invalid(line))?;
+            let addr = addr.parse().map_err(|_| invalid(line))?;
+            let key = parse_hex_key(key.trim()).ok_or_else(|| invalid(line))?;
+            Ok((addr, key))
+        })
+        .collect()
+}
+
+/// Key given in hex, the way rdir prints keys, or in base64
+pub fn parse_key(s: &str) -> Result<PublicKey, String> {
+    parse_hex_key(s)
+        .or_else(|| parse_base64_key(s))
+        .ok_or_else(|| {
+            format!(
+                "Expected a key of {} bytes in hex or base64",
+                size_of::<PublicKey>()
+            )
+        })
+}
+
+/// Takes the standard and the URL safe alphabet, with or without padding
+fn parse_base64_key(base64: &str) -> Option<PublicKey> {
+    let mut bytes = Vec::with_capacity(size_of::<PublicKey>());
+    let (mut bits, mut len) = (0u32, 0);
+    for c in base64.trim_end_matches('=').bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => return None,
+        };
+        bits = (bits << 6) | u32::from(value);
+        len += 6;
+        if len >= 8 {
+            len -= 8;
+            bytes.push((bits >> len) as u8);
+            bits &= (1 << len) - 1;
+        }
+    }
+    bytes.try_into().ok()
+}
+
+fn parse_hex_key(hex: &str) -> Option<PublicKey> {
+    if hex.len() != 2 * size_of::<PublicKey>() || !hex.is_ascii() {
+        return None;
+    }
+    let mut key = PublicKey::default();
+    for (byte, pair) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(key)
+}
+
+fn write(file: &mut File, keys: &BTreeMap<RemotePeerAddr, PublicKey>) -> io::Result<()> {
+    let contents: String = keys
+        .iter()
+        .map(|(addr, key)| format!("{addr} {}\n", to_hex(key)))
+        .collect();
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn keys_are_pinned_on_first_contact() {
+        let dir = TestDir::new("trust");
+        let store = TrustStore::new(dir.join(TRUST_STORE_NAME));
+        let addr: RemotePeerAddr = "10.0.0.1".parse().unwrap();
+        let other: RemotePeerAddr = "10.0.0.1:4000".parse().unwrap();
+
+        assert_eq!(store.check(&addr, &[1; 32]).unwrap(), KeyCheck::Recorded);
+        assert_eq!(store.check(&addr, &[1; 32]).unwrap(), KeyCheck::Matches);
+        assert_eq!(store.check(&addr, &[2; 32]).unwrap(), KeyCheck::Mismatch);
+        assert_eq!(store.check(&other, &[2; 32]).unwrap(), KeyCheck::Recorded);
+        assert_eq!(store.pinned(&addr).unwrap(), Some([1; 32]));
+        assert!(store.is_pinned(&[2; 32]).unwrap());
+        assert!(!store.is_pinned(&[3; 32]).unwrap());
+
+        store.trust(&addr, &[2; 32]).unwrap();
+        assert_eq!(store.check(&addr, &[2; 32]).unwrap(), KeyCheck::Matches);
+        assert!(store.untrust(&addr).unwrap());
+        assert!(!store.untrust(&addr).unwrap());
+        assert_eq!(store.pinned(&addr).unwrap(), None);
+        assert_eq!(store.check(&addr, &[3; 32]).unwrap(), KeyCheck::Recorded);
+    }
+
+    #[test]
+    fn keys_parse_from_hex_and_base64() {
+        let key: PublicKey = std::array::from_fn(|i| (i * 8) as u8);
+        assert_eq!(parse_key(&to_hex(&key)), Ok(key));
+        let base64 = "AAgQGCAoMDhASFBYYGhweICIkJigqLC4wMjQ2ODo8Pg=";
+        assert_eq!(parse_key(base64), Ok(key));
+        assert_eq!(parse_key(base64.trim_end_matches('=')), Ok(key));
+        assert!(parse_key("AAgQ").is_err());
+        assert!(parse_key(&base64.replace('A', "*")).is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use std::fmt;
+
+use bitcode::{Decode, Encode};
+
+/// Version of the messages clients, servers and peers exchange. Bumped
+/// whenever an existing message changes its encoding, which the wire vectors
+/// catch, or the handshake payload does. Clients and peers of another version
+/// are refused
+pub const PROTOCOL_VERSION: u16 = 6;
+
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct BuildInfo {
+    pub version: String,
+    pub git_commit: String,
+    pub rustc_version: String,
+    pub features: Vec<String>,
+}
+
+impl BuildInfo {
+    /// Build info of the running binary
+    pub fn current() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_commit: env!("RDIR_GIT_COMMIT").to_string(),
+            rustc_version: env!("RDIR_RUSTC_VERSION").to_string(),
+            features: env!("RDIR_FEATURES")
+                .split(',')
+                .filter(|f| !f.is_empty())
+                .map(ToString::to_string)
+                .collect(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let features = self
+            .features
+            .iter()
+            .map(|f| json_string(f))
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            r#"{{"version":{},"git_commit":{},"rustc_version":{},"features":[{features}]}}"#,
+            json_string(&self.version),
+            json_string(&self.git_commit),
+            json_string(&self.rustc_version),
+        )
+    }
+}
+
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rdir {} ({})", self.version, self.git_commit)?;
+        write!(f, ", {}", self.rustc_version)?;
+        if !self.features.is_empty() {
+            write!(f, ", features: {}", self.features.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats the client and the optional server build info as one JSON object
+pub fn versions_json(client: &BuildInfo, server: Option<&BuildInfo>) -> String {
+    format!(
+        r#"{{"client":{},"server":{}}}"#,
+        client.to_json(),
+        server.map(BuildInfo::to_json).as_deref().unwrap_or("null"),
+    )
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_contains_version() {
+        let info = BuildInfo::current();
+        let json = versions_json(&info, None);
+        assert!(json.contains(&format!(r#""version":"{}""#, env!("CARGO_PKG_VERSION"))));
+        assert!(json.ends_with(r#""server":null}"#));
+    }
+
+    #[test]
+    fn json_escapes_strings() {
+        assert_eq!(json_string(r#"a"b\c"#), r#""a\"b\\c""#);
+    }
+}
@@ -0,0 +1,148 @@
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    fs,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result as AnyResult};
+
+pub const DEFAULT_ENV_FILE: &str = "rdir.env";
+const ENV_FILE_FLAG: &str = "--env-file";
+
+/// Loads variables from the env file into the process environment. Variables
+/// already set in the environment take precedence over the file.
+///
+/// Must be called before any other threads are spawned.
+pub fn load() -> AnyResult<()> {
+    let (path, explicit) = match env_file_arg(env::args_os()) {
+        Some(path) => (path, true),
+        None => (PathBuf::from(DEFAULT_ENV_FILE), false),
+    };
+    let content = match fs::read_to_string(&path) {
+        Ok(val) => val,
+        Err(err) if !explicit && err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => {
+            return Err(err).context(format!(
+                "Failed to read the env file at: {}",
+                path.to_string_lossy()
+            ));
+        }
+    };
+
+    for (key, value) in unset_vars(parse(&content, &path)?, |k| env::var_os(k).is_some()) {
+        // SAFETY: called at the start of `main` while the process is single threaded
+        unsafe { env::set_var(key, value) };
+    }
+    Ok(())
+}
+
+/// Finds the value of `--env-file` before clap parses the args, which dont
+/// have to be UTF-8
+fn env_file_arg(args: impl IntoIterator<Item = OsString>) -> Option<PathBuf> {
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == ENV_FILE_FLAG {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(val) = arg
+            .as_bytes()
+            .strip_prefix(ENV_FILE_FLAG.as_bytes())
+            .and_then(|a| a.strip_prefix(b"="))
+        {
+            return Some(PathBuf::from(OsStr::from_bytes(val)));
+        }
+    }
+    None
+}
+
+/// Parses `KEY=VALUE` lines, ignoring blank lines, `#` comments and an
+/// optional `export ` prefix. Values may be wrapped in single or double quotes
+fn parse<'a>(content: &'a str, path: &Path) -> AnyResult<Vec<(&'a str, &'a str)>> {
+    let mut vars = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = line.split_once('=').with_context(|| {
+            format!(
+                "Expected KEY=VALUE on line {} of {}",
+                i + 1,
+                path.to_string_lossy()
+            )
+        })?;
+        let value = value.trim();
+        let value = [('"', '"'), ('\'', '\'')]
+            .iter()
+            .find_map(|(start, end)| value.strip_prefix(*start)?.strip_suffix(*end))
+            .unwrap_or(value);
+        vars.push((key.trim(), value));
+    }
+    Ok(vars)
+}
+
+/// Drops the variables that are already set in the environment
+fn unset_vars<'a>(
+    vars: Vec<(&'a str, &'a str)>,
+    is_set: impl Fn(&str) -> bool,
+) -> Vec<(&'a str, &'a str)> {
+    vars.into_iter().filter(|(key, _)| !is_set(key)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_file_parse() {
+        let content = r#"
+            # comment
+            RDIR_TMPDIR=/var/tmp
+            export RDIR_TCP_SOCKET = "0.0.0.0:29284"
+            RDIR_UDP_SOCKET='0.0.0.0:29284'
+        "#;
+        let vars = parse(content, Path::new(DEFAULT_ENV_FILE)).unwrap();
+        assert_eq!(
+            vars,
+            [
+                ("RDIR_TMPDIR", "/var/tmp"),
+                ("RDIR_TCP_SOCKET", "0.0.0.0:29284"),
+                ("RDIR_UDP_SOCKET", "0.0.0.0:29284"),
+            ]
+        );
+        assert!(parse("NO_SEPARATOR", Path::new(DEFAULT_ENV_FILE)).is_err());
+    }
+
+    #[test]
+    fn real_env_takes_precedence() {
+        let vars = parse(
+            "RDIR_TMPDIR=/var/tmp\nRDIR_TCP_SOCKET=0.0.0.0:1",
+            Path::new(DEFAULT_ENV_FILE),
+        )
+        .unwrap();
+        let vars = unset_vars(vars, |key| key == "RDIR_TCP_SOCKET");
+        assert_eq!(vars, [("RDIR_TMPDIR", "/var/tmp")]);
+    }
+
+    #[test]
+    fn env_file_flag() {
+        let args = |a: &[&str]| env_file_arg(a.iter().map(OsString::from));
+        assert_eq!(
+            args(&["rdir", "--env-file", "/a.env", "ls"]),
+            Some("/a.env".into())
+        );
+        assert_eq!(args(&["rdir", "--env-file=/b.env"]), Some("/b.env".into()));
+        assert_eq!(args(&["rdir", "ls"]), None);
+
+        let non_utf8 = OsStr::from_bytes(b"/caf\xe9.env");
+        let args = [
+            OsString::from("rdir"),
+            OsString::from("--env-file"),
+            non_utf8.into(),
+        ];
+        assert_eq!(env_file_arg(args), Some(non_utf8.into()));
+    }
+}
@@ -1,11 +1,6 @@
-use std::{
-    fs,
-    os::unix::net::{UnixListener, UnixStream},
-    path::Path,
-};
+use std::{fs, os::unix::net::UnixStream, path::Path, time::Duration};
 
 use anyhow::{Context, Result as AnyResult};
-use clap::Parser;
 use nix::unistd::{ForkResult, fork};
 
 use crate::server::SOCKET_NAME;
@@ -13,21 +8,46 @@ use crate::server::SOCKET_NAME;
 mod args;
 mod client;
 mod common;
+mod env_file;
 mod server;
+#[cfg(test)]
+mod test_dir;
+mod tmp_dir;
 
 fn main() -> AnyResult<()> {
-    let args = args::Args::parse();
+    env_file::load()?;
+    let args = args::Args::parse_checked();
+    // Created before the server gets spawned, so the client doesnt race it
+    match args.expects_active_server()
+        || args.command.is_peer_only()
+        || args.command.is_rotate_key()
+        || args.command.is_selftest()
+    {
+        true => tmp_dir::prepare(&args.tmp_dir)?,
+        false => {
+            tmp_dir::prepare_existing(&args.tmp_dir)?;
+        }
+    }
+    if args.command.is_peer_only() {
+        return server::Server::run(args, None);
+    }
+    if let args::Command::Selftest {
+        command: args::SelftestCommand::Mount,
+    } = &args.command
+    {
+        return server::selftest::run(&args.tmp_dir);
+    }
+    if let args::Command::RotateKey { force, grace } = &args.command {
+        let grace = Duration::from_secs(*grace);
+        return server::rotate_key(&args.tmp_dir, *force, grace);
+    }
 
     let sock_path = args.tmp_dir.join(SOCKET_NAME);
     let mut is_client = true;
     let maybe_sock = try_connect(&sock_path);
     let mut maybe_listener = None;
     if args.expects_active_server() && maybe_sock.is_none() {
-        let _ = fs::create_dir(&args.tmp_dir);
-        let listener = UnixListener::bind(&sock_path).context(format!(
-            "Failed to create a unix socket at: {}",
-            sock_path.to_string_lossy()
-        ))?;
+        let listener = tmp_dir::bind_socket(&args.tmp_dir, SOCKET_NAME)?;
 
         match unsafe { fork() } {
             Ok(ForkResult::Parent { .. }) => {
@@ -43,7 +63,7 @@ fn main() -> AnyResult<()> {
 
     match is_client {
         true => client::Client::run(args, maybe_sock),
-        false => server::Server::run(args, maybe_listener.unwrap()),
+        false => server::Server::run(args, maybe_listener),
     }
 }
 
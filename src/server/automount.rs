@@ -0,0 +1,284 @@
+use std::{fs, future::Future, path::Path, path::PathBuf, time::Duration};
+
+use anyhow::{Context, Result as AnyResult, bail};
+use backoff::{ExponentialBackoff, ExponentialBackoffBuilder, backoff::Backoff};
+use smol::{
+    Timer,
+    channel::{Receiver, Sender},
+    future::FutureExt,
+};
+use tracing::{error, info};
+
+use crate::{
+    common::{ConnectToRemoteShareErrorDto, MountOptions, shares::FullShareName, trust},
+    server::{ConnectToRemoteShareError, net::PublicKey},
+};
+
+/// Sent to a retrying automount to attempt a mount right away, gets the outcome back
+pub type ReconnectReply = Sender<Result<(), ConnectToRemoteShareErrorDto>>;
+
+/// Remote share mounted when the server starts
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Automount {
+    pub name: FullShareName,
+    pub path: Option<PathBuf>,
+    /// Key the peer has to prove to have, like `--pin-key`
+    pub pin_key: Option<PublicKey>,
+}
+
+impl Automount {
+    pub fn options(&self) -> MountOptions {
+        MountOptions {
+            pin_key: self.pin_key,
+            ..Default::default()
+        }
+    }
+}
+
+/// Reads the automount config. Each line is
+/// `FULL_SHARE_NAME [MOUNT_PATH] [PIN_KEY]`, blank lines and `#` comments are
+/// ignored. Without a mount path, or with `-` in its place, the one suggested
+/// by the share is used
+pub fn load(path: &Path) -> AnyResult<Vec<Automount>> {
+    let content = fs::read_to_string(path).context(format!(
+        "Failed to read the automount config at: {}",
+        path.to_string_lossy()
+    ))?;
+    parse(&content, path)
+}
+
+fn parse(content: &str, path: &Path) -> AnyResult<Vec<Automount>> {
+    let mut mounts = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let context = || format!("On line {} of {}", i + 1, path.to_string_lossy());
+        let mut fields = line.split_whitespace();
+        let name = fields
+            .next()
+            .unwrap_or_default()
+            .parse()
+            .with_context(context)?;
+        let mount_path = fields.next().filter(|p| *p != "-").map(PathBuf::from);
+        let pin_key = fields
+            .next()
+            .map(trust::parse_key)
+            .transpose()
+            .map_err(anyhow::Error::msg)
+            .with_context(context)?;
+        if fields.next().is_some() {
+            bail!(
+                "Expected FULL_SHARE_NAME [MOUNT_PATH] [PIN_KEY]. {}",
+                context()
+            );
+        }
+        // The daemon changes its working dir, so relative paths would be surprising
+        if mount_path.as_ref().is_some_and(|p| p.is_relative()) {
+            bail!("Mount path has to be absolute. {}", context());
+        }
+        mounts.push(Automount {
+            name,
+            path: mount_path,
+            pin_key,
+        });
+    }
+    Ok(mounts)
+}
+
+pub fn retry_backoff() -> ExponentialBackoff {
+    ExponentialBackoffBuilder::new()
+        .with_initial_interval(Duration::from_secs(1))
+        .with_max_interval(Duration::from_secs(60))
+        .with_max_elapsed_time(None)
+        .build()
+}
+
+/// Calls `mount` until it succeeds, waiting longer after every failure. Gives up
+/// only on errors that retrying cant fix. A reply sent on `reconnect_rx` cuts
+/// the wait short and resets the backoff. Returns whether the share ended up
+/// mounted, by this or another mount
+pub async fn retry<F, Fut>(
+    automount: &Automount,
+    mut backoff: ExponentialBackoff,
+    reconnect_rx: Receiver<ReconnectReply>,
+    mut mount: F,
+) -> bool
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), ConnectToRemoteShareError>>,
+{
+    let mut forced: Option<ReconnectReply> = None;
+    loop {
+        let result = mount().await;
+        let mounted = matches!(
+            result,
+            Ok(()) | Err(ConnectToRemoteShareError::RepeatedRemoteShare(_))
+        );
+        let delay = match &result {
+            Ok(()) => {
+                info!("Automounted {}", automount.name);
+                None
+            }
+            Err(ConnectToRemoteShareError::RepeatedRemoteShare(_)) => None,
+            Err(err @ ConnectToRemoteShareError::NoMountPath) => {
+                error!("Failed to automount {}: {err}", automount.name);
+                None
+            }
+            Err(err) => {
+                let delay = backoff.next_backoff();
+                match delay {
+                    Some(delay) => error!(
+                        "Failed to automount {}, retrying in {delay:?}: {err}",
+                        automount.name
+                    ),
+                    None => error!("Gave up automounting {}: {err}", automount.name),
+                }
+                delay
+            }
+        };
+        if let Some(reply) = forced.take() {
+            let _ = reply.try_send(result.map_err(Into::into));
+        }
+        let Some(delay) = delay else {
+            return mounted;
+        };
+
+        let natural = async {
+            Timer::after(delay).await;
+            None
+        };
+        forced = natural.or(next_forced(&reconnect_rx)).await;
+        if forced.is_some() {
+            backoff.reset();
+        }
+    }
+}
+
+async fn next_forced(reconnect_rx: &Receiver<ReconnectReply>) -> Option<ReconnectReply> {
+    match reconnect_rx.recv().await {
+        Ok(reply) => Some(reply),
+        // Nobody can force a reconnect anymore, wait out the backoff
+        Err(_) => smol::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, time::Instant};
+
+    use smol::{channel::bounded, future::zip};
+
+    use super::*;
+    use crate::server::state::RepeatedRemoteShareError;
+
+    #[test]
+    fn parses_automounts() {
+        let content = "# always mounted\n127.0.0.1:29284/photos /mnt/photos\n\n10.0.0.2/docs\n";
+        let mounts = parse(content, Path::new("automount.conf")).unwrap();
+        assert_eq!(
+            mounts,
+            [
+                Automount {
+                    name: "127.0.0.1:29284/photos".parse().unwrap(),
+                    path: Some("/mnt/photos".into()),
+                    pin_key: None,
+                },
+                Automount {
+                    name: "10.0.0.2/docs".parse().unwrap(),
+                    path: None,
+                    pin_key: None,
+                },
+            ]
+        );
+
+        let path = Path::new("automount.conf");
+        let key = "00".repeat(size_of::<PublicKey>());
+        let mounts = parse(&format!("10.0.0.2/docs - {key}"), path).unwrap();
+        assert_eq!(mounts[0].path, None);
+        assert_eq!(mounts[0].options().pin_key, Some([0; 32]));
+        assert!(parse("photos /mnt/photos", path).is_err());
+        assert!(parse("10.0.0.2/docs mnt/docs", path).is_err());
+        assert!(parse("10.0.0.2/docs /mnt/docs pin", path).is_err());
+        let extra = format!("10.0.0.2/docs /mnt/docs {key} more");
+        assert!(parse(&extra, path).is_err());
+    }
+
+    #[test]
+    fn failed_automount_is_retried() {
+        let automount = Automount {
+            name: "127.0.0.1/photos".parse().unwrap(),
+            path: None,
+            pin_key: None,
+        };
+        let backoff = || {
+            ExponentialBackoffBuilder::new()
+                .with_initial_interval(Duration::from_millis(1))
+                .with_max_elapsed_time(None)
+                .build()
+        };
+
+        let attempts = Cell::new(0);
+        let (_reconnect_tx, reconnect_rx) = bounded(1);
+        let mounted = smol::block_on(retry(&automount, backoff(), reconnect_rx.clone(), || {
+            attempts.set(attempts.get() + 1);
+            let result = match attempts.get() {
+                1 | 2 => Err(ConnectToRemoteShareError::PeerClosedDuringHandshake),
+                _ => Ok(()),
+            };
+            async { result }
+        }));
+        assert_eq!(attempts.get(), 3);
+        assert!(mounted);
+
+        // Already mounted, nothing to retry
+        attempts.set(0);
+        let mounted = smol::block_on(retry(&automount, backoff(), reconnect_rx.clone(), || {
+            attempts.set(attempts.get() + 1);
+            async { Err(RepeatedRemoteShareError.into()) }
+        }));
+        assert_eq!(attempts.get(), 1);
+        assert!(mounted);
+
+        let mounted = smol::block_on(retry(&automount, backoff(), reconnect_rx, || async {
+            Err(ConnectToRemoteShareError::NoMountPath)
+        }));
+        assert!(!mounted);
+    }
+
+    #[test]
+    fn forced_reconnect_skips_the_backoff() {
+        let automount = Automount {
+            name: "127.0.0.1/photos".parse().unwrap(),
+            path: None,
+            pin_key: None,
+        };
+        let backoff = ExponentialBackoffBuilder::new()
+            .with_initial_interval(Duration::from_secs(60))
+            .with_max_elapsed_time(None)
+            .build();
+        let (reconnect_tx, reconnect_rx) = bounded(1);
+        let attempts = Cell::new(0);
+        let start = Instant::now();
+
+        let retry = retry(&automount, backoff, reconnect_rx, || {
+            attempts.set(attempts.get() + 1);
+            let result = match attempts.get() {
+                1 => Err(ConnectToRemoteShareError::PeerClosedDuringHandshake),
+                _ => Ok(()),
+            };
+            async { result }
+        });
+        let force = async {
+            let (reply_tx, reply_rx) = bounded(1);
+            reconnect_tx.send(reply_tx).await.unwrap();
+            reply_rx.recv().await.unwrap()
+        };
+        let (mounted, reply) = smol::block_on(zip(retry, force));
+
+        assert!(mounted && reply.is_ok());
+        assert_eq!(attempts.get(), 2);
+        assert!(start.elapsed() < Duration::from_secs(30));
+    }
+}
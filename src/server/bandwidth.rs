@@ -0,0 +1,144 @@
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    num::NonZeroU64,
+    time::{Duration, Instant},
+};
+
+use smol::Timer;
+
+use crate::server::state::PeerId;
+
+/// Longest a read waits for its bytes once the peer used up its burst, well
+/// within the time the mounter waits for the response
+const MAX_READ_WAIT: Duration = Duration::from_millis(500);
+
+/// Splits a total upload rate evenly between the connected peers, so one peer
+/// cant starve the others and a lone peer gets all of it
+#[derive(Debug)]
+pub struct FairBandwidth {
+    total: NonZeroU64,
+    buckets: RefCell<BTreeMap<PeerId, TokenBucket>>,
+}
+
+impl FairBandwidth {
+    pub fn new(total: NonZeroU64) -> Self {
+        Self {
+            total,
+            buckets: Default::default(),
+        }
+    }
+
+    pub fn peer_joined(&self, peer_id: PeerId) {
+        let mut buckets = self.buckets.borrow_mut();
+        buckets
+            .entry(peer_id)
+            .or_insert_with(|| TokenBucket::new(self.total.get()));
+        Self::rebalance(self.total, &mut buckets);
+    }
+
+    pub fn peer_left(&self, peer_id: PeerId) {
+        let mut buckets = self.buckets.borrow_mut();
+        buckets.remove(&peer_id);
+        Self::rebalance(self.total, &mut buckets);
+    }
+
+    fn rebalance(total: NonZeroU64, buckets: &mut BTreeMap<PeerId, TokenBucket>) {
+        let rate = total.get() / buckets.len().max(1) as u64;
+        for bucket in buckets.values_mut() {
+            bucket.set_rate(rate.max(1));
+        }
+    }
+
+    /// Bytes per second the peer currently gets
+    #[cfg(test)]
+    pub fn rate(&self, peer_id: PeerId) -> Option<u64> {
+        self.buckets
+            .borrow()
+            .get(&peer_id)
+            .map(|bucket| bucket.rate)
+    }
+
+    /// Most bytes a single read of the peer may return, so that paying them
+    /// off takes at most [`MAX_READ_WAIT`]
+    pub fn max_read_len(&self, peer_id: PeerId) -> Option<u64> {
+        self.buckets.borrow().get(&peer_id).map(|bucket| {
+            let len = bucket.rate as f64 * MAX_READ_WAIT.as_secs_f64();
+            (len as u64).max(1)
+        })
+    }
+
+    /// Waits until `bytes` more can be sent to the peer
+    pub async fn throttle(&self, peer_id: PeerId, bytes: u64) {
+        let wait = self
+            .buckets
+            .borrow_mut()
+            .get_mut(&peer_id)
+            .map(|bucket| bucket.take(bytes, Instant::now()));
+        if let Some(wait) = wait.filter(|wait| !wait.is_zero()) {
+            Timer::after(wait).await;
+        }
+    }
+}
+
+/// Holds up to a second worth of bytes, so short bursts arent slowed down
+#[derive(Debug)]
+struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            tokens: rate as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn set_rate(&mut self, rate: u64) {
+        self.refill(Instant::now());
+        self.rate = rate;
+        self.tokens = self.tokens.min(rate as f64);
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() * self.rate as f64).min(self.rate as f64);
+        self.last_refill = now;
+    }
+
+    /// Takes `bytes` even if there arent enough tokens, returning how long the
+    /// caller has to wait to pay off the debt
+    fn take(&mut self, bytes: u64, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= bytes as f64;
+        match self.tokens < 0.0 {
+            true => Duration::from_secs_f64(-self.tokens / self.rate as f64),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_waits_off_debt() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(100);
+        bucket.last_refill = start;
+        assert_eq!(bucket.take(100, start), Duration::ZERO);
+        assert_eq!(bucket.take(50, start), Duration::from_millis(500));
+        // Half a second later the debt is paid off
+        let later = start + Duration::from_millis(500);
+        assert_eq!(bucket.take(0, later), Duration::ZERO);
+        // Never holds more than a second worth of bytes
+        let much_later = start + Duration::from_secs(60);
+        assert_eq!(bucket.take(200, much_later), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,90 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, VecDeque},
+    fmt::Write as _,
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use blake2::{Blake2s256, Digest};
+
+/// Most hashes a share remembers, the oldest path is forgotten first
+pub const MAX_CACHED_HASHES: usize = 100_000;
+
+/// BLAKE2s digest of the contents of a file
+pub type ContentHash = [u8; 32];
+
+pub fn hash_file(path: &Path) -> io::Result<ContentHash> {
+    let mut file = File::open(path)?;
+    let mut hasher = Blake2s256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buf)? {
+            0 => break,
+            n => hasher.update(&buf[..n]),
+        }
+    }
+    Ok(hasher.finalize().into())
+}
+
+pub fn hash_bytes(bytes: &[u8]) -> ContentHash {
+    Blake2s256::digest(bytes).into()
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+/// Hashes of the files of a share, so a file is only read again once its size
+/// or mtime changes
+#[derive(Debug, Default)]
+pub struct HashCache {
+    hashes: RefCell<BTreeMap<PathBuf, CachedHash>>,
+    order: RefCell<VecDeque<PathBuf>>,
+}
+
+#[derive(Debug)]
+struct CachedHash {
+    len: u64,
+    modified: SystemTime,
+    hash: ContentHash,
+}
+
+impl HashCache {
+    pub fn get(&self, path: &Path) -> io::Result<ContentHash> {
+        let metadata = path.metadata()?;
+        let modified = metadata.modified()?;
+        if let Some(cached) = self.hashes.borrow().get(path)
+            && cached.len == metadata.len()
+            && cached.modified == modified
+        {
+            return Ok(cached.hash);
+        }
+
+        let hash = hash_file(path)?;
+        let mut hashes = self.hashes.borrow_mut();
+        let mut order = self.order.borrow_mut();
+        if !hashes.contains_key(path) {
+            if hashes.len() >= MAX_CACHED_HASHES
+                && let Some(oldest) = order.pop_front()
+            {
+                hashes.remove(&oldest);
+            }
+            order.push_back(path.to_path_buf());
+        }
+        hashes.insert(
+            path.to_path_buf(),
+            CachedHash {
+                len: metadata.len(),
+                modified,
+                hash,
+            },
+        );
+        Ok(hash)
+    }
+}
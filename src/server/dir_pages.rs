@@ -0,0 +1,200 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    ffi::OsString,
+    io,
+    num::NonZeroUsize,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use bitcode::{Decode, Encode};
+
+use crate::{common::shares::CommonShareName, server::state::Share};
+
+/// Dirs with more entries arent snapshotted, paging them falls back to
+/// continuing after the last name seen
+pub const MAX_SNAPSHOT_ENTRIES: usize = 10_000;
+pub const MAX_SNAPSHOTS: usize = 64;
+/// Most entries a peer gets in one page, whatever it asks for
+pub const MAX_PAGE_LEN: u32 = 1024;
+/// How long a snapshot waits for its next page before being dropped
+pub const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Where the next page of a dir listing starts. Names are listed sorted, so
+/// the next page is whatever comes after `after`
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct PageCursor {
+    snapshot: Option<u64>,
+    /// Raw bytes of the name, names dont have to be valid UTF-8
+    after: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct DirPage {
+    pub names: Vec<OsString>,
+    /// `None` on the last page
+    pub next: Option<PageCursor>,
+}
+
+/// Pages through big dirs for peers. The entries are snapshotted on the first
+/// page, so later pages are consistent with it even if the dir changes in the
+/// meantime. Once a snapshot is too big or timed out, the dir is read again
+/// and the page continues after the last name seen. That still never repeats
+/// a name, but can miss entries created meanwhile or list ones removed since
+#[derive(Debug, Default)]
+pub struct DirPages {
+    next_id: Cell<u64>,
+    snapshots: RefCell<BTreeMap<u64, Snapshot>>,
+}
+
+#[derive(Debug)]
+struct Snapshot {
+    /// The snapshot is only used for pages of the dir it was taken of
+    share: CommonShareName,
+    dir: PathBuf,
+    names: Vec<OsString>,
+    last_used: Instant,
+}
+
+impl DirPages {
+    /// Up to `limit` names of `requested` in the share, starting at `cursor`
+    /// or at the beginning without one
+    pub fn page(
+        &self,
+        share: &Share,
+        requested: &Path,
+        cursor: Option<&PageCursor>,
+        limit: NonZeroUsize,
+    ) -> io::Result<DirPage> {
+        let now = Instant::now();
+        let mut snapshots = self.snapshots.borrow_mut();
+        snapshots.retain(|_, snapshot| now.duration_since(snapshot.last_used) < SNAPSHOT_TIMEOUT);
+
+        let Some(cursor) = cursor else {
+            let names = share.read_dir(requested)?;
+            if names.len() <= limit.get() {
+                return Ok(DirPage { names, next: None });
+            }
+            let snapshot = match names.len() <= MAX_SNAPSHOT_ENTRIES {
+                true => {
+                    if snapshots.len() >= MAX_SNAPSHOTS
+                        && let Some(oldest) = snapshots
+                            .iter()
+                            .min_by_key(|(_, snapshot)| snapshot.last_used)
+                            .map(|(id, _)| *id)
+                    {
+                        snapshots.remove(&oldest);
+                    }
+                    let id = self.next_id.get();
+                    self.next_id.set(id + 1);
+                    snapshots.insert(
+                        id,
+                        Snapshot {
+                            share: share.name.clone(),
+                            dir: requested.to_path_buf(),
+                            names: names.clone(),
+                            last_used: now,
+                        },
+                    );
+                    Some(id)
+                }
+                false => None,
+            };
+            return Ok(Self::cut(&names, 0, limit, snapshot));
+        };
+
+        let snapshot = cursor
+            .snapshot
+            .and_then(|id| snapshots.get_mut(&id))
+            .filter(|snapshot| snapshot.share == share.name && snapshot.dir == requested);
+        let (names, id) = match snapshot {
+            Some(snapshot) => {
+                snapshot.last_used = now;
+                (snapshot.names.clone(), cursor.snapshot)
+            }
+            None => (share.read_dir(requested)?, None),
+        };
+        let start = names.partition_point(|name| name.as_bytes() <= cursor.after.as_slice());
+        let page = Self::cut(&names, start, limit, id);
+        if page.next.is_none()
+            && let Some(id) = id
+        {
+            snapshots.remove(&id);
+        }
+        Ok(page)
+    }
+
+    fn cut(
+        names: &[OsString],
+        start: usize,
+        limit: NonZeroUsize,
+        snapshot: Option<u64>,
+    ) -> DirPage {
+        let end = names.len().min(start + limit.get());
+        let next = match (end < names.len(), end.checked_sub(1)) {
+            (true, Some(last)) => Some(PageCursor {
+                snapshot,
+                after: names[last].as_bytes().to_vec(),
+            }),
+            _ => None,
+        };
+        DirPage {
+            names: names[start.min(end)..end].to_vec(),
+            next,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn pages_dont_repeat_names() {
+        let dir = TestDir::new("dir-pages");
+        for i in 0..10 {
+            fs::write(dir.join(format!("{i:02}")), "").unwrap();
+        }
+        let share = Share::new("dir".parse().unwrap(), dir.to_path_buf());
+        let pages = DirPages::default();
+        let limit = NonZeroUsize::new(3).unwrap();
+
+        let mut names = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = pages
+                .page(&share, Path::new(""), cursor.as_ref(), limit)
+                .unwrap();
+            names.extend(page.names);
+            // Changes after the first page dont show up in the snapshot
+            fs::write(
+                dir.join(format!("{:02}", names.len() - 1))
+                    .with_extension("new"),
+                "",
+            )
+            .unwrap();
+            match page.next {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        let expected: Vec<OsString> = (0..10).map(|i| format!("{i:02}").into()).collect();
+        assert_eq!(names, expected);
+        assert!(pages.snapshots.borrow().is_empty());
+
+        // Without the snapshot the dir is read again, continuing after the name
+        let cursor = PageCursor {
+            snapshot: Some(42),
+            after: b"04".to_vec(),
+        };
+        let page = pages
+            .page(&share, Path::new(""), Some(&cursor), limit)
+            .unwrap();
+        assert_eq!(page.names, ["05", "05.new", "06"]);
+    }
+}
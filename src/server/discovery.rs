@@ -0,0 +1,224 @@
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    hash::{BuildHasher, RandomState},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::{Duration, Instant},
+};
+
+use bitcode::{decode, encode};
+use smol::{
+    Timer,
+    channel::{Sender, unbounded},
+    future::FutureExt,
+    io,
+    net::UdpSocket,
+};
+use tracing::{debug, warn};
+
+use crate::common::{
+    DiscoveredDto, DiscoveredPeerDto,
+    discovery::{DiscoveryPacket, MAX_ANNOUNCED_SHARES, MAX_DISCOVERED_PEERS, MAX_PACKET_SIZE},
+    shares::CommonShareName,
+};
+
+/// Time between the announcements a daemon broadcasts on its own
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Share announced by a daemon, as received by the discovery listener
+type Announced = (SocketAddrV4, Vec<CommonShareName>, Instant);
+
+/// UDP side of a daemon, answers queries of other daemons and collects their
+/// answers for `rdir discover`
+pub struct Discovery {
+    socket: UdpSocket,
+    /// Where queries and announcements are sent, every daemon on the network
+    /// unless changed for tests
+    target: SocketAddrV4,
+    sender: u64,
+    tcp_port: u16,
+    collectors: RefCell<Vec<Sender<Announced>>>,
+}
+
+impl Discovery {
+    /// Binds the UDP socket, peers learn to connect to `tcp_port` from the announcements
+    pub fn bind(addr: SocketAddrV4, tcp_port: u16) -> io::Result<Self> {
+        let socket = std::net::UdpSocket::bind(addr)?;
+        socket.set_broadcast(true)?;
+        let port = socket.local_addr()?.port();
+        Ok(Self {
+            socket: socket.try_into()?,
+            target: SocketAddrV4::new(Ipv4Addr::BROADCAST, port),
+            sender: RandomState::new().hash_one(addr),
+            tcp_port,
+            collectors: Default::default(),
+        })
+    }
+
+    /// Answers queries and hands announcements to running discoveries. `shares`
+    /// lists the shares to announce
+    pub async fn serve(&self, shares: impl Fn() -> Vec<CommonShareName>) {
+        let listen = async {
+            let mut buf = vec![0; MAX_PACKET_SIZE];
+            loop {
+                let (len, from) = match self.socket.recv_from(&mut buf).await {
+                    Ok(received) => received,
+                    Err(err) => {
+                        warn!("Failed to receive a discovery packet: {err}");
+                        continue;
+                    }
+                };
+                let SocketAddr::V4(from) = from else {
+                    continue;
+                };
+                let Ok(packet) = decode::<DiscoveryPacket>(&buf[..len]) else {
+                    debug!("Ignoring a malformed discovery packet from {from}");
+                    continue;
+                };
+                self.handle(packet, from, &shares).await;
+            }
+        };
+        let announce = async {
+            loop {
+                if let Err(err) = self.send(self.announcement(&shares), self.target).await {
+                    warn!("Failed to announce the shares: {err}");
+                }
+                Timer::after(ANNOUNCE_INTERVAL).await;
+            }
+        };
+        listen.or(announce).await
+    }
+
+    async fn handle(
+        &self,
+        packet: DiscoveryPacket,
+        from: SocketAddrV4,
+        shares: &impl Fn() -> Vec<CommonShareName>,
+    ) {
+        match packet {
+            DiscoveryPacket::Query { sender } | DiscoveryPacket::Announce { sender, .. }
+                if sender == self.sender =>
+            {
+                // Our own broadcast
+            }
+            DiscoveryPacket::Query { .. } => {
+                if let Err(err) = self.send(self.announcement(shares), from).await {
+                    debug!("Failed to answer a discovery query of {from}: {err}");
+                }
+            }
+            DiscoveryPacket::Announce { port, shares, .. } => {
+                let peer = SocketAddrV4::new(*from.ip(), port);
+                let mut collectors = self.collectors.borrow_mut();
+                collectors.retain(|collector| {
+                    collector
+                        .try_send((peer, shares.clone(), Instant::now()))
+                        .is_ok()
+                });
+            }
+        }
+    }
+
+    fn announcement(&self, shares: &impl Fn() -> Vec<CommonShareName>) -> DiscoveryPacket {
+        let mut shares = shares();
+        shares.truncate(MAX_ANNOUNCED_SHARES);
+        DiscoveryPacket::Announce {
+            sender: self.sender,
+            port: self.tcp_port,
+            shares,
+        }
+    }
+
+    async fn send(&self, packet: DiscoveryPacket, to: SocketAddrV4) -> io::Result<()> {
+        self.socket.send_to(&encode(&packet), to).await.map(|_| ())
+    }
+
+    /// Asks the network for shares, collecting answers for `window`. A peer
+    /// answering more than once is listed once
+    pub async fn discover(&self, window: Duration) -> io::Result<DiscoveredDto> {
+        let (tx, rx) = unbounded();
+        self.collectors.borrow_mut().push(tx);
+        let started = Instant::now();
+        self.send(
+            DiscoveryPacket::Query {
+                sender: self.sender,
+            },
+            self.target,
+        )
+        .await?;
+
+        let mut peers = BTreeMap::new();
+        let collect = async {
+            while let Ok((addr, shares, received)) = rx.recv().await {
+                if peers.len() >= MAX_DISCOVERED_PEERS && !peers.contains_key(&addr) {
+                    continue;
+                }
+                let rtt = received.saturating_duration_since(started);
+                peers.entry(addr).or_insert(DiscoveredPeerDto {
+                    addr,
+                    rtt_ms: rtt.as_millis().try_into().unwrap_or(u32::MAX),
+                    shares,
+                });
+            }
+        };
+        collect
+            .or(async {
+                Timer::after(window).await;
+            })
+            .await;
+        drop(rx);
+        Ok(DiscoveredDto {
+            peers: peers.into_values().collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smol::block_on;
+
+    use super::*;
+
+    fn local(tcp_port: u16) -> Discovery {
+        Discovery::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0), tcp_port).unwrap()
+    }
+
+    fn addr(discovery: &Discovery) -> SocketAddrV4 {
+        match discovery.socket.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn discovers_peers_once() {
+        let result = async {
+            let mut asking = local(1000);
+            let mut answering = local(2000);
+            // Loopback has no broadcast, so the two only talk to each other
+            asking.target = addr(&answering);
+            answering.target = addr(&asking);
+
+            let photos: CommonShareName = "photos".parse()?;
+            let shares = || vec![photos.clone()];
+            let discovered = asking
+                .discover(Duration::from_millis(300))
+                .or(async {
+                    // Also announces on start, which mustnt list it twice
+                    answering.serve(shares).await;
+                    unreachable!()
+                })
+                .or(async {
+                    asking.serve(Vec::new).await;
+                    unreachable!()
+                })
+                .await?;
+
+            assert_eq!(discovered.peers.len(), 1);
+            let peer = &discovered.peers[0];
+            assert_eq!(peer.addr, SocketAddrV4::new(Ipv4Addr::LOCALHOST, 2000));
+            assert_eq!(peer.shares, [photos]);
+            anyhow::Ok(())
+        };
+        block_on(result).unwrap();
+    }
+}
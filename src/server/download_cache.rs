@@ -0,0 +1,267 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeSet,
+    fs::{self, File},
+    io::{self, ErrorKind},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use tracing::warn;
+
+use crate::server::content_hash::{self, ContentHash};
+
+/// Numbers the tmp files of writes, so concurrent writes of the same file by
+/// several mounts dont clobber each other
+static NEXT_TMP: AtomicU64 = AtomicU64::new(0);
+
+/// Contents of remote files downloaded by mounts, stored under their content
+/// hash so identical files, even from different shares, are downloaded once
+#[derive(Debug)]
+pub struct DownloadCache {
+    dir: PathBuf,
+    /// Files checked against their hash since this cache was created
+    verified: RefCell<BTreeSet<ContentHash>>,
+    /// Set once a write failed in a way the next one would too, like on a
+    /// full disk. Nothing is written for the rest of the session then
+    disabled: Cell<bool>,
+}
+
+impl DownloadCache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            verified: Default::default(),
+            disabled: Default::default(),
+        }
+    }
+
+    /// Whether downloads stopped being cached, see [`Self::write_failed`]
+    pub fn is_disabled(&self) -> bool {
+        self.disabled.get()
+    }
+
+    /// Contents of a remote file, taken from the cache when a file with the
+    /// same `hash` was downloaded before. Files without a hash, from shares
+    /// without `--dedup`, are always downloaded. Failing to cache the download
+    /// only costs a download the next time
+    pub async fn fetch(
+        &self,
+        hash: Option<&ContentHash>,
+        download: impl Future<Output = io::Result<Vec<u8>>>,
+    ) -> io::Result<Vec<u8>> {
+        let Some(hash) = hash else {
+            return download.await;
+        };
+        let path = self.path(hash);
+        if self.is_intact(hash, &path)? {
+            return fs::read(&path);
+        }
+
+        let contents = download.await?;
+        // A peer lying about the hash mustnt poison the cache for other shares
+        if !self.is_disabled() && content_hash::hash_bytes(&contents) == *hash {
+            match write_atomically(&path, &contents) {
+                Ok(()) => {
+                    self.verified.borrow_mut().insert(*hash);
+                }
+                Err(err) => self.write_failed(&path, err),
+            }
+        }
+        Ok(contents)
+    }
+
+    /// A full disk or missing permissions fail every later write as well, so
+    /// caching is disabled for the session. Other failures are tried again
+    /// with the next download
+    fn write_failed(&self, path: &Path, err: io::Error) {
+        match err.kind() {
+            ErrorKind::StorageFull | ErrorKind::QuotaExceeded | ErrorKind::PermissionDenied => {
+                warn!(
+                    "Failed to cache {}: {err}, downloads arent cached anymore",
+                    path.to_string_lossy()
+                );
+                self.disabled.set(true);
+            }
+            _ => warn!("Failed to cache {}: {err}", path.to_string_lossy()),
+        }
+    }
+
+    /// Up to `len` bytes at `offset` of the cached file with `hash`, fewer only
+    /// at its end. `None` unless it's cached
+    pub fn read_at(
+        &self,
+        hash: &ContentHash,
+        offset: u64,
+        len: u32,
+    ) -> io::Result<Option<Vec<u8>>> {
+        let path = self.path(hash);
+        if !self.is_intact(hash, &path)? {
+            return Ok(None);
+        }
+        let file = File::open(&path)?;
+        let mut data = vec![0; len as usize];
+        let mut read = 0;
+        while read < data.len() {
+            match file.read_at(&mut data[read..], offset + read as u64)? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        data.truncate(read);
+        Ok(Some(data))
+    }
+
+    fn path(&self, hash: &ContentHash) -> PathBuf {
+        self.dir.join(content_hash::to_hex(hash))
+    }
+
+    /// Whether the file with `hash` is cached at `path`. It's checked against
+    /// its hash once, a file that doesnt match is removed
+    fn is_intact(&self, hash: &ContentHash, path: &Path) -> io::Result<bool> {
+        if self.verified.borrow().contains(hash) && path.exists() {
+            return Ok(true);
+        }
+        match content_hash::hash_file(path) {
+            Ok(found) if found == *hash => {
+                self.verified.borrow_mut().insert(*hash);
+                Ok(true)
+            }
+            Ok(_) => {
+                warn!(
+                    "Removing the corrupt {} from the cache",
+                    path.to_string_lossy()
+                );
+                fs::remove_file(path)?;
+                Ok(false)
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Writes `contents` to a tmp file next to `path` first, so `path` is never
+/// seen half written
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let n = NEXT_TMP.fetch_add(1, Ordering::Relaxed);
+    let tmp_path = path.with_extension(format!("{}.{n}.tmp", std::process::id()));
+    let result = fs::write(&tmp_path, contents).and_then(|()| fs::rename(&tmp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::{
+        common::ShareOptions,
+        server::{
+            messages::{PeerMessage, PeerResponse},
+            state::Share,
+        },
+        test_dir::TestDir,
+    };
+
+    #[test]
+    fn identical_files_are_downloaded_once() {
+        let dir = TestDir::new("dedup");
+        let (shared, cached) = (dir.join("shared"), dir.join("cache"));
+        fs::create_dir_all(&shared).unwrap();
+        fs::create_dir_all(&cached).unwrap();
+        fs::write(shared.join("a.bak"), "same").unwrap();
+        fs::write(shared.join("b.bak"), "same").unwrap();
+        let mut share = Share::new("backups".parse().unwrap(), shared.clone());
+        share.set_options(ShareOptions {
+            dedup: true,
+            ..Default::default()
+        });
+
+        let cache = DownloadCache::new(cached);
+        let downloads = Cell::new(0);
+        let read = |name: &str| {
+            let message = PeerMessage::FileHash {
+                share: "backups".parse().unwrap(),
+                rel_path: name.to_string(),
+            };
+            let PeerResponse::FileHash(hash) = message.respond(&share) else {
+                panic!("expected a hash");
+            };
+            let download = async {
+                downloads.set(downloads.get() + 1);
+                fs::read(shared.join(name))
+            };
+            smol::block_on(cache.fetch(hash.as_ref(), download)).unwrap()
+        };
+
+        assert_eq!(read("a.bak"), b"same");
+        assert_eq!(read("b.bak"), b"same");
+        assert_eq!(downloads.get(), 1);
+    }
+
+    #[test]
+    fn corrupt_files_are_downloaded_again() {
+        let dir = TestDir::new("corrupt-cache");
+        let contents = b"0123456789".to_vec();
+        let hash = content_hash::hash_bytes(&contents);
+        let downloads = Cell::new(0);
+        let fetch = |cache: &DownloadCache| {
+            let download = async {
+                downloads.set(downloads.get() + 1);
+                Ok(contents.clone())
+            };
+            smol::block_on(cache.fetch(Some(&hash), download)).unwrap()
+        };
+
+        let cache = DownloadCache::new(dir.to_path_buf());
+        assert_eq!(cache.read_at(&hash, 0, 4).unwrap(), None);
+        assert_eq!(fetch(&cache), contents);
+        assert_eq!(cache.read_at(&hash, 8, 4).unwrap().unwrap(), b"89");
+
+        // Cut short after it was written, the next cache notices
+        fs::write(dir.join(content_hash::to_hex(&hash)), b"0123").unwrap();
+        let cache = DownloadCache::new(dir.to_path_buf());
+        assert_eq!(cache.read_at(&hash, 0, 4).unwrap(), None);
+        assert_eq!(fetch(&cache), contents);
+        assert_eq!(downloads.get(), 2);
+        assert_eq!(fs::read_dir(&*dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn failed_writes_still_return_the_download() {
+        let dir = TestDir::new("unwritable-cache");
+        let contents = b"hello".to_vec();
+        let hash = content_hash::hash_bytes(&contents);
+        let cache = DownloadCache::new(dir.join("missing"));
+        let download = async { Ok(contents.clone()) };
+        assert_eq!(
+            smol::block_on(cache.fetch(Some(&hash), download)).unwrap(),
+            contents
+        );
+        assert_eq!(cache.read_at(&hash, 0, 5).unwrap(), None);
+        assert!(!cache.is_disabled());
+    }
+
+    #[test]
+    fn full_disk_disables_caching() {
+        let dir = TestDir::new("full-cache");
+        let contents = b"hello".to_vec();
+        let hash = content_hash::hash_bytes(&contents);
+        let cache = DownloadCache::new(dir.to_path_buf());
+        cache.write_failed(&cache.path(&hash), io::Error::from(ErrorKind::StorageFull));
+        assert!(cache.is_disabled());
+
+        let download = async { Ok(contents.clone()) };
+        assert_eq!(
+            smol::block_on(cache.fetch(Some(&hash), download)).unwrap(),
+            contents
+        );
+        assert_eq!(cache.read_at(&hash, 0, 5).unwrap(), None);
+        assert_eq!(fs::read_dir(&*dir).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,116 @@
+use std::path::{Component, Path};
+
+use glob::{MatchOptions, Pattern};
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Decides which paths of a share peers can see. Include patterns are matched
+/// from the share root, like `docs` or `src/*.rs`. Exclude patterns without a
+/// `/` match a name at any depth, so `.git` hides every `.git` dir. Everything
+/// under a matched dir is matched as well
+#[derive(Debug, Default)]
+pub struct PathFilter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl PathFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Self {
+        Self {
+            include: include.iter().map(|pattern| compile(pattern)).collect(),
+            exclude: exclude.iter().map(|pattern| compile(pattern)).collect(),
+        }
+    }
+
+    /// Whether a path relative to the share root is visible to peers
+    pub fn is_visible(&self, path: &Path) -> bool {
+        let names: Vec<_> = path
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(name.to_string_lossy()),
+                _ => None,
+            })
+            .collect();
+        let prefixes: Vec<_> = (1..=names.len()).map(|n| names[..n].join("/")).collect();
+
+        let excluded = self
+            .exclude
+            .iter()
+            .any(|pattern| match pattern.as_str().contains('/') {
+                true => prefixes
+                    .iter()
+                    .any(|prefix| pattern.matches_with(prefix, MATCH_OPTIONS)),
+                false => names
+                    .iter()
+                    .any(|name| pattern.matches_with(name, MATCH_OPTIONS)),
+            });
+        if excluded {
+            return false;
+        }
+        if self.include.is_empty() || names.is_empty() {
+            return true;
+        }
+        self.include.iter().any(|pattern| {
+            prefixes
+                .iter()
+                .any(|prefix| pattern.matches_with(prefix, MATCH_OPTIONS))
+                || Self::leads_to(pattern, &names)
+        })
+    }
+
+    /// Whether the dir at `names` is on the way to paths matching `pattern`,
+    /// so it has to stay visible for them to be reachable
+    fn leads_to(pattern: &Pattern, names: &[impl AsRef<str>]) -> bool {
+        let parts: Vec<_> = pattern.as_str().split('/').collect();
+        for (i, name) in names.iter().enumerate() {
+            match parts.get(i) {
+                Some(&"**") => return true,
+                Some(part) if i + 1 < parts.len() => {
+                    if !compile(part).matches_with(name.as_ref(), MATCH_OPTIONS) {
+                        return false;
+                    }
+                }
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+/// Patterns are checked when parsing the args, one that still doesnt compile
+/// is matched literally
+fn compile(pattern: &str) -> Pattern {
+    Pattern::new(pattern)
+        .or_else(|_| Pattern::new(&Pattern::escape(pattern)))
+        .expect("escaped patterns always compile")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn include_and_exclude() {
+        let filter = PathFilter::new(
+            &["docs".to_string(), "src/*.rs".to_string()],
+            &[".git".to_string(), "docs/drafts".to_string()],
+        );
+        let visible = |path: &str| filter.is_visible(Path::new(path));
+        assert!(visible(""));
+        assert!(visible("docs/guide.md"));
+        assert!(visible("src"));
+        assert!(visible("src/main.rs"));
+        assert!(!visible("src/nested/lib.rs"));
+        assert!(!visible("target"));
+        assert!(!visible("docs/drafts/todo.md"));
+        assert!(!visible("docs/.git"));
+
+        let filter = PathFilter::new(&[], &["*.key".to_string()]);
+        assert!(filter.is_visible(Path::new("/photos/cat.jpg")));
+        assert!(!filter.is_visible(Path::new("/secrets/ssh.key")));
+    }
+}
@@ -0,0 +1,184 @@
+use std::{
+    collections::{BTreeMap, btree_map::Entry},
+    fs::OpenOptions,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use bitcode::{Decode, Encode};
+use derive_more::{Display, Error};
+use nix::errno::Errno;
+
+use crate::server::messages::PeerResponseError;
+
+pub const FUSE_DEVICE: &str = "/dev/fuse";
+/// Inode FUSE uses for the root of a mount
+pub const ROOT_INODE: u64 = 1;
+
+#[derive(Encode, Decode, Clone, Debug, Display, Error, PartialEq, Eq)]
+#[display("FUSE is not available: {reason}")]
+pub struct FuseUnavailableError {
+    #[error(ignore)]
+    pub reason: String,
+}
+
+/// Checks that the FUSE device at `device` can be opened, so that mounting
+/// fails early with an actionable error instead of a mount errno
+pub fn check_available(device: &Path) -> Result<(), FuseUnavailableError> {
+    let device_name = device.to_string_lossy();
+    let reason = match OpenOptions::new().read(true).write(true).open(device) {
+        Ok(_) => return Ok(()),
+        Err(err) => match err.kind() {
+            ErrorKind::NotFound => format!(
+                "{device_name} doesnt exist, make sure the fuse kernel module is loaded \
+                or, in a container, that the device is passed through"
+            ),
+            ErrorKind::PermissionDenied => {
+                format!("no permission to open {device_name}, make sure your user can use FUSE")
+            }
+            _ => format!("failed to open {device_name}: {err}"),
+        },
+    };
+    Err(FuseUnavailableError { reason })
+}
+
+/// Inode numbers of a mount, by the path relative to the share root. A path
+/// keeps its inode until the kernel forgets every lookup of it, numbers are
+/// never reused within a mount
+#[derive(Debug)]
+pub struct Inodes {
+    next: u64,
+    by_path: BTreeMap<PathBuf, Inode>,
+    by_inode: BTreeMap<u64, PathBuf>,
+}
+
+#[derive(Debug)]
+struct Inode {
+    inode: u64,
+    lookups: u64,
+}
+
+impl Default for Inodes {
+    fn default() -> Self {
+        let root = PathBuf::new();
+        Self {
+            next: ROOT_INODE + 1,
+            by_path: BTreeMap::from([(
+                root.clone(),
+                Inode {
+                    inode: ROOT_INODE,
+                    lookups: 1,
+                },
+            )]),
+            by_inode: BTreeMap::from([(ROOT_INODE, root)]),
+        }
+    }
+}
+
+impl Inodes {
+    /// Inode of `path`, counted as a lookup the kernel has to forget
+    pub fn lookup(&mut self, path: &Path) -> u64 {
+        match self.by_path.entry(path.to_path_buf()) {
+            Entry::Occupied(mut entry) => {
+                let entry = entry.get_mut();
+                entry.lookups += 1;
+                entry.inode
+            }
+            Entry::Vacant(entry) => {
+                let inode = self.next;
+                self.next += 1;
+                entry.insert(Inode { inode, lookups: 1 });
+                self.by_inode.insert(inode, path.to_path_buf());
+                inode
+            }
+        }
+    }
+
+    /// Inode of `path` if it was looked up, without counting a lookup
+    pub fn get(&self, path: &Path) -> Option<u64> {
+        self.by_path.get(path).map(|entry| entry.inode)
+    }
+
+    pub fn path(&self, inode: u64) -> Option<&Path> {
+        self.by_inode.get(&inode).map(PathBuf::as_path)
+    }
+
+    /// Handles the `forget` op, the inode is dropped once all its lookups are
+    /// forgotten. The root lives as long as the mount
+    pub fn forget(&mut self, inode: u64, lookups: u64) {
+        if inode == ROOT_INODE {
+            return;
+        }
+        let Some(path) = self.by_inode.get(&inode) else {
+            return;
+        };
+        let Some(entry) = self.by_path.get_mut(path) else {
+            return;
+        };
+        entry.lookups = entry.lookups.saturating_sub(lookups);
+        if entry.lookups == 0 {
+            self.by_path.remove(path);
+            self.by_inode.remove(&inode);
+        }
+    }
+}
+
+/// Reply to the `listxattr` op, names are sent nul terminated back to back
+pub fn xattr_list(names: &[String]) -> Vec<u8> {
+    let mut list = Vec::new();
+    for name in names {
+        list.extend_from_slice(name.as_bytes());
+        list.push(0);
+    }
+    list
+}
+
+/// Error a FUSE op replies with when the owner of the share refused it
+pub fn errno(err: &PeerResponseError) -> Errno {
+    match err {
+        PeerResponseError::ShareRemoved | PeerResponseError::NoSuchShare => Errno::ENOENT,
+        PeerResponseError::Io(_) => Errno::EIO,
+        // Only answers introductions, which mounts dont send
+        PeerResponseError::Introduction(_) => Errno::EPROTO,
+        PeerResponseError::Unsupported => Errno::ENOSYS,
+        PeerResponseError::PermissionDenied => Errno::EACCES,
+        PeerResponseError::NotADirectory => Errno::ENOTDIR,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_device_is_unavailable() {
+        let err = check_available(Path::new("/rdir/missing/fuse")).unwrap_err();
+        assert!(err.reason.contains("doesnt exist"));
+        assert!(err.to_string().starts_with("FUSE is not available"));
+    }
+
+    #[test]
+    fn inodes_are_stable() {
+        let mut inodes = Inodes::default();
+        let photo = inodes.lookup(Path::new("photos/a.jpg"));
+        let other = inodes.lookup(Path::new("photos/b.jpg"));
+        assert_ne!(photo, other);
+        assert_ne!(photo, ROOT_INODE);
+        assert_eq!(inodes.lookup(Path::new("photos/a.jpg")), photo);
+        assert_eq!(inodes.path(photo), Some(Path::new("photos/a.jpg")));
+        assert_eq!(inodes.path(ROOT_INODE), Some(Path::new("")));
+        assert_eq!(inodes.get(Path::new("photos/b.jpg")), Some(other));
+        assert_eq!(inodes.get(Path::new("photos/c.jpg")), None);
+
+        // Still looked up once
+        inodes.forget(photo, 1);
+        assert_eq!(inodes.path(photo), Some(Path::new("photos/a.jpg")));
+        inodes.forget(photo, 1);
+        assert_eq!(inodes.path(photo), None);
+        let relookup = inodes.lookup(Path::new("photos/a.jpg"));
+        assert!(relookup > other, "forgotten numbers arent reused");
+
+        inodes.forget(ROOT_INODE, 10);
+        assert_eq!(inodes.path(ROOT_INODE), Some(Path::new("")));
+    }
+}
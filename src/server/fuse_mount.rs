@@ -0,0 +1,409 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use fuser::{
+    BackgroundSession, FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData,
+    ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyXattr, Request,
+};
+use nix::errno::Errno;
+use smol::channel::{Sender, bounded};
+
+use crate::{
+    common::{DirEntryDto, DirEntryKind, MountOptions, shares::CommonShareName},
+    server::{
+        content_hash::ContentHash,
+        dir_pages::MAX_PAGE_LEN,
+        download_cache::DownloadCache,
+        fuse::{Inodes, ROOT_INODE, errno, xattr_list},
+        messages::{FileAttrs, PeerMessage, PeerResponse},
+        net::MAX_READ_LEN,
+    },
+};
+
+/// How long the kernel caches attrs and lookups, changes on the remote show up
+/// after that
+const TTL: Duration = Duration::from_secs(1);
+
+/// Request of the FUSE thread, sent to the peer by the executor which replies
+/// with the response
+pub type FsRequest = (PeerMessage, Sender<Result<PeerResponse, Errno>>);
+
+/// Mounts `share` read only at `mount_path`, its ops are sent as requests over
+/// `requests`. Files of shares with `--dedup` are kept in `cache`. The kernel
+/// is served on a thread of its own until the returned session is dropped,
+/// which unmounts it
+pub fn mount(
+    share: CommonShareName,
+    mount_path: &Path,
+    options: MountOptions,
+    cache: DownloadCache,
+    requests: Sender<FsRequest>,
+) -> io::Result<BackgroundSession> {
+    let fs = RemoteFs {
+        options,
+        share: share.clone(),
+        requests,
+        inodes: Inodes::default(),
+        cache,
+        open_dirs: Default::default(),
+        next_dir_handle: 0,
+    };
+    let mount_options = [
+        MountOption::RO,
+        MountOption::DefaultPermissions,
+        MountOption::FSName(format!("rdir:{share}")),
+        MountOption::Subtype("rdir".to_string()),
+    ];
+    fuser::spawn_mount2(fs, mount_path, &mount_options)
+}
+
+struct RemoteFs {
+    share: CommonShareName,
+    requests: Sender<FsRequest>,
+    inodes: Inodes,
+    cache: DownloadCache,
+    options: MountOptions,
+    /// Listings of the opened dirs by their handle, so `readdir` calls for
+    /// later offsets continue the same listing
+    open_dirs: BTreeMap<u64, (PathBuf, Vec<DirEntryDto>)>,
+    next_dir_handle: u64,
+}
+
+impl RemoteFs {
+    /// Blocks the FUSE thread until the executor got the response
+    fn request(&self, message: PeerMessage) -> Result<PeerResponse, Errno> {
+        let (reply_tx, reply_rx) = bounded(1);
+        self.requests
+            .send_blocking((message, reply_tx))
+            .map_err(|_| Errno::ENOTCONN)?;
+        match reply_rx.recv_blocking().map_err(|_| Errno::ENOTCONN)?? {
+            PeerResponse::Err(err) => Err(errno(&err)),
+            response => Ok(response),
+        }
+    }
+
+    /// Whole listing of a dir, fetched page by page. The peer snapshots big
+    /// dirs, so the pages dont repeat or skip entries
+    fn read_dir(&self, path: &Path) -> Result<Vec<DirEntryDto>, Errno> {
+        let mut entries = Vec::new();
+        let mut cursor = None;
+        loop {
+            let message = PeerMessage::ReadDirPage {
+                share: self.share.clone(),
+                rel_path: path.to_string_lossy().into_owned(),
+                cursor,
+                limit: MAX_PAGE_LEN,
+            };
+            match self.request(message)? {
+                PeerResponse::DirPage {
+                    entries: page,
+                    next,
+                } => {
+                    entries.extend(page);
+                    match next {
+                        Some(next) => cursor = Some(next),
+                        None => return Ok(entries),
+                    }
+                }
+                _ => return Err(Errno::EIO),
+            }
+        }
+    }
+
+    fn path(&self, inode: u64) -> Result<PathBuf, Errno> {
+        self.inodes
+            .path(inode)
+            .map(Path::to_path_buf)
+            .ok_or(Errno::ENOENT)
+    }
+
+    /// Attrs of `path` as the peer reports them
+    fn remote_attrs(&self, path: &Path) -> Result<FileAttrs, Errno> {
+        let message = PeerMessage::Stat {
+            share: self.share.clone(),
+            rel_path: path.to_string_lossy().into_owned(),
+        };
+        match self.request(message)? {
+            PeerResponse::Attrs(attrs) => Ok(attrs),
+            _ => Err(Errno::EIO),
+        }
+    }
+
+    fn attr(&self, inode: u64, attrs: FileAttrs) -> FileAttr {
+        let attrs = attrs.mapped(&self.options);
+        let mtime = UNIX_EPOCH + Duration::from_secs(attrs.mtime);
+        FileAttr {
+            ino: inode,
+            size: attrs.size,
+            blocks: attrs.size.div_ceil(512),
+            atime: mtime,
+            mtime,
+            ctime: mtime,
+            crtime: mtime,
+            kind: file_type(attrs.kind),
+            // The mount is read only whatever the mode says
+            perm: (attrs.mode & 0o7777) as u16,
+            nlink: 1,
+            uid: attrs.uid,
+            gid: attrs.gid,
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        }
+    }
+
+    fn stat(&self, inode: u64) -> Result<FileAttr, Errno> {
+        let attrs = self.remote_attrs(&self.path(inode)?)?;
+        Ok(self.attr(inode, attrs))
+    }
+
+    fn read_file(&self, inode: u64, offset: i64, size: u32) -> Result<Vec<u8>, Errno> {
+        let rel_path = self.path(inode)?.to_string_lossy().into_owned();
+        let offset: u64 = offset.try_into().map_err(|_| Errno::EINVAL)?;
+        let size = size.min(MAX_READ_LEN);
+        let Some(hash) = self.file_hash(&rel_path)? else {
+            return self.read_range(&rel_path, offset, size);
+        };
+        let io_errno = |err: io::Error| err.raw_os_error().map_or(Errno::EIO, Errno::from_raw);
+        if let Some(data) = self.cache.read_at(&hash, offset, size).map_err(io_errno)? {
+            return Ok(data);
+        }
+        // Downloading the whole file only pays off when it can be cached
+        if self.cache.is_disabled() {
+            return self.read_range(&rel_path, offset, size);
+        }
+        // Hashed files are downloaded whole into the cache, so identical ones
+        // are only downloaded once
+        let download = async { self.download(&rel_path) };
+        let contents = smol::block_on(self.cache.fetch(Some(&hash), download)).map_err(io_errno)?;
+        match self.cache.read_at(&hash, offset, size).map_err(io_errno)? {
+            Some(data) => Ok(data),
+            // Couldnt be cached, so the range is served from the download
+            None => {
+                let start = contents.len().min(offset.try_into().unwrap_or(usize::MAX));
+                let end = contents.len().min(start + size as usize);
+                Ok(contents[start..end].to_vec())
+            }
+        }
+    }
+
+    /// `None` unless the share has `--dedup`
+    fn file_hash(&self, rel_path: &str) -> Result<Option<ContentHash>, Errno> {
+        let message = PeerMessage::FileHash {
+            share: self.share.clone(),
+            rel_path: rel_path.to_string(),
+        };
+        match self.request(message)? {
+            PeerResponse::FileHash(hash) => Ok(hash),
+            _ => Err(Errno::EIO),
+        }
+    }
+
+    /// Up to `len` bytes at `offset`, fewer only at the end of the file. The
+    /// peer can answer with less, so the rest is asked for again
+    fn read_range(&self, rel_path: &str, offset: u64, len: u32) -> Result<Vec<u8>, Errno> {
+        let mut data = Vec::new();
+        while data.len() < len as usize {
+            let message = PeerMessage::ReadFile {
+                share: self.share.clone(),
+                rel_path: rel_path.to_string(),
+                offset: offset + data.len() as u64,
+                len: len - data.len() as u32,
+            };
+            match self.request(message)? {
+                PeerResponse::FileData(chunk) if chunk.is_empty() => break,
+                PeerResponse::FileData(chunk) => data.extend(chunk),
+                _ => return Err(Errno::EIO),
+            }
+        }
+        Ok(data)
+    }
+
+    /// Whole contents of a file, read up to the first short read
+    fn download(&self, rel_path: &str) -> io::Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        loop {
+            let chunk = self.read_range(rel_path, contents.len() as u64, MAX_READ_LEN)?;
+            let done = chunk.len() < MAX_READ_LEN as usize;
+            contents.extend(chunk);
+            if done {
+                return Ok(contents);
+            }
+        }
+    }
+
+    fn xattr(&self, inode: u64, name: &OsStr) -> Result<Vec<u8>, Errno> {
+        let message = PeerMessage::GetXattr {
+            share: self.share.clone(),
+            rel_path: self.path(inode)?.to_string_lossy().into_owned(),
+            name: name.to_string_lossy().into_owned(),
+        };
+        match self.request(message)? {
+            PeerResponse::Xattr(Some(value)) => Ok(value),
+            PeerResponse::Xattr(None) => Err(Errno::ENODATA),
+            _ => Err(Errno::EIO),
+        }
+    }
+
+    fn xattr_names(&self, inode: u64) -> Result<Vec<u8>, Errno> {
+        let message = PeerMessage::ListXattr {
+            share: self.share.clone(),
+            rel_path: self.path(inode)?.to_string_lossy().into_owned(),
+        };
+        match self.request(message)? {
+            PeerResponse::XattrNames(names) => Ok(xattr_list(&names)),
+            _ => Err(Errno::EIO),
+        }
+    }
+}
+
+/// Replies with the length of `value` when the kernel asks for it with a
+/// `size` of 0, otherwise with `value` if it fits
+fn reply_xattr(reply: ReplyXattr, size: u32, value: Result<Vec<u8>, Errno>) {
+    match value {
+        Ok(value) if size == 0 => reply.size(value.len().try_into().unwrap_or(u32::MAX)),
+        Ok(value) if value.len() > size as usize => reply.error(Errno::ERANGE as i32),
+        Ok(value) => reply.data(&value),
+        Err(err) => reply.error(err as i32),
+    }
+}
+
+fn file_type(kind: DirEntryKind) -> FileType {
+    match kind {
+        DirEntryKind::File => FileType::RegularFile,
+        DirEntryKind::Dir => FileType::Directory,
+        DirEntryKind::Symlink => FileType::Symlink,
+    }
+}
+
+impl Filesystem for RemoteFs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let attrs = self
+            .path(parent)
+            .map(|parent| parent.join(name))
+            .and_then(|path| Ok((self.remote_attrs(&path)?, path)));
+        match attrs {
+            Ok((attrs, path)) => {
+                let inode = self.inodes.lookup(&path);
+                reply.entry(&TTL, &self.attr(inode, attrs), 0);
+            }
+            Err(err) => reply.error(err as i32),
+        }
+    }
+
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        self.inodes.forget(ino, nlookup);
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match self.stat(ino) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(err) => reply.error(err as i32),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        match self.read_file(ino, offset, size) {
+            Ok(data) => reply.data(&data),
+            Err(err) => reply.error(err as i32),
+        }
+    }
+
+    fn getxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        reply_xattr(reply, size, self.xattr(ino, name));
+    }
+
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        reply_xattr(reply, size, self.xattr_names(ino));
+    }
+
+    fn opendir(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        let listing = self
+            .path(ino)
+            .and_then(|path| Ok((path.clone(), self.read_dir(&path)?)));
+        match listing {
+            Ok(listing) => {
+                let handle = self.next_dir_handle;
+                self.next_dir_handle += 1;
+                self.open_dirs.insert(handle, listing);
+                reply.opened(handle, 0);
+            }
+            Err(err) => reply.error(err as i32),
+        }
+    }
+
+    fn releasedir(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        reply: ReplyEmpty,
+    ) {
+        self.open_dirs.remove(&fh);
+        reply.ok();
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        // Listed once on `opendir`
+        let Some((path, entries)) = self.open_dirs.get(&fh) else {
+            return reply.error(Errno::EBADF as i32);
+        };
+        let parent = path
+            .parent()
+            .and_then(|parent| self.inodes.get(parent))
+            .unwrap_or(ROOT_INODE);
+        // Entries get their inode once looked up, the number here is only shown
+        // by tools like `ls -i`
+        let unknown = u64::MAX;
+        let children = entries.iter().map(|entry| {
+            let inode = self.inodes.get(&path.join(&entry.name)).unwrap_or(unknown);
+            (inode, file_type(entry.kind), entry.name.clone())
+        });
+        let listing = [
+            (ino, FileType::Directory, ".".to_string()),
+            (parent, FileType::Directory, "..".to_string()),
+        ]
+        .into_iter()
+        .chain(children);
+        for (i, (inode, kind, name)) in listing
+            .enumerate()
+            .skip(offset.try_into().unwrap_or_default())
+        {
+            // The offset of an entry is where the next call continues from
+            if reply.add(inode, i as i64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
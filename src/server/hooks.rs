@@ -0,0 +1,46 @@
+use std::{future::Future, net::SocketAddrV4};
+
+use derive_more::Display;
+use smol::process::{Command, Stdio};
+use tracing::error;
+
+use crate::common::shares::CommonShareName;
+
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq)]
+pub enum HookEvent {
+    #[display("connect")]
+    Connect,
+    #[display("disconnect")]
+    Disconnect,
+}
+
+/// Starts a share hook through `sh` right away. The share name and the peer
+/// address are passed as `$1` and `$2`, and as `RDIR_SHARE` and `RDIR_PEER`.
+/// The returned future only waits for the hook to log a failure
+pub fn run(
+    command: &str,
+    event: HookEvent,
+    share: &CommonShareName,
+    peer: SocketAddrV4,
+) -> impl Future<Output = ()> + 'static {
+    let (share, peer) = (share.to_string(), peer.to_string());
+    let child = Command::new("sh")
+        .args(["-c", command, "rdir-hook", &share, &peer])
+        .env("RDIR_SHARE", &share)
+        .env("RDIR_PEER", &peer)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    async move {
+        let status = match child {
+            Ok(mut child) => child.status().await,
+            Err(err) => Err(err),
+        };
+        match status {
+            Ok(status) if status.success() => {}
+            Ok(status) => error!("The {event} hook of share {share} failed with {status}"),
+            Err(err) => error!("Failed to run the {event} hook of share {share}: {err}"),
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bitcode::{Decode, Encode};
+use curve25519_dalek::{
+    edwards::{CompressedEdwardsY, EdwardsPoint},
+    montgomery::MontgomeryPoint,
+    scalar::{Scalar, clamp_integer},
+};
+use derive_more::{Display, Error, IsVariant};
+use sha2::{Digest, Sha512};
+
+use crate::{common::shares::RemotePeerAddr, server::net::PublicKey};
+
+/// Longest an introduction is accepted for, a leaked one stops working by then
+pub const MAX_INTRODUCTION_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// Keeps signatures of introductions from passing as signatures of anything else
+const DOMAIN: &[u8] = b"rdir introduction\0";
+/// Prefix of the nonce hash of XEdDSA, so it never collides with the challenge hash
+const NONCE_PREFIX: [u8; 32] = {
+    let mut prefix = [0xff; 32];
+    prefix[0] = 0xfe;
+    prefix
+};
+
+/// Key of a peer vouched for by another peer, signed with the static key of
+/// the voucher. Peers that trust the voucher pin the key without connecting
+/// first, see `--accept-introductions`
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct Introduction {
+    pub addr: RemotePeerAddr,
+    pub key: PublicKey,
+    /// Seconds since the unix epoch
+    pub expires: u64,
+    signature: Signature,
+}
+
+/// XEdDSA signature, the static keys are X25519 keys rather than Ed25519 ones
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+struct Signature {
+    r: [u8; 32],
+    s: [u8; 32],
+}
+
+#[derive(Encode, Decode, Clone, Debug, Display, Error, IsVariant, PartialEq, Eq)]
+pub enum IntroductionError {
+    #[display("Peer doesnt accept introductions")]
+    Disabled,
+    #[display("Peer hasnt pinned the key of the introducer")]
+    UntrustedVoucher,
+    #[display("Signature of the introduction is invalid")]
+    BadSignature,
+    #[display("Introduction expired")]
+    Expired,
+    #[display("Introduction is valid for longer than the peer allows")]
+    TooLong,
+    #[display("Peer has another key pinned for the introduced address")]
+    Conflict,
+}
+
+impl Introduction {
+    /// Introduces `key` as the key of `addr` until `expires`, signed with the
+    /// `private` static key of the voucher
+    pub fn sign(private: &[u8; 32], addr: RemotePeerAddr, key: PublicKey, expires: u64) -> Self {
+        let message = signed_message(&addr, &key, expires);
+        Self {
+            addr,
+            key,
+            expires,
+            signature: xeddsa_sign(private, &message),
+        }
+    }
+
+    /// Checks the introduction is signed by `voucher` and valid at `now`, for
+    /// no longer than [`MAX_INTRODUCTION_TTL`] from then
+    pub fn verify(&self, voucher: &PublicKey, now: SystemTime) -> Result<(), IntroductionError> {
+        let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        if self.expires <= now {
+            return Err(IntroductionError::Expired);
+        }
+        if self.expires - now > MAX_INTRODUCTION_TTL.as_secs() {
+            return Err(IntroductionError::TooLong);
+        }
+        let message = signed_message(&self.addr, &self.key, self.expires);
+        match xeddsa_verify(voucher, &message, &self.signature) {
+            true => Ok(()),
+            false => Err(IntroductionError::BadSignature),
+        }
+    }
+}
+
+fn signed_message(addr: &RemotePeerAddr, key: &PublicKey, expires: u64) -> Vec<u8> {
+    [
+        DOMAIN,
+        addr.to_string().as_bytes(),
+        b"\0",
+        key,
+        &expires.to_be_bytes(),
+    ]
+    .concat()
+}
+
+/// Signs like Ed25519 with the Edwards form of an X25519 key. The Montgomery
+/// form fixes the Edwards point only up to its sign, so the key is negated when
+/// needed to sign as the point with a zero sign bit, which is what verifiers
+/// recover. The nonce is derived from the key and the message like in Ed25519
+fn xeddsa_sign(private: &[u8; 32], message: &[u8]) -> Signature {
+    let k = Scalar::from_bytes_mod_order(clamp_integer(*private));
+    let a = match EdwardsPoint::mul_base(&k).compress().as_bytes()[31] >> 7 {
+        0 => k,
+        _ => -k,
+    };
+    let public = EdwardsPoint::mul_base(&a).compress();
+    let r = hash_to_scalar(&[&NONCE_PREFIX, a.as_bytes(), message]);
+    let big_r = EdwardsPoint::mul_base(&r).compress();
+    let h = hash_to_scalar(&[big_r.as_bytes(), public.as_bytes(), message]);
+    Signature {
+        r: big_r.to_bytes(),
+        s: (r + h * a).to_bytes(),
+    }
+}
+
+fn xeddsa_verify(public: &PublicKey, message: &[u8], signature: &Signature) -> bool {
+    let Some(public) = MontgomeryPoint(*public).to_edwards(0) else {
+        return false;
+    };
+    let Some(s) = Option::<Scalar>::from(Scalar::from_canonical_bytes(signature.s)) else {
+        return false;
+    };
+    if public.is_small_order() {
+        return false;
+    }
+    let h = hash_to_scalar(&[&signature.r, public.compress().as_bytes(), message]);
+    let big_r = EdwardsPoint::vartime_double_scalar_mul_basepoint(&-h, &public, &s);
+    big_r.compress() == CompressedEdwardsY(signature.r)
+}
+
+fn hash_to_scalar(parts: &[&[u8]]) -> Scalar {
+    let mut hasher = Sha512::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    Scalar::from_bytes_mod_order_wide(&hasher.finalize().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use snow::Builder;
+
+    use super::*;
+
+    fn keypair() -> ([u8; 32], PublicKey) {
+        let keypair = Builder::new("Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap())
+            .generate_keypair()
+            .unwrap();
+        (
+            keypair.private.try_into().unwrap(),
+            keypair.public.try_into().unwrap(),
+        )
+    }
+
+    #[test]
+    fn introductions_verify_against_the_key_of_the_voucher() {
+        let (private, public) = keypair();
+        let (_, other) = keypair();
+        let now = SystemTime::now();
+        let expires = now.duration_since(UNIX_EPOCH).unwrap().as_secs() + 60;
+        let addr: RemotePeerAddr = "10.0.0.2".parse().unwrap();
+        let introduction = Introduction::sign(&private, addr.clone(), [7; 32], expires);
+
+        assert_eq!(introduction.verify(&public, now), Ok(()));
+        assert_eq!(
+            introduction.verify(&other, now),
+            Err(IntroductionError::BadSignature)
+        );
+        let forged = Introduction {
+            key: [8; 32],
+            ..introduction.clone()
+        };
+        assert_eq!(
+            forged.verify(&public, now),
+            Err(IntroductionError::BadSignature)
+        );
+        let later = now + Duration::from_secs(60);
+        assert_eq!(
+            introduction.verify(&public, later),
+            Err(IntroductionError::Expired)
+        );
+        let expires = expires + MAX_INTRODUCTION_TTL.as_secs();
+        let lasting = Introduction::sign(&private, addr, [7; 32], expires);
+        assert_eq!(
+            lasting.verify(&public, now),
+            Err(IntroductionError::TooLong)
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use tracing::{Subscriber, level_filters::LevelFilter};
+use tracing_subscriber::{Layer, Registry, fmt::MakeWriter, prelude::*, reload};
+
+use crate::common::LogLevel;
+
+pub type LogLevelHandle = reload::Handle<LevelFilter, Registry>;
+
+/// Logs everything at `level`, until changed with `rdir log-level`, to `main`,
+/// and warnings and errors to `errors` as well so they can be triaged without
+/// the noise
+pub fn subscriber<M, E>(
+    level: LevelFilter,
+    main: M,
+    errors: E,
+) -> (impl Subscriber + Send + Sync, LogLevelHandle)
+where
+    M: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+    E: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let (filter, handle) = reload::Layer::new(level);
+    let subscriber = tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer().with_writer(main))
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(errors)
+                .with_filter(LevelFilter::WARN),
+        );
+    (subscriber, handle)
+}
+
+/// Level the running subscriber currently logs at
+pub fn current_level(handle: &LogLevelHandle) -> Option<LogLevel> {
+    handle.clone_current().map(Into::into)
+}
+
+/// Swaps the level of the running subscriber, returning the previous one
+pub fn set_level(handle: &LogLevelHandle, level: LogLevel) -> Result<LogLevel, reload::Error> {
+    let mut previous = LevelFilter::OFF;
+    handle.modify(|filter| previous = std::mem::replace(filter, level.into()))?;
+    Ok(previous.into())
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use std::{
+        io,
+        sync::{
+            Arc, Mutex,
+            atomic::{AtomicUsize, Ordering},
+        },
+    };
+
+    use tracing::{Event, debug, info, warn};
+    use tracing_subscriber::layer::Context;
+
+    use super::*;
+
+    struct CountingLayer(Arc<AtomicUsize>);
+
+    impl<S: Subscriber> Layer<S> for CountingLayer {
+        fn on_event(&self, _event: &Event<'_>, _ctx: Context<'_, S>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn reload_changes_emitted_events() {
+        let events = Arc::new(AtomicUsize::new(0));
+        let (filter, handle) = reload::Layer::new(LevelFilter::INFO);
+        let subscriber = tracing_subscriber::registry()
+            .with(filter)
+            .with(CountingLayer(events.clone()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            debug!("hidden");
+            info!("shown");
+            assert_eq!(events.load(Ordering::Relaxed), 1);
+
+            let previous = set_level(&handle, LogLevel::Debug).unwrap();
+            assert_eq!(previous, LogLevel::Info);
+            debug!("shown");
+            assert_eq!(events.load(Ordering::Relaxed), 2);
+
+            set_level(&handle, LogLevel::Off).unwrap();
+            info!("hidden");
+            assert_eq!(events.load(Ordering::Relaxed), 2);
+        });
+    }
+
+    /// Log stream kept in memory
+    #[derive(Clone, Default)]
+    pub(in crate::server) struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Captured {
+        pub(in crate::server) fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    #[test]
+    fn warnings_also_go_to_the_error_log() {
+        let (main, errors) = (Captured::default(), Captured::default());
+        let (subscriber, _handle) = {
+            let (main, errors) = (main.clone(), errors.clone());
+            subscriber(
+                LevelFilter::DEBUG,
+                move || main.clone(),
+                move || errors.clone(),
+            )
+        };
+
+        tracing::subscriber::with_default(subscriber, || {
+            debug!("details");
+            warn!("trouble");
+        });
+        let (main, errors) = (main.contents(), errors.contents());
+        assert!(main.contains("details") && main.contains("trouble"));
+        assert!(errors.contains("trouble"));
+        assert!(!errors.contains("details"));
+    }
+}
@@ -1,17 +1,47 @@
+use std::{
+    fs::Metadata,
+    io::{self, ErrorKind},
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
 use bitcode::{Decode, Encode};
-use derive_more::IsVariant;
+use derive_more::{Display, Error, IsVariant};
 
-use crate::{common::shares::CommonShareName, server::state::NewPeerConnectedToShareError};
+use crate::{
+    common::{
+        DirEntryDto, DirEntryKind, MountOptions, PeerStatusDto, ShareOptions,
+        shares::CommonShareName,
+    },
+    server::{
+        content_hash::ContentHash,
+        dir_pages::PageCursor,
+        introduction::{Introduction, IntroductionError},
+        state::{NewPeerConnectedToShareError, Share},
+    },
+};
 
 #[derive(Encode, Decode, Clone, Debug, IsVariant)]
 pub enum PeerInitMessage {
-    ConnectToShare { name: CommonShareName },
+    ConnectToShare {
+        name: CommonShareName,
+    },
     ListShares,
+    Status,
+    /// Entries of a dir of a share, for peers that dont mount it
+    ReadDir {
+        name: CommonShareName,
+        rel_path: String,
+    },
 }
 
 #[derive(Encode, Decode, Clone, Debug, IsVariant)]
 pub enum PeerInitConnectToShareResponse {
-    Ok,
+    Ok {
+        suggested_mount: Option<String>,
+        banner: Option<String>,
+    },
     Err(NewPeerConnectedToShareError),
 }
 
@@ -21,7 +51,339 @@ pub struct PeerInitListSharesRosponse {
 }
 
 #[derive(Encode, Decode, Clone, Debug, IsVariant)]
-pub enum PeerMessage {}
+pub enum PeerInitStatusResponse {
+    Ok(PeerStatusDto),
+    Refused,
+}
+
+#[derive(Encode, Decode, Clone, Debug, IsVariant)]
+pub enum PeerInitReadDirResponse {
+    /// Sorted by name
+    Ok(Vec<DirEntryDto>),
+    Err(ReadDirError),
+}
+
+#[derive(Encode, Decode, Clone, Debug, Display, Error, IsVariant, PartialEq, Eq)]
+pub enum ReadDirError {
+    #[display("Share doesnt exist")]
+    NoSuchShare,
+    #[display("Permission denied")]
+    PermissionDenied,
+    #[display("Not a directory")]
+    NotADirectory,
+    #[display("{_0}")]
+    Io(#[error(ignore)] String),
+}
+
+impl From<io::Error> for ReadDirError {
+    fn from(value: io::Error) -> Self {
+        match value.kind() {
+            ErrorKind::PermissionDenied => Self::PermissionDenied,
+            ErrorKind::NotADirectory => Self::NotADirectory,
+            _ => Self::Io(value.to_string()),
+        }
+    }
+}
+
+#[derive(Encode, Decode, Clone, Debug, IsVariant)]
+pub enum PeerMessage {
+    /// Value of an extended attribute of a file, answered with `Xattr`
+    GetXattr {
+        share: CommonShareName,
+        rel_path: String,
+        name: String,
+    },
+    /// Names of the extended attributes of a file, answered with `XattrNames`
+    ListXattr {
+        share: CommonShareName,
+        rel_path: String,
+    },
+    /// Content hash of a file, answered with `FileHash`
+    FileHash {
+        share: CommonShareName,
+        rel_path: String,
+    },
+    /// Up to `len` bytes at `offset` of a file, answered with `FileData`. Sent
+    /// over a stream of the connection, so it names the share itself
+    ReadFile {
+        share: CommonShareName,
+        rel_path: String,
+        offset: u64,
+        len: u32,
+    },
+    /// Entries of a dir, answered with `DirEntries`
+    ReadDir {
+        share: CommonShareName,
+        rel_path: String,
+    },
+    /// Attrs of a single file, answered with `Attrs`
+    Stat {
+        share: CommonShareName,
+        rel_path: String,
+    },
+    /// Up to `limit` entries of a dir, starting at `cursor` or at the
+    /// beginning without one. Answered with `DirPage`
+    ReadDirPage {
+        share: CommonShareName,
+        rel_path: String,
+        cursor: Option<PageCursor>,
+        limit: u32,
+    },
+    /// Joins another share over the connection of an already joined peer,
+    /// answered with a `PeerInitConnectToShareResponse` like the first share
+    ConnectToShare { share: CommonShareName },
+    /// Leaves a share joined with `ConnectToShare` while the connection stays
+    /// for the other shares, answered with `Left`
+    LeaveShare { share: CommonShareName },
+    /// Vouches for the key of another peer, answered with `Introduced`
+    Introduce { introduction: Introduction },
+}
+
+impl PeerMessage {
+    /// Share the request is about, by the name its owner advertises. `None`
+    /// for requests about the peer itself
+    pub fn share(&self) -> Option<&CommonShareName> {
+        let share = match self {
+            Self::GetXattr { share, .. }
+            | Self::ListXattr { share, .. }
+            | Self::FileHash { share, .. }
+            | Self::ReadFile { share, .. }
+            | Self::ReadDir { share, .. }
+            | Self::Stat { share, .. }
+            | Self::ReadDirPage { share, .. }
+            | Self::ConnectToShare { share }
+            | Self::LeaveShare { share } => share,
+            Self::Introduce { .. } => return None,
+        };
+        Some(share)
+    }
+
+    /// Whether sending the request again cant change anything on either side
+    pub fn is_idempotent(&self) -> bool {
+        !matches!(self, Self::ConnectToShare { .. } | Self::LeaveShare { .. })
+    }
+
+    pub fn respond(&self, share: &Share) -> PeerResponse {
+        let result = match self {
+            Self::GetXattr { rel_path, name, .. } => share
+                .xattr(Path::new(rel_path), name)
+                .map(PeerResponse::Xattr),
+            Self::ListXattr { rel_path, .. } => {
+                share.xattr_names(Path::new(rel_path)).map(|names| {
+                    PeerResponse::XattrNames(
+                        names
+                            .into_iter()
+                            .map(|name| name.to_string_lossy().into_owned())
+                            .collect(),
+                    )
+                })
+            }
+            Self::FileHash { rel_path, .. } => share
+                .content_hash(Path::new(rel_path))
+                .map(PeerResponse::FileHash),
+            // Needs the open files of the server, see `ChannelResponder`
+            Self::ReadFile { .. } => Ok(PeerResponse::Err(PeerResponseError::Unsupported)),
+            Self::ReadDir { rel_path, .. } => share
+                .dir_entries(Path::new(rel_path))
+                .map(PeerResponse::DirEntries),
+            Self::Stat { rel_path, .. } => {
+                share.attrs(Path::new(rel_path)).map(PeerResponse::Attrs)
+            }
+            // Needs the dir snapshots of the server, see `ChannelResponder`
+            Self::ReadDirPage { .. } => Ok(PeerResponse::Err(PeerResponseError::Unsupported)),
+            // Changes the state of the server, see `ChannelResponder`
+            Self::ConnectToShare { .. } | Self::LeaveShare { .. } | Self::Introduce { .. } => {
+                Ok(PeerResponse::Err(PeerResponseError::Unsupported))
+            }
+        };
+        result.unwrap_or_else(|err| PeerResponse::Err(err.into()))
+    }
+}
+
+/// Attributes of a file as shown to mounters
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct FileAttrs {
+    pub kind: DirEntryKind,
+    pub size: u64,
+    /// Seconds since the unix epoch
+    pub mtime: u64,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl FileAttrs {
+    /// Hides what the share owner chose not to expose
+    pub fn new(metadata: &Metadata, options: &ShareOptions) -> Self {
+        let kind = match metadata.file_type() {
+            kind if kind.is_symlink() => DirEntryKind::Symlink,
+            kind if kind.is_dir() => DirEntryKind::Dir,
+            _ => DirEntryKind::File,
+        };
+        let mtime = match options.hide_mtime {
+            true => Duration::ZERO,
+            false => metadata
+                .modified()
+                .ok()
+                .and_then(|mtime| mtime.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .unwrap_or_default(),
+        };
+        let mode = match (options.hide_mode, kind) {
+            (true, DirEntryKind::Dir) => 0o755,
+            (true, _) => 0o644,
+            (false, _) => metadata.permissions().mode() & 0o7777,
+        };
+        Self {
+            kind,
+            size: metadata.len(),
+            mtime: mtime.as_secs(),
+            mode,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+        }
+    }
+
+    /// Rewrites the owner the peer reported as the mounter chose, like NFS root squash
+    #[cfg_attr(not(feature = "fuse"), allow(dead_code))]
+    pub fn mapped(self, options: &MountOptions) -> Self {
+        Self {
+            uid: options.map_uid.unwrap_or(self.uid),
+            gid: options.map_gid.unwrap_or(self.gid),
+            ..self
+        }
+    }
+}
 
 #[derive(Encode, Decode, Clone, Debug, IsVariant)]
-pub enum PeerResponse {}
+pub enum PeerResponse {
+    Err(PeerResponseError),
+    Xattr(Option<Vec<u8>>),
+    XattrNames(Vec<String>),
+    /// `None` unless the share has `--dedup`
+    FileHash(Option<ContentHash>),
+    /// Shorter than requested once the end of the file is reached, or when the
+    /// owner cut the read to fit `--fair-bandwidth`. Empty past the end
+    FileData(Vec<u8>),
+    DirEntries(Vec<DirEntryDto>),
+    Attrs(FileAttrs),
+    /// `next` is `None` on the last page
+    DirPage {
+        entries: Vec<DirEntryDto>,
+        next: Option<PageCursor>,
+    },
+    Left,
+    Introduced,
+}
+
+#[derive(Encode, Decode, Clone, Debug, Display, Error, IsVariant, PartialEq, Eq)]
+pub enum PeerResponseError {
+    #[display("Share was removed while the request was in progress")]
+    ShareRemoved,
+    #[display("{_0}")]
+    Io(#[error(ignore)] String),
+    #[display("Share doesnt exist or wasnt joined")]
+    NoSuchShare,
+    #[display("Request isnt supported here")]
+    Unsupported,
+    #[display("Permission denied")]
+    PermissionDenied,
+    #[display("Not a directory")]
+    NotADirectory,
+    #[display("{_0}")]
+    Introduction(IntroductionError),
+}
+
+impl From<io::Error> for PeerResponseError {
+    fn from(value: io::Error) -> Self {
+        match value.kind() {
+            ErrorKind::PermissionDenied => Self::PermissionDenied,
+            ErrorKind::NotADirectory => Self::NotADirectory,
+            _ => Self::Io(value.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn hidden_attrs_are_normalized() {
+        let dir = TestDir::new("attrs");
+        let path = dir.join("file");
+        fs::write(&path, [0; 10]).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+
+        let attrs = FileAttrs::new(&metadata, &ShareOptions::default());
+        assert!(attrs.mtime > 0);
+        assert_eq!(attrs.mode, 0o600);
+        assert_eq!(attrs.size, 10);
+
+        let options = ShareOptions {
+            hide_mtime: true,
+            hide_mode: true,
+            ..Default::default()
+        };
+        let hidden = FileAttrs::new(&metadata, &options);
+        assert_eq!(hidden.mtime, 0);
+        assert_eq!(hidden.mode, 0o644);
+        assert_eq!(hidden.size, 10);
+    }
+
+    #[test]
+    fn mapped_attrs_show_the_chosen_owner() {
+        let attrs = FileAttrs {
+            kind: DirEntryKind::File,
+            size: 10,
+            mtime: 1,
+            mode: 0o644,
+            uid: 0,
+            gid: 0,
+        };
+        let options = MountOptions {
+            map_uid: Some(1000),
+            ..Default::default()
+        };
+        let mapped = attrs.clone().mapped(&options);
+        assert_eq!((mapped.uid, mapped.gid), (1000, 0));
+        assert_eq!(attrs.clone().mapped(&MountOptions::default()), attrs);
+    }
+
+    #[test]
+    fn xattrs_are_only_shown_when_enabled() {
+        let dir = TestDir::new("xattrs");
+        fs::write(dir.join("tagged"), "").unwrap();
+        if xattr::set(dir.join("tagged"), "user.rdir", b"yes").is_err() {
+            // The filesystem of the tmp dir doesnt support user xattrs
+            return;
+        }
+        let get = PeerMessage::GetXattr {
+            share: "tagged".parse().unwrap(),
+            rel_path: "tagged".to_string(),
+            name: "user.rdir".to_string(),
+        };
+        let list = PeerMessage::ListXattr {
+            share: "tagged".parse().unwrap(),
+            rel_path: "tagged".to_string(),
+        };
+
+        let mut share = Share::new("tagged".parse().unwrap(), dir.to_path_buf());
+        assert!(matches!(get.respond(&share), PeerResponse::Xattr(None)));
+        assert!(
+            matches!(list.respond(&share), PeerResponse::XattrNames(names) if names.is_empty())
+        );
+
+        share.set_options(ShareOptions {
+            xattrs: true,
+            ..Default::default()
+        });
+        assert!(matches!(get.respond(&share), PeerResponse::Xattr(Some(value)) if value == b"yes"));
+        assert!(
+            matches!(list.respond(&share), PeerResponse::XattrNames(names) if names.contains(&"user.rdir".to_string()))
+        );
+    }
+}
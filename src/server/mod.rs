@@ -1,167 +1,743 @@
+#![deny(clippy::await_holding_refcell_ref)]
+
 use std::{
-    cell::RefCell,
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    cell::{Cell, OnceCell, RefCell},
+    collections::BTreeMap,
+    net::{Ipv4Addr, SocketAddrV4},
     os::fd::AsFd,
-    path::PathBuf,
+    path::{Component, Path, PathBuf},
     rc::Rc,
-    time::Duration,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::{Context, Result as AnyResult, bail};
-use async_broadcast::{InactiveReceiver, Sender, broadcast};
+use anyhow::{Context, Result as AnyResult};
+use async_broadcast::{InactiveReceiver, RecvError, Sender, broadcast};
 use bitcode::{Decode, Encode, decode, encode};
 use derive_more::{Display, Error, From, IsVariant};
-use futures::TryFutureExt;
 use nix::{
     libc,
     unistd::{ForkResult, fork, setsid},
 };
 use smol::{
-    LocalExecutor,
+    LocalExecutor, Timer,
     channel::{Receiver, bounded, unbounded},
     future::FutureExt,
-    io,
+    io::{self, AsyncRead, AsyncWrite},
     net::{
         TcpListener, TcpStream,
         unix::{UnixListener, UnixStream},
     },
-    stream::StreamExt,
+    stream::{Stream, StreamExt},
 };
 use smol_timeout::TimeoutExt;
-use tracing::{debug, error, info, level_filters::LevelFilter};
-use tracing_appender::non_blocking::WorkerGuard;
+use tracing::{debug, error, info, warn};
+use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
+use tracing_subscriber::util::SubscriberInitExt;
 
 use crate::{
-    args::Args,
+    args::{Args, Command},
     common::{
-        ClientMessage, ConnectMessage, ServerError, ServerResponse, ShareMessage,
+        Cipher, ClientEnvelope, ClientMessage, ConfigDto, ConnectMessage, DirEntryDto,
+        MAX_BANNER_LEN, MountOptions, PeerStatusDto, ServerError, ServerResponse, ShareMessage,
+        ShareOptions, ShareOutcomeDto, ShutdownReason, StatusExposure,
+        discovery::DISCOVERY_WINDOW,
         framing::FramedStream,
-        shares::{FullShareName, ShareName},
+        shares::{CommonShareName, FullShareName, RemoteDirPath, RemotePeerAddr, ShareName},
+        trust::{KeyCheck, KeyMismatchError, NotPinnedError, TRUST_STORE_NAME, TrustStore},
+        version::BuildInfo,
     },
     server::{
-        messages::{PeerInitConnectToShareResponse, PeerInitListSharesRosponse, PeerInitMessage},
-        net::{NoiseStream, NoiseStreamError},
+        automount::{Automount, ReconnectReply},
+        bandwidth::FairBandwidth,
+        dir_pages::DirPages,
+        discovery::Discovery,
+        hooks::HookEvent,
+        introduction::{Introduction, IntroductionError},
+        logs::LogLevelHandle,
+        messages::{
+            PeerInitConnectToShareResponse, PeerInitListSharesRosponse, PeerInitMessage,
+            PeerInitReadDirResponse, PeerInitStatusResponse, PeerMessage, PeerResponse,
+            PeerResponseError, ReadDirError,
+        },
+        net::{
+            BufferBudget, FRAMED_TCP_TIMEOUT, FileHandles, NoiseStreamError, PeerConnection,
+            PublicKey, ReadLimiter,
+        },
         state::{
-            NewPeerConnectedToShareError, Peer, PeerId, RepeatedPeerError,
-            RepeatedRemoteShareError, Share, ShareDoesntExistError, State, StateNotification,
+            ExitPeerShareError, NewPeerConnectedToShareError, NoSuchRemoteShareError, Peer,
+            PeerConnectedToShareError, PeerId, ReAttachPeerError, RepeatedPeerError,
+            RepeatedRemoteShareError, Share, ShareDoesntExistError, SharePathOverlapError, State,
+            StateNotification,
         },
+        transfers::Transfers,
     },
 };
 
-mod messages;
+mod automount;
+mod bandwidth;
+pub mod content_hash;
+mod dir_pages;
+mod discovery;
+// Only mounts download files
+#[cfg_attr(not(feature = "fuse"), allow(dead_code))]
+mod download_cache;
+mod filter;
+// Only mounts use the inode table and errno mapping
+#[cfg_attr(not(feature = "fuse"), allow(dead_code))]
+pub mod fuse;
+#[cfg(feature = "fuse")]
+mod fuse_mount;
+mod hooks;
+pub mod introduction;
+mod logs;
+pub mod messages;
 pub mod net;
+mod pool;
+mod resolve;
+mod sandbox;
+pub mod selftest;
+pub mod shares_config;
 pub mod state;
+mod transfers;
+mod walk;
+#[cfg(test)]
+mod wire_vectors;
 
 pub const DOWNLOAD_CACHE_DIR: &str = "cache";
 pub const LOGS_DIR: &str = "logs";
 pub const LOGS_PREFIX: &str = "rdir.log";
+pub const ERROR_LOGS_PREFIX: &str = "rdir.error.log";
 pub const SOCKET_NAME: &str = "rdir.sock";
+/// File under the tmp dir the shares are saved to, so they survive a restart
+pub const STATE_FILE_NAME: &str = "shares.state";
+/// File under the tmp dir holding the static Noise keypair of the daemon
+pub const STATIC_KEY_NAME: &str = "rdir.key";
+/// File under the tmp dir holding the keypair replaced by `rdir rotate-key`
+/// until its grace period ends
+pub const RETIRED_KEY_NAME: &str = "rdir.key.old";
+/// Left in the tmp dir on exit, everything else is removed
+const KEPT_ON_EXIT: [&str; 5] = [
+    LOGS_DIR,
+    STATIC_KEY_NAME,
+    RETIRED_KEY_NAME,
+    TRUST_STORE_NAME,
+    STATE_FILE_NAME,
+];
 /// 29284
 pub const NETWORK_PORT: u16 = u16::from_be_bytes(*b"rd");
+/// Wait after running out of file descriptors before accepting again
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// Clients waiting to be served before new ones are told to retry, past this
+/// they could time out before the executor even reads their command
+const MAX_PENDING_CLIENTS: usize = 64;
+/// How long a refused client is told to wait before retrying
+const BUSY_RETRY_AFTER: Duration = Duration::from_millis(200);
+/// Time clients get to receive `ShuttingDown` before the server exits
+const SHUTDOWN_GRACE: Duration = Duration::from_millis(50);
+/// Files the sandbox lets the server read besides the shares, name resolution
+/// of peers needs them
+const SANDBOX_READABLE: [&str; 3] = ["/etc/hosts", "/etc/resolv.conf", "/etc/nsswitch.conf"];
 
 pub struct Server<'a> {
     ex: LocalExecutor<'a>,
     // TODO Check if want to hold on to this, maybe parse as config
     args: Args,
     state: RefCell<State>,
-    shutdown_tx: Sender<()>,
-    shutdown_rx: InactiveReceiver<()>,
+    log_level: LogLevelHandle,
+    reads: ReadLimiter,
+    files: FileHandles,
+    /// Snapshots of the dirs peers are paging through
+    dir_pages: DirPages,
+    transfers: Transfers,
+    /// Proposed for the sessions this daemon opens, `--cipher` or detected
+    cipher: Cipher,
+    /// Read ahead of every peer connection, within `--io-buffer-budget`
+    io_buffers: Arc<BufferBudget>,
+    bandwidth: Option<FairBandwidth>,
+    /// Set once the UDP socket is bound, only with `--udp-socket`
+    discovery: OnceCell<Rc<Discovery>>,
+    /// Where the shares are saved on every change, unset in peer only mode
+    /// and for in memory servers
+    state_file: OnceCell<PathBuf>,
+    /// Automounts still retrying, by the share they mount
+    reconnects: RefCell<BTreeMap<FullShareName, smol::channel::Sender<ReconnectReply>>>,
+    /// Automounts told when the connection of their mount is lost, so they
+    /// mount it again
+    automount_drops: RefCell<BTreeMap<FullShareName, smol::channel::Sender<()>>>,
+    /// Clients accepted but not yet fully served
+    pending_clients: Cell<usize>,
+    /// Set once Landlock confines the server, shares cant be added from then on
+    sandboxed: Cell<bool>,
+    /// Requests to send over the connections of mounted shares, by their peer
+    peer_requests: RefCell<BTreeMap<PeerId, smol::channel::Sender<PeerRequest>>>,
+    /// Static keys the connections of mounted shares were authenticated with,
+    /// by their peer. Same host peers of this user have none
+    peer_keys: RefCell<BTreeMap<PeerId, PublicKey>>,
+    /// FUSE sessions of mounted shares, dropping one unmounts it
+    #[cfg(feature = "fuse")]
+    mounts: RefCell<BTreeMap<FullShareName, fuser::BackgroundSession>>,
+    shutdown_tx: Sender<ShutdownReason>,
+    shutdown_rx: InactiveReceiver<ShutdownReason>,
+    /// Next port of [`Self::same_host_peer_addr`]
+    next_same_host_port: Cell<u16>,
 }
 
 impl Server<'_> {
-    pub fn run(args: Args, std_listener: std::os::unix::net::UnixListener) -> AnyResult<()> {
-        let _tracing_guard = Self::init(&args)?;
+    /// Without an IPC listener the server runs in peer only mode
+    pub fn run(
+        args: Args,
+        std_listener: Option<std::os::unix::net::UnixListener>,
+    ) -> AnyResult<()> {
+        // Read before daemonizing, so errors still reach the terminal
+        let configured_shares = match &args.command {
+            Command::PeerOnly { shares_config } => shares_config::load(shares_config)?,
+            _ => Vec::new(),
+        };
+        let automounts = match &args.automount {
+            Some(path) => automount::load(path)?,
+            None => Vec::new(),
+        };
+        let (tracing_guard, log_level) = Self::init(&args)?;
         info!("Init successful");
-        let unix_listener: UnixListener = std_listener
-            .try_into()
-            .context("Failed to register the IPC socket as async")?;
-        let tcp_listener: TcpListener = std::net::TcpListener::bind(
-            args.tcp_socket
-                .unwrap_or(SocketAddrV4::new(Ipv4Addr::LOCALHOST, NETWORK_PORT)),
-        )?
-        .try_into()?;
-
-        let ex = LocalExecutor::new();
-        let (shutdown_tx, mut shutdown_rx) = broadcast(1);
-        let self_ = Rc::new(Self {
-            ex,
-            args,
-            state: RefCell::new(State::default()),
-            shutdown_tx,
-            shutdown_rx: shutdown_rx.clone().deactivate(),
-        });
+        let result = Self::new(args, log_level).serve(std_listener, configured_shares, automounts);
+        finish(tracing_guard, Path::new("."));
+        result
+    }
+
+    /// Serves clients on `std_listener` if any, and peers until shut down
+    fn serve(
+        self: &Rc<Self>,
+        std_listener: Option<std::os::unix::net::UnixListener>,
+        configured_shares: Vec<Share>,
+        automounts: Vec<Automount>,
+    ) -> AnyResult<()> {
+        let args = &self.args;
+        info!("Opening peer sessions with {}", self.cipher);
+        net::check_forward_secrecy(self.cipher, args.require_forward_secrecy)?;
+        net::load_static_key(&args.tmp_dir.join(STATIC_KEY_NAME))
+            .context("Failed to load the static key")?;
+        net::load_retired_key(&args.tmp_dir.join(RETIRED_KEY_NAME))
+            .context("Failed to load the retired key")?;
+        let unix_listener: Option<UnixListener> =
+            std_listener
+                .map(TryInto::try_into)
+                .transpose()
+                .context("Failed to register the IPC socket as async")?;
+        let tcp_socket = args.tcp_socket_or_default();
+        let tcp_listener: TcpListener = bind_tcp(tcp_socket)?.try_into()?;
+        let same_host_listener = match args
+            .same_host_socket
+            .then(|| net::bind_same_host(tcp_socket.port()))
+        {
+            // Any process could have taken the name, peers then use TCP
+            Some(Err(err)) if err.kind() == io::ErrorKind::AddrInUse => {
+                warn!("Same host peer socket is already taken, same host peers use TCP instead");
+                None
+            }
+            listener => listener
+                .transpose()
+                .context("Failed to bind the same host peer socket")?,
+        };
+
+        let mut shutdown_rx = self.shutdown_rx.activate_cloned();
+        // Peer only mode gets its shares from the config instead
+        if !matches!(args.command, Command::PeerOnly { .. }) {
+            let state_file = args.tmp_dir.join(STATE_FILE_NAME);
+            let state = State::load(&state_file).context("Failed to load the saved shares")?;
+            *self.state.borrow_mut() = state;
+            let _ = self.state_file.set(state_file);
+        }
+        self.add_configured_shares(configured_shares)?;
+        if args.sandbox {
+            self.sandbox(&automounts)?;
+        }
         info!("Starting jobs");
-        let client_fut = self_.clone().accept_client(unix_listener);
-        let tcp_fut = self_.clone().accept_peer(tcp_listener);
-        let main_fut = client_fut.or(tcp_fut);
+        let client_fut = {
+            let self_ = self.clone();
+            async move {
+                match unix_listener {
+                    Some(listener) => self_.accept_client(listener).await,
+                    None => smol::future::pending().await,
+                }
+            }
+        };
+        let tcp_fut = self.clone().accept_peer(tcp_listener);
+        let same_host_fut = {
+            let self_ = self.clone();
+            async move {
+                match same_host_listener {
+                    Some(listener) => self_.accept_same_host_peer(listener).await,
+                    None => smol::future::pending().await,
+                }
+            }
+        };
+        let main_fut = client_fut.or(tcp_fut).or(same_host_fut);
+        self.spawn_automounts(automounts);
+        self.ex.spawn(self.clone().log_transfers()).detach();
+        if let Some(udp_socket) = args.udp_socket {
+            let discovery = Discovery::bind(udp_socket, tcp_socket.port())
+                .context(format!("Failed to bind the udp socket {udp_socket}"))?;
+            let discovery = Rc::new(discovery);
+            let _ = self.discovery.set(discovery.clone());
+            let server = self.clone();
+            let fut = async move { discovery.serve(|| server.advertised_shares()).await };
+            self.ex.spawn(fut).detach();
+        }
 
-        let result = smol::block_on(
-            shutdown_rx
-                .recv()
-                .map_err(anyhow::Error::from)
-                .or(self_.ex.run(main_fut)),
-        );
+        let shutdown = async {
+            let reason = shutdown_triggered(&mut shutdown_rx).await;
+            info!("Shutting down, {reason}");
+            anyhow::Ok(())
+        };
+        let result = smol::block_on(shutdown.or(self.ex.run(main_fut)));
+        // Lets clients still waiting on a response learn why there wont be one
+        smol::block_on(self.ex.run(Timer::after(SHUTDOWN_GRACE)));
+        #[cfg(feature = "fuse")]
+        self.mounts.borrow_mut().clear();
         if let Err(ref err) = result {
             error!("{err}");
         }
-        self_.clean_up();
-        info!("Exitting");
         result
     }
 
-    async fn accept_client(self: Rc<Self>, listener: UnixListener) -> AnyResult<()> {
-        let mut incoming = listener.incoming();
+    fn new(args: Args, log_level: LogLevelHandle) -> Rc<Self> {
+        let (shutdown_tx, shutdown_rx) = shutdown_channel();
+        Rc::new(Self {
+            ex: LocalExecutor::new(),
+            state: Default::default(),
+            log_level,
+            reads: ReadLimiter::new(
+                args.max_concurrent_reads,
+                args.max_concurrent_reads_per_peer,
+            ),
+            files: Default::default(),
+            dir_pages: Default::default(),
+            transfers: Default::default(),
+            cipher: args.cipher.unwrap_or_else(net::detect_cipher),
+            io_buffers: BufferBudget::new(args.io_buffer_budget),
+            bandwidth: args.fair_bandwidth.map(FairBandwidth::new),
+            discovery: Default::default(),
+            state_file: Default::default(),
+            reconnects: Default::default(),
+            automount_drops: Default::default(),
+            pending_clients: Default::default(),
+            sandboxed: Default::default(),
+            peer_requests: Default::default(),
+            peer_keys: Default::default(),
+            #[cfg(feature = "fuse")]
+            mounts: Default::default(),
+            args,
+            shutdown_tx,
+            shutdown_rx: shutdown_rx.deactivate(),
+            next_same_host_port: Default::default(),
+        })
+    }
+
+    /// Server living only in this process, for tests and `rdir selftest`. It
+    /// isnt daemonized, logs to the subscriber of the caller and creates no
+    /// files or dirs. Clients are served by passing their streams to
+    /// `handle_client`
+    pub fn in_memory(args: Args) -> Rc<Self> {
+        let level = tracing::level_filters::LevelFilter::from(args.log_level);
+        Self::new(args, tracing_subscriber::reload::Layer::new(level).1)
+    }
+
+    /// Adds a share named after its dir unless `name` is given. Returns the
+    /// name and the shares it overlaps with
+    fn add_share(
+        self: &Rc<Self>,
+        path: PathBuf,
+        name: Option<CommonShareName>,
+        options: ShareOptions,
+        no_overlap: bool,
+        force: bool,
+    ) -> Result<(CommonShareName, Option<SharePathOverlapError>), ServerError> {
+        self.check_share_path(&path, force)?;
+        let name = match name {
+            Some(val) => CommonShareName::local(&val.to_string())?,
+            None => path
+                .file_name()
+                .ok_or(ServerError::InvalidShareName)
+                .and_then(|n| CommonShareName::local(&n.to_string_lossy()).map_err(Into::into))?,
+        };
+        let mut share = Share::new(name.clone(), path);
+        let expires_in = options.expires_in.map(Duration::from_secs);
+        share.expires_at = expires_in.map(|d| Instant::now() + d);
+        share.set_options(options);
+        let removal_signal = share.removal_signal();
+        let overlap = self.check_overlap(&share.path, &name, no_overlap)?;
+        self.state.borrow_mut().add_share(share)?;
+        self.save_state();
+        if let Some(expires_in) = expires_in {
+            let fut = self
+                .clone()
+                .expire_share(name.clone(), expires_in, removal_signal);
+            self.ex.spawn(fut).detach();
+        }
+        Ok((name, overlap))
+    }
+
+    /// Refuses a new dir of a share once sandboxed, or a sensitive one unless
+    /// `force`d
+    fn check_share_path(&self, path: &Path, force: bool) -> Result<(), ServerError> {
+        if self.sandboxed.get() {
+            return Err(SandboxedError.into());
+        }
+        if let Err(err) = self.check_sensitive_path(path) {
+            match force {
+                true => warn!("Forced to share: {err}"),
+                false => return Err(err.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Shares other than `name` that `path` overlaps, refused with `no_overlap`
+    fn check_overlap(
+        &self,
+        path: &Path,
+        name: &CommonShareName,
+        no_overlap: bool,
+    ) -> Result<Option<SharePathOverlapError>, SharePathOverlapError> {
+        let mut overlapping = self.state.borrow().overlapping_shares(path);
+        overlapping.retain(|share| share != name);
+        let overlap = (!overlapping.is_empty()).then_some(SharePathOverlapError {
+            shares: overlapping,
+        });
+        match overlap {
+            Some(overlap) if no_overlap => Err(overlap),
+            overlap => Ok(overlap),
+        }
+    }
+
+    /// Saves the shares if the server has a state file. A failed save only
+    /// loses the change on restart, so it is logged instead of failing the
+    /// request
+    fn save_state(&self) {
+        let Some(path) = self.state_file.get() else {
+            return;
+        };
+        if let Err(err) = self.state.borrow().save(path) {
+            warn!(
+                "Failed to save the shares to {}: {err}",
+                path.to_string_lossy()
+            );
+        }
+    }
 
-        while let Some(stream) = incoming.next().await {
-            let stream = stream?;
-            self.ex.spawn(self.clone().handle_client(stream)).detach();
+    fn add_configured_shares(&self, shares: Vec<Share>) -> AnyResult<()> {
+        let mut state = self.state.borrow_mut();
+        for share in shares {
+            let name = share.name.clone();
+            let context = || format!("Failed to add the configured share {name}");
+            self.check_sensitive_path(&share.path)
+                .with_context(context)?;
+            state.add_share(share).with_context(context)?;
         }
+        Ok(())
+    }
 
+    /// Applied once the listeners are bound and the configured shares added,
+    /// anything opened later has to be within the allowed paths
+    fn sandbox(&self, automounts: &[Automount]) -> AnyResult<()> {
+        for automount in automounts
+            .iter()
+            .filter(|automount| automount.path.is_none())
+        {
+            warn!(
+                "Automount of {} has no mount path, the sandbox wont let it create the \
+                 default one",
+                automount.name
+            );
+        }
+        let (readable, writable) = self.sandbox_paths(automounts);
+        match sandbox::restrict(readable, writable).context("Failed to apply the sandbox")? {
+            true => {
+                self.sandboxed.set(true);
+                info!("Sandbox applied");
+            }
+            false => warn!("Landlock isnt supported by this kernel, running without a sandbox"),
+        }
         Ok(())
     }
 
-    async fn handle_client(self: Rc<Self>, stream: UnixStream) {
+    /// Paths the sandbox lets the server read and write. Besides the tmp dir
+    /// only the paths shares are mounted at are written
+    fn sandbox_paths(&self, automounts: &[Automount]) -> (Vec<PathBuf>, Vec<PathBuf>) {
+        let state = self.state.borrow();
+        let readable = state
+            .get_shares()
+            .values()
+            .map(|share| share.path.clone())
+            .chain(SANDBOX_READABLE.map(PathBuf::from))
+            .collect();
+        let writable = [self.args.tmp_dir.clone()]
+            .into_iter()
+            .chain(
+                state
+                    .get_remote_shares()
+                    .values()
+                    .map(|share| share.mount_path.clone()),
+            )
+            .chain(
+                automounts
+                    .iter()
+                    .filter_map(|automount| automount.path.clone()),
+            )
+            .collect();
+        (readable, writable)
+    }
+
+    /// Mounts the configured remote shares in the background, failed mounts are
+    /// retried without holding up the rest of the server
+    fn spawn_automounts(self: &Rc<Self>, automounts: Vec<Automount>) {
+        for automount in automounts {
+            let (dropped_tx, dropped_rx) = bounded(1);
+            self.automount_drops
+                .borrow_mut()
+                .insert(automount.name.clone(), dropped_tx);
+            let self_ = self.clone();
+            let fut = async move {
+                let self_ = &self_;
+                loop {
+                    let (reconnect_tx, reconnect_rx) = bounded(1);
+                    self_
+                        .reconnects
+                        .borrow_mut()
+                        .insert(automount.name.clone(), reconnect_tx);
+                    let mounted = automount::retry(
+                        &automount,
+                        automount::retry_backoff(),
+                        reconnect_rx,
+                        || async {
+                            self_
+                                .connect_to_remote_share(
+                                    automount.name.clone(),
+                                    automount.path.clone(),
+                                    automount.options(),
+                                )
+                                .await
+                                .map(drop)
+                        },
+                    )
+                    .await;
+                    self_.reconnects.borrow_mut().remove(&automount.name);
+                    if !mounted || dropped_rx.recv().await.is_err() {
+                        break;
+                    }
+                    info!(
+                        "Lost the mount of {}, automounting it again",
+                        automount.name
+                    );
+                }
+                self_.automount_drops.borrow_mut().remove(&automount.name);
+            };
+            self.ex.spawn(fut).detach();
+        }
+    }
+
+    /// Periodically logs the progress of long transfers, so slow or stuck ones
+    /// show up in the logs
+    async fn log_transfers(self: Rc<Self>) {
+        let interval = Duration::from_secs(self.args.transfer_log_interval.get());
+        let threshold = Duration::from_secs(self.args.transfer_log_after);
+        loop {
+            Timer::after(interval).await;
+            self.transfers.log_progress(Instant::now(), threshold);
+        }
+    }
+
+    async fn accept_client(self: Rc<Self>, listener: UnixListener) -> AnyResult<()> {
+        accept_loop(listener.incoming(), |stream| self.spawn_client(stream)).await
+    }
+
+    /// Counts the client as pending right away, so the ones still queued on
+    /// the executor count too
+    fn spawn_client(self: &Rc<Self>, stream: UnixStream) {
+        if self.pending_clients.get() >= MAX_PENDING_CLIENTS {
+            warn!("Too many pending clients, telling a new one to retry");
+            let busy = ServerBusyError {
+                retry_after_ms: BUSY_RETRY_AFTER.as_millis() as u32,
+            };
+            let resp = ServerResponse::from(ServerError::from(busy));
+            let fut = async move {
+                let _ = FramedStream::new(stream)
+                    .write(&encode(&resp))
+                    .timeout(FRAMED_TCP_TIMEOUT)
+                    .await;
+            };
+            self.ex.spawn(fut).detach();
+            return;
+        }
+        let pending = PendingClient::new(self.clone());
+        let fut = async move {
+            pending.0.clone().handle_client(stream).await;
+            drop(pending);
+        };
+        self.ex.spawn(fut).detach();
+    }
+
+    pub async fn handle_client(self: Rc<Self>, stream: UnixStream) {
         let mut stream = FramedStream::new(stream);
-        let result = async {
-            let buf = stream
-                .read()
-                .timeout(Duration::from_millis(500))
-                .await
-                .context("Client timed out")??;
-            let message: ClientMessage = decode(&buf)?;
-            anyhow::Ok(message)
+        let buf = match stream.read().timeout(Duration::from_millis(500)).await {
+            Some(Ok(val)) => val,
+            Some(Err(err)) => {
+                error!("Error while accepting the client {err}");
+                return;
+            }
+            None => {
+                error!("Client timed out");
+                return;
+            }
         };
-        let message = match result.await {
+        // The client is still listening, so it can be told what went wrong
+        let envelope: ClientEnvelope = match decode(&buf) {
             Ok(val) => val,
             Err(err) => {
-                error!("Error while accepting the client {err}");
+                error!("Client sent an undecodable message: {err}");
+                let resp = ServerResponse::from(ServerError::from(ProtocolError));
+                let _ = stream.write(&encode(&resp)).await;
                 return;
             }
         };
-        debug!("Client sent: {message:?}");
 
-        let result: Result<ServerResponse, ServerError> = async {
+        // Other clients are served on the same executor, so borrows of `state`
+        // must never be held across an `.await`
+        let request = async {
+            let message = ClientMessage::try_from(envelope)?;
+            debug!("Client sent: {message:?}");
             match message {
                 ClientMessage::Connect(connect_message) => match connect_message {
                     ConnectMessage::Ls => {
                         let shares = self.state.borrow().remote_shares_dto();
                         Ok(ServerResponse::LsMountedShares(shares))
                     }
-                    ConnectMessage::Mount { path, name } => {
-                        let path = PathBuf::from(path);
-                        match name {
-                            ShareName::Common(_share_name) => todo!("Make autodiscovery"),
-                            ShareName::Full(share_name) => {
-                                self.connect_to_remote_share(share_name, path).await?;
-                                Ok(ServerResponse::Ok)
+                    ConnectMessage::Mount {
+                        path,
+                        name,
+                        options,
+                    } => {
+                        fuse::check_available(Path::new(fuse::FUSE_DEVICE))?;
+                        let path = path.map(PathBuf::from);
+                        let share_name = match name {
+                            ShareName::Common(share_name) => {
+                                self.discover_share(share_name).await?
+                            }
+                            ShareName::Full(share_name) => share_name,
+                        };
+                        let banner = self
+                            .connect_to_remote_share(share_name, path, options)
+                            .await?;
+                        Ok(ServerResponse::Mounted { banner })
+                    }
+                    ConnectMessage::Status { addr } => {
+                        match self.peer_status((&addr).into()).await? {
+                            PeerInitStatusResponse::Ok(status) => {
+                                Ok(ServerResponse::PeerStatus(status))
+                            }
+                            PeerInitStatusResponse::Refused => Ok(ServerResponse::Warning(
+                                format!("{addr} doesnt expose its status"),
+                            )),
+                        }
+                    }
+                    ConnectMessage::LsRemote { dir } => {
+                        let entries = self.list_remote_dir(dir).await?;
+                        Ok(ServerResponse::DirEntries(entries))
+                    }
+                    ConnectMessage::Unmount { name } => {
+                        let mut state = self.state.borrow_mut();
+                        let (name, owner) = state
+                            .resolve_remote_share(&name)?
+                            .ok_or(NoSuchRemoteShareError)?;
+                        let peer = &state.get_peers()[&owner];
+                        // The last share of the peer ends its connection, which
+                        // is cleaned up like any other disconnect
+                        if peer.used_shares().is_empty() && peer.used_remote_shares().len() == 1 {
+                            drop(state);
+                            self.remove_peer(owner);
+                            return Ok(ServerResponse::Ok);
+                        }
+                        state
+                            .exit_remote_share(owner, name.clone(), &self.shutdown_tx)
+                            .map_err(|ExitPeerShareError::NoSuchConnectionError(err)| err)?;
+                        #[cfg(feature = "fuse")]
+                        self.mounts.borrow_mut().remove(&name);
+                        Ok(ServerResponse::Ok)
+                    }
+                    ConnectMessage::Browse { name, path } => {
+                        let (name, owner) = self
+                            .state
+                            .borrow()
+                            .resolve_remote_share(&name)?
+                            .ok_or(NoSuchRemoteShareError)?;
+                        let message = PeerMessage::ReadDir {
+                            share: name.name,
+                            rel_path: path.unwrap_or_default(),
+                        };
+                        match self.request_peer(owner, &message).await? {
+                            PeerResponse::DirEntries(entries) => {
+                                Ok(ServerResponse::DirEntries(entries))
                             }
+                            PeerResponse::Err(err) => Err(ServerError::Browse(err)),
+                            _ => Err(ProtocolError.into()),
+                        }
+                    }
+                    ConnectMessage::Trust { addr } => {
+                        let key = self.trust_peer(&addr).await?;
+                        Ok(ServerResponse::Trusted {
+                            key: content_hash::to_hex(&key),
+                        })
+                    }
+                    ConnectMessage::Untrust { addr } => match self.trust_store().untrust(&addr)? {
+                        true => Ok(ServerResponse::Ok),
+                        false => Ok(ServerResponse::Warning(format!(
+                            "No key of {addr} was pinned"
+                        ))),
+                    },
+                    ConnectMessage::Introduce { name, addr, ttl } => {
+                        let (_, owner) = self
+                            .state
+                            .borrow()
+                            .resolve_remote_share(&name)?
+                            .ok_or(NoSuchRemoteShareError)?;
+                        let key = self
+                            .trust_store()
+                            .pinned(&addr)?
+                            .ok_or_else(|| NotPinnedError { addr: addr.clone() })?;
+                        let now = SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap_or_default()
+                            .as_secs();
+                        let message = PeerMessage::Introduce {
+                            introduction: net::introduce(addr, key, now + ttl),
+                        };
+                        match self.request_peer(owner, &message).await? {
+                            PeerResponse::Introduced => Ok(ServerResponse::Ok),
+                            PeerResponse::Err(err) => Err(ServerError::Introduce(err)),
+                            _ => Err(ProtocolError.into()),
                         }
                     }
-                    ConnectMessage::Unmount { name } => todo!(),
                 },
-                ClientMessage::Discover => todo!(),
+                ClientMessage::DebugDump => match self.args.allow_debug {
+                    true => Ok(ServerResponse::DebugDump(format!(
+                        "{:#?}\nio buffers in use: {} bytes",
+                        self.state.borrow(),
+                        self.io_buffers.used()
+                    ))),
+                    false => Err(DebugDisabledError.into()),
+                },
+                ClientMessage::DisconnectPeer { addr } => Ok(ServerResponse::Disconnected {
+                    peers: self.disconnect_host(addr),
+                }),
+                ClientMessage::Discover => match self.discovery.get() {
+                    Some(discovery) => Ok(ServerResponse::Discovered(
+                        discovery.discover(DISCOVERY_WINDOW).await?,
+                    )),
+                    None => Err(DiscoveryDisabledError.into()),
+                },
                 ClientMessage::Kill => {
-                    let _ = self.shutdown_tx.try_broadcast(());
+                    let _ = self.shutdown_tx.try_broadcast(ShutdownReason::Kill);
                     Ok(ServerResponse::Ok)
                 }
                 ClientMessage::Ls => {
@@ -173,32 +749,146 @@ impl Server<'_> {
                     })
                 }
                 ClientMessage::Ping => Ok(ServerResponse::Ok),
+                ClientMessage::Config => {
+                    let log_level = logs::current_level(&self.log_level);
+                    Ok(ServerResponse::Config(ConfigDto::new(
+                        &self.args, log_level,
+                    )))
+                }
+                ClientMessage::SetLogLevel(level) => {
+                    let previous = logs::set_level(&self.log_level, level)?;
+                    info!("Log level changed from {previous} to {level}");
+                    Ok(ServerResponse::LogLevel { previous })
+                }
                 ClientMessage::Share(share_message) => match share_message {
-                    ShareMessage::Ls => {
-                        let shares = self.state.borrow().shares_dto();
+                    ShareMessage::Ls {
+                        availability,
+                        idle_only,
+                    } => {
+                        let state = self.state.borrow();
+                        let idle = state.idle_shares();
+                        let shares = state.shares_dto_where(|share| {
+                            availability.is_none_or(|availability| availability.matches(share))
+                                && (!idle_only || idle.contains(&&share.name))
+                        });
                         Ok(ServerResponse::LsShares(shares))
                     }
-                    ShareMessage::Remove { name } => Ok(self
-                        .state
-                        .borrow_mut()
-                        .remove_share(&name, &self.shutdown_tx)
-                        .into()),
-                    ShareMessage::Share { path, name } => {
+                    ShareMessage::Remove { name } => {
+                        let result = self.remove_share(&name);
+                        self.save_state();
+                        Ok(result.into())
+                    }
+                    ShareMessage::Repath {
+                        name,
+                        path,
+                        expected_current,
+                        no_overlap,
+                        force,
+                    } => {
                         let path = PathBuf::from(path);
-                        let name = match name {
-                            Some(val) => val,
-                            None => path
-                                .file_name()
-                                .ok_or(ServerError::InvalidShareName)
-                                .and_then(|n| n.to_string_lossy().parse().map_err(Into::into))?,
-                        };
-                        let share = Share::new(name, path);
-                        Ok(self.state.borrow_mut().add_share(share).into())
+                        self.check_share_path(&path, force)?;
+                        let overlap = self.check_overlap(&path, &name, no_overlap)?;
+                        let previous = self.state.borrow_mut().update_share_path(
+                            &name,
+                            path,
+                            expected_current.as_deref().map(Path::new),
+                        )?;
+                        self.files.forget_share(&name);
+                        self.save_state();
+                        info!("Share {name} moved from {}", previous.to_string_lossy());
+                        Ok(match overlap {
+                            Some(overlap) => ServerResponse::Warning(overlap.to_string()),
+                            None => ServerResponse::Ok,
+                        })
+                    }
+                    ShareMessage::Size { name } => {
+                        let path = self
+                            .state
+                            .borrow()
+                            .get_shares()
+                            .get(&name)
+                            .ok_or(ShareDoesntExistError)?
+                            .path
+                            .clone();
+                        let bytes = walk::dir_size(path, self.args.walk_concurrency).await?;
+                        Ok(ServerResponse::ShareSize { name, bytes })
+                    }
+                    ShareMessage::Share {
+                        path,
+                        name,
+                        options,
+                        no_overlap,
+                        force,
+                    } => self
+                        .add_share(path.into(), name, options, no_overlap, force)
+                        .map(|(_, overlap)| match overlap {
+                            Some(overlap) => ServerResponse::Warning(overlap.to_string()),
+                            None => ServerResponse::Ok,
+                        }),
+                    ShareMessage::ShareMany {
+                        paths,
+                        options,
+                        no_overlap,
+                        force,
+                    } => {
+                        let outcomes = paths
+                            .into_iter()
+                            .map(|path| {
+                                let result = self
+                                    .add_share(
+                                        PathBuf::from(&path),
+                                        None,
+                                        options.clone(),
+                                        no_overlap,
+                                        force,
+                                    )
+                                    .map(|(name, _)| name)
+                                    .map_err(Into::into);
+                                ShareOutcomeDto { path, result }
+                            })
+                            .collect();
+                        Ok(ServerResponse::PartialOk(outcomes))
                     }
                 },
+                ClientMessage::ReconnectMount { name } => {
+                    if self.state.borrow().get_remote_shares().contains_key(&name) {
+                        return Ok(ServerResponse::Ok);
+                    }
+                    let reconnect_tx = self
+                        .reconnects
+                        .borrow()
+                        .get(&name)
+                        .cloned()
+                        .ok_or(NoSuchRemoteShareError)?;
+                    let (reply_tx, reply_rx) = bounded(1);
+                    let reply = match reconnect_tx.send(reply_tx).await {
+                        Ok(()) => reply_rx.recv().await.ok(),
+                        Err(_) => None,
+                    };
+                    match reply {
+                        Some(Ok(())) => Ok(ServerResponse::Ok),
+                        Some(Err(err)) => Ok(ServerResponse::Err(err.into())),
+                        // The automount finished without answering, after its
+                        // own attempt mounted the share or it gave up
+                        None if self.state.borrow().get_remote_shares().contains_key(&name) => {
+                            Ok(ServerResponse::Ok)
+                        }
+                        None => Err(NoSuchRemoteShareError.into()),
+                    }
+                }
+                ClientMessage::ShareExists { name } => Ok(ServerResponse::Bool(
+                    self.state.borrow().get_shares().contains_key(&name),
+                )),
+                ClientMessage::Transfers => Ok(ServerResponse::Transfers(self.transfers.dtos())),
+                ClientMessage::Version => Ok(ServerResponse::Version(BuildInfo::current())),
             }
-        }
-        .await;
+        };
+        let mut shutdown_rx = self.shutdown_rx.activate_cloned();
+        let shutting_down = async {
+            let reason = shutdown_triggered(&mut shutdown_rx).await;
+            Ok(ServerResponse::ShuttingDown { reason })
+        };
+        let result: Result<ServerResponse, ServerError> = request.or(shutting_down).await;
 
         let resp = result
             .inspect_err(|e| error!("Error during handling local client: {e}"))
@@ -207,227 +897,3965 @@ impl Server<'_> {
         self.state.borrow().should_server_close(&self.shutdown_tx);
     }
 
-    async fn accept_peer(self: Rc<Self>, listener: TcpListener) -> AnyResult<()> {
-        let mut incoming = listener.incoming();
+    /// Removes the share once `expires_in` elapses, unless it was removed sooner
+    async fn expire_share(
+        self: Rc<Self>,
+        name: CommonShareName,
+        expires_in: Duration,
+        removal_signal: Receiver<()>,
+    ) {
+        let expired = async {
+            Timer::after(expires_in).await;
+            true
+        };
+        let removed = async {
+            let _ = removal_signal.recv().await;
+            false
+        };
+        if expired.or(removed).await {
+            info!("Share {name} expired");
+            let _ = self.remove_share(&name);
+        }
+    }
 
-        while let Some(stream) = incoming.next().await {
-            let stream = stream?;
-            debug!("Received a connection from peer");
-            self.ex.spawn(self.clone().handle_peer(stream)).detach();
+    /// Same host peers of another user have to authenticate with Noise, see
+    /// [`net::is_same_user`]
+    async fn accept_same_host_peer(self: Rc<Self>, listener: UnixListener) -> AnyResult<()> {
+        accept_loop(listener.incoming(), |stream| {
+            let same_user = match net::is_same_user(&stream) {
+                Ok(same_user) => same_user,
+                Err(err) => return error!("Failed to check a same host peer: {err}"),
+            };
+            debug!("Received a connection from a same host peer");
+            let addr = self.same_host_peer_addr();
+            if same_user {
+                let conn = PeerConnection::accept_same_host(stream, addr);
+                return self
+                    .ex
+                    .spawn(self.clone().handle_peer_connection(conn))
+                    .detach();
+            }
+            let self_ = self.clone();
+            let fut = async move {
+                match PeerConnection::accept_other_user(
+                    stream,
+                    addr,
+                    self_.args.cipher,
+                    &self_.io_buffers,
+                )
+                .await
+                {
+                    Ok(conn) => self_.handle_peer_connection(conn).await,
+                    Err(err) => error!("Error during handling a same host peer: {err}"),
+                }
+            };
+            self.ex.spawn(fut).detach();
+        })
+        .await
+    }
+
+    /// Key a same host peer is told apart by. No TCP peer connects from the
+    /// unspecified address, so only the port has to be unique among them
+    fn same_host_peer_addr(&self) -> SocketAddrV4 {
+        let state = self.state.borrow();
+        loop {
+            let port = self.next_same_host_port.get();
+            self.next_same_host_port.set(port.wrapping_add(1));
+            let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port);
+            if !state.get_peers_by_scoket().contains_key(&addr) {
+                break addr;
+            }
         }
+    }
 
-        Ok(())
+    async fn accept_peer(self: Rc<Self>, listener: TcpListener) -> AnyResult<()> {
+        accept_loop(listener.incoming(), |stream| {
+            debug!("Received a connection from peer");
+            self.ex.spawn(self.clone().handle_peer(stream)).detach();
+        })
+        .await
     }
 
     async fn handle_peer(self: Rc<Self>, stream: TcpStream) {
+        debug!("Entered `handle_peer`");
+        match PeerConnection::accept(stream, self.args.cipher, &self.io_buffers).await {
+            Ok(conn) => self.handle_peer_connection(conn).await,
+            Err(err) => error!("Error during handling TCP client: {err}"),
+        }
+    }
+
+    /// Answers the first request of a peer, serving the connection from then
+    /// on if the peer joined a share with it
+    async fn handle_peer_connection<T>(self: Rc<Self>, mut conn: PeerConnection<T>)
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
         let value = async {
-            debug!("Entered `handle_peer`");
-            let mut stream = accept_from_peer(stream).await?;
-            stream.open_stream(cx);
-            let buf = stream.read_timeout().await?;
+            let (stream, buf) = conn.next_request().await?;
             let message: PeerInitMessage = decode(&buf)?;
             debug!("Peer sent a message: {message:?}");
 
-            match message {
+            let new_peer = match message {
                 PeerInitMessage::ConnectToShare { name } => {
-                    let SocketAddr::V4(address) = stream.peer_addr()? else {
-                        bail!("IPv6 is unsupported");
+                    let joined = match self.local_name(&name) {
+                        Some(name) => self
+                            .join_share(conn.peer_addr(), name.clone())
+                            .map(|joined| (joined, name)),
+                        None => Err(ShareDoesntExistError.into()),
                     };
-                    let (shutdown_tx, shutdown_rx) = bounded(1);
-                    let (notification_tx, notification_rx) = unbounded();
-                    let peer = Peer::new(address, shutdown_tx, notification_tx);
-                    let result = self
-                        .state
-                        .borrow_mut()
-                        .new_peer_connected_to_share(peer, name);
-                    match result {
-                        Ok(peer_id) => {
-                            let buf = encode(&PeerInitConnectToShareResponse::Ok);
-                            stream.write(&buf).await?;
-                            self.long_lived_peer_connection(peer_id, shutdown_rx, notification_rx)
-                                .await?;
-                        }
-                        Err(err) => {
-                            let buf = encode(&PeerInitConnectToShareResponse::Err(err));
-                            stream.write(&buf).await?;
-                        }
-                    }
+                    let (new_peer, response) = match joined {
+                        Ok((joined, name)) => (
+                            Some((joined.peer_id, joined.shutdown_rx, joined.notification_rx)),
+                            self.joined_response(&name),
+                        ),
+                        Err(err) => (None, PeerInitConnectToShareResponse::Err(err)),
+                    };
+                    conn.reply(stream, &encode(&response)).await?;
+                    new_peer
                 }
                 PeerInitMessage::ListShares => {
-                    let shares = self
-                        .state
-                        .borrow()
-                        .get_shares()
-                        .keys()
-                        .cloned()
-                        .collect::<Vec<_>>();
-                    let resp = PeerInitListSharesRosponse { shares };
-                    let buf = encode(&resp);
-                    stream.write(&buf).await?;
+                    let resp = PeerInitListSharesRosponse {
+                        shares: self.advertised_shares(),
+                    };
+                    conn.reply(stream, &encode(&resp)).await?;
+                    None
                 }
-            }
+                PeerInitMessage::Status => {
+                    conn.reply(stream, &encode(&self.public_status())).await?;
+                    None
+                }
+                PeerInitMessage::ReadDir { name, rel_path } => {
+                    conn.reply(stream, &encode(&self.read_dir(&name, &rel_path)))
+                        .await?;
+                    None
+                }
+            };
 
+            match new_peer {
+                Some((peer_id, shutdown_rx, notification_rx)) => {
+                    self.serve_peer(conn, peer_id, shutdown_rx, notification_rx)
+                        .await
+                }
+                // Flushes the response before the connection goes away
+                None => conn.close().await,
+            }
             anyhow::Ok(())
         }
         .await;
 
         if let Err(err) = value {
-            error!("Error during handling TCP client: {err}");
+            error!("Error during handling a peer: {err}");
         }
     }
 
-    async fn connect_to_remote_share(
+    /// Serves the first connection of a peer until it closes or the peer is
+    /// dropped from the state, then drops whatever the peer still uses
+    async fn serve_peer<T>(
         self: &Rc<Self>,
-        share_name: FullShareName,
-        mount_path: PathBuf,
-    ) -> Result<(), ConnectToRemoteShareError> {
-        let mut stream = NoiseStream::new_initiator((&share_name.addr).into()).await?;
-        stream
-            .write(&encode(&PeerInitMessage::ConnectToShare {
-                name: share_name.name.clone(),
-            }))
-            .await?;
-        let resp: PeerInitConnectToShareResponse =
-            decode(&stream.read_timeout().await?).map_err(|_| ProtocolError)?;
-        if let PeerInitConnectToShareResponse::Err(err) = resp {
-            return Err(err.into());
-        }
-
-        let SocketAddr::V4(address) = stream.peer_addr()? else {
-            panic!("IPv6 is unsupported");
+        conn: PeerConnection<T>,
+        peer_id: PeerId,
+        shutdown_rx: Receiver<()>,
+        notification_rx: Receiver<StateNotification>,
+    ) where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        // Nothing acts on notifications yet, but the state expects them to be received
+        let _notification_rx = notification_rx;
+        let dropped = async {
+            let _ = shutdown_rx.recv().await;
         };
-        let (shutdown_tx, shutdown_rx) = bounded(1);
-        let (notification_tx, notification_rx) = unbounded();
-        let peer = Peer::new(address, shutdown_tx, notification_tx);
-        let peer_id = self
-            .state
-            .borrow_mut()
-            .join_remote_share_new(peer, share_name, mount_path)?;
-        let fut = self
-            .clone()
-            .long_lived_peer_connection(peer_id, shutdown_rx, notification_rx);
-        self.ex.spawn(fut).detach();
-        Ok(())
+        net::serve_inbound(self.clone(), conn, Some(peer_id))
+            .or(dropped)
+            .await;
+        self.remove_peer(peer_id);
     }
 
-    async fn list_peer_shares(
-        self: Rc<Self>,
-        addr: SocketAddrV4,
-    ) -> Result<PeerInitListSharesRosponse, ListPeerSharesError> {
-        let mut stream = NoiseStream::new_initiator(addr).await?;
-        stream.write(&encode(&PeerInitMessage::ListShares)).await?;
-        let resp: PeerInitListSharesRosponse =
-            decode(&stream.read().await?).map_err(|_| ProtocolError)?;
-        Ok(resp)
+    /// Adds the peer at `address` to a share, as a new peer served over the
+    /// connection it joined with
+    fn join_share(
+        &self,
+        address: SocketAddrV4,
+        name: CommonShareName,
+    ) -> Result<JoinedShare, NewPeerConnectedToShareError> {
+        let joined = self.add_peer_to_share(address, name.clone())?;
+        if let Some(bandwidth) = &self.bandwidth {
+            bandwidth.peer_joined(joined.peer_id);
+        }
+        self.run_hook(HookEvent::Connect, &name, address);
+        Ok(joined)
     }
 
-    async fn long_lived_peer_connection(
-        self: Rc<Self>,
+    /// Adds a peer to another share over the connection it is already served
+    /// on. Joining a share again is fine, the other side could have unmounted it
+    fn join_another_share(
+        &self,
         peer_id: PeerId,
-        shutdown_rx: Receiver<()>,
-        notification_rx: Receiver<StateNotification>,
-    ) -> AnyResult<()> {
-        info!("Entered the long living handler");
-        smol::Timer::never().await;
-        Ok(())
+        name: &CommonShareName,
+    ) -> PeerInitConnectToShareResponse {
+        let Some(name) = self.local_name(name) else {
+            return PeerInitConnectToShareResponse::Err(ShareDoesntExistError.into());
+        };
+        let joined = self
+            .state
+            .borrow_mut()
+            .peer_connected_to_share(peer_id, name.clone());
+        match joined {
+            Ok(()) => {
+                let address = self.state.borrow().get_peers()[&peer_id].address;
+                debug!("Peer {address} also joined {name}");
+                self.run_hook(HookEvent::Connect, &name, address);
+            }
+            Err(PeerConnectedToShareError::RepeatedPeer(_)) => {}
+            // The connection goes away along with the peer
+            Err(
+                PeerConnectedToShareError::ShareDoesntExist(_)
+                | PeerConnectedToShareError::PeerDoesntExist(_),
+            ) => return PeerInitConnectToShareResponse::Err(ShareDoesntExistError.into()),
+        }
+        self.joined_response(&name)
     }
 
-    fn init(args: &Args) -> AnyResult<WorkerGuard> {
-        unsafe {
-            Self::daemonize(args)?;
+    /// Drops a peer from a share it joined over its connection, which stays
+    /// open for its other shares
+    fn leave_share(&self, peer_id: PeerId, name: &CommonShareName) -> PeerResponse {
+        let Some(name) = self.local_name(name) else {
+            return PeerResponse::Err(PeerResponseError::NoSuchShare);
+        };
+        let Some(address) = self
+            .state
+            .borrow()
+            .get_peers()
+            .get(&peer_id)
+            .map(|peer| peer.address)
+        else {
+            return PeerResponse::Err(PeerResponseError::NoSuchShare);
+        };
+        let left = self
+            .state
+            .borrow_mut()
+            .peer_disconnected_from_share(peer_id, name.clone());
+        match left {
+            Ok(()) => {
+                debug!("Peer {address} left {name}");
+                self.run_hook(HookEvent::Disconnect, &name, address);
+                PeerResponse::Left
+            }
+            Err(_) => PeerResponse::Err(PeerResponseError::NoSuchShare),
         }
-        let guard = Self::init_logs();
-        let _ = std::fs::create_dir(DOWNLOAD_CACHE_DIR);
-        Ok(guard)
     }
 
-    fn init_logs() -> WorkerGuard {
-        let file_appender = tracing_appender::rolling::daily(LOGS_DIR, LOGS_PREFIX);
-        let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
-        tracing_subscriber::fmt()
-            .with_max_level(LevelFilter::DEBUG)
-            .with_writer(non_blocking)
-            .init();
-        std::panic::set_hook(Box::new(move |panic_info| {
-            error!(
-                message = %panic_info,
-                "panic occurred"
-            );
-        }));
+    /// Answer to a peer that joined the local share `name`
+    fn joined_response(&self, name: &CommonShareName) -> PeerInitConnectToShareResponse {
+        let options = self.state.borrow().get_shares()[name].options.clone();
+        PeerInitConnectToShareResponse::Ok {
+            suggested_mount: options.suggested_mount,
+            banner: options.banner,
+        }
+    }
 
-        guard
+    /// Drops every peer connected from `ip`, returns how many there were
+    fn disconnect_host(&self, ip: Ipv4Addr) -> u32 {
+        let host = SocketAddrV4::new(ip, 0)..=SocketAddrV4::new(ip, u16::MAX);
+        let peers: Vec<_> = self
+            .state
+            .borrow()
+            .get_peers_by_scoket()
+            .range(host)
+            .map(|(_, peer_id)| *peer_id)
+            .collect();
+        peers
+            .into_iter()
+            .filter(|peer_id| self.remove_peer(*peer_id))
+            .count() as u32
     }
 
-    unsafe fn daemonize(args: &Args) -> AnyResult<()> {
-        // Fork again to prevent terminal re-acquisition
-        match unsafe { fork()? } {
-            ForkResult::Parent { .. } => std::process::exit(0),
-            ForkResult::Child => {}
+    /// Tells the automounts of the shares mounted from the peer that its
+    /// connection was lost, see [`Self::spawn_automounts`]
+    fn automounts_lost(&self, peer_id: PeerId) {
+        let state = self.state.borrow();
+        let Some(peer) = state.get_peers().get(&peer_id) else {
+            return;
+        };
+        let drops = self.automount_drops.borrow();
+        for name in peer.used_remote_shares() {
+            if let Some(dropped_tx) = drops.get(name) {
+                let _ = dropped_tx.try_send(());
+            }
         }
+    }
 
-        // Detach from terminal
-        setsid()?;
-
-        // Change working directory
-        std::env::set_current_dir(&args.tmp_dir)?;
-
-        // Reset file creation mask
-        unsafe { libc::umask(0) };
-
-        // Close standard fds
-        for fd in 0..3 {
-            unsafe { libc::close(fd) };
+    /// Drops a peer with everything it uses, running the disconnect hooks of
+    /// its shares. Returns false when it was already gone
+    fn remove_peer(&self, peer_id: PeerId) -> bool {
+        let (address, shares, remote_shares) = {
+            let mut state = self.state.borrow_mut();
+            let Some(peer) = state.get_peers().get(&peer_id) else {
+                return false;
+            };
+            let (address, remote_shares) = (peer.address, peer.used_remote_shares().clone());
+            let shares = state.remove_peer(peer_id).unwrap_or_default();
+            state.should_server_close(&self.shutdown_tx);
+            (address, shares, remote_shares)
+        };
+        // Without the peer every op of its mounts would fail
+        for name in &remote_shares {
+            #[cfg(feature = "fuse")]
+            self.mounts.borrow_mut().remove(name);
+            info!("Unmounted {name}");
         }
+        if let Some(bandwidth) = &self.bandwidth {
+            bandwidth.peer_left(peer_id);
+        }
+        info!("Disconnected peer {address}");
+        for name in &shares {
+            self.run_hook(HookEvent::Disconnect, name, address);
+        }
+        true
+    }
 
-        // Redirect stdin, stdout, stderr to /dev/null
-        let devnull = std::fs::File::open("/dev/null")?;
-        let devnull_fd = devnull.as_fd();
-        let _ = nix::unistd::dup2_stdin(devnull_fd);
-        let _ = nix::unistd::dup2_stdout(devnull_fd);
-        let _ = nix::unistd::dup2_stderr(devnull_fd);
-
-        Ok(())
+    /// Removes a local share, its participants leave it like they would by
+    /// disconnecting. Peers it was the last share of are dropped with `remove_peer`
+    fn remove_share(&self, name: &CommonShareName) -> Result<(), ShareDoesntExistError> {
+        let participants: Vec<_> = {
+            let state = self.state.borrow();
+            let share = state.get_shares().get(name).ok_or(ShareDoesntExistError)?;
+            share
+                .participants
+                .iter()
+                .filter_map(|peer_id| {
+                    let peer = state.get_peers().get(peer_id)?;
+                    let last =
+                        peer.used_shares().len() == 1 && peer.used_remote_shares().is_empty();
+                    Some((*peer_id, peer.address, last))
+                })
+                .collect()
+        };
+        // Hooks are looked up in the share, so they run before it is gone
+        for (peer_id, address, last) in participants {
+            match last {
+                true => {
+                    self.remove_peer(peer_id);
+                }
+                false => self.run_hook(HookEvent::Disconnect, name, address),
+            }
+        }
+        let result = self
+            .state
+            .borrow_mut()
+            .remove_share(name, &self.shutdown_tx);
+        self.files.forget_share(name);
+        result
     }
 
-    fn clean_up(&self) {
-        let _ = std::fs::remove_dir_all(".");
+    /// Starts the hook the owner of a share set for `event`, if any, without
+    /// waiting for it
+    fn run_hook(&self, event: HookEvent, name: &CommonShareName, peer: SocketAddrV4) {
+        let command = {
+            let state = self.state.borrow();
+            let Some(share) = state.get_shares().get(name) else {
+                return;
+            };
+            match event {
+                HookEvent::Connect => share.options.on_connect.clone(),
+                HookEvent::Disconnect => share.options.on_disconnect.clone(),
+            }
+        };
+        if let Some(command) = command {
+            self.ex
+                .spawn(hooks::run(&command, event, name, peer))
+                .detach();
+        }
     }
-}
 
-#[derive(Encode, Decode, Clone, Debug, Display, Error)]
-#[display("Other side sent an unexpected message")]
-pub struct ProtocolError;
+    fn add_peer_to_share(
+        &self,
+        address: SocketAddrV4,
+        name: CommonShareName,
+    ) -> Result<JoinedShare, NewPeerConnectedToShareError> {
+        let (shutdown_tx, shutdown_rx) = bounded(1);
+        let (notification_tx, notification_rx) = unbounded();
+        let peer = Peer::new(address, shutdown_tx, notification_tx);
+        let peer_id = self
+            .state
+            .borrow_mut()
+            .new_peer_connected_to_share(peer, name)?;
+        Ok(JoinedShare {
+            peer_id,
+            shutdown_rx,
+            notification_rx,
+        })
+    }
 
-#[derive(Debug, Display, Error, From, IsVariant)]
-#[display("Failed to list shares of a remote peer")]
-pub enum ListPeerSharesError {
-    Io(NoiseStreamError),
-    ProtocolError(ProtocolError),
+    async fn connect_to_remote_share(
+        self: &Rc<Self>,
+        share_name: FullShareName,
+        mount_path: Option<PathBuf>,
+        options: MountOptions,
+    ) -> Result<Option<String>, ConnectToRemoteShareError> {
+        if let Some(mount_path) = &mount_path {
+            self.check_mount_path(mount_path)?;
+        }
+        // Further shares of a peer are joined over the connection of its first one
+        if let Some(peer_id) = self.connected_peer(&share_name) {
+            return self
+                .join_remote_share_over(peer_id, share_name, mount_path, options)
+                .await;
+        }
+        self.stale_owner(&share_name)?;
+
+        let addr = (&share_name.addr).into();
+        let timeout = Duration::from_secs(self.args.connect_timeout.get());
+        // A trust store that cant be read fails the key check below instead
+        let pinned = self.trust_store().pinned(&share_name.addr).ok().flatten();
+        let pins = self.pinned_keys(&share_name, &options);
+        let mut conn = PeerConnection::connect_auto(
+            addr,
+            self.cipher,
+            pinned.as_ref(),
+            !pins.is_empty(),
+            &self.io_buffers,
+            timeout,
+        )
+        .await?;
+        if pins.iter().any(|pin| conn.peer_key() != Some(pin)) {
+            conn.close().await;
+            return Err(KeyMismatchError {
+                addr: share_name.addr,
+            }
+            .into());
+        }
+        // Same host peers of this user have no key, they are trusted by their
+        // user instead
+        if let Some(key) = conn.peer_key() {
+            match self.trust_store().check(&share_name.addr, key)? {
+                KeyCheck::Recorded => {
+                    info!("Pinned the key of {} on first contact", share_name.addr)
+                }
+                KeyCheck::Matches => {
+                    if let Some(successor) = conn.successor_key() {
+                        self.trust_store().trust(&share_name.addr, successor)?;
+                        info!("{} rotated its key, pinned the new one", share_name.addr);
+                    }
+                }
+                KeyCheck::Mismatch => {
+                    conn.close().await;
+                    return Err(KeyMismatchError {
+                        addr: share_name.addr,
+                    }
+                    .into());
+                }
+            }
+        }
+        let request = encode(&PeerInitMessage::ConnectToShare {
+            name: share_name.name.clone(),
+        });
+        let buf = conn
+            .request(&request)
+            .await
+            .map_err(ConnectToRemoteShareError::from_first_request)?;
+        // Another share of the peer could have been mounted meanwhile
+        if let Some(peer_id) = self.connected_peer(&share_name) {
+            conn.close().await;
+            return self
+                .join_remote_share_over(peer_id, share_name, mount_path, options)
+                .await;
+        }
+        let (suggested_mount, banner) = self.joined_remote_share(&buf)?;
+        let mount_path = self.remote_mount_path(mount_path, suggested_mount)?;
+        self.rejoin_mounted_shares(&mut conn, &share_name).await?;
+
+        let (shutdown_tx, shutdown_rx) = bounded(1);
+        let (notification_tx, notification_rx) = unbounded();
+        let peer = Peer::new(conn.peer_addr(), shutdown_tx, notification_tx);
+        // A share still mounted over a connection that went stale keeps its
+        // mount, only the connection is replaced by this one
+        let stale = match self.stale_owner(&share_name) {
+            Ok(stale) => stale,
+            Err(err) => {
+                conn.close().await;
+                return Err(err.into());
+            }
+        };
+        #[cfg(feature = "fuse")]
+        let fuse_mount = stale.is_none().then(|| share_name.clone());
+        let peer_id = match stale {
+            Some(old) => {
+                info!("Reconnected to {share_name}");
+                let mut state = self.state.borrow_mut();
+                let shares: Vec<_> = state.get_peers()[&old]
+                    .used_shares()
+                    .iter()
+                    .cloned()
+                    .collect();
+                state.re_attach_peer(old, peer, &shares)?
+            }
+            None => self
+                .state
+                .borrow_mut()
+                .join_remote_share_new(peer, share_name, mount_path, options)?,
+        };
+        let (requests_tx, requests_rx) = unbounded::<PeerRequest>();
+        self.peer_requests.borrow_mut().insert(peer_id, requests_tx);
+        if let Some(key) = conn.peer_key() {
+            self.peer_keys.borrow_mut().insert(peer_id, *key);
+        }
+        let server = self.clone();
+        let fut = async move {
+            // Stays open until the peer is dropped from the state, by the last
+            // unmount or otherwise, or until the peer closes it
+            let mut conn = conn;
+            let _notification_rx = notification_rx;
+            let dropped = async {
+                let _ = shutdown_rx.recv().await;
+                false
+            };
+            let serve = async {
+                loop {
+                    let request = async { requests_rx.recv().await.ok() };
+                    let closed = async {
+                        match conn.closed().await {
+                            Ok(()) => info!("Peer {} closed the connection", conn.peer_addr()),
+                            Err(err) => {
+                                error!("Connection to peer {} failed: {err}", conn.peer_addr())
+                            }
+                        }
+                        None
+                    };
+                    let Some((request, repeatable, reply_tx)) = request.or(closed).await else {
+                        break;
+                    };
+                    let response = match repeatable {
+                        true => conn.request_repeatable(&request).await,
+                        false => conn.request(&request).await,
+                    };
+                    let _ = reply_tx.try_send(response);
+                }
+                true
+            };
+            let lost = serve.or(dropped).await;
+            server.peer_requests.borrow_mut().remove(&peer_id);
+            server.peer_keys.borrow_mut().remove(&peer_id);
+            conn.close().await;
+            if lost {
+                server.automounts_lost(peer_id);
+            }
+            server.remove_peer(peer_id);
+        };
+        self.ex.spawn(fut).detach();
+        #[cfg(feature = "fuse")]
+        if let Some(share_name) = fuse_mount {
+            self.mount_joined(peer_id, &share_name)?;
+        }
+        Ok(banner)
+    }
+
+    /// Mounts another share of a peer whose shares are already mounted, over
+    /// the connection they use
+    async fn join_remote_share_over(
+        self: &Rc<Self>,
+        peer_id: PeerId,
+        share_name: FullShareName,
+        mount_path: Option<PathBuf>,
+        options: MountOptions,
+    ) -> Result<Option<String>, ConnectToRemoteShareError> {
+        if let Some(pin) = options.pin_key
+            && self.peer_keys.borrow().get(&peer_id) != Some(&pin)
+        {
+            return Err(KeyMismatchError {
+                addr: share_name.addr,
+            }
+            .into());
+        }
+        let request = encode(&PeerMessage::ConnectToShare {
+            share: share_name.name.clone(),
+        });
+        let buf = self
+            .peer_exchange(peer_id, request, false)
+            .await
+            .ok_or(io::Error::from(io::ErrorKind::NotConnected))??;
+        let (suggested_mount, banner) = self.joined_remote_share(&buf)?;
+        // The peer already added this side to the share, which has to be undone
+        // when it cant be mounted after all
+        let mount_path = match self.remote_mount_path(mount_path, suggested_mount) {
+            Ok(mount_path) => mount_path,
+            Err(err) => {
+                self.leave_remote_share(peer_id, &share_name).await;
+                return Err(err);
+            }
+        };
+        self.state.borrow_mut().join_remote_share(
+            peer_id,
+            share_name.clone(),
+            mount_path,
+            options,
+        )?;
+        info!("Joined {share_name} over the connection of peer {peer_id}");
+        #[cfg(feature = "fuse")]
+        self.mount_joined(peer_id, &share_name)?;
+        Ok(banner)
+    }
+
+    /// Suggested mount path and banner of a share the peer answered `buf` to
+    /// joining
+    fn joined_remote_share(
+        &self,
+        buf: &[u8],
+    ) -> Result<(Option<String>, Option<String>), ConnectToRemoteShareError> {
+        let resp: PeerInitConnectToShareResponse = decode(buf).map_err(|_| ProtocolError)?;
+        match resp {
+            PeerInitConnectToShareResponse::Ok {
+                suggested_mount,
+                banner,
+            } => Ok((suggested_mount, banner.map(truncate_banner))),
+            PeerInitConnectToShareResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Where a joined share is mounted. A path that was given is checked before
+    /// connecting, the default one only once the peer suggested it
+    fn remote_mount_path(
+        &self,
+        mount_path: Option<PathBuf>,
+        suggested_mount: Option<String>,
+    ) -> Result<PathBuf, ConnectToRemoteShareError> {
+        match (mount_path, std::env::home_dir(), suggested_mount) {
+            (Some(mount_path), _, _) => Ok(mount_path),
+            (None, Some(home), Some(suggested)) => self.create_default_mount(&home, &suggested),
+            _ => Err(ConnectToRemoteShareError::NoMountPath),
+        }
+    }
+
+    /// Creates `<home>/rdir/<suggested>` to mount a share at. The daemon does
+    /// it rather than the client, as only the daemon learns the suggestion
+    /// from the peer. Both run as the same user on this host, so the dir ends
+    /// up owned by the user either way
+    fn create_default_mount(
+        &self,
+        home: &Path,
+        suggested: &str,
+    ) -> Result<PathBuf, ConnectToRemoteShareError> {
+        let mount_path =
+            default_mount_path(home, suggested).ok_or(ConnectToRemoteShareError::NoMountPath)?;
+        self.check_mount_path(&mount_path)?;
+        std::fs::create_dir_all(&mount_path)?;
+        Ok(mount_path)
+    }
+
+    /// Leaves a share the peer joined this side to, while the connection stays
+    /// for the other mounted shares of the peer
+    async fn leave_remote_share(&self, peer_id: PeerId, share_name: &FullShareName) {
+        let request = encode(&PeerMessage::LeaveShare {
+            share: share_name.name.clone(),
+        });
+        let left = match self.peer_exchange(peer_id, request, false).await {
+            Some(Ok(buf)) => decode::<PeerResponse>(&buf).ok(),
+            _ => None,
+        };
+        if !left.is_some_and(|left| left.is_left()) {
+            warn!("Couldnt leave {share_name} after failing to mount it");
+        }
+    }
+
+    /// Keys a connection for `share_name` has to be authenticated with, the
+    /// `--pin-key` of the new mount and, when it replaces a stale connection,
+    /// those of every share mounted over it
+    fn pinned_keys(&self, share_name: &FullShareName, options: &MountOptions) -> Vec<PublicKey> {
+        let state = self.state.borrow();
+        let remote_shares = state.get_remote_shares();
+        let mounted = remote_shares
+            .get(share_name)
+            .map(|share| state.get_peers()[&share.owner()].used_remote_shares());
+        let mounted_pins = mounted
+            .into_iter()
+            .flatten()
+            .filter_map(|name| remote_shares[name].options.pin_key);
+        options.pin_key.into_iter().chain(mounted_pins).collect()
+    }
+
+    /// Peer at the address of `share_name` whose shares are mounted, unless
+    /// `share_name` itself is and its connection is being replaced
+    fn connected_peer(&self, share_name: &FullShareName) -> Option<PeerId> {
+        let state = self.state.borrow();
+        if state.get_remote_shares().contains_key(share_name) {
+            return None;
+        }
+        let addr = (&share_name.addr).into();
+        state.get_peers_by_scoket().get(&addr).copied()
+    }
+
+    /// Peer the mounted `share_name` is owned by, if its connection is gone so a
+    /// new one can take over the mount. That is once the connection stopped
+    /// serving requests or the share is being reconnected. Fails while the
+    /// connection is still in use
+    fn stale_owner(
+        &self,
+        share_name: &FullShareName,
+    ) -> Result<Option<PeerId>, RepeatedRemoteShareError> {
+        let state = self.state.borrow();
+        let Some(share) = state.get_remote_shares().get(share_name) else {
+            return Ok(None);
+        };
+        let gone = !self.peer_requests.borrow().contains_key(&share.owner())
+            || self.reconnects.borrow().contains_key(share_name);
+        match gone {
+            true => Ok(Some(share.owner())),
+            false => Err(RepeatedRemoteShareError),
+        }
+    }
+
+    /// Joins the other shares mounted from the peer over `conn` too, when it
+    /// replaces the stale connection of `share_name`
+    async fn rejoin_mounted_shares<T>(
+        &self,
+        conn: &mut PeerConnection<T>,
+        share_name: &FullShareName,
+    ) -> Result<(), ConnectToRemoteShareError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let others: Vec<_> = {
+            let state = self.state.borrow();
+            let Some(share) = state.get_remote_shares().get(share_name) else {
+                return Ok(());
+            };
+            state.get_peers()[&share.owner()]
+                .used_remote_shares()
+                .iter()
+                .filter(|name| *name != share_name)
+                .cloned()
+                .collect()
+        };
+        for name in others {
+            let request = encode(&PeerMessage::ConnectToShare { share: name.name });
+            let buf = conn.request(&request).await?;
+            let resp: PeerInitConnectToShareResponse = decode(&buf).map_err(|_| ProtocolError)?;
+            if let PeerInitConnectToShareResponse::Err(err) = resp {
+                return Err(err.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Mounts a share that was just joined, leaving it again when that fails
+    #[cfg(feature = "fuse")]
+    fn mount_joined(
+        self: &Rc<Self>,
+        peer_id: PeerId,
+        share_name: &FullShareName,
+    ) -> Result<(), ConnectToRemoteShareError> {
+        if let Err(err) = self.mount_fuse(share_name) {
+            let _ = self.state.borrow_mut().exit_remote_share(
+                peer_id,
+                share_name.clone(),
+                &self.shutdown_tx,
+            );
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    /// Mounts a joined share at its mount path with its options, its FUSE ops
+    /// become requests to the peer sent from the executor
+    #[cfg(feature = "fuse")]
+    fn mount_fuse(self: &Rc<Self>, share_name: &FullShareName) -> std::io::Result<()> {
+        let (mount_path, options) = {
+            let state = self.state.borrow();
+            let remote_share = &state.get_remote_shares()[share_name];
+            (remote_share.mount_path.clone(), remote_share.options)
+        };
+        let (fs_tx, fs_rx) = unbounded::<fuse_mount::FsRequest>();
+        let cache_dir = self.args.tmp_dir.join(DOWNLOAD_CACHE_DIR);
+        std::fs::create_dir_all(&cache_dir)?;
+        let session = fuse_mount::mount(
+            share_name.name.clone(),
+            &mount_path,
+            options,
+            download_cache::DownloadCache::new(cache_dir),
+            fs_tx,
+        )?;
+        let server = self.clone();
+        let name = share_name.clone();
+        let fut = async move {
+            // Ends once the session is dropped along with the sender
+            while let Ok((message, reply_tx)) = fs_rx.recv().await {
+                // Looked up every time, a peer that reconnected has a new id
+                let owner = server
+                    .state
+                    .borrow()
+                    .get_remote_shares()
+                    .get(&name)
+                    .map(|share| share.owner());
+                let response = match owner {
+                    Some(peer_id) => server.request_peer(peer_id, &message).await,
+                    None => Err(NoSuchRemoteShareError.into()),
+                };
+                let response = response.map_err(|err| {
+                    debug!("FUSE request failed: {err}");
+                    nix::errno::Errno::EIO
+                });
+                let _ = reply_tx.try_send(response);
+            }
+        };
+        self.ex.spawn(fut).detach();
+        self.mounts.borrow_mut().insert(share_name.clone(), session);
+        info!("Mounted {share_name} at {}", mount_path.to_string_lossy());
+        Ok(())
+    }
+
+    /// Sends `message` to the peer over the connection of its mounted shares
+    async fn request_peer(
+        &self,
+        peer_id: PeerId,
+        message: &PeerMessage,
+    ) -> Result<PeerResponse, ServerError> {
+        let buf = self
+            .peer_exchange(peer_id, encode(message), message.is_idempotent())
+            .await
+            .ok_or(NoSuchRemoteShareError)??;
+        Ok(decode(&buf).map_err(|_| ProtocolError)?)
+    }
+
+    /// Raw response to `request` over the connection of the mounted shares of
+    /// a peer, `None` once there is no such connection. A `repeatable` request
+    /// is sent again when its stream fails, see [`PeerConnection::request_repeatable`]
+    async fn peer_exchange(
+        &self,
+        peer_id: PeerId,
+        request: Vec<u8>,
+        repeatable: bool,
+    ) -> Option<Result<Vec<u8>, NoiseStreamError>> {
+        let requests_tx = self.peer_requests.borrow().get(&peer_id).cloned()?;
+        let (reply_tx, reply_rx) = bounded(1);
+        requests_tx
+            .send((request, repeatable, reply_tx))
+            .await
+            .ok()?;
+        reply_rx.recv().await.ok()
+    }
+
+    /// Finds the peer sharing `name` with discovery, no other discovered peer
+    /// may share one named the same
+    async fn discover_share(&self, name: CommonShareName) -> Result<FullShareName, ServerError> {
+        let discovery = self.discovery.get().ok_or(DiscoveryDisabledError)?;
+        let discovered = discovery.discover(DISCOVERY_WINDOW).await?;
+        let addr = discovered.owner(&name)?.ok_or(ShareDoesntExistError)?;
+        Ok(FullShareName {
+            addr: addr.into(),
+            name,
+        })
+    }
+
+    /// Connects to the peer at `addr`, giving up after `--connect-timeout`
+    async fn connect_peer(&self, addr: SocketAddrV4) -> Result<PeerConnection, NoiseStreamError> {
+        let timeout = Duration::from_secs(self.args.connect_timeout.get());
+        PeerConnection::connect(addr, self.cipher, &self.io_buffers, timeout).await
+    }
+
+    fn trust_store(&self) -> TrustStore {
+        TrustStore::new(self.args.tmp_dir.join(TRUST_STORE_NAME))
+    }
+
+    /// Pins the key `addr` has now, for a peer whose key changed on purpose
+    async fn trust_peer(&self, addr: &RemotePeerAddr) -> Result<PublicKey, ServerError> {
+        let mut conn = self.connect_peer(addr.into()).await?;
+        conn.close().await;
+        let key = *conn.peer_key().ok_or(ProtocolError)?;
+        self.trust_store().trust(addr, &key)?;
+        info!("Pinned the key of {addr}");
+        Ok(key)
+    }
+
+    /// Pins the key of a peer introduced by the peer with the `voucher` key, if
+    /// this daemon accepts introductions and pinned the key of the voucher
+    fn accept_introduction(
+        &self,
+        voucher: Option<&PublicKey>,
+        introduction: &Introduction,
+    ) -> Result<(), PeerResponseError> {
+        if !self.args.accept_introductions {
+            return Err(PeerResponseError::Introduction(IntroductionError::Disabled));
+        }
+        let store = self.trust_store();
+        // Same host peers have no key to sign with
+        match voucher {
+            Some(voucher) if store.is_pinned(voucher)? => introduction
+                .verify(voucher, SystemTime::now())
+                .map_err(PeerResponseError::Introduction)?,
+            _ => {
+                return Err(PeerResponseError::Introduction(
+                    IntroductionError::UntrustedVoucher,
+                ));
+            }
+        }
+        let addr = &introduction.addr;
+        match store.check(addr, &introduction.key)? {
+            KeyCheck::Recorded => info!("Pinned the key of {addr}, a trusted peer introduced it"),
+            KeyCheck::Matches => {}
+            KeyCheck::Mismatch => {
+                return Err(PeerResponseError::Introduction(IntroductionError::Conflict));
+            }
+        }
+        Ok(())
+    }
+
+    /// Mounting into the dirs of rdir or into a local share would feed the mount
+    /// back into itself
+    fn check_mount_path(&self, mount_path: &Path) -> Result<(), InvalidMountPathError> {
+        let mount_path =
+            std::fs::canonicalize(mount_path).unwrap_or_else(|_| mount_path.to_path_buf());
+        let tmp_dir = &self.args.tmp_dir;
+        let location = if mount_path.starts_with(tmp_dir.join(DOWNLOAD_CACHE_DIR)) {
+            Some(ProtectedLocation::DownloadCache)
+        } else if mount_path.starts_with(tmp_dir) {
+            Some(ProtectedLocation::TmpDir)
+        } else {
+            self.state
+                .borrow()
+                .get_shares()
+                .values()
+                .find(|share| mount_path.starts_with(&share.path))
+                .map(|share| ProtectedLocation::Share(share.name.clone()))
+        };
+        match location {
+            Some(location) => Err(InvalidMountPathError {
+                path: mount_path.to_string_lossy().to_string(),
+                location,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Sharing these or anything in them, apart from `/` itself, would likely
+    /// expose secrets. Paired with how they are named to the user
+    fn sensitive_paths(&self) -> Vec<(&'static str, PathBuf)> {
+        let mut paths = Vec::new();
+        // Before /root, which is the home of root
+        if let Some(home) = std::env::home_dir() {
+            paths.push(("~/.ssh", home.join(".ssh")));
+        }
+        paths.push(("/etc", "/etc".into()));
+        paths.push(("/root", "/root".into()));
+        paths.push(("the rdir tmp dir", self.args.tmp_dir.clone()));
+        paths
+    }
+
+    fn check_sensitive_path(&self, path: &Path) -> Result<(), RefusedSensitivePathError> {
+        let canonical = |path: &Path| std::fs::canonicalize(path).unwrap_or(path.to_path_buf());
+        let path = canonical(path);
+        let pattern = match path == Path::new("/") {
+            true => Some("/"),
+            false => self
+                .sensitive_paths()
+                .into_iter()
+                .find(|(_, sensitive)| path.starts_with(canonical(sensitive)))
+                .map(|(pattern, _)| pattern),
+        };
+        match pattern {
+            Some(pattern) => Err(RefusedSensitivePathError {
+                path: path.to_string_lossy().to_string(),
+                pattern: pattern.to_string(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Status of this daemon as shown to peers, limited by `--expose-status`
+    fn public_status(&self) -> PeerInitStatusResponse {
+        match self.args.expose_status {
+            StatusExposure::None => PeerInitStatusResponse::Refused,
+            StatusExposure::Shares => PeerInitStatusResponse::Ok(PeerStatusDto {
+                shares: self.advertised_shares(),
+                peers: None,
+            }),
+            StatusExposure::Full => PeerInitStatusResponse::Ok(PeerStatusDto {
+                shares: self.advertised_shares(),
+                peers: Some(self.state.borrow().get_peers().len() as u32),
+            }),
+        }
+    }
+
+    /// Names peers see the local shares under, prefixed with `--namespace`
+    fn advertised_shares(&self) -> Vec<CommonShareName> {
+        let state = self.state.borrow();
+        let names = state.get_shares().keys();
+        match &self.args.namespace {
+            Some(namespace) => names.map(|name| namespace.prefix(name)).collect(),
+            None => names.cloned().collect(),
+        }
+    }
+
+    /// Local share a peer means by `name`, with `--namespace` only prefixed
+    /// names resolve
+    fn local_name(&self, name: &CommonShareName) -> Option<CommonShareName> {
+        match &self.args.namespace {
+            Some(namespace) => namespace.strip(name),
+            None => Some(name.clone()),
+        }
+    }
+
+    /// Status the peer at `addr` exposes, it might refuse to share any
+    async fn peer_status(
+        &self,
+        addr: SocketAddrV4,
+    ) -> Result<PeerInitStatusResponse, PeerStatusError> {
+        let mut conn = self.connect_peer(addr).await?;
+        let buf = conn.request(&encode(&PeerInitMessage::Status)).await;
+        conn.close().await;
+        Ok(decode(&buf?).map_err(|_| ProtocolError)?)
+    }
+
+    /// Entries of a dir of a share, for a peer listing it without mounting
+    fn read_dir(&self, name: &CommonShareName, rel_path: &str) -> PeerInitReadDirResponse {
+        let state = self.state.borrow();
+        let share = self
+            .local_name(name)
+            .and_then(|name| state.get_shares().get(&name));
+        let Some(share) = share else {
+            return PeerInitReadDirResponse::Err(ReadDirError::NoSuchShare);
+        };
+        match share.dir_entries(Path::new(rel_path)) {
+            Ok(entries) => PeerInitReadDirResponse::Ok(entries),
+            Err(err) => PeerInitReadDirResponse::Err(err.into()),
+        }
+    }
+
+    /// Entries of a dir of a share that isnt mounted, the connection is only
+    /// kept for the listing
+    async fn list_remote_dir(
+        &self,
+        dir: RemoteDirPath,
+    ) -> Result<Vec<DirEntryDto>, ListRemoteDirError> {
+        let addr = (&dir.share.addr).into();
+        let timeout = Duration::from_secs(self.args.connect_timeout.get());
+        let pinned = self.trust_store().pinned(&dir.share.addr).ok().flatten();
+        let mut conn = PeerConnection::connect_auto(
+            addr,
+            self.cipher,
+            pinned.as_ref(),
+            false,
+            &self.io_buffers,
+            timeout,
+        )
+        .await?;
+        let request = encode(&PeerInitMessage::ReadDir {
+            name: dir.share.name,
+            rel_path: dir.path,
+        });
+        let buf = conn.request(&request).await;
+        conn.close().await;
+        let resp: PeerInitReadDirResponse = decode(&buf?).map_err(|_| ProtocolError)?;
+        match resp {
+            PeerInitReadDirResponse::Ok(entries) => Ok(entries),
+            PeerInitReadDirResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    fn init(args: &Args) -> AnyResult<([WorkerGuard; 2], LogLevelHandle)> {
+        unsafe {
+            Self::daemonize(args)?;
+        }
+        let logs = Self::init_logs(args);
+        let _ = std::fs::create_dir(DOWNLOAD_CACHE_DIR);
+        Ok(logs)
+    }
+
+    fn init_logs(args: &Args) -> ([WorkerGuard; 2], LogLevelHandle) {
+        let ([main, errors], guards) = log_writers(Path::new(LOGS_DIR), args);
+        let (subscriber, handle) = logs::subscriber(args.log_level.into(), main, errors);
+        subscriber.init();
+        std::panic::set_hook(Box::new(move |panic_info| {
+            error!(
+                message = %panic_info,
+                "panic occurred"
+            );
+        }));
+
+        (guards, handle)
+    }
+
+    unsafe fn daemonize(args: &Args) -> AnyResult<()> {
+        // Fork again to prevent terminal re-acquisition
+        match unsafe { fork()? } {
+            ForkResult::Parent { .. } => std::process::exit(0),
+            ForkResult::Child => {}
+        }
+
+        // Detach from terminal
+        setsid()?;
+
+        // Change working directory
+        std::env::set_current_dir(&args.tmp_dir)?;
+
+        // Reset file creation mask
+        unsafe { libc::umask(0) };
+
+        // Close standard fds
+        for fd in 0..3 {
+            unsafe { libc::close(fd) };
+        }
+
+        // Redirect stdin, stdout, stderr to /dev/null
+        let devnull = std::fs::File::open("/dev/null")?;
+        let devnull_fd = devnull.as_fd();
+        let _ = nix::unistd::dup2_stdin(devnull_fd);
+        let _ = nix::unistd::dup2_stdout(devnull_fd);
+        let _ = nix::unistd::dup2_stderr(devnull_fd);
+
+        Ok(())
+    }
 }
 
-#[derive(Debug, Display, Error, From, IsVariant)]
-#[display("Failed connect to a remote share")]
-pub enum ConnectToRemoteShareError {
-    Io(NoiseStreamError),
-    ShareDoesntExist(ShareDoesntExistError),
-    #[display("Tried to connect to the same share for the second time")]
-    RepeatedRemoteShare(RepeatedRemoteShareError),
-    #[display("Tried to open a new connection to a server while already connected")]
-    RepeatedPeer(RepeatedPeerError),
-    ProtocolError(ProtocolError),
+/// Main and error log files under `dir`, written from a background thread
+/// until the guards are dropped
+fn log_writers(dir: &Path, args: &Args) -> ([NonBlocking; 2], [WorkerGuard; 2]) {
+    let main_appender = tracing_appender::rolling::daily(dir, &args.log_prefix);
+    let (main, main_guard) = tracing_appender::non_blocking(main_appender);
+    let error_appender = tracing_appender::rolling::daily(dir, &args.error_log_prefix);
+    let (errors, error_guard) = tracing_appender::non_blocking(error_appender);
+    ([main, errors], [main_guard, error_guard])
 }
 
-impl From<NewPeerConnectedToShareError> for ConnectToRemoteShareError {
-    fn from(value: NewPeerConnectedToShareError) -> Self {
-        match value {
-            NewPeerConnectedToShareError::RepeatedPeer(err) => Self::RepeatedPeer(err),
-            NewPeerConnectedToShareError::ShareDoesntExist(err) => Self::ShareDoesntExist(err),
+/// Runs `rdir rotate-key`. The running server keeps its key until it restarts
+pub fn rotate_key(tmp_dir: &Path, force: bool, grace: Duration) -> AnyResult<()> {
+    if !force {
+        anyhow::bail!(
+            "Peers that pinned the current key and dont connect within the grace period refuse this daemon until they trust the new key, pass --force to rotate anyway"
+        );
+    }
+    let key = net::rotate_static_key(
+        &tmp_dir.join(STATIC_KEY_NAME),
+        &tmp_dir.join(RETIRED_KEY_NAME),
+        grace,
+    )
+    .context("Failed to rotate the static key")?;
+    println!(
+        "New key is {}, restart the server with `rdir kill` to use it",
+        content_hash::to_hex(&key)
+    );
+    Ok(())
+}
+
+/// The log guards go first, so the buffered lines, the last one included, are
+/// written out before anything gets removed
+fn finish(log_guards: [WorkerGuard; 2], tmp_dir: &Path) {
+    info!("Exitting");
+    drop(log_guards);
+    clean_up(tmp_dir);
+}
+
+/// Removes what the run left in the tmp dir, apart from the logs and the files
+/// that have to survive restarts
+fn clean_up(tmp_dir: &Path) {
+    let Ok(entries) = std::fs::read_dir(tmp_dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if KEPT_ON_EXIT.iter().any(|kept| entry.file_name() == *kept) {
+            continue;
         }
+        let path = entry.path();
+        let _ = match entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+            true => std::fs::remove_dir_all(path),
+            false => std::fs::remove_file(path),
+        };
     }
 }
 
-impl From<io::Error> for ConnectToRemoteShareError {
-    fn from(value: io::Error) -> Self {
-        Self::Io(NoiseStreamError::Io(value))
+/// Any number of shutdown triggers can race, with overflow the newest replaces
+/// the oldest pending one instead of failing, so a shutdown is never dropped.
+/// The room for a few keeps the reason of the first one, a kill is usually
+/// followed by the server finding itself idle
+fn shutdown_channel() -> (
+    Sender<ShutdownReason>,
+    async_broadcast::Receiver<ShutdownReason>,
+) {
+    let (mut shutdown_tx, shutdown_rx) = broadcast(4);
+    shutdown_tx.set_overflow(true);
+    (shutdown_tx, shutdown_rx)
+}
+
+/// Encoded `PeerMessage`, whether it may be sent again on a failed stream and
+/// where its response goes
+type PeerRequest = (
+    Vec<u8>,
+    bool,
+    smol::channel::Sender<Result<Vec<u8>, NoiseStreamError>>,
+);
+
+/// Client counted in `pending_clients` until dropped
+struct PendingClient<'a>(Rc<Server<'a>>);
+
+impl<'a> PendingClient<'a> {
+    fn new(server: Rc<Server<'a>>) -> Self {
+        server.pending_clients.set(server.pending_clients.get() + 1);
+        Self(server)
+    }
+}
+
+impl Drop for PendingClient<'_> {
+    fn drop(&mut self) {
+        let pending = &self.0.pending_clients;
+        pending.set(pending.get() - 1);
+    }
+}
+
+/// Hands every accepted connection to `handle`. Errors of a single accept are
+/// logged and skipped, only an error of the listener itself ends the loop
+async fn accept_loop<T>(
+    mut incoming: impl Stream<Item = io::Result<T>> + Unpin,
+    mut handle: impl FnMut(T),
+) -> AnyResult<()> {
+    while let Some(result) = incoming.next().await {
+        match result {
+            Ok(conn) => handle(conn),
+            Err(err) if is_out_of_fds(&err) => {
+                // Retrying right away would spin until a connection closes
+                error!("Failed to accept a connection, out of file descriptors: {err}");
+                Timer::after(ACCEPT_RETRY_DELAY).await;
+            }
+            Err(err) if is_transient_accept_error(&err) => {
+                error!("Failed to accept a connection: {err}");
+            }
+            Err(err) => return Err(err).context("Listener failed"),
+        }
+    }
+    Ok(())
+}
+
+fn is_transient_accept_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::TimedOut
+    )
+}
+
+/// Binds the peer listener, explaining the usual reasons it fails
+fn bind_tcp(addr: SocketAddrV4) -> AnyResult<std::net::TcpListener> {
+    std::net::TcpListener::bind(addr).map_err(|err| {
+        let context = match err.kind() {
+            io::ErrorKind::AddrNotAvailable => format!(
+                "Address {} is not assigned to this host, check the interfaces with `ip addr` or change `--tcp-socket`",
+                addr.ip()
+            ),
+            io::ErrorKind::AddrInUse => format!(
+                "Port {} is already in use, another rdir server might be running on it",
+                addr.port()
+            ),
+            _ => format!("Failed to bind the peer socket at {addr}"),
+        };
+        anyhow::Error::from(err).context(context)
+    })
+}
+
+fn is_out_of_fds(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM)
+    )
+}
+
+async fn shutdown_triggered(
+    shutdown_rx: &mut async_broadcast::Receiver<ShutdownReason>,
+) -> ShutdownReason {
+    loop {
+        match shutdown_rx.recv().await {
+            Ok(reason) => return reason,
+            // Several shutdowns raced, the newest one is received next
+            Err(RecvError::Overflowed(_)) => continue,
+            Err(RecvError::Closed) => unreachable!("The sender lives as long as the server"),
+        }
+    }
+}
+
+/// Peers arent bound by the parser of `--banner`, so their banners are cut
+/// to [`MAX_BANNER_LEN`] here
+fn truncate_banner(mut banner: String) -> String {
+    if banner.len() > MAX_BANNER_LEN {
+        let end = (0..=MAX_BANNER_LEN)
+            .rev()
+            .find(|&end| banner.is_char_boundary(end))
+            .unwrap_or(0);
+        banner.truncate(end);
+    }
+    banner
+}
+
+/// Resolves a mount suggestion of a peer to `<home>/rdir/<suggestion>`,
+/// suggestions that arent a single plain dir name are ignored
+fn default_mount_path(home: &Path, suggested_mount: &str) -> Option<PathBuf> {
+    let mut components = Path::new(suggested_mount).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) => Some(home.join("rdir").join(name)),
+        _ => None,
+    }
+}
+
+/// Peer that joined its first share, the connection it joined with becomes
+/// its long lived one
+#[derive(Debug)]
+struct JoinedShare {
+    peer_id: PeerId,
+    shutdown_rx: Receiver<()>,
+    notification_rx: Receiver<StateNotification>,
+}
+
+#[derive(Encode, Decode, Clone, Debug, Display, Error)]
+#[display("Other side sent an unexpected message")]
+pub struct ProtocolError;
+
+#[derive(Encode, Decode, Clone, Debug, Display, Error)]
+#[display("The server is busy, retry in {retry_after_ms}ms")]
+pub struct ServerBusyError {
+    pub retry_after_ms: u32,
+}
+
+#[derive(Encode, Decode, Clone, Debug, Display, Error)]
+#[display("Debug commands are disabled, restart the server with `--allow-debug`")]
+pub struct DebugDisabledError;
+
+#[derive(Encode, Decode, Clone, Debug, Display, Error)]
+#[display("Discovery is disabled, restart the server with `--udp-socket`")]
+pub struct DiscoveryDisabledError;
+
+#[derive(Encode, Decode, Clone, Debug, Display, Error)]
+#[display("Server runs with `--sandbox`, shares can only be configured when it starts")]
+pub struct SandboxedError;
+
+#[derive(Encode, Decode, Clone, Debug, Display, Error, PartialEq, Eq)]
+#[display(
+    "Refusing to share {path}, it is or lies in {pattern}. Pass `--force` to share it anyway"
+)]
+pub struct RefusedSensitivePathError {
+    #[error(ignore)]
+    pub path: String,
+    #[error(ignore)]
+    pub pattern: String,
+}
+
+/// Dir a remote share cant be mounted in
+#[derive(Encode, Decode, Clone, Debug, Display, PartialEq, Eq)]
+pub enum ProtectedLocation {
+    #[display("the download cache")]
+    DownloadCache,
+    #[display("the tmp dir")]
+    TmpDir,
+    #[display("the local share {_0}")]
+    Share(CommonShareName),
+}
+
+#[derive(Encode, Decode, Clone, Debug, Display, Error, PartialEq, Eq)]
+#[display("Cant mount at {path}, it lies in {location}")]
+pub struct InvalidMountPathError {
+    #[error(ignore)]
+    pub path: String,
+    #[error(ignore)]
+    pub location: ProtectedLocation,
+}
+
+#[derive(Debug, Display, Error, From, IsVariant)]
+#[display("Failed to get the status of a remote peer")]
+pub enum PeerStatusError {
+    Io(NoiseStreamError),
+    ProtocolError(ProtocolError),
+}
+
+#[derive(Debug, Display, Error, From, IsVariant)]
+#[display("Failed to list a dir of a remote share")]
+pub enum ListRemoteDirError {
+    Io(NoiseStreamError),
+    ProtocolError(ProtocolError),
+    #[display("{_0}")]
+    ReadDir(ReadDirError),
+}
+
+#[derive(Debug, Display, Error, From, IsVariant)]
+#[display("Failed connect to a remote share")]
+pub enum ConnectToRemoteShareError {
+    Io(NoiseStreamError),
+    ShareDoesntExist(ShareDoesntExistError),
+    #[display("Tried to connect to the same share for the second time")]
+    RepeatedRemoteShare(RepeatedRemoteShareError),
+    #[display("Tried to open a new connection to a server while already connected")]
+    RepeatedPeer(RepeatedPeerError),
+    ProtocolError(ProtocolError),
+    #[display("No mount path was given and the share doesnt suggest one")]
+    NoMountPath,
+    #[display("Peer closed the connection before answering, it might be unstable")]
+    PeerClosedDuringHandshake,
+    InvalidMountPath(InvalidMountPathError),
+    KeyMismatch(KeyMismatchError),
+}
+
+impl ConnectToRemoteShareError {
+    /// The handshake already succeeded at this point, so the peer closing the
+    /// connection means that it is flaky rather than the share being wrong
+    fn from_first_request(err: NoiseStreamError) -> Self {
+        match err.is_peer_closed() {
+            true => Self::PeerClosedDuringHandshake,
+            false => Self::Io(err),
+        }
+    }
+}
+
+impl From<NewPeerConnectedToShareError> for ConnectToRemoteShareError {
+    fn from(value: NewPeerConnectedToShareError) -> Self {
+        match value {
+            NewPeerConnectedToShareError::RepeatedPeer(err) => Self::RepeatedPeer(err),
+            NewPeerConnectedToShareError::ShareDoesntExist(err) => Self::ShareDoesntExist(err),
+        }
+    }
+}
+
+impl From<ReAttachPeerError> for ConnectToRemoteShareError {
+    fn from(value: ReAttachPeerError) -> Self {
+        match value {
+            ReAttachPeerError::RepeatedPeer(err) => Self::RepeatedPeer(err),
+            ReAttachPeerError::ShareDoesntExist(err) => Self::ShareDoesntExist(err),
+            ReAttachPeerError::PeerDoesntExist(_) => {
+                unreachable!("peer was looked up as the owner of the share")
+            }
+        }
+    }
+}
+
+impl From<io::Error> for ConnectToRemoteShareError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(NoiseStreamError::Io(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, net::SocketAddr, os::unix::fs::PermissionsExt};
+
+    use clap::Parser;
+    use smol::{future::zip, net::unix::UnixStream};
+    use tracing::level_filters::LevelFilter;
+
+    use super::*;
+    use crate::{
+        common::{
+            ConnectToRemoteShareErrorDto, ConnectionErrorCategory, DirEntryKind, ServerErrorDto,
+            ShareOptions, version::PROTOCOL_VERSION,
+        },
+        server::logs::{self, tests::Captured},
+        test_dir::TestDir,
+    };
+
+    fn test_server() -> Rc<Server<'static>> {
+        test_server_with(Args::parse_from(["rdir", "ls"]))
+    }
+
+    fn test_server_with(args: Args) -> Rc<Server<'static>> {
+        Server::in_memory(args)
+    }
+
+    async fn request(server: &Rc<Server<'static>>, message: ClientMessage) -> ServerResponse {
+        let (local, remote) = UnixStream::pair().unwrap();
+        let client = async {
+            let mut stream = FramedStream::new(remote);
+            stream
+                .write(&encode(&ClientEnvelope::from(&message)))
+                .await
+                .unwrap();
+            decode(&stream.read().await.unwrap()).unwrap()
+        };
+        zip(server.clone().handle_client(local), client).await.1
+    }
+
+    /// Reads a file of a share mounted from the peer to its end, a read at a
+    /// time like the mount does
+    async fn read_to_end(
+        server: &Rc<Server<'static>>,
+        peer_id: PeerId,
+        share: &str,
+        rel_path: &str,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        loop {
+            let message = PeerMessage::ReadFile {
+                share: share.parse()?,
+                rel_path: rel_path.to_string(),
+                offset: data.len() as u64,
+                len: net::MAX_READ_LEN,
+            };
+            match server.request_peer(peer_id, &message).await? {
+                PeerResponse::FileData(chunk) if chunk.is_empty() => return Ok(data),
+                PeerResponse::FileData(chunk) => data.extend(chunk),
+                response => anyhow::bail!("Unexpected response {response:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn in_memory_server_leaves_no_files() {
+        let dir = TestDir::new("in-memory");
+        let share_path = dir.join("share");
+        std::fs::create_dir(&share_path).unwrap();
+        let tmp_dir = dir.to_string_lossy().to_string();
+        let server = Server::in_memory(Args::parse_from(["rdir", "--tmpdir", &tmp_dir, "ls"]));
+
+        smol::block_on(async {
+            assert!(request(&server, ClientMessage::Ping).await.is_ok());
+            let share = ShareMessage::Share {
+                path: share_path.to_string_lossy().to_string(),
+                name: None,
+                options: Default::default(),
+                no_overlap: false,
+                force: false,
+            };
+            assert!(request(&server, ClientMessage::Share(share)).await.is_ok());
+            let remove = ShareMessage::Remove {
+                name: "share".parse().unwrap(),
+            };
+            assert!(request(&server, ClientMessage::Share(remove)).await.is_ok());
+        });
+        drop(server);
+
+        let entries: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(entries, ["share"]);
+    }
+
+    #[test]
+    fn shares_are_saved_on_every_change() {
+        let dir = TestDir::new("state-file");
+        let share_path = dir.join("share");
+        fs::create_dir_all(&share_path).unwrap();
+        let state_file = dir.join(STATE_FILE_NAME);
+        let server = test_server();
+        server.state_file.set(state_file.clone()).unwrap();
+        let name: CommonShareName = "share".parse().unwrap();
+
+        smol::block_on(async {
+            let share = ShareMessage::Share {
+                path: share_path.to_string_lossy().to_string(),
+                name: None,
+                options: Default::default(),
+                no_overlap: false,
+                force: false,
+            };
+            assert!(request(&server, ClientMessage::Share(share)).await.is_ok());
+            let saved = State::load(&state_file).unwrap();
+            assert_eq!(saved.get_shares()[&name].path, share_path);
+
+            let remove = ShareMessage::Remove { name: name.clone() };
+            assert!(request(&server, ClientMessage::Share(remove)).await.is_ok());
+            assert!(State::load(&state_file).unwrap().get_shares().is_empty());
+        });
+    }
+
+    #[test]
+    fn last_log_lines_survive_the_clean_up() {
+        let dir = TestDir::new("finish");
+        fs::create_dir_all(dir.join(DOWNLOAD_CACHE_DIR)).unwrap();
+        fs::write(dir.join(SOCKET_NAME), "").unwrap();
+        fs::write(dir.join(STATIC_KEY_NAME), "").unwrap();
+        let args = Args::parse_from(["rdir", "ls"]);
+        let ([main, errors], guards) = log_writers(&dir.join(LOGS_DIR), &args);
+        let subscriber = logs::subscriber(args.log_level.into(), main, errors).0;
+
+        tracing::subscriber::with_default(subscriber, || {
+            error!("Listener failed");
+            finish(guards, &dir);
+        });
+
+        let mut entries: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        entries.sort();
+        assert_eq!(entries, [LOGS_DIR, STATIC_KEY_NAME]);
+        let logs: String = fs::read_dir(dir.join(LOGS_DIR))
+            .unwrap()
+            .map(|entry| fs::read_to_string(entry.unwrap().path()).unwrap())
+            .collect();
+        assert!(logs.contains("Listener failed"));
+        assert!(logs.contains("Exitting"));
+    }
+
+    #[test]
+    fn transfers_are_listed_while_in_progress() {
+        let server = test_server();
+        let peer = "1.1.1.1:1".parse().unwrap();
+        let transfer = server.transfers.start("A", "photos/cat.jpg", peer, 1000);
+        transfer.advance(300);
+        transfer.advance(200);
+
+        let response = smol::block_on(request(&server, ClientMessage::Transfers));
+        let ServerResponse::Transfers(transfers) = response else {
+            panic!("Expected transfers, got {response:?}");
+        };
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].path, "photos/cat.jpg");
+        assert_eq!((transfers[0].done, transfers[0].total), (500, 1000));
+
+        drop(transfer);
+        let response = smol::block_on(request(&server, ClientMessage::Transfers));
+        assert!(matches!(response, ServerResponse::Transfers(transfers) if transfers.is_empty()));
+    }
+
+    #[test]
+    fn undecodable_message_gets_a_protocol_error() {
+        let server = test_server();
+        let (local, remote) = UnixStream::pair().unwrap();
+        let client = async {
+            let mut stream = FramedStream::new(remote);
+            stream.write(&[0xff; 7]).await.unwrap();
+            decode::<ServerResponse>(&stream.read().await.unwrap()).unwrap()
+        };
+        let ((), response) = smol::block_on(zip(server.clone().handle_client(local), client));
+        assert!(matches!(
+            response,
+            ServerResponse::Err(ServerErrorDto::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn mount_suggestion_resolves_under_home() {
+        let home = Path::new("/home/user");
+        assert_eq!(
+            default_mount_path(home, "photos"),
+            Some(PathBuf::from("/home/user/rdir/photos"))
+        );
+        assert_eq!(default_mount_path(home, "../.ssh"), None);
+        assert_eq!(default_mount_path(home, "a/b"), None);
+        assert_eq!(default_mount_path(home, "/etc"), None);
+        assert_eq!(default_mount_path(home, ""), None);
+    }
+
+    #[test]
+    fn default_mount_dir_is_created_by_the_daemon() {
+        let home = TestDir::new("mount-home");
+        let server = test_server();
+        let mount_path = server.create_default_mount(&home, "photos").unwrap();
+        assert_eq!(mount_path, home.join("rdir/photos"));
+        assert!(mount_path.is_dir());
+        assert!(matches!(
+            server.create_default_mount(&home, "../.ssh"),
+            Err(ConnectToRemoteShareError::NoMountPath)
+        ));
+    }
+
+    #[test]
+    fn suggested_mount_reaches_the_share() {
+        let server = test_server();
+        let options = ShareOptions {
+            suggested_mount: Some("photos".to_string()),
+            ..Default::default()
+        };
+        let response = smol::block_on(request(
+            &server,
+            ClientMessage::Share(ShareMessage::Share {
+                path: "/usr".to_string(),
+                name: Some("A".parse().unwrap()),
+                options: options.clone(),
+                no_overlap: false,
+                force: false,
+            }),
+        ));
+        assert!(response.is_ok());
+        let lock = server.state.borrow();
+        assert_eq!(lock.get_shares()[&"A".parse().unwrap()].options, options);
+    }
+
+    #[test]
+    fn overlapping_share_warns_or_is_refused() {
+        let server = test_server();
+        let share = Share::new("A".parse().unwrap(), "/usr".into());
+        server.state.borrow_mut().add_share(share).unwrap();
+        let share = |name: &str, no_overlap| {
+            let message = ClientMessage::Share(ShareMessage::Share {
+                path: "/usr/bin".to_string(),
+                name: Some(name.parse().unwrap()),
+                options: Default::default(),
+                no_overlap,
+                force: false,
+            });
+            smol::block_on(request(&server, message))
+        };
+
+        match share("B", true) {
+            ServerResponse::Err(ServerErrorDto::SharePathOverlap(err)) => {
+                assert_eq!(err.shares, ["A".parse().unwrap()]);
+            }
+            resp => panic!("unexpected response: {resp:?}"),
+        }
+        assert_eq!(server.state.borrow().get_shares().len(), 1);
+        assert!(share("B", false).is_warning());
+        assert_eq!(server.state.borrow().get_shares().len(), 2);
+    }
+
+    #[test]
+    fn sensitive_paths_need_force() {
+        let server = test_server();
+        let share = |path: &Path, force| {
+            let message = ClientMessage::Share(ShareMessage::Share {
+                path: path.to_string_lossy().to_string(),
+                name: Some("A".parse().unwrap()),
+                options: Default::default(),
+                no_overlap: false,
+                force,
+            });
+            smol::block_on(request(&server, message))
+        };
+
+        let home = std::env::home_dir().unwrap();
+        let denied = [
+            ("/", "/".into()),
+            ("/etc", "/etc".into()),
+            ("/etc", "/etc/ssh".into()),
+            ("/root", "/root".into()),
+            ("~/.ssh", home.join(".ssh")),
+            ("the rdir tmp dir", server.args.tmp_dir.join("logs")),
+        ];
+        for (pattern, path) in denied {
+            match share(&path, false) {
+                ServerResponse::Err(ServerErrorDto::RefusedSensitivePath(err)) => {
+                    assert_eq!(err.pattern, pattern, "{}", path.display());
+                }
+                resp => panic!("unexpected response for {}: {resp:?}", path.display()),
+            }
+        }
+        assert!(server.state.borrow().get_shares().is_empty());
+
+        assert!(share(Path::new("/etc"), true).is_ok());
+        assert_eq!(server.state.borrow().get_shares().len(), 1);
+
+        // Forcing `/` still needs a name, it has no dir name to go by
+        let message = ClientMessage::Share(ShareMessage::Share {
+            path: "/".to_string(),
+            name: None,
+            options: Default::default(),
+            no_overlap: false,
+            force: true,
+        });
+        assert!(matches!(
+            smol::block_on(request(&server, message)),
+            ServerResponse::Err(ServerErrorDto::InvalidShareName)
+        ));
+        let message = ClientMessage::Share(ShareMessage::ShareMany {
+            paths: vec!["/".to_string()],
+            options: Default::default(),
+            no_overlap: false,
+            force: true,
+        });
+        let ServerResponse::PartialOk(outcomes) = smol::block_on(request(&server, message)) else {
+            panic!("Expected a partial ok");
+        };
+        assert!(matches!(
+            outcomes[0].result,
+            Err(ServerErrorDto::InvalidShareName)
+        ));
+        assert_eq!(server.state.borrow().get_shares().len(), 1);
+    }
+
+    #[test]
+    fn repath_checks_the_new_dir_like_share() {
+        let server = test_server();
+        for (name, path) in [("A", "/usr/bin"), ("B", "/usr/lib")] {
+            let share = Share::new(name.parse().unwrap(), path.into());
+            server.state.borrow_mut().add_share(share).unwrap();
+        }
+        let repath = |path: &str, no_overlap, force| {
+            let message = ClientMessage::Share(ShareMessage::Repath {
+                name: "A".parse().unwrap(),
+                path: path.to_string(),
+                expected_current: None,
+                no_overlap,
+                force,
+            });
+            smol::block_on(request(&server, message))
+        };
+        let a: CommonShareName = "A".parse().unwrap();
+        let path_of_a = || server.state.borrow().get_shares()[&a].path.clone();
+
+        assert!(matches!(
+            repath("/etc", false, false),
+            ServerResponse::Err(ServerErrorDto::RefusedSensitivePath(_))
+        ));
+        match repath("/usr", true, false) {
+            ServerResponse::Err(ServerErrorDto::SharePathOverlap(err)) => {
+                assert_eq!(err.shares, ["B".parse().unwrap()]);
+            }
+            resp => panic!("unexpected response: {resp:?}"),
+        }
+        assert_eq!(path_of_a(), Path::new("/usr/bin"));
+        // Only other shares count, the share overlaps its own dir
+        assert!(repath("/usr/bin/old", true, false).is_ok());
+        assert!(repath("/usr", false, false).is_warning());
+        assert!(repath("/etc", false, true).is_ok());
+        assert_eq!(path_of_a(), Path::new("/etc"));
+
+        server.sandboxed.set(true);
+        assert!(matches!(
+            repath("/usr/share", false, false),
+            ServerResponse::Err(ServerErrorDto::Sandboxed(_))
+        ));
+        assert_eq!(path_of_a(), Path::new("/etc"));
+    }
+
+    #[test]
+    fn configured_shares_cant_be_sensitive() {
+        let server = test_server();
+        let share = Share::new("A".parse().unwrap(), "/etc".into());
+        let err = server.add_configured_shares(vec![share]).unwrap_err();
+        assert!(err.is::<RefusedSensitivePathError>());
+        assert!(server.state.borrow().get_shares().is_empty());
+    }
+
+    #[test]
+    fn common_names_need_discovery() {
+        let server = test_server();
+        let name: CommonShareName = "photos".parse().unwrap();
+        assert!(matches!(
+            smol::block_on(server.discover_share(name)),
+            Err(ServerError::DiscoveryDisabled(_))
+        ));
+    }
+
+    #[test]
+    fn sandboxed_server_refuses_new_shares() {
+        let server = test_server();
+        server.sandboxed.set(true);
+        let message = ClientMessage::Share(ShareMessage::Share {
+            path: "/usr/bin".to_string(),
+            name: None,
+            options: Default::default(),
+            no_overlap: false,
+            force: false,
+        });
+        assert!(matches!(
+            smol::block_on(request(&server, message)),
+            ServerResponse::Err(ServerErrorDto::Sandboxed(_))
+        ));
+        assert!(server.state.borrow().get_shares().is_empty());
+    }
+
+    #[test]
+    fn sandbox_grants_only_what_is_used() {
+        let server = test_server();
+        let share = Share::new("bin".parse().unwrap(), "/usr/bin".into());
+        server.state.borrow_mut().add_share(share).unwrap();
+        let automounts = [
+            Automount {
+                name: "127.0.0.1/A".parse().unwrap(),
+                path: Some("/mnt/a".into()),
+                pin_key: None,
+            },
+            Automount {
+                name: "127.0.0.1/B".parse().unwrap(),
+                path: None,
+                pin_key: None,
+            },
+        ];
+        let (readable, writable) = server.sandbox_paths(&automounts);
+        assert!(readable.contains(&PathBuf::from("/usr/bin")));
+        assert!(readable.contains(&PathBuf::from("/etc/hosts")));
+        assert!(!readable.contains(&PathBuf::from("/etc")));
+        assert_eq!(
+            writable,
+            [server.args.tmp_dir.clone(), PathBuf::from("/mnt/a")]
+        );
+    }
+
+    #[test]
+    fn share_many_reports_each_dir() {
+        let server = test_server();
+        let share = Share::new("bin".parse().unwrap(), "/usr/bin".into());
+        server.state.borrow_mut().add_share(share).unwrap();
+        let message = ClientMessage::Share(ShareMessage::ShareMany {
+            paths: ["/usr/lib", "/usr/bin", "/usr/share"]
+                .map(String::from)
+                .to_vec(),
+            options: Default::default(),
+            no_overlap: false,
+            force: false,
+        });
+
+        let ServerResponse::PartialOk(outcomes) = smol::block_on(request(&server, message)) else {
+            panic!("Expected a partial ok");
+        };
+        let results: Vec<_> = outcomes
+            .iter()
+            .map(|outcome| (outcome.path.as_str(), outcome.result.as_ref().ok()))
+            .collect();
+        assert_eq!(
+            results,
+            [
+                ("/usr/lib", Some(&"lib".parse().unwrap())),
+                ("/usr/bin", None),
+                ("/usr/share", Some(&"share".parse().unwrap())),
+            ]
+        );
+        assert!(matches!(
+            outcomes[1].result,
+            Err(ServerErrorDto::RepeatedShare(_))
+        ));
+        assert_eq!(server.state.borrow().get_shares().len(), 3);
+    }
+
+    #[test]
+    fn unanswered_handshake_times_out() {
+        let server = test_server_with(Args::parse_from(["rdir", "--connect-timeout", "1", "ls"]));
+        // Connections get accepted by the kernel, but nobody answers the handshake
+        let silent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = silent.local_addr().unwrap();
+        let name: FullShareName = format!("{addr}/photos").parse().unwrap();
+        let mount_path = std::env::temp_dir().join("rdir-unanswered");
+
+        let start = Instant::now();
+        let err = smol::block_on(server.connect_to_remote_share(
+            name,
+            Some(mount_path),
+            Default::default(),
+        ))
+        .unwrap_err();
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        let ConnectToRemoteShareError::Io(err) = err else {
+            panic!("Expected an IO error, got {err:?}");
+        };
+        assert_eq!(err.category(), ConnectionErrorCategory::Timeout);
+        assert!(server.state.borrow().get_remote_shares().is_empty());
+    }
+
+    #[test]
+    fn tcp_peers_list_and_join_shares() {
+        let server = test_server();
+        let share = Share::new("A".parse().unwrap(), "/srv/a".into());
+        server.state.borrow_mut().add_share(share).unwrap();
+
+        let result = async {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let SocketAddr::V4(addr) = listener.local_addr()? else {
+                unreachable!()
+            };
+            let accept = async {
+                loop {
+                    let (stream, _) = listener.accept().await?;
+                    server.ex.spawn(server.clone().handle_peer(stream)).detach();
+                }
+            };
+            let peer = async {
+                let buffers = BufferBudget::new(None);
+                let mut conn = PeerConnection::connect(
+                    addr,
+                    server.cipher,
+                    &buffers,
+                    net::FRAMED_TCP_CONNECT_TIMEOUT,
+                )
+                .await?;
+                let listed = conn.request(&encode(&PeerInitMessage::ListShares)).await?;
+                let listed: PeerInitListSharesRosponse = decode(&listed)?;
+                assert_eq!(listed.shares, ["A".parse()?]);
+
+                let mut conn = PeerConnection::connect(
+                    addr,
+                    server.cipher,
+                    &buffers,
+                    net::FRAMED_TCP_CONNECT_TIMEOUT,
+                )
+                .await?;
+                let join = PeerInitMessage::ConnectToShare { name: "A".parse()? };
+                let joined: PeerInitConnectToShareResponse =
+                    decode(&conn.request(&encode(&join)).await?)?;
+                assert!(joined.is_ok());
+                assert_eq!(server.state.borrow().get_peers().len(), 1);
+
+                // Closing the connection drops the peer
+                conn.close().await;
+                while !server.state.borrow().get_peers().is_empty() {
+                    Timer::after(Duration::from_millis(10)).await;
+                }
+                anyhow::Ok(())
+            };
+            peer.or(accept).await
+        };
+        smol::block_on(server.ex.run(result.timeout(Duration::from_secs(5))))
+            .expect("timed out")
+            .unwrap();
+    }
+
+    #[test]
+    fn mounted_shares_can_be_browsed() {
+        let dir = TestDir::new("browse");
+        fs::create_dir_all(dir.join("shared/photos")).unwrap();
+        fs::create_dir_all(dir.join("shared/empty")).unwrap();
+        fs::create_dir_all(dir.join("mnt")).unwrap();
+        // Where the mounter pins the key of the owner
+        fs::create_dir_all(dir.join("rdir")).unwrap();
+        fs::write(dir.join("shared/notes.txt"), "hello").unwrap();
+        let owner = test_server();
+        let share = Share::new("A".parse().unwrap(), dir.join("shared"));
+        owner.state.borrow_mut().add_share(share).unwrap();
+        let tmp_dir = dir.to_string_lossy().to_string();
+        let mounter = test_server_with(Args::parse_from(["rdir", "--tmpdir", &tmp_dir, "ls"]));
+
+        let result = async {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = listener.local_addr()?;
+            let accept = async {
+                loop {
+                    let (stream, _) = listener.accept().await?;
+                    owner.ex.spawn(owner.clone().handle_peer(stream)).detach();
+                }
+            };
+            let mount = async {
+                let name = format!("{addr}/A").parse()?;
+                mounter
+                    .connect_to_remote_share(name, Some(dir.join("mnt")), Default::default())
+                    .await?;
+                let browse = async |path: Option<&str>| {
+                    let message = ConnectMessage::Browse {
+                        name: "A".parse().unwrap(),
+                        path: path.map(ToString::to_string),
+                    };
+                    request(&mounter, ClientMessage::Connect(message)).await
+                };
+
+                let ServerResponse::DirEntries(entries) = browse(None).await else {
+                    panic!("expected the entries of the root");
+                };
+                let listed: Vec<_> = entries
+                    .iter()
+                    .map(|entry| (entry.name.as_str(), entry.kind, entry.size))
+                    .collect();
+                // Sizes of dirs depend on the filesystem of the tmp dir
+                let dir_size =
+                    |name: &str| fs::metadata(dir.join("shared").join(name)).unwrap().len();
+                assert_eq!(listed[0], ("empty", DirEntryKind::Dir, dir_size("empty")));
+                assert_eq!(listed[1], ("notes.txt", DirEntryKind::File, 5));
+                assert_eq!(listed[2], ("photos", DirEntryKind::Dir, dir_size("photos")));
+                let empty = browse(Some("empty")).await;
+                assert!(
+                    matches!(&empty, ServerResponse::DirEntries(entries) if entries.is_empty())
+                );
+                assert_eq!(empty.to_string(), "Directory is empty\n");
+                assert!(matches!(
+                    browse(Some("../..")).await,
+                    ServerResponse::Err(ServerErrorDto::Browse(
+                        PeerResponseError::PermissionDenied
+                    ))
+                ));
+                assert!(matches!(
+                    browse(Some("notes.txt")).await,
+                    ServerResponse::Err(ServerErrorDto::Browse(PeerResponseError::NotADirectory))
+                ));
+
+                let peer_id = *mounter.state.borrow().get_peers().keys().next().unwrap();
+                let stat = PeerMessage::Stat {
+                    share: "A".parse()?,
+                    rel_path: "notes.txt".to_string(),
+                };
+                let PeerResponse::Attrs(attrs) = mounter.request_peer(peer_id, &stat).await? else {
+                    panic!("expected the attrs of the file");
+                };
+                let metadata = fs::metadata(dir.join("shared/notes.txt"))?;
+                assert_eq!(attrs.kind, DirEntryKind::File);
+                assert_eq!(attrs.size, 5);
+                assert_eq!(attrs.mode, metadata.permissions().mode() & 0o7777);
+                anyhow::Ok(())
+            };
+            mount.or(accept).await
+        };
+        let run = owner
+            .ex
+            .run(mounter.ex.run(result.timeout(Duration::from_secs(5))));
+        smol::block_on(run).expect("timed out").unwrap();
+    }
+
+    #[test]
+    fn closed_connection_drops_the_mount() {
+        let dir = TestDir::new("closed");
+        fs::create_dir_all(dir.join("shared")).unwrap();
+        fs::create_dir_all(dir.join("mnt")).unwrap();
+        fs::create_dir_all(dir.join("rdir")).unwrap();
+        let owner = test_server();
+        let share = Share::new("A".parse().unwrap(), dir.join("shared"));
+        owner.state.borrow_mut().add_share(share).unwrap();
+        let tmp_dir = dir.to_string_lossy().to_string();
+        let mounter = test_server_with(Args::parse_from(["rdir", "--tmpdir", &tmp_dir, "ls"]));
+
+        let result = async {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = listener.local_addr()?;
+            let accept = async {
+                loop {
+                    let (stream, _) = listener.accept().await?;
+                    owner.ex.spawn(owner.clone().handle_peer(stream)).detach();
+                }
+            };
+            let mount = async {
+                let name = format!("{addr}/A").parse()?;
+                mounter
+                    .connect_to_remote_share(name, Some(dir.join("mnt")), Default::default())
+                    .await?;
+                assert_eq!(mounter.state.borrow().get_peers().len(), 1);
+                // The owner drops the mounter without a request in flight
+                assert_eq!(owner.disconnect_host(Ipv4Addr::LOCALHOST), 1);
+                while !mounter.state.borrow().get_peers().is_empty() {
+                    Timer::after(Duration::from_millis(10)).await;
+                }
+                assert!(mounter.state.borrow().get_remote_shares().is_empty());
+                #[cfg(feature = "fuse")]
+                assert!(mounter.mounts.borrow().is_empty());
+                anyhow::Ok(())
+            };
+            mount.or(accept).await
+        };
+        let run = owner
+            .ex
+            .run(mounter.ex.run(result.timeout(Duration::from_secs(5))));
+        smol::block_on(run).expect("timed out").unwrap();
+    }
+
+    #[test]
+    fn automount_mounts_again_after_losing_the_connection() {
+        let dir = TestDir::new("automount-lost");
+        fs::create_dir_all(dir.join("shared")).unwrap();
+        fs::create_dir_all(dir.join("mnt")).unwrap();
+        fs::create_dir_all(dir.join("rdir")).unwrap();
+        let owner = test_server();
+        for name in ["A", "B"] {
+            let share = Share::new(name.parse().unwrap(), dir.join("shared"));
+            owner.state.borrow_mut().add_share(share).unwrap();
+        }
+        let tmp_dir = dir.to_string_lossy().to_string();
+        let mounter = test_server_with(Args::parse_from(["rdir", "--tmpdir", &tmp_dir, "ls"]));
+
+        let result = async {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = listener.local_addr()?;
+            let accept = async {
+                loop {
+                    let (stream, _) = listener.accept().await?;
+                    owner.ex.spawn(owner.clone().handle_peer(stream)).detach();
+                }
+            };
+            let automount = async {
+                let SocketAddr::V4(addr) = addr else {
+                    unreachable!()
+                };
+                let timeout = Duration::from_secs(1);
+                let mut conn =
+                    PeerConnection::connect(addr, mounter.cipher, &mounter.io_buffers, timeout)
+                        .await?;
+                let key = *conn.peer_key().unwrap();
+                conn.close().await;
+                let name: FullShareName = format!("{addr}/A").parse()?;
+                let wrong_pin: FullShareName = format!("{addr}/B").parse()?;
+                mounter.spawn_automounts(vec![
+                    Automount {
+                        name: name.clone(),
+                        path: Some(dir.join("mnt")),
+                        pin_key: Some(key),
+                    },
+                    Automount {
+                        name: wrong_pin.clone(),
+                        path: Some(dir.join("mnt")),
+                        pin_key: Some([0; 32]),
+                    },
+                ]);
+                let mounted_by = async |previous: Option<PeerId>| loop {
+                    let owner = mounter
+                        .state
+                        .borrow()
+                        .get_remote_shares()
+                        .get(&name)
+                        .map(|share| share.owner());
+                    if owner.is_some() && owner != previous {
+                        return owner;
+                    }
+                    Timer::after(Duration::from_millis(10)).await;
+                };
+                let first = mounted_by(None).await;
+                assert_eq!(owner.disconnect_host(Ipv4Addr::LOCALHOST), 1);
+                mounted_by(first).await;
+                let state = mounter.state.borrow();
+                assert!(!state.get_remote_shares().contains_key(&wrong_pin));
+                anyhow::Ok(())
+            };
+            automount.or(accept).await
+        };
+        let run = owner
+            .ex
+            .run(mounter.ex.run(result.timeout(Duration::from_secs(5))));
+        smol::block_on(run).expect("timed out").unwrap();
+    }
+
+    #[test]
+    fn introductions_are_accepted_from_trusted_peers_only() {
+        let dir = TestDir::new("introductions");
+        fs::create_dir_all(dir.join("shared")).unwrap();
+        fs::create_dir_all(dir.join("mnt")).unwrap();
+        let server = |name: &str, flags: &[&str]| {
+            fs::create_dir_all(dir.join(name).join("rdir")).unwrap();
+            let tmp_dir = dir.join(name).to_string_lossy().to_string();
+            let args = [&["rdir", "--tmpdir", &tmp_dir], flags, &["ls"]].concat();
+            test_server_with(Args::parse_from(args))
+        };
+        let owner = server("owner", &["--accept-introductions"]);
+        let share = Share::new("A".parse().unwrap(), dir.join("shared"));
+        owner.state.borrow_mut().add_share(share).unwrap();
+        let mounter = server("mounter", &[]);
+        let introduced: RemotePeerAddr = "10.0.0.2".parse().unwrap();
+        mounter.trust_store().trust(&introduced, &[7; 32]).unwrap();
+
+        let result = async {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = listener.local_addr()?;
+            let accept = async {
+                loop {
+                    let (stream, _) = listener.accept().await?;
+                    owner.ex.spawn(owner.clone().handle_peer(stream)).detach();
+                }
+            };
+            let introduce = async {
+                let name: FullShareName = format!("{addr}/A").parse()?;
+                let mount = mounter.connect_to_remote_share(
+                    name.clone(),
+                    Some(dir.join("mnt")),
+                    Default::default(),
+                );
+                Box::pin(mount).await?;
+                let introduce = |addr: &RemotePeerAddr| {
+                    ClientMessage::Connect(ConnectMessage::Introduce {
+                        name: ShareName::Full(name.clone()),
+                        addr: addr.clone(),
+                        ttl: 60,
+                    })
+                };
+                let refused = |err| {
+                    ServerErrorDto::Introduce(PeerResponseError::Introduction(err)).to_string()
+                };
+
+                // Nothing vouches for the mounter yet
+                let response = request(&mounter, introduce(&introduced)).await;
+                let expected = refused(IntroductionError::UntrustedVoucher);
+                assert!(
+                    matches!(&response, ServerResponse::Err(err) if err.to_string() == expected),
+                    "{response:?}"
+                );
+                assert_eq!(owner.trust_store().pinned(&introduced)?, None);
+
+                let voucher = mounter.trust_store().pinned(&name.addr)?.unwrap();
+                let mounter_addr: RemotePeerAddr = "127.0.0.1".parse()?;
+                owner.trust_store().trust(&mounter_addr, &voucher)?;
+                let response = request(&mounter, introduce(&introduced)).await;
+                assert!(matches!(response, ServerResponse::Ok), "{response:?}");
+                assert_eq!(owner.trust_store().pinned(&introduced)?, Some([7; 32]));
+
+                // Introductions never replace a pinned key
+                mounter.trust_store().trust(&introduced, &[8; 32])?;
+                let response = request(&mounter, introduce(&introduced)).await;
+                let expected = refused(IntroductionError::Conflict);
+                assert!(
+                    matches!(&response, ServerResponse::Err(err) if err.to_string() == expected),
+                    "{response:?}"
+                );
+                assert_eq!(owner.trust_store().pinned(&introduced)?, Some([7; 32]));
+
+                let unknown: RemotePeerAddr = "10.0.0.3".parse()?;
+                let response = request(&mounter, introduce(&unknown)).await;
+                assert!(
+                    matches!(response, ServerResponse::Err(ServerErrorDto::NotPinned(_))),
+                    "{response:?}"
+                );
+                anyhow::Ok(())
+            };
+            introduce.or(accept).await
+        };
+        let run = owner
+            .ex
+            .run(mounter.ex.run(result.timeout(Duration::from_secs(5))));
+        smol::block_on(run).expect("timed out").unwrap();
+    }
+
+    #[test]
+    fn introductions_are_refused_unless_enabled() {
+        let server = test_server();
+        let expires = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 60;
+        let introduction = net::introduce("10.0.0.2".parse().unwrap(), [7; 32], expires);
+        assert_eq!(
+            server.accept_introduction(Some(&[1; 32]), &introduction),
+            Err(PeerResponseError::Introduction(IntroductionError::Disabled))
+        );
+    }
+
+    #[test]
+    fn xattrs_are_read_over_the_wire() {
+        let dir = TestDir::new("wire-xattrs");
+        fs::create_dir_all(dir.join("shared")).unwrap();
+        fs::create_dir_all(dir.join("mnt")).unwrap();
+        fs::create_dir_all(dir.join("rdir")).unwrap();
+        fs::write(dir.join("shared/tagged"), "").unwrap();
+        if xattr::set(dir.join("shared/tagged"), "user.rdir", b"yes").is_err() {
+            // The filesystem of the tmp dir doesnt support user xattrs
+            return;
+        }
+        let owner = test_server();
+        let mut share = Share::new("A".parse().unwrap(), dir.join("shared"));
+        share.set_options(ShareOptions {
+            xattrs: true,
+            ..Default::default()
+        });
+        owner.state.borrow_mut().add_share(share).unwrap();
+        let tmp_dir = dir.to_string_lossy().to_string();
+        let mounter = test_server_with(Args::parse_from(["rdir", "--tmpdir", &tmp_dir, "ls"]));
+
+        let result = async {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = listener.local_addr()?;
+            let accept = async {
+                loop {
+                    let (stream, _) = listener.accept().await?;
+                    owner.ex.spawn(owner.clone().handle_peer(stream)).detach();
+                }
+            };
+            let mount = async {
+                let name = format!("{addr}/A").parse()?;
+                mounter
+                    .connect_to_remote_share(name, Some(dir.join("mnt")), Default::default())
+                    .await?;
+                let peer_id = *mounter.state.borrow().get_peers().keys().next().unwrap();
+                let get = PeerMessage::GetXattr {
+                    share: "A".parse()?,
+                    rel_path: "tagged".to_string(),
+                    name: "user.rdir".to_string(),
+                };
+                assert!(matches!(
+                    mounter.request_peer(peer_id, &get).await?,
+                    PeerResponse::Xattr(Some(value)) if value == b"yes"
+                ));
+                let list = PeerMessage::ListXattr {
+                    share: "A".parse()?,
+                    rel_path: "tagged".to_string(),
+                };
+                assert!(matches!(
+                    mounter.request_peer(peer_id, &list).await?,
+                    PeerResponse::XattrNames(names) if names.contains(&"user.rdir".to_string())
+                ));
+                anyhow::Ok(())
+            };
+            mount.or(accept).await
+        };
+        let run = owner
+            .ex
+            .run(mounter.ex.run(result.timeout(Duration::from_secs(5))));
+        smol::block_on(run).expect("timed out").unwrap();
+    }
+
+    #[test]
+    fn same_host_mounts_skip_tcp() {
+        let dir = TestDir::new("same-host");
+        fs::create_dir_all(dir.join("shared")).unwrap();
+        fs::create_dir_all(dir.join("mnt")).unwrap();
+        fs::create_dir_all(dir.join("rdir")).unwrap();
+        fs::write(dir.join("shared/notes.txt"), "hello").unwrap();
+        let owner = test_server();
+        let share = Share::new("A".parse().unwrap(), dir.join("shared"));
+        owner.state.borrow_mut().add_share(share).unwrap();
+        let tmp_dir = dir.to_string_lossy().to_string();
+        let mounter = test_server_with(Args::parse_from(["rdir", "--tmpdir", &tmp_dir, "ls"]));
+
+        let result = async {
+            // Nothing accepts on TCP, so only the same host socket can be used
+            let tcp_listener = TcpListener::bind("127.0.0.1:0").await?;
+            let SocketAddr::V4(addr) = tcp_listener.local_addr()? else {
+                unreachable!("bound to an IPv4 address")
+            };
+            let listener = net::bind_same_host(addr.port())?;
+            let accept = owner.clone().accept_same_host_peer(listener);
+            let mount = async {
+                let name = format!("{addr}/A").parse()?;
+                mounter
+                    .connect_to_remote_share(name, Some(dir.join("mnt")), Default::default())
+                    .await?;
+                let peer_id = *mounter.state.borrow().get_peers().keys().next().unwrap();
+                let read = PeerMessage::ReadFile {
+                    share: "A".parse()?,
+                    rel_path: "notes.txt".to_string(),
+                    offset: 0,
+                    len: 5,
+                };
+                assert!(matches!(
+                    mounter.request_peer(peer_id, &read).await?,
+                    PeerResponse::FileData(data) if data == b"hello"
+                ));
+                let state = owner.state.borrow();
+                let peer = state.get_peers().values().next().unwrap();
+                assert!(peer.address.ip().is_unspecified());
+                anyhow::Ok(())
+            };
+            mount.or(accept).await
+        };
+        let run = owner
+            .ex
+            .run(mounter.ex.run(result.timeout(Duration::from_secs(4))));
+        smol::block_on(run).expect("timed out").unwrap();
+    }
+
+    #[test]
+    fn shares_of_one_host_share_a_connection() {
+        let dir = TestDir::new("one-host");
+        fs::create_dir_all(dir.join("rdir")).unwrap();
+        let owner = test_server();
+        for name in ["A", "B"] {
+            fs::create_dir_all(dir.join(name)).unwrap();
+            fs::create_dir_all(dir.join("mnt").join(name)).unwrap();
+            fs::write(dir.join(name).join("notes.txt"), name).unwrap();
+            let share = Share::new(name.parse().unwrap(), dir.join(name));
+            owner.state.borrow_mut().add_share(share).unwrap();
+        }
+        let tmp_dir = dir.to_string_lossy().to_string();
+        let mounter = test_server_with(Args::parse_from(["rdir", "--tmpdir", &tmp_dir, "ls"]));
+
+        let result = async {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = listener.local_addr()?;
+            let accept = async {
+                loop {
+                    let (stream, _) = listener.accept().await?;
+                    owner.ex.spawn(owner.clone().handle_peer(stream)).detach();
+                }
+            };
+            let mount = async |name: &str| {
+                let share_name = format!("{addr}/{name}").parse()?;
+                let path = dir.join("mnt").join(name);
+                mounter
+                    .connect_to_remote_share(share_name, Some(path), Default::default())
+                    .await?;
+                anyhow::Ok(())
+            };
+            let unmount = async |name: &str| {
+                let name = format!("{addr}/{name}").parse()?;
+                let message = ClientMessage::Connect(ConnectMessage::Unmount { name });
+                assert!(matches!(
+                    request(&mounter, message).await,
+                    ServerResponse::Ok
+                ));
+                anyhow::Ok(())
+            };
+            let read = async |name: &str| {
+                let peer_id = *mounter.state.borrow().get_peers().keys().next().unwrap();
+                let read = PeerMessage::ReadFile {
+                    share: name.parse()?,
+                    rel_path: "notes.txt".to_string(),
+                    offset: 0,
+                    len: 1,
+                };
+                let read = mounter.request_peer(peer_id, &read).await?;
+                assert!(matches!(read, PeerResponse::FileData(data) if data == name.as_bytes()));
+                anyhow::Ok(())
+            };
+            let check = async {
+                mount("A").await?;
+                mount("B").await?;
+                {
+                    let state = mounter.state.borrow();
+                    assert_eq!(state.get_peers().len(), 1);
+                    assert_eq!(state.get_remote_shares().len(), 2);
+                }
+                read("A").await?;
+                read("B").await?;
+                assert_eq!(owner.state.borrow().get_peers().len(), 1);
+
+                // The connection stays for the share that is still mounted
+                unmount("B").await?;
+                assert_eq!(mounter.state.borrow().get_peers().len(), 1);
+                mount("B").await?;
+                read("B").await?;
+
+                // Unmounting the last share closes it, mounting again opens a new one
+                unmount("A").await?;
+                unmount("B").await?;
+                {
+                    let state = mounter.state.borrow();
+                    assert!(state.get_peers().is_empty());
+                    assert!(state.get_peers_by_scoket().is_empty());
+                }
+                mount("B").await?;
+                read("B").await?;
+                anyhow::Ok(())
+            };
+            check.or(accept).await
+        };
+        let run = owner
+            .ex
+            .run(mounter.ex.run(result.timeout(Duration::from_secs(5))));
+        smol::block_on(run).expect("timed out").unwrap();
+    }
+
+    #[test]
+    fn unmountable_shares_are_left() {
+        let dir = TestDir::new("unmountable");
+        fs::create_dir_all(dir.join("rdir")).unwrap();
+        fs::create_dir_all(dir.join("mnt")).unwrap();
+        let owner = test_server();
+        for name in ["A", "B"] {
+            fs::create_dir_all(dir.join(name)).unwrap();
+            let share = Share::new(name.parse().unwrap(), dir.join(name));
+            owner.state.borrow_mut().add_share(share).unwrap();
+        }
+        let tmp_dir = dir.to_string_lossy().to_string();
+        let mounter = test_server_with(Args::parse_from(["rdir", "--tmpdir", &tmp_dir, "ls"]));
+        let participants = || {
+            owner.state.borrow().get_shares()[&"B".parse().unwrap()]
+                .participants
+                .len()
+        };
+
+        let result = async {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = listener.local_addr()?;
+            let accept = async {
+                loop {
+                    let (stream, _) = listener.accept().await?;
+                    owner.ex.spawn(owner.clone().handle_peer(stream)).detach();
+                }
+            };
+            let check = async {
+                let share_name: FullShareName = format!("{addr}/A").parse()?;
+                let path = Some(dir.join("mnt"));
+                mounter
+                    .connect_to_remote_share(share_name, path, Default::default())
+                    .await?;
+
+                // A given path is refused before the peer is asked to join
+                let share_name: FullShareName = format!("{addr}/B").parse()?;
+                let path = Some(dir.join("rdir").join("B"));
+                let err = mounter
+                    .connect_to_remote_share(share_name.clone(), path, Default::default())
+                    .await
+                    .unwrap_err();
+                assert!(err.is_invalid_mount_path());
+                assert_eq!(participants(), 0);
+
+                // A share joined over the connection is left again
+                let peer_id = *mounter.state.borrow().get_peers().keys().next().unwrap();
+                let join = encode(&PeerMessage::ConnectToShare {
+                    share: "B".parse()?,
+                });
+                mounter.peer_exchange(peer_id, join, false).await.unwrap()?;
+                assert_eq!(participants(), 1);
+                mounter.leave_remote_share(peer_id, &share_name).await;
+                assert_eq!(participants(), 0);
+                assert_eq!(owner.state.borrow().get_peers().len(), 1);
+                anyhow::Ok(())
+            };
+            check.or(accept).await
+        };
+        let run = owner
+            .ex
+            .run(mounter.ex.run(result.timeout(Duration::from_secs(5))));
+        smol::block_on(run).expect("timed out").unwrap();
+    }
+
+    #[test]
+    fn pinned_mounts_need_the_key() {
+        let dir = TestDir::new("pinned");
+        fs::create_dir_all(dir.join("rdir")).unwrap();
+        let owner = test_server();
+        for name in ["A", "B"] {
+            fs::create_dir_all(dir.join(name)).unwrap();
+            fs::create_dir_all(dir.join("mnt").join(name)).unwrap();
+            let share = Share::new(name.parse().unwrap(), dir.join(name));
+            owner.state.borrow_mut().add_share(share).unwrap();
+        }
+        let tmp_dir = dir.to_string_lossy().to_string();
+        let mounter = test_server_with(Args::parse_from(["rdir", "--tmpdir", &tmp_dir, "ls"]));
+
+        let result = async {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let SocketAddr::V4(addr) = listener.local_addr()? else {
+                unreachable!()
+            };
+            let accept = async {
+                loop {
+                    let (stream, _) = listener.accept().await?;
+                    owner.ex.spawn(owner.clone().handle_peer(stream)).detach();
+                }
+            };
+            let mount = async |name: &str, pin_key| {
+                let share_name = format!("{addr}/{name}").parse()?;
+                let path = Some(dir.join("mnt").join(name));
+                let options = MountOptions {
+                    pin_key: Some(pin_key),
+                    ..Default::default()
+                };
+                anyhow::Ok(
+                    mounter
+                        .connect_to_remote_share(share_name, path, options)
+                        .await,
+                )
+            };
+            let check = async {
+                let err = mount("A", [0; 32]).await?.unwrap_err();
+                assert!(err.is_key_mismatch());
+                assert!(matches!(
+                    ConnectToRemoteShareErrorDto::from(err),
+                    ConnectToRemoteShareErrorDto::Io {
+                        category: ConnectionErrorCategory::Untrusted,
+                        ..
+                    }
+                ));
+                assert!(mounter.state.borrow().get_remote_shares().is_empty());
+
+                let key = mounter.trust_peer(&addr.into()).await?;
+                mount("A", key).await??;
+                // Further shares are joined over the connection, whose key is known
+                assert!(mount("B", [0; 32]).await?.unwrap_err().is_key_mismatch());
+                mount("B", key).await??;
+                assert_eq!(mounter.state.borrow().get_remote_shares().len(), 2);
+                anyhow::Ok(())
+            };
+            check.or(accept).await
+        };
+        let run = owner
+            .ex
+            .run(mounter.ex.run(result.timeout(Duration::from_secs(5))));
+        smol::block_on(run).expect("timed out").unwrap();
+    }
+
+    #[test]
+    fn reconnected_peer_keeps_its_mounts() {
+        let dir = TestDir::new("re-attach");
+        fs::create_dir_all(dir.join("shared")).unwrap();
+        fs::create_dir_all(dir.join("mnt")).unwrap();
+        fs::create_dir_all(dir.join("rdir")).unwrap();
+        fs::write(dir.join("shared/notes.txt"), "hello").unwrap();
+        let owner = test_server();
+        let share = Share::new("A".parse().unwrap(), dir.join("shared"));
+        owner.state.borrow_mut().add_share(share).unwrap();
+        let tmp_dir = dir.to_string_lossy().to_string();
+        let mounter = test_server_with(Args::parse_from(["rdir", "--tmpdir", &tmp_dir, "ls"]));
+
+        let result = async {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = listener.local_addr()?;
+            let accept = async {
+                loop {
+                    let (stream, _) = listener.accept().await?;
+                    owner.ex.spawn(owner.clone().handle_peer(stream)).detach();
+                }
+            };
+            let reconnect = async {
+                let name: FullShareName = format!("{addr}/A").parse()?;
+                let mount = async || {
+                    mounter
+                        .connect_to_remote_share(
+                            name.clone(),
+                            Some(dir.join("mnt")),
+                            Default::default(),
+                        )
+                        .await
+                };
+                let mut peer_ids = Vec::new();
+                for reconnect in [false, true] {
+                    if reconnect {
+                        // Refused while the connection is in use, taken over
+                        // once the share is being reconnected
+                        assert!(mount().await.unwrap_err().is_repeated_remote_share());
+                        let (reconnect_tx, _reconnect_rx) = bounded(1);
+                        mounter
+                            .reconnects
+                            .borrow_mut()
+                            .insert(name.clone(), reconnect_tx);
+                    }
+                    mount().await?;
+                    let state = mounter.state.borrow();
+                    assert_eq!(state.get_peers().len(), 1);
+                    assert_eq!(state.get_remote_shares().len(), 1);
+                    peer_ids.push(*state.get_peers().keys().next().unwrap());
+                }
+                assert_ne!(peer_ids[0], peer_ids[1]);
+                // The mount is served over the new connection
+                let read = PeerMessage::ReadFile {
+                    share: "A".parse()?,
+                    rel_path: "notes.txt".to_string(),
+                    offset: 0,
+                    len: 5,
+                };
+                assert!(matches!(
+                    mounter.request_peer(peer_ids[1], &read).await?,
+                    PeerResponse::FileData(data) if data == b"hello"
+                ));
+                anyhow::Ok(())
+            };
+            reconnect.or(accept).await
+        };
+        let run = owner
+            .ex
+            .run(mounter.ex.run(result.timeout(Duration::from_secs(5))));
+        smol::block_on(run).expect("timed out").unwrap();
+    }
+
+    #[test]
+    fn dirs_are_paged_over_the_wire() {
+        let dir = TestDir::new("wire-dir-pages");
+        fs::create_dir_all(dir.join("shared")).unwrap();
+        fs::create_dir_all(dir.join("mnt")).unwrap();
+        fs::create_dir_all(dir.join("rdir")).unwrap();
+        for i in 0..5 {
+            fs::write(dir.join(format!("shared/{i}")), "").unwrap();
+        }
+        let owner = test_server();
+        let share = Share::new("A".parse().unwrap(), dir.join("shared"));
+        owner.state.borrow_mut().add_share(share).unwrap();
+        let tmp_dir = dir.to_string_lossy().to_string();
+        let mounter = test_server_with(Args::parse_from(["rdir", "--tmpdir", &tmp_dir, "ls"]));
+
+        let result = async {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = listener.local_addr()?;
+            let accept = async {
+                loop {
+                    let (stream, _) = listener.accept().await?;
+                    owner.ex.spawn(owner.clone().handle_peer(stream)).detach();
+                }
+            };
+            let list = async {
+                let name = format!("{addr}/A").parse()?;
+                mounter
+                    .connect_to_remote_share(name, Some(dir.join("mnt")), Default::default())
+                    .await?;
+                let peer_id = *mounter.state.borrow().get_peers().keys().next().unwrap();
+                let mut names = Vec::new();
+                let mut cursor = None;
+                loop {
+                    let message = PeerMessage::ReadDirPage {
+                        share: "A".parse()?,
+                        rel_path: String::new(),
+                        cursor,
+                        limit: 2,
+                    };
+                    let PeerResponse::DirPage { entries, next } =
+                        mounter.request_peer(peer_id, &message).await?
+                    else {
+                        anyhow::bail!("Expected a dir page");
+                    };
+                    names.extend(entries.into_iter().map(|entry| entry.name));
+                    // Created after the first page, so it isnt in the snapshot
+                    fs::write(dir.join("shared/new"), "")?;
+                    match next {
+                        Some(next) => cursor = Some(next),
+                        None => break,
+                    }
+                }
+                assert_eq!(names, ["0", "1", "2", "3", "4"]);
+                anyhow::Ok(())
+            };
+            list.or(accept).await
+        };
+        let run = owner
+            .ex
+            .run(mounter.ex.run(result.timeout(Duration::from_secs(5))));
+        smol::block_on(run).expect("timed out").unwrap();
+    }
+
+    #[test]
+    fn reads_are_throttled_to_the_fair_bandwidth() {
+        let dir = TestDir::new("fair-bandwidth");
+        fs::create_dir_all(dir.join("shared")).unwrap();
+        fs::create_dir_all(dir.join("mnt")).unwrap();
+        fs::create_dir_all(dir.join("rdir")).unwrap();
+        fs::write(dir.join("shared/big"), vec![0; 1500]).unwrap();
+        let owner = test_server_with(Args::parse_from(["rdir", "--fair-bandwidth", "1000", "ls"]));
+        let share = Share::new("A".parse().unwrap(), dir.join("shared"));
+        owner.state.borrow_mut().add_share(share).unwrap();
+        let tmp_dir = dir.to_string_lossy().to_string();
+        let mounter = test_server_with(Args::parse_from(["rdir", "--tmpdir", &tmp_dir, "ls"]));
+
+        let result = async {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = listener.local_addr()?;
+            let accept = async {
+                loop {
+                    let (stream, _) = listener.accept().await?;
+                    owner.ex.spawn(owner.clone().handle_peer(stream)).detach();
+                }
+            };
+            let read = async {
+                let name = format!("{addr}/A").parse()?;
+                mounter
+                    .connect_to_remote_share(name, Some(dir.join("mnt")), Default::default())
+                    .await?;
+                let peer_id = *mounter.state.borrow().get_peers().keys().next().unwrap();
+                let started = Instant::now();
+                // The read waiting off the debt shows up in the meantime
+                let in_progress = async {
+                    loop {
+                        Timer::after(Duration::from_millis(10)).await;
+                        let transfers = owner.transfers.dtos();
+                        if !transfers.is_empty() {
+                            return transfers;
+                        }
+                    }
+                };
+                let (data, transfers) =
+                    zip(read_to_end(&mounter, peer_id, "A", "big"), in_progress).await;
+                // A second worth of bytes is sent right away, the rest waits
+                assert!(started.elapsed() >= Duration::from_millis(400));
+                assert_eq!(data?.len(), 1500);
+                assert_eq!(transfers.len(), 1);
+                assert_eq!(transfers[0].path, "big");
+                assert!(owner.transfers.dtos().is_empty());
+                anyhow::Ok(())
+            };
+            read.or(accept).await
+        };
+        let run = owner
+            .ex
+            .run(mounter.ex.run(result.timeout(Duration::from_secs(5))));
+        smol::block_on(run).expect("timed out").unwrap();
+    }
+
+    #[test]
+    fn large_reads_are_cut_to_the_fair_bandwidth() {
+        let dir = TestDir::new("low-rate");
+        fs::create_dir_all(dir.join("shared")).unwrap();
+        fs::create_dir_all(dir.join("mnt")).unwrap();
+        fs::create_dir_all(dir.join("rdir")).unwrap();
+        let contents: Vec<u8> = (0..net::MAX_READ_LEN).map(|i| i as u8).collect();
+        fs::write(dir.join("shared/big"), &contents).unwrap();
+        let owner = test_server_with(Args::parse_from([
+            "rdir",
+            "--fair-bandwidth",
+            "262144",
+            "ls",
+        ]));
+        let share = Share::new("A".parse().unwrap(), dir.join("shared"));
+        owner.state.borrow_mut().add_share(share).unwrap();
+        let tmp_dir = dir.to_string_lossy().to_string();
+        let mounter = test_server_with(Args::parse_from(["rdir", "--tmpdir", &tmp_dir, "ls"]));
+
+        let result = async {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = listener.local_addr()?;
+            let accept = async {
+                loop {
+                    let (stream, _) = listener.accept().await?;
+                    owner.ex.spawn(owner.clone().handle_peer(stream)).detach();
+                }
+            };
+            let read = async {
+                let name = format!("{addr}/A").parse()?;
+                mounter
+                    .connect_to_remote_share(name, Some(dir.join("mnt")), Default::default())
+                    .await?;
+                let peer_id = *mounter.state.borrow().get_peers().keys().next().unwrap();
+                let message = PeerMessage::ReadFile {
+                    share: "A".parse()?,
+                    rel_path: "big".to_string(),
+                    offset: 0,
+                    len: net::MAX_READ_LEN,
+                };
+                // Half a second worth of bytes
+                assert!(matches!(
+                    mounter.request_peer(peer_id, &message).await?,
+                    PeerResponse::FileData(data) if data.len() == 131072
+                ));
+                // Every read waits less than the mounter, so none time out
+                assert_eq!(read_to_end(&mounter, peer_id, "A", "big").await?, contents);
+                anyhow::Ok(())
+            };
+            read.or(accept).await
+        };
+        let run = owner
+            .ex
+            .run(mounter.ex.run(result.timeout(Duration::from_secs(10))));
+        smol::block_on(run).expect("timed out").unwrap();
+    }
+
+    #[test]
+    fn slow_reads_log_their_progress() {
+        let dir = TestDir::new("transfer-log");
+        fs::create_dir_all(dir.join("shared")).unwrap();
+        fs::create_dir_all(dir.join("mnt")).unwrap();
+        fs::create_dir_all(dir.join("rdir")).unwrap();
+        fs::write(dir.join("shared/big"), vec![0; 2500]).unwrap();
+        let owner = test_server_with(Args::parse_from([
+            "rdir",
+            "--fair-bandwidth",
+            "1000",
+            "--transfer-log-after",
+            "0",
+            "--transfer-log-interval",
+            "1",
+            "ls",
+        ]));
+        let share = Share::new("A".parse().unwrap(), dir.join("shared"));
+        owner.state.borrow_mut().add_share(share).unwrap();
+        let tmp_dir = dir.to_string_lossy().to_string();
+        let mounter = test_server_with(Args::parse_from(["rdir", "--tmpdir", &tmp_dir, "ls"]));
+        owner.ex.spawn(owner.clone().log_transfers()).detach();
+        let log = Captured::default();
+        let (subscriber, _handle) = {
+            let log = log.clone();
+            logs::subscriber(LevelFilter::INFO, move || log.clone(), std::io::sink)
+        };
+
+        let result = async {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = listener.local_addr()?;
+            let accept = async {
+                loop {
+                    let (stream, _) = listener.accept().await?;
+                    owner.ex.spawn(owner.clone().handle_peer(stream)).detach();
+                }
+            };
+            let read = async {
+                let name = format!("{addr}/A").parse()?;
+                mounter
+                    .connect_to_remote_share(name, Some(dir.join("mnt")), Default::default())
+                    .await?;
+                let peer_id = *mounter.state.borrow().get_peers().keys().next().unwrap();
+                // Throttled to a second and a half in reads of 500 bytes, the
+                // heartbeat fires once in between
+                read_to_end(&mounter, peer_id, "A", "big").await?;
+                anyhow::Ok(())
+            };
+            read.or(accept).await
+        };
+        let run = owner
+            .ex
+            .run(mounter.ex.run(result.timeout(Duration::from_secs(5))));
+        tracing::subscriber::with_default(subscriber, || smol::block_on(run))
+            .expect("timed out")
+            .unwrap();
+        let log = log.contents();
+        assert!(log.contains("Transfer of A/big with 127.0.0.1:"), "{log}");
+        assert!(log.contains("/500 bytes"), "{log}");
+    }
+
+    #[test]
+    fn namespaced_shares_are_mounted_by_the_prefixed_name() {
+        let dir = TestDir::new("namespace");
+        fs::create_dir_all(dir.join("shared")).unwrap();
+        fs::create_dir_all(dir.join("mnt")).unwrap();
+        fs::create_dir_all(dir.join("rdir")).unwrap();
+        fs::write(dir.join("shared/notes.txt"), "hello").unwrap();
+        let owner = test_server_with(Args::parse_from(["rdir", "--namespace", "team-a", "ls"]));
+        let share = Share::new("A".parse().unwrap(), dir.join("shared"));
+        owner.state.borrow_mut().add_share(share).unwrap();
+        let tmp_dir = dir.to_string_lossy().to_string();
+        let mounter = test_server_with(Args::parse_from(["rdir", "--tmpdir", &tmp_dir, "ls"]));
+
+        let advertised: CommonShareName = "team-a:A".parse().unwrap();
+        let PeerInitStatusResponse::Ok(status) = owner.public_status() else {
+            panic!("expected the status");
+        };
+        assert_eq!(status.shares, std::slice::from_ref(&advertised));
+
+        let result = async {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = listener.local_addr()?;
+            let accept = async {
+                loop {
+                    let (stream, _) = listener.accept().await?;
+                    owner.ex.spawn(owner.clone().handle_peer(stream)).detach();
+                }
+            };
+            let mount = async {
+                let mount = async |name: &str| {
+                    let name = format!("{addr}/{name}").parse().unwrap();
+                    let path = Some(dir.join("mnt"));
+                    mounter
+                        .connect_to_remote_share(name, path, Default::default())
+                        .await
+                };
+                assert!(mount("A").await.is_err());
+                mount("team-a:A").await?;
+                // The mounter refers to it without the namespace
+                let message = ConnectMessage::Browse {
+                    name: "A".parse().unwrap(),
+                    path: None,
+                };
+                let ServerResponse::DirEntries(entries) =
+                    request(&mounter, ClientMessage::Connect(message)).await
+                else {
+                    panic!("expected the entries of the root");
+                };
+                assert_eq!(entries[0].name, "notes.txt");
+                anyhow::Ok(())
+            };
+            mount.or(accept).await
+        };
+        let run = owner
+            .ex
+            .run(mounter.ex.run(result.timeout(Duration::from_secs(5))));
+        smol::block_on(run).expect("timed out").unwrap();
+    }
+
+    #[test]
+    fn banner_reaches_the_mounter_cut_to_size() {
+        assert!(Args::try_parse_from(["rdir", "share", "share", "/", "--banner", "hi"]).is_ok());
+        let long = "x".repeat(MAX_BANNER_LEN + 1);
+        assert!(Args::try_parse_from(["rdir", "share", "share", "/", "--banner", &long]).is_err());
+
+        let dir = TestDir::new("banner");
+        fs::create_dir_all(dir.join("rdir")).unwrap();
+        let owner = test_server();
+        for (name, banner) in [("A", "Ask ana for access".to_string()), ("B", long)] {
+            fs::create_dir_all(dir.join(name)).unwrap();
+            let mut share = Share::new(name.parse().unwrap(), dir.join(name));
+            share.set_options(ShareOptions {
+                banner: Some(banner),
+                ..Default::default()
+            });
+            owner.state.borrow_mut().add_share(share).unwrap();
+        }
+        let tmp_dir = dir.to_string_lossy().to_string();
+        let args = || Args::parse_from(["rdir", "--tmpdir", &tmp_dir, "ls"]);
+
+        let result = async {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = listener.local_addr()?;
+            let accept = async {
+                loop {
+                    let (stream, _) = listener.accept().await?;
+                    owner.ex.spawn(owner.clone().handle_peer(stream)).detach();
+                }
+            };
+            let mount = async {
+                // A mounter per share, a peer mounts one share per connection
+                let mount = async |name: &str| {
+                    let mounter = test_server_with(args());
+                    let share = format!("{addr}/{name}").parse()?;
+                    let path = dir.join(name);
+                    let banner = mounter
+                        .connect_to_remote_share(share, Some(path), Default::default())
+                        .await?;
+                    anyhow::Ok(banner)
+                };
+                assert_eq!(mount("A").await?.as_deref(), Some("Ask ana for access"));
+                assert_eq!(mount("B").await?.unwrap().len(), MAX_BANNER_LEN);
+                anyhow::Ok(())
+            };
+            mount.or(accept).await
+        };
+        let run = owner.ex.run(result.timeout(Duration::from_secs(5)));
+        smol::block_on(run).expect("timed out").unwrap();
+    }
+
+    #[test]
+    fn unassigned_tcp_address_is_explained() {
+        // TEST-NET-1, never assigned to a local interface
+        let err = bind_tcp("192.0.2.1:0".parse().unwrap()).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Address 192.0.2.1 is not assigned to this host"),
+            "{err}"
+        );
+
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let SocketAddr::V4(addr) = taken.local_addr().unwrap() else {
+            unreachable!()
+        };
+        let err = bind_tcp(addr).unwrap_err();
+        assert!(err.to_string().contains("already in use"), "{err}");
+    }
+
+    #[test]
+    fn share_exists() {
+        let server = test_server();
+        let share = Share::new("A".parse().unwrap(), "/".into());
+        server.state.borrow_mut().add_share(share).unwrap();
+
+        let exists = |name: &str| {
+            let message = ClientMessage::ShareExists {
+                name: name.parse().unwrap(),
+            };
+            match smol::block_on(request(&server, message)) {
+                ServerResponse::Bool(val) => val,
+                resp => panic!("Unexpected response {resp:?}"),
+            }
+        };
+        assert!(exists("A"));
+        assert!(!exists("B"));
+    }
+
+    #[test]
+    fn expired_share_is_removed() {
+        let args = Args::parse_from(["rdir", "--fair-bandwidth", "1000", "ls"]);
+        let server = test_server_with(args);
+        let name: CommonShareName = "A".parse().unwrap();
+        let share = Share::new(name.clone(), "/".into());
+        let removal_signal = share.removal_signal();
+        server.state.borrow_mut().add_share(share).unwrap();
+        let (peer_id, shutdown_rx) =
+            match server.join_share("1.1.1.1:1".parse().unwrap(), name.clone()) {
+                Ok(JoinedShare {
+                    peer_id,
+                    shutdown_rx,
+                    ..
+                }) => (peer_id, shutdown_rx),
+                joined => panic!("unexpected join: {joined:?}"),
+            };
+
+        let expiry =
+            server
+                .clone()
+                .expire_share(name.clone(), Duration::from_millis(20), removal_signal);
+        smol::block_on(expiry.timeout(Duration::from_secs(1))).unwrap();
+
+        // The peer is dropped like on any other disconnect
+        assert!(shutdown_rx.try_recv().is_ok());
+        let bandwidth = server.bandwidth.as_ref().unwrap();
+        assert_eq!(bandwidth.rate(peer_id), None);
+        let state = server.state.borrow();
+        assert!(state.get_shares().is_empty());
+        assert!(state.get_peers().is_empty());
+        assert!(state.get_peers_by_scoket().is_empty());
+    }
+
+    #[test]
+    fn share_removed_before_expiry() {
+        let server = test_server();
+        let name: CommonShareName = "A".parse().unwrap();
+        let share = Share::new(name.clone(), "/".into());
+        let removal_signal = share.removal_signal();
+        server.state.borrow_mut().add_share(share).unwrap();
+        let expiry =
+            server
+                .clone()
+                .expire_share(name.clone(), Duration::from_secs(60), removal_signal);
+        server
+            .state
+            .borrow_mut()
+            .remove_share(&name, &server.shutdown_tx)
+            .unwrap();
+
+        smol::block_on(expiry.timeout(Duration::from_secs(1))).unwrap();
+    }
+
+    #[test]
+    fn peer_joins_two_shares_over_one_connection() {
+        let dir = TestDir::new("two-shares");
+        let server = test_server();
+        for name in ["A", "B"] {
+            fs::create_dir_all(dir.join(name)).unwrap();
+            fs::write(dir.join(name).join("notes.txt"), name).unwrap();
+            let share = Share::new(name.parse().unwrap(), dir.join(name));
+            server.state.borrow_mut().add_share(share).unwrap();
+        }
+
+        let result = async {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let SocketAddr::V4(addr) = listener.local_addr()? else {
+                unreachable!()
+            };
+            let accept = async {
+                loop {
+                    let (stream, _) = listener.accept().await?;
+                    server.ex.spawn(server.clone().handle_peer(stream)).detach();
+                }
+            };
+            let peer = async {
+                let buffers = BufferBudget::new(None);
+                let mut conn = PeerConnection::connect(
+                    addr,
+                    server.cipher,
+                    &buffers,
+                    net::FRAMED_TCP_CONNECT_TIMEOUT,
+                )
+                .await?;
+                let join = PeerInitMessage::ConnectToShare { name: "A".parse()? };
+                let joined: PeerInitConnectToShareResponse =
+                    decode(&conn.request(&encode(&join)).await?)?;
+                assert!(joined.is_ok());
+                // Joining again is fine, it might have been unmounted on this side
+                for _ in 0..2 {
+                    let join = PeerMessage::ConnectToShare {
+                        share: "B".parse()?,
+                    };
+                    let joined: PeerInitConnectToShareResponse =
+                        decode(&conn.request(&encode(&join)).await?)?;
+                    assert!(joined.is_ok());
+                }
+                let join = PeerMessage::ConnectToShare {
+                    share: "C".parse()?,
+                };
+                let joined: PeerInitConnectToShareResponse =
+                    decode(&conn.request(&encode(&join)).await?)?;
+                assert!(matches!(
+                    joined,
+                    PeerInitConnectToShareResponse::Err(err) if err.is_share_doesnt_exist()
+                ));
+
+                let read = PeerMessage::ReadFile {
+                    share: "B".parse()?,
+                    rel_path: "notes.txt".to_string(),
+                    offset: 0,
+                    len: 1,
+                };
+                let read: PeerResponse = decode(&conn.request(&encode(&read)).await?)?;
+                assert!(matches!(read, PeerResponse::FileData(data) if data == b"B"));
+                {
+                    let state = server.state.borrow();
+                    assert_eq!(state.get_peers().len(), 1);
+                    let peer = state.get_peers().values().next().unwrap();
+                    let joined: Vec<_> = peer
+                        .used_shares()
+                        .iter()
+                        .map(|name| name.to_string())
+                        .collect();
+                    assert_eq!(joined, ["A", "B"]);
+                }
+
+                // Both shares are left once the connection closes
+                conn.close().await;
+                while !server.state.borrow().get_peers().is_empty() {
+                    Timer::after(Duration::from_millis(10)).await;
+                }
+                assert_eq!(server.state.borrow().idle_shares().len(), 2);
+                anyhow::Ok(())
+            };
+            peer.or(accept).await
+        };
+        smol::block_on(server.ex.run(result.timeout(Duration::from_secs(5))))
+            .expect("timed out")
+            .unwrap();
+    }
+
+    #[test]
+    fn peer_only_serves_configured_shares() {
+        let dir = TestDir::new("peer-only");
+        let (shared, mount_path) = (dir.join("photos"), dir.join("mnt"));
+        fs::create_dir_all(&shared).unwrap();
+        fs::create_dir_all(&mount_path).unwrap();
+        let config = dir.join("shares.conf");
+        fs::write(&config, format!("photos={}\n", shared.to_string_lossy())).unwrap();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+        let args = Args::parse_from([
+            "rdir",
+            "--peer-only",
+            "--shares-config",
+            &config.to_string_lossy(),
+            "--tmpdir",
+            &dir.join("owner").to_string_lossy(),
+            "--tcp-socket",
+            &addr.to_string(),
+        ]);
+        // `main` only binds the IPC socket for commands that need a server
+        assert!(!args.expects_active_server());
+        let Command::PeerOnly { shares_config } = &args.command else {
+            panic!("--peer-only must select the peer only mode");
+        };
+        let shares = shares_config::load(shares_config).unwrap();
+        let owner = Server::in_memory(args);
+        fs::create_dir_all(&owner.args.tmp_dir).unwrap();
+
+        let shutdown_tx = owner.shutdown_tx.clone();
+        let mounter_dir = dir.join("mounter");
+        let mounter = std::thread::spawn(move || {
+            let mounter = test_server_with(Args::parse_from([
+                "rdir",
+                "--tmpdir",
+                &mounter_dir.to_string_lossy(),
+                "ls",
+            ]));
+            fs::create_dir_all(&mounter.args.tmp_dir).unwrap();
+            let name: FullShareName = format!("{addr}/photos").parse().unwrap();
+            // The owner could still be binding its listener
+            let mut result = Err(String::new());
+            for _ in 0..50 {
+                let mount = mounter.connect_to_remote_share(
+                    name.clone(),
+                    Some(mount_path.clone()),
+                    Default::default(),
+                );
+                result = smol::block_on(mounter.ex.run(mount)).map_err(|err| err.to_string());
+                if result.is_ok() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            let _ = shutdown_tx.try_broadcast(ShutdownReason::Kill);
+            result
+        });
+        owner.serve(None, shares, Vec::new()).unwrap();
+        mounter.join().unwrap().unwrap();
+        assert!(!owner.args.tmp_dir.join(SOCKET_NAME).exists());
+    }
+
+    #[test]
+    fn reconnect_unknown_or_connected_mount() {
+        let server = test_server();
+        let name: FullShareName = "1.1.1.1:29284/R".parse().unwrap();
+        let reconnect = || {
+            let message = ClientMessage::ReconnectMount { name: name.clone() };
+            smol::block_on(request(&server, message))
+        };
+        assert!(matches!(
+            reconnect(),
+            ServerResponse::Err(ServerErrorDto::NoSuchRemoteShare(_))
+        ));
+
+        let (shutdown_tx, _) = bounded(1);
+        let address = "1.1.1.1:29284".parse().unwrap();
+        let peer = Peer::new(address, shutdown_tx, unbounded().0);
+        let _ = server
+            .state
+            .borrow_mut()
+            .join_remote_share_new(peer, name.clone(), "/mnt/remote".into(), Default::default())
+            .unwrap();
+        assert!(matches!(reconnect(), ServerResponse::Ok));
+    }
+
+    #[test]
+    fn reconnect_of_an_automount_that_mounted_meanwhile() {
+        let server = test_server();
+        let name: FullShareName = "1.1.1.1:29284/R".parse().unwrap();
+        let (reconnect_tx, reconnect_rx) = bounded(1);
+        server
+            .reconnects
+            .borrow_mut()
+            .insert(name.clone(), reconnect_tx);
+
+        // Its own attempt mounted the share, the reply is dropped unanswered
+        let automount = async {
+            let _reply = reconnect_rx.recv().await.unwrap();
+            let (shutdown_tx, _) = bounded(1);
+            let address = "1.1.1.1:29284".parse().unwrap();
+            let peer = Peer::new(address, shutdown_tx, unbounded().0);
+            let _ = server
+                .state
+                .borrow_mut()
+                .join_remote_share_new(peer, name.clone(), "/mnt/remote".into(), Default::default())
+                .unwrap();
+        };
+        let message = ClientMessage::ReconnectMount { name: name.clone() };
+        let (response, ()) = smol::block_on(zip(request(&server, message), automount));
+        assert!(matches!(response, ServerResponse::Ok));
+    }
+
+    #[test]
+    fn unmount_resolves_the_name() {
+        let server = test_server();
+        let mount = |name: &str| {
+            let name: FullShareName = name.parse().unwrap();
+            let (shutdown_tx, shutdown_rx) = bounded(1);
+            let peer = Peer::new((&name.addr).into(), shutdown_tx, unbounded().0);
+            let _ = server
+                .state
+                .borrow_mut()
+                .join_remote_share_new(peer, name, "/mnt/remote".into(), Default::default())
+                .unwrap();
+            shutdown_rx
+        };
+        let _first = mount("1.1.1.1:29284/R");
+        let second = mount("2.2.2.2:29284/R");
+        let other = mount("3.3.3.3:29284/S");
+        let unmount = |name: &str| {
+            let name = name.parse().unwrap();
+            let message = ClientMessage::Connect(ConnectMessage::Unmount { name });
+            smol::block_on(request(&server, message))
+        };
+
+        assert!(matches!(
+            unmount("R"),
+            ServerResponse::Err(ServerErrorDto::AmbiguousShareName(_))
+        ));
+        assert!(matches!(unmount("S"), ServerResponse::Ok));
+        assert!(other.try_recv().is_ok(), "the connection has to be closed");
+        assert!(matches!(
+            unmount("S"),
+            ServerResponse::Err(ServerErrorDto::NoSuchRemoteShare(_))
+        ));
+        assert!(matches!(unmount("2.2.2.2:29284/R"), ServerResponse::Ok));
+        assert!(second.try_recv().is_ok());
+        assert!(matches!(unmount("R"), ServerResponse::Ok));
+        let state = server.state.borrow();
+        assert!(state.get_remote_shares().is_empty());
+        assert!(state.get_peers().is_empty());
+        assert!(state.get_peers_by_scoket().is_empty());
+    }
+
+    #[test]
+    fn disconnect_drops_every_peer_of_the_host() {
+        let server = test_server();
+        let share = Share::new("A".parse().unwrap(), "/srv/a".into());
+        server.state.borrow_mut().add_share(share).unwrap();
+        let join = |address: &str| {
+            let joined = server.join_share(address.parse().unwrap(), "A".parse().unwrap());
+            let Ok(JoinedShare {
+                peer_id,
+                shutdown_rx,
+                ..
+            }) = joined
+            else {
+                panic!("every address is a new peer");
+            };
+            (peer_id, shutdown_rx)
+        };
+        let (_, first) = join("10.0.0.1:1000");
+        let (_, second) = join("10.0.0.1:2000");
+        let (other, _other_rx) = join("10.0.0.2:1000");
+
+        let message = ClientMessage::DisconnectPeer {
+            addr: "10.0.0.1".parse().unwrap(),
+        };
+        let response = smol::block_on(request(&server, message));
+        assert!(matches!(
+            response,
+            ServerResponse::Disconnected { peers: 2 }
+        ));
+        assert!(first.try_recv().is_ok());
+        assert!(second.try_recv().is_ok());
+        let state = server.state.borrow();
+        assert_eq!(state.get_peers().keys().collect::<Vec<_>>(), [&other]);
+        let participants = &state.get_shares()[&"A".parse().unwrap()].participants;
+        assert_eq!(participants.iter().collect::<Vec<_>>(), [&other]);
+    }
+
+    impl ConfigDto {
+        fn get(&self, name: &str) -> Option<&str> {
+            self.settings
+                .iter()
+                .find(|(setting, _)| setting == name)
+                .map(|(_, value)| value.as_str())
+        }
+    }
+
+    #[test]
+    fn config_reflects_flags() {
+        let args = Args::parse_from(["rdir", "--request-timeout", "5", "config"]);
+        let server = test_server_with(args);
+        let ServerResponse::Config(config) =
+            smol::block_on(request(&server, ClientMessage::Config))
+        else {
+            panic!("expected the config");
+        };
+        assert_eq!(config.get("request-timeout"), Some("5s"));
+        assert_eq!(config.get("stream-idle-timeout"), Some("30s"));
+        let log_level = logs::current_level(&server.log_level);
+        assert_eq!(config, ConfigDto::new(&server.args, log_level));
+    }
+
+    #[test]
+    fn protected_mount_paths_are_refused() {
+        let args = Args::parse_from(["rdir", "--tmpdir", "/var/tmp", "ls"]);
+        let server = test_server_with(args);
+        let share = Share::new("A".parse().unwrap(), "/srv/photos".into());
+        server.state.borrow_mut().add_share(share).unwrap();
+        let location = |path: &str| {
+            server
+                .check_mount_path(Path::new(path))
+                .map_err(|err| err.location)
+        };
+
+        assert_eq!(
+            location("/var/tmp/rdir/cache/x"),
+            Err(ProtectedLocation::DownloadCache)
+        );
+        assert_eq!(location("/var/tmp/rdir"), Err(ProtectedLocation::TmpDir));
+        assert_eq!(
+            location("/srv/photos/remote"),
+            Err(ProtectedLocation::Share("A".parse().unwrap()))
+        );
+        assert_eq!(location("/srv/photos"), location("/srv/photos/remote"));
+        assert_eq!(location("/srv/photos2"), Ok(()));
+        assert_eq!(location("/var/tmp/other"), Ok(()));
+    }
+
+    #[test]
+    fn joining_runs_the_connect_hook() {
+        let server = test_server();
+        let dir = TestDir::new("hook");
+        let marker = dir.join("marker");
+        let mut share = Share::new("A".parse().unwrap(), "/".into());
+        share.options.on_connect = Some(format!("echo \"$1 $2\" > {}", marker.display()));
+        server.state.borrow_mut().add_share(share).unwrap();
+
+        let address = "1.1.1.1:1".parse().unwrap();
+        server.join_share(address, "A".parse().unwrap()).unwrap();
+        // The hook is detached, give it a moment
+        let start = Instant::now();
+        let content = loop {
+            let content = fs::read_to_string(&marker).unwrap_or_default();
+            if content.ends_with('\n') || start.elapsed() > Duration::from_secs(5) {
+                break content;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(content, "A 1.1.1.1:1\n");
+    }
+
+    #[test]
+    fn fair_bandwidth_is_split_between_peers() {
+        let args = Args::parse_from(["rdir", "--fair-bandwidth", "1000", "ls"]);
+        let server = test_server_with(args);
+        let share = Share::new("A".parse().unwrap(), "/".into());
+        server.state.borrow_mut().add_share(share).unwrap();
+        let join = |address: &str| match server
+            .join_share(address.parse().unwrap(), "A".parse().unwrap())
+        {
+            Ok(JoinedShare { peer_id, .. }) => peer_id,
+            joined => panic!("unexpected join: {joined:?}"),
+        };
+        let bandwidth = server.bandwidth.as_ref().unwrap();
+
+        let first = join("1.1.1.1:1");
+        assert_eq!(bandwidth.rate(first), Some(1000));
+        let second = join("2.2.2.2:1");
+        assert_eq!(bandwidth.rate(first), Some(500));
+        assert_eq!(bandwidth.rate(second), Some(500));
+
+        assert!(server.remove_peer(second));
+        assert_eq!(bandwidth.rate(second), None);
+        assert_eq!(bandwidth.rate(first), Some(1000));
+    }
+
+    #[test]
+    fn removed_share_disconnects_its_peers() {
+        let args = Args::parse_from(["rdir", "--fair-bandwidth", "1000", "ls"]);
+        let server = test_server_with(args);
+        let dir = TestDir::new("remove-hook");
+        let marker = dir.join("marker");
+        for name in ["A", "B"] {
+            let mut share = Share::new(name.parse().unwrap(), "/".into());
+            share.options.on_disconnect = Some(format!("echo \"$2\" >> {}", marker.display()));
+            server.state.borrow_mut().add_share(share).unwrap();
+        }
+        let join = |address: &str| {
+            server
+                .join_share(address.parse().unwrap(), "A".parse().unwrap())
+                .unwrap()
+        };
+        let only_a = join("1.1.1.1:1");
+        let both = join("2.2.2.2:1");
+        let _ = server.join_another_share(both.peer_id, &"B".parse().unwrap());
+        let bandwidth = server.bandwidth.as_ref().unwrap();
+        assert_eq!(bandwidth.rate(only_a.peer_id), Some(500));
+
+        let message = ClientMessage::Share(ShareMessage::Remove {
+            name: "A".parse().unwrap(),
+        });
+        assert!(matches!(
+            smol::block_on(request(&server, message)),
+            ServerResponse::Ok
+        ));
+        assert!(only_a.shutdown_rx.try_recv().is_ok());
+        assert!(both.shutdown_rx.try_recv().is_err());
+        assert_eq!(bandwidth.rate(only_a.peer_id), None);
+        assert_eq!(bandwidth.rate(both.peer_id), Some(1000));
+        let state = server.state.borrow();
+        assert_eq!(
+            state.get_peers().keys().collect::<Vec<_>>(),
+            [&both.peer_id]
+        );
+        assert!(
+            state
+                .get_peers_by_scoket()
+                .contains_key(&"2.2.2.2:1".parse().unwrap())
+        );
+
+        // Both peers left the share, the hooks are detached
+        let start = Instant::now();
+        let content = loop {
+            let content = fs::read_to_string(&marker).unwrap_or_default();
+            if content.lines().count() == 2 || start.elapsed() > Duration::from_secs(5) {
+                break content;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        let mut left: Vec<_> = content.lines().collect();
+        left.sort();
+        assert_eq!(left, ["1.1.1.1:1", "2.2.2.2:1"]);
+    }
+
+    #[test]
+    fn debug_dump_needs_allow_debug() {
+        let response = smol::block_on(request(&test_server(), ClientMessage::DebugDump));
+        assert!(matches!(
+            response,
+            ServerResponse::Err(ServerErrorDto::DebugDisabled(_))
+        ));
+
+        let args = Args::parse_from(["rdir", "--allow-debug", "debug", "dump"]);
+        let server = test_server_with(args);
+        let share = Share::new("A".parse().unwrap(), "/".into());
+        server.state.borrow_mut().add_share(share).unwrap();
+        server
+            .join_share("1.1.1.1:1".parse().unwrap(), "A".parse().unwrap())
+            .unwrap();
+        let ServerResponse::DebugDump(dump) = smol::block_on(request(
+            &server,
+            ClientMessage::try_from(&server.args).unwrap(),
+        )) else {
+            panic!("expected a dump");
+        };
+        assert!(dump.contains("next_peer_id: 1"), "{dump}");
+        assert!(dump.contains("used_shares"), "{dump}");
+        assert!(dump.ends_with("io buffers in use: 0 bytes"), "{dump}");
+    }
+
+    #[test]
+    fn public_status_respects_exposure() {
+        let status = |exposure: &str| {
+            let args = Args::parse_from(["rdir", "--expose-status", exposure, "ls"]);
+            let server = test_server_with(args);
+            let share = Share::new("A".parse().unwrap(), "/".into());
+            server.state.borrow_mut().add_share(share).unwrap();
+            let address = "1.1.1.1:1".parse().unwrap();
+            server.join_share(address, "A".parse().unwrap()).unwrap();
+            server.public_status()
+        };
+
+        let shares = vec!["A".parse().unwrap()];
+        match status("full") {
+            PeerInitStatusResponse::Ok(status) => assert_eq!(
+                status,
+                PeerStatusDto {
+                    shares: shares.clone(),
+                    peers: Some(1),
+                }
+            ),
+            PeerInitStatusResponse::Refused => panic!("full status was refused"),
+        }
+        match status("shares") {
+            PeerInitStatusResponse::Ok(status) => assert_eq!(
+                status,
+                PeerStatusDto {
+                    shares,
+                    peers: None,
+                }
+            ),
+            PeerInitStatusResponse::Refused => panic!("share names were refused"),
+        }
+        assert!(status("none").is_refused());
+    }
+
+    #[test]
+    fn peer_status_is_queried_over_tcp() {
+        let asker = test_server();
+        let status = |exposure: &str| {
+            let args = Args::parse_from(["rdir", "--expose-status", exposure, "ls"]);
+            let owner = test_server_with(args);
+            let share = Share::new("A".parse().unwrap(), "/".into());
+            owner.state.borrow_mut().add_share(share).unwrap();
+            let result = async {
+                let listener = TcpListener::bind("127.0.0.1:0").await?;
+                let addr = listener.local_addr()?;
+                let accept = async {
+                    loop {
+                        let (stream, _) = listener.accept().await?;
+                        owner.ex.spawn(owner.clone().handle_peer(stream)).detach();
+                    }
+                };
+                let ask = async {
+                    let status = ConnectMessage::Status {
+                        addr: addr.to_string().parse()?,
+                    };
+                    let response = request(&asker, ClientMessage::Connect(status)).await;
+                    anyhow::Ok((addr, response))
+                };
+                ask.or(accept).await
+            };
+            let run = owner
+                .ex
+                .run(asker.ex.run(result.timeout(Duration::from_secs(5))));
+            smol::block_on(run).expect("timed out").unwrap()
+        };
+
+        let (addr, response) = status("none");
+        let refused = format!("{addr} doesnt expose its status");
+        assert!(
+            matches!(&response, ServerResponse::Warning(warning) if *warning == refused),
+            "{response:?}"
+        );
+        let (_, response) = status("full");
+        let ServerResponse::PeerStatus(status) = response else {
+            panic!("expected the status, got {response:?}");
+        };
+        assert_eq!(
+            status,
+            PeerStatusDto {
+                shares: vec!["A".parse().unwrap()],
+                peers: Some(0),
+            }
+        );
+    }
+
+    #[test]
+    fn remote_dirs_are_listed_without_mounting() {
+        let dir = TestDir::new("ls-remote");
+        fs::create_dir_all(dir.join("photos")).unwrap();
+        fs::write(dir.join("notes.txt"), "hello").unwrap();
+        fs::write(dir.join("photos/cat.jpg"), [0; 10]).unwrap();
+        let owner = test_server();
+        let share = Share::new("A".parse().unwrap(), dir.to_path_buf());
+        owner.state.borrow_mut().add_share(share).unwrap();
+        let lister = test_server();
+
+        let result = async {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = listener.local_addr()?;
+            let accept = async {
+                loop {
+                    let (stream, _) = listener.accept().await?;
+                    owner.ex.spawn(owner.clone().handle_peer(stream)).detach();
+                }
+            };
+            let list = async {
+                let ls = async |dir: String| {
+                    let message = ConnectMessage::LsRemote {
+                        dir: dir.parse().unwrap(),
+                    };
+                    request(&lister, ClientMessage::Connect(message)).await
+                };
+
+                let ServerResponse::DirEntries(entries) = ls(format!("{addr}/A")).await else {
+                    panic!("expected the entries of the root");
+                };
+                let names: Vec<_> = entries
+                    .iter()
+                    .map(|entry| (entry.name.as_str(), entry.kind))
+                    .collect();
+                assert_eq!(
+                    names,
+                    [
+                        ("notes.txt", DirEntryKind::File),
+                        ("photos", DirEntryKind::Dir)
+                    ]
+                );
+                assert_eq!(entries[0].size, 5);
+                let ServerResponse::DirEntries(entries) = ls(format!("{addr}/A/photos")).await
+                else {
+                    panic!("expected the entries of the subdir");
+                };
+                assert_eq!(entries.len(), 1);
+                assert_eq!((entries[0].name.as_str(), entries[0].size), ("cat.jpg", 10));
+                assert!(
+                    entries[0]
+                        .to_json()
+                        .starts_with(r#"{"name":"cat.jpg","kind":"file","size":10,"#)
+                );
+                for missing in [format!("{addr}/B"), format!("{addr}/A/notes.txt")] {
+                    assert!(matches!(
+                        ls(missing).await,
+                        ServerResponse::Err(ServerErrorDto::ListRemoteDir(_))
+                    ));
+                }
+
+                // Nothing got mounted and the owner kept no peer
+                assert!(lister.state.borrow().get_remote_shares().is_empty());
+                assert!(owner.state.borrow().get_peers().is_empty());
+                anyhow::Ok(())
+            };
+            list.or(accept).await
+        };
+        let run = owner
+            .ex
+            .run(lister.ex.run(result.timeout(Duration::from_secs(5))));
+        smol::block_on(run).expect("timed out").unwrap();
+    }
+
+    #[test]
+    fn racing_shutdowns_arent_lost() {
+        let server = test_server();
+        let mut shutdown_rx = server.shutdown_rx.activate_cloned();
+        smol::block_on(request(&server, ClientMessage::Kill));
+        server
+            .state
+            .borrow()
+            .should_server_close(&server.shutdown_tx);
+        for _ in 0..10 {
+            assert!(
+                server
+                    .shutdown_tx
+                    .try_broadcast(ShutdownReason::Idle)
+                    .is_ok(),
+                "a pending shutdown must not make later ones fail"
+            );
+        }
+        let triggered = shutdown_triggered(&mut shutdown_rx).timeout(Duration::from_secs(1));
+        assert_eq!(smol::block_on(triggered), Some(ShutdownReason::Idle));
+    }
+
+    #[test]
+    fn kill_reaches_waiting_clients() {
+        let server = test_server();
+        let name: FullShareName = "127.0.0.1/photos".parse().unwrap();
+        // A reconnect nobody answers keeps the client waiting
+        let (reconnect_tx, _reconnect_rx) = bounded(1);
+        server
+            .reconnects
+            .borrow_mut()
+            .insert(name.clone(), reconnect_tx);
+
+        let waiting = request(&server, ClientMessage::ReconnectMount { name });
+        let kill = async {
+            Timer::after(Duration::from_millis(10)).await;
+            request(&server, ClientMessage::Kill).await
+        };
+        let (waiting, kill) = smol::block_on(zip(waiting, kill));
+        assert!(kill.is_ok());
+        assert!(matches!(
+            waiting,
+            ServerResponse::ShuttingDown {
+                reason: ShutdownReason::Kill
+            }
+        ));
+    }
+
+    #[test]
+    fn accept_loop_survives_transient_errors() {
+        let accepted = RefCell::new(Vec::new());
+        let incoming = smol::stream::iter([
+            Ok(1),
+            Err(io::ErrorKind::ConnectionAborted.into()),
+            Err(io::Error::from_raw_os_error(libc::EMFILE)),
+            Ok(2),
+        ]);
+        let result = smol::block_on(accept_loop(incoming, |n| accepted.borrow_mut().push(n)));
+        assert!(result.is_ok());
+        assert_eq!(*accepted.borrow(), [1, 2]);
+
+        accepted.borrow_mut().clear();
+        let incoming =
+            smol::stream::iter([Ok(1), Err(io::Error::from_raw_os_error(libc::EBADF)), Ok(2)]);
+        let result = smol::block_on(accept_loop(incoming, |n| accepted.borrow_mut().push(n)));
+        assert!(result.is_err());
+        assert_eq!(*accepted.borrow(), [1]);
+    }
+
+    #[test]
+    fn saturated_server_tells_clients_to_retry() {
+        let server = test_server();
+        // Clients that never send keep their handlers pending until they hang up
+        let idle: Vec<_> = (0..MAX_PENDING_CLIENTS)
+            .map(|_| {
+                let (local, remote) = UnixStream::pair().unwrap();
+                server.spawn_client(local);
+                remote
+            })
+            .collect();
+        let (local, remote) = UnixStream::pair().unwrap();
+        server.spawn_client(local);
+
+        smol::block_on(server.ex.run(async {
+            let resp: ServerResponse =
+                decode(&FramedStream::new(remote).read().await.unwrap()).unwrap();
+            let ServerResponse::Err(ServerErrorDto::ServerBusy(err)) = resp else {
+                panic!("expected the busy response, got {resp:?}");
+            };
+            assert_eq!(err.retry_after_ms, 200);
+
+            drop(idle);
+            while server.pending_clients.get() > 0 {
+                smol::future::yield_now().await;
+            }
+        }));
+    }
+
+    #[test]
+    fn unknown_command_gets_an_error() {
+        let send = |envelope: ClientEnvelope| {
+            let server = test_server();
+            let (local, remote) = UnixStream::pair().unwrap();
+            let client = async {
+                let mut stream = FramedStream::new(remote);
+                stream.write(&encode(&envelope)).await.unwrap();
+                decode(&stream.read().await.unwrap()).unwrap()
+            };
+            smol::block_on(zip(server.clone().handle_client(local), client)).1
+        };
+
+        let response = send(ClientEnvelope {
+            version: PROTOCOL_VERSION,
+            kind: "share teleport".to_string(),
+            message: vec![u8::MAX; 4],
+        });
+        match response {
+            ServerResponse::Err(ServerErrorDto::UnknownCommand(err)) => {
+                assert_eq!(err.kind, "share teleport")
+            }
+            resp => panic!("unexpected response: {resp:?}"),
+        }
+
+        // Known commands of a client of another protocol version are refused too
+        let mut envelope = ClientEnvelope::from(&ClientMessage::Ping);
+        envelope.version = PROTOCOL_VERSION + 1;
+        assert!(matches!(
+            send(envelope),
+            ServerResponse::Err(ServerErrorDto::UnknownCommand(_))
+        ));
+    }
+
+    #[test]
+    fn refused_mount_is_reported_as_unreachable() {
+        let addr = smol::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let SocketAddr::V4(addr) = listener.local_addr().unwrap() else {
+                unreachable!()
+            };
+            addr
+        });
+        let err = smol::block_on(PeerConnection::connect(
+            addr,
+            net::detect_cipher(),
+            &BufferBudget::new(None),
+            net::FRAMED_TCP_CONNECT_TIMEOUT,
+        ))
+        .err()
+        .unwrap();
+        let response =
+            ServerResponse::from(ServerError::from(ConnectToRemoteShareError::from(err)));
+
+        let response: ServerResponse = decode(&encode(&response)).unwrap();
+        match response {
+            ServerResponse::Err(ServerErrorDto::ConnectToRemoteShare(
+                ConnectToRemoteShareErrorDto::Io { category, .. },
+            )) => assert_eq!(category, ConnectionErrorCategory::Unreachable),
+            resp => panic!("unexpected response: {resp:?}"),
+        }
+    }
+
+    #[test]
+    fn concurrent_clients_dont_conflict() {
+        let dir = TestDir::new("concurrent");
+        for i in 0..50 {
+            fs::create_dir_all(dir.join(format!("{i}"))).unwrap();
+            fs::write(dir.join(format!("{i}/file")), [0; 10]).unwrap();
+        }
+        let server = test_server();
+        let share = Share::new("slow".parse().unwrap(), dir.to_path_buf());
+        server.state.borrow_mut().add_share(share).unwrap();
+
+        let slow = request(
+            &server,
+            ClientMessage::Share(ShareMessage::Size {
+                name: "slow".parse().unwrap(),
+            }),
+        );
+        let fast = request(
+            &server,
+            ClientMessage::Share(ShareMessage::Share {
+                path: "/dev".to_string(),
+                name: Some("fast".parse().unwrap()),
+                options: Default::default(),
+                no_overlap: false,
+                force: false,
+            }),
+        );
+        let (slow, fast) = smol::block_on(server.ex.run(zip(slow, fast)));
+
+        assert!(matches!(slow, ServerResponse::ShareSize { bytes: 500, .. }));
+        assert!(fast.is_ok());
+        assert_eq!(server.state.borrow().get_shares().len(), 2);
     }
 }
@@ -1,207 +1,1022 @@
 use std::{
-    io::ErrorKind,
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{ErrorKind, Read, Seek, SeekFrom, Write},
     net::{SocketAddr, SocketAddrV4},
+    num::{NonZeroU32, NonZeroU64, NonZeroUsize},
+    os::{fd::AsFd, linux::net::SocketAddrExt, unix::fs::OpenOptionsExt},
+    path::{Path, PathBuf},
     pin::Pin,
     rc::Rc,
-    sync::LazyLock,
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicUsize, Ordering},
+    },
     task::{Context, Poll, Waker},
-    time::Duration,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use bitcode::{Decode, Encode};
-use derive_more::{Constructor, Display, Error, From, IsVariant};
-use futures::{FutureExt, future::poll_fn, ready, select};
+use bitcode::{decode, encode};
+use derive_more::{Display, Error, From, IsVariant};
+use futures::{
+    FutureExt,
+    future::{Either, poll_fn},
+    ready,
+};
+use nix::{
+    sys::socket::{getsockopt, sockopt::PeerCredentials},
+    unistd::getuid,
+};
 use pin_project::pin_project;
 use smol::{
-    LocalExecutor,
-    channel::{Receiver, Recv, RecvError, Send, SendError, Sender, unbounded},
+    Timer,
+    channel::{Receiver, Sender, unbounded},
     future::FutureExt as _,
     io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
-    net::TcpStream,
-    pin,
+    lock::Semaphore,
+    net::{
+        TcpStream,
+        unix::{UnixListener, UnixStream},
+    },
+    unblock,
 };
 use smol_timeout::TimeoutExt;
-use snow::{Builder, HandshakeState, TransportState, params::NoiseParams};
-use tracing::{debug, error};
-
-use crate::{common::shares::CommonShareName, server::Server};
+use snow::{Builder, HandshakeState, Keypair, TransportState, params::NoiseParams};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    common::{
+        Cipher, ConnectionErrorCategory,
+        framing::FramedStream,
+        shares::{CommonShareName, RemotePeerAddr},
+        version::PROTOCOL_VERSION,
+    },
+    server::{
+        Server, content_hash,
+        dir_pages::{MAX_PAGE_LEN, PageCursor},
+        introduction::Introduction,
+        messages::{PeerMessage, PeerResponse, PeerResponseError},
+        pool::{self, PooledBuffer},
+        state::{PeerId, Share},
+    },
+};
 
+/// Most bytes one `ReadFile` returns, the peer asks again for the rest
+pub const MAX_READ_LEN: u32 = 1024 * 1024;
+/// Time an accepted peer has to finish the handshake
 pub const FRAMED_TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
 pub const FRAMED_TCP_TIMEOUT: Duration = Duration::from_secs(2);
+/// Times a repeatable request is sent again after a stream error before it fails
+const REQUEST_RETRIES: u32 = 2;
+/// Wait before the first resend of a request, the later ones wait longer
+const REQUEST_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// Index of a cipher is the suite byte the initiator sends before the handshake,
+/// new ciphers have to be appended
+const SUITES: [Cipher; 2] = [Cipher::AesGcm, Cipher::ChaChaPoly];
+
+/// Keypair peers know this daemon by, stays the same across restarts
+static STATIC_KEY: OnceLock<Keypair> = OnceLock::new();
+/// Keypair this daemon had before the last `rdir rotate-key`, see [`RetiredKey`]
+static RETIRED_KEY: OnceLock<RetiredKey> = OnceLock::new();
+
+/// Public half of the static keypair of a daemon
+pub type PublicKey = [u8; 32];
+
+/// Most files kept open between reads, the least recently used one is closed first
+const MAX_OPEN_FILES: usize = 64;
+/// Open files that werent read for this long get closed
+const OPEN_FILE_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Period the traffic limits of a peer apply to
+const TRAFFIC_WINDOW: Duration = Duration::from_secs(1);
+/// Longest a failing connection is kept up for the responses still in flight
+const PEER_DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Both sides learn the static key of the other, which a peer can be
+/// recognized by later on
+fn noise_params(cipher: Cipher) -> NoiseParams {
+    format!("Noise_XX_25519_{cipher}_BLAKE2b").parse().unwrap()
+}
+
+/// Loads the static keypair of this daemon from `path`, generating and saving
+/// one on the first run
+pub fn load_static_key(path: &Path) -> io::Result<()> {
+    let keypair = read_or_create_keypair(path)?;
+    info!(
+        "Peers know this daemon by the key {}",
+        content_hash::to_hex(&keypair.public)
+    );
+    let _ = STATIC_KEY.set(keypair);
+    Ok(())
+}
+
+fn read_or_create_keypair(path: &Path) -> io::Result<Keypair> {
+    match std::fs::read(path) {
+        Ok(bytes) if bytes.len() == 2 * size_of::<PublicKey>() => {
+            let (private, public) = bytes.split_at(size_of::<PublicKey>());
+            return Ok(Keypair {
+                private: private.to_vec(),
+                public: public.to_vec(),
+            });
+        }
+        Ok(_) => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Static key at {} is corrupt", path.to_string_lossy()),
+            ));
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    let keypair = generate_keypair();
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(&keypair.private)?;
+    file.write_all(&keypair.public)?;
+    file.sync_all()?;
+    Ok(keypair)
+}
+
+fn generate_keypair() -> Keypair {
+    Builder::new(noise_params(Cipher::ChaChaPoly))
+        .generate_keypair()
+        .unwrap()
+}
+
+/// Keypair of this daemon, a throwaway one unless [`load_static_key`] ran first
+fn static_key() -> &'static Keypair {
+    STATIC_KEY.get_or_init(generate_keypair)
+}
+
+/// Vouches for `key` as the key of `addr` until `expires`, signed with the
+/// static key of this daemon
+pub fn introduce(addr: RemotePeerAddr, key: PublicKey, expires: u64) -> Introduction {
+    let private = static_key().private.as_slice().try_into().unwrap();
+    Introduction::sign(private, addr, key, expires)
+}
+
+/// Keypair replaced by a rotation. Peers that pinned it still get it
+/// presented until `valid_until`, along with the key it was replaced by, which
+/// they pin instead
+struct RetiredKey {
+    keypair: Keypair,
+    valid_until: SystemTime,
+}
+
+impl RetiredKey {
+    fn is_valid(&self) -> bool {
+        SystemTime::now() < self.valid_until
+    }
+}
+
+/// Replaces the static keypair at `key_path` with a new one, returning its
+/// public key. The replaced one is kept at `retired_path` for `grace`, a zero
+/// `grace` drops it right away. Only the latest replaced keypair is kept. The
+/// daemon uses both once it restarts
+pub fn rotate_static_key(
+    key_path: &Path,
+    retired_path: &Path,
+    grace: Duration,
+) -> io::Result<PublicKey> {
+    let current = read_or_create_keypair(key_path)?;
+    match grace.is_zero() {
+        true => match std::fs::remove_file(retired_path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+            _ => {}
+        },
+        false => {
+            let valid_until = (SystemTime::now() + grace)
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            write_key_file(
+                retired_path,
+                &[
+                    &current.private,
+                    &current.public,
+                    &valid_until.to_be_bytes(),
+                ],
+            )?;
+        }
+    }
+    let keypair = generate_keypair();
+    write_key_file(key_path, &[&keypair.private, &keypair.public])?;
+    Ok(keypair.public.try_into().unwrap())
+}
+
+/// Writes a tmp file only this user can read first, so a key is never seen
+/// half written
+fn write_key_file(path: &Path, parts: &[&[u8]]) -> io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    let result = (|| {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)?;
+        for part in parts {
+            file.write_all(part)?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, path)
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    result
+}
 
-static PARAMS: LazyLock<NoiseParams> =
-    LazyLock::new(|| "Noise_NN_25519_AESGCM_BLAKE2b".parse().unwrap());
+/// Loads the keypair replaced by the last rotation from `path`, unless its
+/// grace period is over, then the file is removed
+pub fn load_retired_key(path: &Path) -> io::Result<()> {
+    let Some(retired) = read_retired_key(path)? else {
+        return Ok(());
+    };
+    if !retired.is_valid() {
+        return std::fs::remove_file(path);
+    }
+    let left = retired
+        .valid_until
+        .duration_since(SystemTime::now())
+        .unwrap_or_default();
+    info!(
+        "Peers that pinned the retired key {} get it for another {}s",
+        content_hash::to_hex(&retired.keypair.public),
+        left.as_secs()
+    );
+    let _ = RETIRED_KEY.set(retired);
+    Ok(())
+}
+
+fn read_retired_key(path: &Path) -> io::Result<Option<RetiredKey>> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let key_len = size_of::<PublicKey>();
+    let (Some(private), Some(public), Some(valid_until)) = (
+        bytes.get(..key_len),
+        bytes.get(key_len..2 * key_len),
+        bytes
+            .get(2 * key_len..)
+            .and_then(|secs| secs.try_into().ok()),
+    ) else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Retired key at {} is corrupt", path.to_string_lossy()),
+        ));
+    };
+    Ok(Some(RetiredKey {
+        keypair: Keypair {
+            private: private.to_vec(),
+            public: public.to_vec(),
+        },
+        valid_until: UNIX_EPOCH + Duration::from_secs(u64::from_be_bytes(valid_until)),
+    }))
+}
+
+/// AES-GCM is only fast and constant time with hardware support
+pub fn detect_cipher() -> Cipher {
+    #[cfg(target_arch = "x86_64")]
+    let hardware_aes = std::arch::is_x86_feature_detected!("aes");
+    #[cfg(target_arch = "aarch64")]
+    let hardware_aes = std::arch::is_aarch64_feature_detected!("aes");
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    let hardware_aes = false;
+    match hardware_aes {
+        true => Cipher::AesGcm,
+        false => Cipher::ChaChaPoly,
+    }
+}
+
+/// One way patterns (`N`, `K`, `X`) lack an ephemeral key of the responder, so a
+/// leaked static key exposes past sessions. Every interactive pattern does `ee`
+fn has_forward_secrecy(params: &NoiseParams) -> bool {
+    !params.handshake.pattern.is_oneway()
+}
+
+/// Logs whether peer sessions with `cipher` provide forward secrecy, failing
+/// if they dont but it is `required`
+pub fn check_forward_secrecy(cipher: Cipher, required: bool) -> Result<(), ForwardSecrecyError> {
+    let params = noise_params(cipher);
+    let forward_secrecy = has_forward_secrecy(&params);
+    info!(
+        "Noise pattern {} provides forward secrecy: {forward_secrecy}",
+        params.name
+    );
+    if required && !forward_secrecy {
+        return Err(ForwardSecrecyError {
+            pattern: params.name,
+        });
+    }
+    Ok(())
+}
+
+#[derive(Debug, Display, Error)]
+#[display("Forward secrecy is required, but the Noise pattern {pattern} doesnt provide it")]
+pub struct ForwardSecrecyError {
+    pattern: String,
+}
 
 const LENGTH_FIELD_LEN: usize = std::mem::size_of::<u16>();
 const TAG_LEN: usize = 16;
 const MAX_MESSAGE_LEN: usize = u16::MAX as usize;
 
-pub struct PeerConnection2 {
-    command_tx: Sender<ConnectionCommand>,
-    pub peer_closed: Receiver<()>,
-    stream_rx: Receiver<NewStream>,
+/// Serves the requests of one stream. Without a `responder` the requests are
+/// only accounted for
+async fn handle_new_channel<S>(
+    stream: S,
+    responder: Option<ChannelResponder<'_>>,
+    traffic: Rc<PeerTraffic>,
+    idle_timeout: Duration,
+    request_timeout: Duration,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    serve_until_idle(stream, idle_timeout, async |stream| {
+        debug!("Peer opened a new stream");
+        loop {
+            match read_request(stream, request_timeout).await {
+                Ok(buf) => {
+                    debug!("Peer sent a request of {} bytes", buf.len());
+                    if let Err(err) = traffic.record(buf.len()) {
+                        debug!("Closing a peer stream, the peer {err}");
+                        break;
+                    }
+                    let Some(responder) = &responder else {
+                        continue;
+                    };
+                    let message = match decode::<PeerMessage>(&buf) {
+                        Ok(message) => message,
+                        Err(err) => {
+                            debug!("Closing a peer stream, the request is undecodable: {err}");
+                            break;
+                        }
+                    };
+                    if let Err(err) = responder.respond(stream, message).await {
+                        debug!("Closing a peer stream: {err}");
+                        break;
+                    }
+                }
+                Err(err) => {
+                    debug!("Closing a peer stream: {err}");
+                    break;
+                }
+            }
+        }
+    })
+    .await;
+}
+
+/// Answers the requests a joined peer sends over its streams
+#[derive(Clone)]
+pub struct ChannelResponder<'a> {
+    server: Rc<Server<'a>>,
+    peer_id: PeerId,
+    /// Static key of the peer, `None` for same host peers
+    peer_key: Option<PublicKey>,
 }
 
-impl PeerConnection2 {
-    pub async fn connect(
-        ex: &LocalExecutor<'_>,
-        addr: SocketAddrV4,
-    ) -> Result<Self, NoiseStreamError> {
-        let noise_stream = async {
-            let stream = TcpStream::connect(addr).await?;
-            let state = Builder::new(PARAMS.clone()).build_initiator()?;
-            NoiseStream::handshake(stream, state).await
+impl<'a> ChannelResponder<'a> {
+    pub fn new(server: Rc<Server<'a>>, peer_id: PeerId, peer_key: Option<PublicKey>) -> Self {
+        Self {
+            server,
+            peer_id,
+            peer_key,
         }
-        .timeout(FRAMED_TCP_CONNECT_TIMEOUT)
-        .await
-        .ok_or(io::Error::from(io::ErrorKind::TimedOut))??;
+    }
 
-        let SocketAddr::V4(peer_addr) = noise_stream.get_inner().peer_addr()? else {
-            return Err(io::Error::from(io::ErrorKind::Unsupported).into());
+    async fn respond<S>(&self, stream: &mut S, message: PeerMessage) -> io::Result<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        // Answered like the first share of the peer rather than with a `PeerResponse`
+        if let PeerMessage::ConnectToShare { share } = &message {
+            let response = self.server.join_another_share(self.peer_id, share);
+            return FramedStream::new(stream).write(&encode(&response)).await;
+        }
+        if let PeerMessage::LeaveShare { share } = &message {
+            let response = self.server.leave_share(self.peer_id, share);
+            return FramedStream::new(stream).write(&encode(&response)).await;
+        }
+        if let PeerMessage::Introduce { introduction } = &message {
+            let response = match self
+                .server
+                .accept_introduction(self.peer_key.as_ref(), introduction)
+            {
+                Ok(()) => PeerResponse::Introduced,
+                Err(err) => PeerResponse::Err(err),
+            };
+            return FramedStream::new(stream).write(&encode(&response)).await;
+        }
+        let share = message
+            .share()
+            .and_then(|share| self.server.local_name(share));
+        let Some(share) = share else {
+            let response = PeerResponse::Err(PeerResponseError::NoSuchShare);
+            return FramedStream::new(stream).write(&encode(&response)).await;
         };
-        let mut conn =
-            yamux::Connection::new(noise_stream, Default::default(), yamux::Mode::Client);
-        let (command_tx, command_rx) = unbounded();
-        let (stream_tx, stream_rx) = unbounded();
-        let (shutdown_tx, shutdown_rx) = unbounded();
-        poll_fn(move |cx| {
-            let command_fut = command_rx.recv();
-            pin!(command_fut);
-            match command_fut.poll(cx) {
-                Poll::Ready(command) => match command.unwrap_or(ConnectionCommand::Shutdown) {
-                    ConnectionCommand::NewChannel => todo!(),
-                    ConnectionCommand::Shutdown => todo!(),
-                },
-                Poll::Pending => {}
+        // Peers only read from shares they joined
+        let prepared = {
+            let state = self.server.state.borrow();
+            let joined = state
+                .get_shares()
+                .get(&share)
+                .filter(|joined| joined.participants.contains(&self.peer_id));
+            match (joined, message) {
+                (None, _) => Err(PeerResponse::Err(PeerResponseError::NoSuchShare)),
+                (
+                    Some(joined),
+                    PeerMessage::ReadFile {
+                        rel_path,
+                        offset,
+                        len,
+                        ..
+                    },
+                ) => {
+                    let rel_path = PathBuf::from(rel_path);
+                    let path = joined.resolve(&rel_path);
+                    let peer = state
+                        .get_peers()
+                        .get(&self.peer_id)
+                        .map(|peer| peer.address);
+                    Ok((rel_path, path, offset, len, peer, joined.removal_signal()))
+                }
+                (
+                    Some(joined),
+                    PeerMessage::ReadDirPage {
+                        rel_path,
+                        cursor,
+                        limit,
+                        ..
+                    },
+                ) => Err(self.dir_page(joined, Path::new(&rel_path), cursor.as_ref(), limit)),
+                (Some(joined), message) => Err(message.respond(joined)),
             }
+        };
+        let (rel_path, path, offset, len, peer, removal_signal) = match prepared {
+            Ok(prepared) => prepared,
+            Err(response) => return FramedStream::new(stream).write(&encode(&response)).await,
+        };
 
-            match ready!(conn.poll_next_inbound(cx)) {
-                Some(Ok(stream)) => {
-                    let fut = stream_tx.send(NewStream::Inbound(stream));
-                    pin!(fut);
-                    let _ = fut.poll(cx);
-                    Poll::Pending
-                }
-                Some(Err(e)) => {
-                    error!("Error while handling a connection with peer: {e}");
-                    let _ = shutdown_tx.try_send(());
-                    Poll::Ready(())
-                }
-                None => {
-                    let _ = shutdown_tx.try_send(());
-                    Poll::Ready(())
+        // Reads a peer has to wait for are cut short, so that the wait never
+        // outlasts the timeout of the mounter. It reads the rest separately
+        let max_len = self
+            .server
+            .bandwidth
+            .as_ref()
+            .and_then(|bandwidth| bandwidth.max_read_len(self.peer_id))
+            .map_or(MAX_READ_LEN, |max_len| {
+                max_len.try_into().unwrap_or(u32::MAX)
+            });
+        let len = len.min(MAX_READ_LEN).min(max_len);
+        // Listed in `rdir transfers` until the response is written
+        let transfer = peer.map(|peer| {
+            let rel_path = rel_path.to_string_lossy();
+            self.server
+                .transfers
+                .start(&share, rel_path, peer, len.into())
+        });
+        let read = async {
+            let path = path?;
+            let len = len as usize;
+            let read = self
+                .server
+                .files
+                .read(&share, &rel_path, &path, offset, len);
+            let data = self.server.reads.run(self.peer_id, read).await?;
+            // Each peer gets its share of `--fair-bandwidth`
+            if let Some(bandwidth) = &self.server.bandwidth {
+                bandwidth.throttle(self.peer_id, data.len() as u64).await;
+            }
+            io::Result::Ok(data)
+        };
+        let response = read.map(|read| match read {
+            Ok(data) => {
+                if let Some(transfer) = &transfer {
+                    transfer.advance(data.len() as u64);
                 }
+                PeerResponse::FileData(data)
             }
-        })
-        .await;
+            Err(err) => PeerResponse::Err(err.into()),
+        });
+        respond_unless_removed(stream, removal_signal, response).await
+    }
 
-        Ok(Self {
-            command_tx,
-            stream_rx,
-            peer_closed: shutdown_rx,
-        })
+    /// Page of a dir listing, consistent with the first page as long as its
+    /// snapshot is kept
+    fn dir_page(
+        &self,
+        share: &Share,
+        requested: &Path,
+        cursor: Option<&PageCursor>,
+        limit: u32,
+    ) -> PeerResponse {
+        let limit =
+            NonZeroUsize::new(limit.min(MAX_PAGE_LEN) as usize).unwrap_or(NonZeroUsize::MIN);
+        let page = self
+            .server
+            .dir_pages
+            .page(share, requested, cursor, limit)
+            .and_then(|page| {
+                Ok(PeerResponse::DirPage {
+                    entries: share.entries(requested, page.names)?,
+                    next: page.next,
+                })
+            });
+        page.unwrap_or_else(|err| PeerResponse::Err(err.into()))
     }
 }
 
-pub enum NewStream {
-    Inbound(yamux::Stream),
-    Outbound(yamux::Stream),
+/// Reads one request, which has to arrive whole within `deadline`. Every byte
+/// resets the idle timeout, so this is what stops a peer trickling bytes
+async fn read_request<S>(stream: &mut S, deadline: Duration) -> io::Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    FramedStream::new(stream)
+        .read()
+        .timeout(deadline)
+        .await
+        .unwrap_or_else(|| Err(ErrorKind::TimedOut.into()))
 }
 
-async fn background_handler(
-    server: Rc<Server<'_>>,
-    mut conn: yamux::Connection<NoiseStream<TcpStream>>,
-    command_rx: Receiver<ConnectionCommand>,
-    peer_closed_tx: Sender<()>,
-) {
-    loop {
-        let command = select! {
-            command = command_rx.recv().fuse() => {
-                command.unwrap_or(ConnectionCommand::Shutdown)
-            },
-            new_inbound = poll_fn(|cx| conn.poll_next_inbound(cx)).fuse() => {
-                match new_inbound {
-                    Some(Ok(stream)) => {
-                        server.ex.spawn(handle_new_channel(stream, None)).detach();
-                        continue;
-                    },
-                    Some(Err(err)) => {
-                        error!("IO Error from peer: {err}");
-                        let _ = peer_closed_tx.try_send(());
-                        ConnectionCommand::Shutdown
-                    },
-                    None => {
-                        let _ = peer_closed_tx.try_send(());
-                        ConnectionCommand::Shutdown
-                    },
-                }
-            },
+/// Runs `serve` on the stream until it finishes or the stream sees no traffic
+/// for `idle_timeout`, the stream is closed in both cases
+async fn serve_until_idle<S, F>(stream: S, idle_timeout: Duration, serve: F)
+where
+    S: AsyncWrite + Unpin,
+    F: AsyncFnOnce(&mut ActivityStream<S>),
+{
+    let mut stream = ActivityStream::new(stream);
+    let last_activity = stream.last_activity.clone();
+    let reaper = async {
+        loop {
+            let idle_for = last_activity.get().elapsed();
+            if idle_for >= idle_timeout {
+                debug!("Closing a stream idle for {idle_for:?}");
+                break;
+            }
+            Timer::after(idle_timeout - idle_for).await;
+        }
+    };
+    serve(&mut stream).or(reaper).await;
+    let _ = stream.close().await;
+}
+
+/// Writes the response to a peer request, unless the share gets removed before
+/// the response is ready, in which case the peer gets `ShareRemoved` instead
+async fn respond_unless_removed<S>(
+    stream: &mut S,
+    removal_signal: Receiver<()>,
+    response: impl Future<Output = PeerResponse>,
+) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let removed = async {
+        let _ = removal_signal.recv().await;
+        PeerResponse::Err(PeerResponseError::ShareRemoved)
+    };
+    let response = response.or(removed).await;
+    FramedStream::new(stream).write(&encode(&response)).await
+}
+
+/// Caps the file reads running at once, in total and per peer, so that peers
+/// opening many channels cant thrash the disk. Reads over the cap are queued
+pub struct ReadLimiter {
+    global: Semaphore,
+    per_peer_limit: usize,
+    per_peer: RefCell<BTreeMap<PeerId, Rc<Semaphore>>>,
+}
+
+impl ReadLimiter {
+    pub fn new(global_limit: NonZeroUsize, per_peer_limit: NonZeroUsize) -> Self {
+        Self {
+            global: Semaphore::new(global_limit.get()),
+            per_peer_limit: per_peer_limit.get(),
+            per_peer: Default::default(),
+        }
+    }
+
+    /// Runs `read` once both a permit of the peer and a global one are free
+    pub async fn run<T>(&self, peer_id: PeerId, read: impl Future<Output = T>) -> T {
+        let peer_semaphore = self
+            .per_peer
+            .borrow_mut()
+            .entry(peer_id)
+            .or_insert_with(|| Rc::new(Semaphore::new(self.per_peer_limit)))
+            .clone();
+        let result = {
+            let _peer_permit = peer_semaphore.acquire().await;
+            let _permit = self.global.acquire().await;
+            read.await
         };
+        // Only the map holds it now, so no reads of the peer are queued
+        if Rc::strong_count(&peer_semaphore) == 2 {
+            self.per_peer.borrow_mut().remove(&peer_id);
+        }
+        result
+    }
+}
 
-        match command {
-            ConnectionCommand::NewChannel(ctx) => {
-                // server.ex.spawn(handle_new_channel(stream, None)).detach();
-                poll_fn(|cx| {
+#[derive(Clone, Debug, Display, Error, PartialEq, Eq)]
+pub enum TrafficExceededError {
+    #[display("sent more than {_0} requests in a second")]
+    Requests(#[error(not(source))] u32),
+    #[display("sent more than {_0} bytes of requests in a second")]
+    Bytes(#[error(not(source))] u64),
+}
 
-                })
+/// Counts the requests a peer sends over all its streams, so that a peer
+/// flooding the daemon with them can be disconnected
+pub struct PeerTraffic {
+    max_requests: u32,
+    max_bytes: u64,
+    window_start: Cell<Instant>,
+    requests: Cell<u32>,
+    bytes: Cell<u64>,
+    exceeded_tx: Sender<TrafficExceededError>,
+    exceeded_rx: Receiver<TrafficExceededError>,
+}
+
+impl PeerTraffic {
+    pub fn new(max_requests: NonZeroU32, max_bytes: NonZeroU64) -> Self {
+        let (exceeded_tx, exceeded_rx) = smol::channel::bounded(1);
+        Self {
+            max_requests: max_requests.get(),
+            max_bytes: max_bytes.get(),
+            window_start: Cell::new(Instant::now()),
+            requests: Cell::new(0),
+            bytes: Cell::new(0),
+            exceeded_tx,
+            exceeded_rx,
+        }
+    }
+
+    /// Counts a request of `bytes`, fails once the peer is over a limit
+    pub fn record(&self, bytes: usize) -> Result<(), TrafficExceededError> {
+        if self.window_start.get().elapsed() >= TRAFFIC_WINDOW {
+            self.window_start.set(Instant::now());
+            self.requests.set(0);
+            self.bytes.set(0);
+        }
+        self.requests.set(self.requests.get().saturating_add(1));
+        self.bytes
+            .set(self.bytes.get().saturating_add(bytes as u64));
+
+        let err = if self.requests.get() > self.max_requests {
+            TrafficExceededError::Requests(self.max_requests)
+        } else if self.bytes.get() > self.max_bytes {
+            TrafficExceededError::Bytes(self.max_bytes)
+        } else {
+            return Ok(());
+        };
+        let _ = self.exceeded_tx.try_send(err.clone());
+        Err(err)
+    }
+
+    /// Resolves once the peer went over a limit
+    pub async fn exceeded(&self) -> TrafficExceededError {
+        match self.exceeded_rx.recv().await {
+            Ok(err) => err,
+            // Holds the sender itself, so this cant happen
+            Err(_) => smol::future::pending().await,
+        }
+    }
+}
+
+/// Files recently read by peers, kept open so that sequential ranged reads of a
+/// file dont reopen it and seek every time
+#[derive(Default)]
+pub struct FileHandles {
+    open: RefCell<BTreeMap<(CommonShareName, PathBuf), OpenFile>>,
+    opens: Cell<usize>,
+}
+
+struct OpenFile {
+    file: File,
+    position: u64,
+    last_used: Instant,
+}
+
+impl FileHandles {
+    /// Reads up to `len` bytes at `offset` of `rel_path` in the share. `path`
+    /// is where `rel_path` resolved to and is only opened if it isnt open yet.
+    /// The read runs on the blocking pool, so it doesnt stall the executor
+    pub async fn read(
+        &self,
+        share: &CommonShareName,
+        rel_path: &Path,
+        path: &Path,
+        offset: u64,
+        len: usize,
+    ) -> io::Result<Vec<u8>> {
+        let now = Instant::now();
+        let key = (share.clone(), rel_path.to_path_buf());
+        // Taken out while it is read, a concurrent read of the same file opens
+        // its own
+        let file = {
+            let mut open = self.open.borrow_mut();
+            open.retain(|_, file| now.duration_since(file.last_used) < OPEN_FILE_IDLE_TIMEOUT);
+            open.remove(&key)
+        };
+        if file.is_none() {
+            self.opens.set(self.opens.get() + 1);
+        }
+        let path = path.to_path_buf();
+        let (mut file, read) = unblock(move || {
+            let mut file = match file {
+                Some(file) => file,
+                None => OpenFile {
+                    file: File::open(path)?,
+                    position: 0,
+                    last_used: now,
+                },
+            };
+            let mut buf = Vec::with_capacity(len);
+            let read = Self::read_at(&mut file, offset, len, &mut buf);
+            match read {
+                Ok(()) => file.position = offset + buf.len() as u64,
+                // The position is unknown now, seek on the next read
+                Err(_) => file.position = u64::MAX,
             }
-            ConnectionCommand::Shutdown => {
-                let _ = poll_fn(|cx| conn.poll_close(cx)).await;
-            },
+            io::Result::Ok((file, read.map(|()| buf)))
+        })
+        .await?;
+        file.last_used = now;
+
+        let mut open = self.open.borrow_mut();
+        if !open.contains_key(&key)
+            && open.len() >= MAX_OPEN_FILES
+            && let Some(lru) = open
+                .iter()
+                .min_by_key(|(_, file)| file.last_used)
+                .map(|(key, _)| key.clone())
+        {
+            open.remove(&lru);
         }
+        open.entry(key).or_insert(file);
+        read
+    }
+
+    fn read_at(file: &mut OpenFile, offset: u64, len: usize, buf: &mut Vec<u8>) -> io::Result<()> {
+        if file.position != offset {
+            file.file.seek(SeekFrom::Start(offset))?;
+        }
+        (&mut file.file).take(len as u64).read_to_end(buf)?;
+        Ok(())
+    }
+
+    /// Closes the files of a share, called once it is removed or moved
+    pub fn forget_share(&self, share: &CommonShareName) {
+        self.open.borrow_mut().retain(|(name, _), _| name != share);
     }
 }
 
-pub(super) enum ConnectionCommand {
-    NewChannel(NewChannelCtx),
-    Shutdown,
+/// Stream wrapper that records when data last went through it
+#[pin_project]
+pub struct ActivityStream<S> {
+    #[pin]
+    inner: S,
+    last_activity: Rc<Cell<Instant>>,
 }
 
-pub struct NewChannelCtx {
-    share_name: CommonShareName,
+impl<S> ActivityStream<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            last_activity: Rc::new(Cell::new(Instant::now())),
+        }
+    }
 }
 
-async fn handle_new_channel(stream: yamux::Stream, ctx: Option<NewChannelCtx>) {
-    let _ = stream;
-    let _ = ctx;
-    debug!("Created a new stream with client :D");
+impl<S: AsyncRead> AsyncRead for ActivityStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let n = ready!(this.inner.poll_read(cx, buf))?;
+        this.last_activity.set(Instant::now());
+        Poll::Ready(Ok(n))
+    }
 }
 
-pub struct PeerConnection {
-    inner: yamux::Connection<NoiseStream<TcpStream>>,
+impl<S: AsyncWrite> AsyncWrite for ActivityStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let n = ready!(this.inner.poll_write(cx, buf))?;
+        this.last_activity.set(Instant::now());
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+/// Yamux connection with a peer, over Noise on TCP unless the peer is on the same host
+pub struct PeerConnection<T = NoiseStream<TcpStream>> {
+    inner: yamux::Connection<T>,
     peer_addr: SocketAddrV4,
+    /// Static key the peer authenticated with, `None` on the same host
+    /// socket of this user, which has no handshake
+    peer_key: Option<PublicKey>,
+    /// Key the peer rotated to, announced along with the retired key it
+    /// presented, see [`NoiseStream::initiate`]
+    successor_key: Option<PublicKey>,
 }
 
-impl PeerConnection {
-    pub async fn connect(addr: SocketAddrV4) -> Result<Self, NoiseStreamError> {
-        async {
-            let stream = TcpStream::connect(addr).await?;
-            let state = Builder::new(PARAMS.clone()).build_initiator()?;
-            let noise_stream = NoiseStream::handshake(stream, state).await?;
+/// Transport picked by [`PeerConnection::connect_auto`]. The same host socket
+/// has Noise on it too when another user listens on it
+pub type PeerTransport =
+    Either<NoiseStream<TcpStream>, Either<UnixStream, NoiseStream<UnixStream>>>;
 
-            let SocketAddr::V4(peer_addr) = noise_stream.get_inner().peer_addr()? else {
-                return Err(io::Error::from(io::ErrorKind::Unsupported).into());
+impl<T> PeerConnection<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    fn new(transport: T, peer_addr: SocketAddrV4, mode: yamux::Mode) -> Self {
+        let inner = yamux::Connection::new(transport, Default::default(), mode);
+        Self {
+            inner,
+            peer_addr,
+            peer_key: None,
+            successor_key: None,
+        }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddrV4 {
+        self.peer_addr
+    }
+
+    pub fn peer_key(&self) -> Option<&PublicKey> {
+        self.peer_key.as_ref()
+    }
+
+    pub fn successor_key(&self) -> Option<&PublicKey> {
+        self.successor_key.as_ref()
+    }
+
+    /// Sends `request` on a new stream and returns the response, driving the
+    /// connection until it arrives. Streams the peer opens meanwhile are refused
+    pub async fn request(&mut self, request: &[u8]) -> Result<Vec<u8>, NoiseStreamError> {
+        let mut stream = poll_fn(|cx| self.inner.poll_new_outbound(cx))
+            .await
+            .map_err(io::Error::other)?;
+        let exchange = async {
+            FramedStream::new(&mut stream).write(request).await?;
+            FramedStream::new(&mut stream)
+                .read()
+                .timeout(FRAMED_TCP_TIMEOUT)
+                .await
+                .unwrap_or_else(|| Err(ErrorKind::TimedOut.into()))
+        };
+        let drive = async { Err(self.drive().await) };
+        Ok(exchange.or(drive).await?)
+    }
+
+    /// Like [`Self::request`], for requests that change nothing on the peer,
+    /// like reads. One whose stream fails while the connection stays up, like
+    /// on a reset stream, is sent again on a new stream, a few times and a bit
+    /// later each time
+    pub async fn request_repeatable(
+        &mut self,
+        request: &[u8],
+    ) -> Result<Vec<u8>, NoiseStreamError> {
+        let mut attempt = 0;
+        loop {
+            match self.request(request).await {
+                Err(NoiseStreamError::Io(err)) if attempt < REQUEST_RETRIES => {
+                    attempt += 1;
+                    debug!("Retrying a request to {} after: {err}", self.peer_addr);
+                    Timer::after(REQUEST_RETRY_DELAY * attempt).await;
+                }
+                response => return response,
+            }
+        }
+    }
+
+    /// Waits for the peer to open a stream and send a request on it, returns
+    /// the request with the stream to answer on. Gives up after [`FRAMED_TCP_TIMEOUT`]
+    pub async fn next_request(&mut self) -> io::Result<(yamux::Stream, Vec<u8>)> {
+        async {
+            let mut stream = match poll_fn(|cx| self.inner.poll_next_inbound(cx)).await {
+                Some(stream) => stream.map_err(io::Error::other)?,
+                None => return Err(ErrorKind::UnexpectedEof.into()),
             };
-            let inner =
-                yamux::Connection::new(noise_stream, Default::default(), yamux::Mode::Client);
-            Ok(Self { inner, peer_addr })
+            let read = async { FramedStream::new(&mut stream).read().await };
+            let drive = async { Err(self.drive().await) };
+            let request = read.or(drive).await?;
+            Ok((stream, request))
         }
-        .timeout(FRAMED_TCP_CONNECT_TIMEOUT)
+        .timeout(FRAMED_TCP_TIMEOUT)
         .await
-        .ok_or(io::Error::from(io::ErrorKind::TimedOut))?
+        .unwrap_or_else(|| Err(ErrorKind::TimedOut.into()))
+    }
+
+    /// Answers a request from [`Self::next_request`] and closes its stream
+    pub async fn reply(&mut self, mut stream: yamux::Stream, response: &[u8]) -> io::Result<()> {
+        let write = async {
+            FramedStream::new(&mut stream).write(response).await?;
+            stream.close().await
+        };
+        let drive = async { Err(self.drive().await) };
+        write.or(drive).await
+    }
+
+    /// Tells the peer no more streams are coming and closes the transport,
+    /// giving up after [`FRAMED_TCP_TIMEOUT`]
+    pub async fn close(&mut self) {
+        let close = poll_fn(|cx| self.inner.poll_close(cx));
+        if let Some(Err(err)) = close.timeout(FRAMED_TCP_TIMEOUT).await {
+            debug!(
+                "Failed to close the connection to {}: {err}",
+                self.peer_addr
+            );
+        }
+    }
+
+    /// Keeps the connection going while nothing else does, resolves once the
+    /// peer closes it or it fails. Streams the peer opens meanwhile are refused
+    pub async fn closed(&mut self) -> io::Result<()> {
+        loop {
+            match poll_fn(|cx| self.inner.poll_next_inbound(cx)).await {
+                Some(Ok(stream)) => drop(stream),
+                Some(Err(err)) => return Err(connection_io_error(err)),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Keeps the connection going until it fails. Once the peer closes it,
+    /// streams still get what arrived before and then end on their own, so
+    /// this never returns
+    async fn drive(&mut self) -> io::Error {
+        match self.closed().await {
+            Ok(()) => smol::future::pending().await,
+            Err(err) => err,
+        }
+    }
+}
+
+impl PeerConnection {
+    /// Proposes `cipher` for the session. Read ahead buffers of the connection
+    /// are taken from `buffers`. Gives up when the handshake isnt done within
+    /// `timeout`
+    pub async fn connect(
+        addr: SocketAddrV4,
+        cipher: Cipher,
+        buffers: &Arc<BufferBudget>,
+        timeout: Duration,
+    ) -> Result<Self, NoiseStreamError> {
+        let (noise_stream, peer_addr) = connect_noise(addr, cipher, None, timeout).await?;
+        let noise_stream = noise_stream.with_budgeted_read_ahead(READ_AHEAD_LEN, buffers);
+        let peer_key = noise_stream.remote_static();
+        Ok(Self {
+            peer_key,
+            ..Self::new(noise_stream, peer_addr, yamux::Mode::Client)
+        })
     }
 
-    pub async fn accept(stream: TcpStream) -> Result<Self, NoiseStreamError> {
+    /// Refuses peers proposing another cipher than `required`, if any
+    pub async fn accept(
+        stream: TcpStream,
+        required: Option<Cipher>,
+        buffers: &Arc<BufferBudget>,
+    ) -> Result<Self, NoiseStreamError> {
         async {
-            let state = Builder::new(PARAMS.clone()).build_responder()?;
-            let noise_stream = NoiseStream::handshake(stream, state).await?;
+            let noise_stream = NoiseStream::respond(stream, required)
+                .await?
+                .with_budgeted_read_ahead(READ_AHEAD_LEN, buffers);
 
             let SocketAddr::V4(peer_addr) = noise_stream.get_inner().peer_addr()? else {
-                return Err(io::Error::from(io::ErrorKind::Unsupported).into());
+                let err = io::Error::new(ErrorKind::Unsupported, "IPv6 peers are unsupported");
+                return Err(err.into());
             };
-            let inner =
-                yamux::Connection::new(noise_stream, Default::default(), yamux::Mode::Server);
-            Ok(Self { inner, peer_addr })
+            let peer_key = noise_stream.remote_static();
+            Ok(Self {
+                peer_key,
+                ..Self::new(noise_stream, peer_addr, yamux::Mode::Server)
+            })
         }
         .timeout(FRAMED_TCP_CONNECT_TIMEOUT)
         .await
@@ -209,6 +1024,293 @@ impl PeerConnection {
     }
 }
 
+impl PeerConnection<UnixStream> {
+    /// Same host peers have no address of their own, `addr` is what tells
+    /// them apart
+    pub fn accept_same_host(stream: UnixStream, addr: SocketAddrV4) -> Self {
+        Self::new(stream, addr, yamux::Mode::Server)
+    }
+}
+
+impl PeerConnection<NoiseStream<UnixStream>> {
+    /// Same host peers of another user authenticate with Noise like TCP peers,
+    /// see [`is_same_user`]
+    pub async fn accept_other_user(
+        stream: UnixStream,
+        addr: SocketAddrV4,
+        required: Option<Cipher>,
+        buffers: &Arc<BufferBudget>,
+    ) -> Result<Self, NoiseStreamError> {
+        let noise_stream = NoiseStream::respond(stream, required)
+            .timeout(FRAMED_TCP_CONNECT_TIMEOUT)
+            .await
+            .ok_or(io::Error::from(io::ErrorKind::TimedOut))??
+            .with_budgeted_read_ahead(READ_AHEAD_LEN, buffers);
+        let peer_key = noise_stream.remote_static();
+        Ok(Self {
+            peer_key,
+            ..Self::new(noise_stream, addr, yamux::Mode::Server)
+        })
+    }
+}
+
+impl PeerConnection<PeerTransport> {
+    /// Skips TCP when the peer is on this host and listens on a same host
+    /// socket. A socket of this user needs no Noise either, as the connection
+    /// stays local. One of another user is only used once it proves to have
+    /// `pinned`, the key pinned for the peer, see [`is_same_user`]. With
+    /// `require_key` the peer has to prove its key in any case, so only TCP is
+    /// used
+    pub async fn connect_auto(
+        addr: SocketAddrV4,
+        cipher: Cipher,
+        pinned: Option<&PublicKey>,
+        require_key: bool,
+        buffers: &Arc<BufferBudget>,
+        timeout: Duration,
+    ) -> Result<Self, NoiseStreamError> {
+        if !require_key
+            && addr.ip().is_loopback()
+            && let Ok(stream) = connect_same_host(addr.port())
+        {
+            match (is_same_user(&stream), pinned) {
+                (Ok(true), _) => {
+                    debug!("Connecting to {addr} over the same host socket");
+                    let transport = Either::Right(Either::Left(stream));
+                    return Ok(Self::new(transport, addr, yamux::Mode::Client));
+                }
+                (Ok(false), Some(pinned)) => {
+                    let handshake = NoiseStream::initiate(stream, cipher, Some(pinned))
+                        .timeout(timeout)
+                        .await
+                        .ok_or(io::Error::from(io::ErrorKind::TimedOut).into());
+                    match handshake.and_then(|handshake| handshake) {
+                        Ok(noise_stream)
+                            if noise_stream.remote_static().as_ref() == Some(pinned) =>
+                        {
+                            debug!(
+                                "Connecting to {addr} over the same host socket of another user"
+                            );
+                            let successor_key = noise_stream.successor();
+                            let noise_stream =
+                                noise_stream.with_budgeted_read_ahead(READ_AHEAD_LEN, buffers);
+                            let transport = Either::Right(Either::Right(noise_stream));
+                            return Ok(Self {
+                                peer_key: Some(*pinned),
+                                successor_key,
+                                ..Self::new(transport, addr, yamux::Mode::Client)
+                            });
+                        }
+                        // Someone else could have bound the name first
+                        Ok(_) => warn!(
+                            "Same host socket of {addr} doesnt have the pinned key, connecting over TCP"
+                        ),
+                        Err(err) => {
+                            debug!("Failed to handshake over the same host socket of {addr}: {err}")
+                        }
+                    }
+                }
+                (Ok(false), None) => debug!(
+                    "Same host socket of {addr} belongs to another user whose key isnt pinned yet"
+                ),
+                (Err(err), _) => debug!("Failed to check the same host socket of {addr}: {err}"),
+            }
+        }
+        let (noise_stream, peer_addr) = connect_noise(addr, cipher, pinned, timeout).await?;
+        let noise_stream = noise_stream.with_budgeted_read_ahead(READ_AHEAD_LEN, buffers);
+        let peer_key = noise_stream.remote_static();
+        Ok(Self {
+            peer_key,
+            successor_key: noise_stream.successor(),
+            ..Self::new(Either::Left(noise_stream), peer_addr, yamux::Mode::Client)
+        })
+    }
+}
+
+/// Abstract sockets have no permissions and any process could listen on one
+/// first. The other end is trusted like this user when it runs as this user,
+/// any other has to authenticate with Noise like TCP peers do
+pub fn is_same_user(stream: &impl AsFd) -> io::Result<bool> {
+    let uid = getsockopt(stream, PeerCredentials)?.uid();
+    Ok(uid == getuid().as_raw())
+}
+
+/// Abstract socket a daemon listens on for peers from the same host, named
+/// after its TCP port so that peers can find it from the address alone
+fn same_host_addr(port: u16) -> io::Result<std::os::unix::net::SocketAddr> {
+    std::os::unix::net::SocketAddr::from_abstract_name(format!("rdir-peer-{port}"))
+}
+
+pub fn bind_same_host(port: u16) -> io::Result<UnixListener> {
+    std::os::unix::net::UnixListener::bind_addr(&same_host_addr(port)?)?.try_into()
+}
+
+/// Connecting to a Unix socket doesnt block, so this needs no async
+fn connect_same_host(port: u16) -> io::Result<UnixStream> {
+    std::os::unix::net::UnixStream::connect_addr(&same_host_addr(port)?)?.try_into()
+}
+
+/// Hands every stream the peer opens to `handle_new_channel` until the connection closes
+pub async fn serve_inbound<T>(
+    server: Rc<Server<'_>>,
+    mut conn: PeerConnection<T>,
+    peer_id: Option<PeerId>,
+) where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let traffic = Rc::new(PeerTraffic::new(
+        server.args.peer_max_requests,
+        server.args.peer_max_request_bytes,
+    ));
+    let in_flight = InFlight::new();
+    let peer_key = conn.peer_key().copied();
+    let responder = peer_id.map(|peer_id| ChannelResponder::new(server.clone(), peer_id, peer_key));
+    loop {
+        let inbound = async { Ok(poll_fn(|cx| conn.inner.poll_next_inbound(cx)).await) };
+        let abuse = async { Err(traffic.exceeded().await) };
+        let inbound = match inbound.or(abuse).await {
+            Ok(inbound) => inbound,
+            Err(err) => {
+                error!("Disconnecting peer {}, it {err}", conn.peer_addr);
+                let _ = poll_fn(|cx| conn.inner.poll_close(cx)).await;
+                break;
+            }
+        };
+        match inbound {
+            Some(Ok(stream)) => {
+                let idle_timeout = Duration::from_secs(server.args.stream_idle_timeout);
+                let request_timeout = Duration::from_secs(server.args.request_timeout);
+                let handler = handle_new_channel(
+                    stream,
+                    responder.clone(),
+                    traffic.clone(),
+                    idle_timeout,
+                    request_timeout,
+                );
+                server.ex.spawn(in_flight.track(handler)).detach();
+            }
+            Some(Err(err)) => {
+                error!("IO Error from peer {}: {err}", conn.peer_addr);
+                if !is_hard_disconnect(&err) {
+                    drain(&mut conn.inner, in_flight, PEER_DRAIN_TIMEOUT).await;
+                    let _ = poll_fn(|cx| conn.inner.poll_close(cx)).await;
+                }
+                break;
+            }
+            None => break,
+        }
+    }
+}
+
+/// Streams of a connection still being served, so that a failing connection
+/// can wait for their responses
+pub struct InFlight {
+    /// Never sent on, each stream holds a clone until it is served
+    tx: Sender<()>,
+    rx: Receiver<()>,
+}
+
+impl InFlight {
+    pub fn new() -> Self {
+        let (tx, rx) = unbounded();
+        Self { tx, rx }
+    }
+
+    pub fn track<F: Future>(&self, serve: F) -> impl Future<Output = F::Output> + use<F> {
+        let guard = self.tx.clone();
+        async move {
+            let output = serve.await;
+            drop(guard);
+            output
+        }
+    }
+
+    /// Resolves once every tracked stream is served
+    async fn finished(self) {
+        let Self { tx, rx } = self;
+        drop(tx);
+        let _ = rx.recv().await;
+    }
+}
+
+/// Io error a connection failed with, unwrapped from yamux so a peer going
+/// away keeps its kind, see [`NoiseStreamError::is_peer_closed`]. Reads that
+/// fail come wrapped as a decode error
+fn connection_io_error(err: yamux::ConnectionError) -> io::Error {
+    match err {
+        yamux::ConnectionError::Io(err)
+        | yamux::ConnectionError::Decode(yamux::FrameDecodeError::Io(err)) => err,
+        err => io::Error::other(err),
+    }
+}
+
+/// Errors that leave nothing to send the responses over, unlike a peer that
+/// sent garbage or ran out of stream ids
+fn is_hard_disconnect(err: &yamux::ConnectionError) -> bool {
+    match err {
+        yamux::ConnectionError::Io(err)
+        | yamux::ConnectionError::Decode(yamux::FrameDecodeError::Io(err)) => matches!(
+            err.kind(),
+            ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::BrokenPipe
+                | ErrorKind::UnexpectedEof
+                | ErrorKind::NotConnected
+        ),
+        yamux::ConnectionError::Closed => true,
+        _ => false,
+    }
+}
+
+/// Keeps the connection going for up to `timeout`, until the streams in
+/// flight are served. Streams the peer opens meanwhile are refused
+async fn drain<T>(conn: &mut yamux::Connection<T>, in_flight: InFlight, timeout: Duration)
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let drive = async {
+        while let Some(Ok(stream)) = poll_fn(|cx| conn.poll_next_inbound(cx)).await {
+            drop(stream);
+        }
+    };
+    let _ = in_flight.finished().or(drive).timeout(timeout).await;
+}
+
+/// Opens a Noise session over TCP within `timeout`, returning it with the
+/// address of the peer
+async fn connect_noise(
+    addr: SocketAddrV4,
+    cipher: Cipher,
+    pinned: Option<&PublicKey>,
+    timeout: Duration,
+) -> Result<(NoiseStream<TcpStream>, SocketAddrV4), NoiseStreamError> {
+    async {
+        let stream = connect_tcp(addr).await?;
+        let noise_stream = NoiseStream::initiate(stream, cipher, pinned).await?;
+
+        let SocketAddr::V4(peer_addr) = noise_stream.get_inner().peer_addr()? else {
+            return Err(io::Error::from(io::ErrorKind::Unsupported).into());
+        };
+        Ok((noise_stream, peer_addr))
+    }
+    .timeout(timeout)
+    .await
+    .ok_or(io::Error::from(io::ErrorKind::TimedOut))?
+}
+
+/// Opens a TCP connection, failing right away with `PeerUnreachable` when the
+/// host actively refuses it, instead of waiting out the connect timeout
+async fn connect_tcp(addr: SocketAddrV4) -> Result<TcpStream, NoiseStreamError> {
+    TcpStream::connect(addr)
+        .await
+        .map_err(|err| match err.kind() {
+            ErrorKind::ConnectionRefused
+            | ErrorKind::HostUnreachable
+            | ErrorKind::NetworkUnreachable => NoiseStreamError::PeerUnreachable(err),
+            _ => NoiseStreamError::Io(err),
+        })
+}
+
 #[derive(Debug)]
 enum ReadState {
     ShuttingDown,
@@ -218,11 +1320,120 @@ enum ReadState {
     ServingPayload(usize),
 }
 
-#[derive(Debug)]
-enum WriteState {
-    ShuttingDown,
-    Idle,
-    WritingMessage(usize, usize),
+#[derive(Debug)]
+enum WriteState {
+    ShuttingDown,
+    Idle,
+    WritingMessage(usize, usize),
+}
+
+/// Read ahead a peer connection asks for, granted while the budget allows it
+pub const READ_AHEAD_LEN: usize = 256 * 1024;
+
+/// Bytes of read ahead buffers all connections may hold together. Once spent,
+/// new connections read frame by frame until older ones free theirs. The frame
+/// buffers arent counted, they cant get any smaller than the biggest frame
+#[derive(Debug)]
+pub struct BufferBudget {
+    /// `None` is unlimited
+    limit: Option<usize>,
+    used: AtomicUsize,
+}
+
+impl BufferBudget {
+    pub fn new(limit: Option<usize>) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            used: AtomicUsize::new(0),
+        })
+    }
+
+    /// Grants `len` bytes, or none when that doesnt fit in what is left
+    fn reserve(self: &Arc<Self>, len: usize) -> Reservation {
+        let granted =
+            self.used
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                    match self.limit {
+                        Some(limit) if used + len > limit => None,
+                        _ => Some(used + len),
+                    }
+                });
+        Reservation {
+            budget: self.clone(),
+            len: granted.map_or(0, |_| len),
+        }
+    }
+
+    /// Bytes currently held by read ahead buffers
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+}
+
+/// Bytes taken from a [`BufferBudget`], given back on drop
+#[derive(Debug)]
+struct Reservation {
+    budget: Arc<BufferBudget>,
+    len: usize,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.used.fetch_sub(self.len, Ordering::Relaxed);
+    }
+}
+
+/// Buffer of raw bytes read from the inner stream ahead of the current frame,
+/// so that several small frames can be pulled in with a single read
+#[derive(Debug, Default)]
+struct ReadAhead {
+    buf: Vec<u8>,
+    start: usize,
+    end: usize,
+    /// Keeps the budget of `buf` spent while it is alive
+    reservation: Option<Reservation>,
+}
+
+impl ReadAhead {
+    fn with_capacity(len: usize) -> Self {
+        Self {
+            buf: vec![0; len],
+            start: 0,
+            end: 0,
+            reservation: None,
+        }
+    }
+
+    fn with_budget(len: usize, budget: &Arc<BufferBudget>) -> Self {
+        let reservation = budget.reserve(len);
+        let mut read_ahead = Self::with_capacity(reservation.len);
+        read_ahead.reservation = Some(reservation);
+        read_ahead
+    }
+
+    /// Fills `dst` from the buffered bytes, refilling the buffer from `inner`
+    /// once it runs dry. Reads bypass the buffer when it is disabled or
+    /// smaller than `dst`
+    fn poll_read_through<R: AsyncRead>(
+        &mut self,
+        inner: Pin<&mut R>,
+        cx: &mut Context<'_>,
+        dst: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.start == self.end {
+            if self.buf.len() <= dst.len() {
+                return inner.poll_read(cx, dst);
+            }
+            let n = ready!(inner.poll_read(cx, &mut self.buf))?;
+            self.start = 0;
+            self.end = n;
+        }
+
+        let n = dst.len().min(self.end - self.start);
+        dst[..n].copy_from_slice(&self.buf[self.start..self.start + n]);
+        self.start += n;
+        Poll::Ready(Ok(n))
+    }
 }
 
 #[pin_project]
@@ -235,58 +1446,234 @@ pub struct NoiseStream<T> {
     write_state: WriteState,
     write_clean_waker: Option<Waker>,
 
-    read_message_buffer: Vec<u8>,
-    read_payload_buffer: Vec<u8>,
+    read_ahead: ReadAhead,
+    read_message_buffer: PooledBuffer,
+    read_payload_buffer: PooledBuffer,
 
-    write_message_buffer: Vec<u8>,
+    write_message_buffer: PooledBuffer,
+
+    /// Key the responder rotated to, when it presented the retired key the
+    /// initiator pinned
+    successor: Option<PublicKey>,
 }
 
 impl<T> NoiseStream<T> {
+    fn new(inner: T, transport: TransportState) -> Self {
+        Self {
+            inner,
+            transport,
+            read_state: ReadState::Idle,
+            write_state: WriteState::Idle,
+            write_clean_waker: None,
+            read_ahead: ReadAhead::default(),
+            read_message_buffer: pool::take(MAX_MESSAGE_LEN),
+            read_payload_buffer: pool::take(MAX_MESSAGE_LEN),
+            write_message_buffer: pool::take(LENGTH_FIELD_LEN + MAX_MESSAGE_LEN),
+            successor: None,
+        }
+    }
+
+    /// Enables reading up to `len` bytes from the inner stream at once,
+    /// buffering whole frames ahead of the reader, as far as `budget` allows.
+    /// A `len` of 0 disables it. Must be set before anything is read from the
+    /// stream
+    pub fn with_budgeted_read_ahead(mut self, len: usize, budget: &Arc<BufferBudget>) -> Self {
+        debug_assert_eq!(self.read_ahead.start, self.read_ahead.end);
+        self.read_ahead = ReadAhead::with_budget(len, budget);
+        self
+    }
+
     pub fn get_inner(&self) -> &T {
         &self.inner
     }
+
+    /// Static key the other side proved to own during the handshake
+    pub fn remote_static(&self) -> Option<PublicKey> {
+        self.transport
+            .get_remote_static()
+            .and_then(|key| key.try_into().ok())
+    }
+
+    pub fn successor(&self) -> Option<PublicKey> {
+        self.successor
+    }
 }
 
 impl<T> NoiseStream<T>
 where
     T: AsyncRead + AsyncWrite + Unpin,
 {
-    async fn handshake(mut stream: T, mut state: HandshakeState) -> Result<Self, NoiseStreamError> {
+    /// Proposes `cipher` with its suite byte, then handshakes as the initiator.
+    /// The `pinned` key of the peer is sent along, a peer that rotated its key
+    /// since still presents that one for a while, see [`RetiredKey`]
+    async fn initiate(
+        mut stream: T,
+        cipher: Cipher,
+        pinned: Option<&PublicKey>,
+    ) -> Result<Self, NoiseStreamError> {
+        stream.write_all(&[suite(cipher)]).await?;
+        let state = Builder::new(noise_params(cipher))
+            .local_private_key(&static_key().private)?
+            .build_initiator()?;
+        let pinned = pinned.map_or(&[][..], |pinned| pinned.as_slice());
+        Self::handshake_as(stream, state, PROTOCOL_VERSION, None, pinned).await
+    }
+
+    /// Handshakes as the responder with the cipher the initiator proposed,
+    /// which has to be the `required` one if any
+    async fn respond(stream: T, required: Option<Cipher>) -> Result<Self, NoiseStreamError> {
+        Self::respond_with(stream, static_key(), RETIRED_KEY.get(), required).await
+    }
+
+    /// Presents `retired` instead of `current` to an initiator that pinned it,
+    /// announcing `current` as its successor
+    async fn respond_with(
+        mut stream: T,
+        current: &Keypair,
+        retired: Option<&RetiredKey>,
+        required: Option<Cipher>,
+    ) -> Result<Self, NoiseStreamError> {
+        let mut proposed = [0];
+        stream.read_exact(&mut proposed).await?;
+        let cipher = cipher_of_suite(proposed[0])?;
+        debug!("Peer proposed {cipher}");
+        if let Some(required) = required
+            && required != cipher
+        {
+            // An empty message in place of the second one, so the initiator
+            // learns why it was refused
+            let refusal = [0, 0, proposed[0], suite(required)];
+            stream.write_all(&refusal).await?;
+            stream.flush().await?;
+            return Err(NoiseStreamError::CipherMismatch {
+                proposed: cipher,
+                required,
+            });
+        }
+        // The key to present is picked before the first message is processed,
+        // its payload is sent in the clear after the ephemeral key
+        let mut first = vec![0; read_message_len(&mut stream).await?];
+        stream.read_exact(&mut first).await?;
+        let pinned = first.get(DH_LEN..).map(|payload| parse_payload(payload).1);
+        let (keypair, successor) = match retired {
+            Some(retired)
+                if retired.is_valid() && pinned == Some(retired.keypair.public.as_slice()) =>
+            {
+                debug!("Peer pinned the retired key, presenting it");
+                (&retired.keypair, current.public.as_slice())
+            }
+            _ => (current, &[][..]),
+        };
+        let state = Builder::new(noise_params(cipher))
+            .local_private_key(&keypair.private)?
+            .build_responder()?;
+        Self::handshake_as(stream, state, PROTOCOL_VERSION, Some(&first), successor).await
+    }
+
+    /// Every handshake message carries the protocol `version` of its sender.
+    /// A peer of another version fails the handshake only once it finished,
+    /// so both sides learn why. The `first` message from the other side may
+    /// have been read already. `extra` goes along with the first message this
+    /// side sends, the initiator keeps what the responder sent as the
+    /// successor of its key
+    async fn handshake_as(
+        mut stream: T,
+        mut state: HandshakeState,
+        version: u16,
+        mut first: Option<&[u8]>,
+        extra: &[u8],
+    ) -> Result<Self, NoiseStreamError> {
+        let mut message = pool::take(MAX_MESSAGE_LEN);
+        let mut payload = pool::take(MAX_MESSAGE_LEN);
+        let mut sent = [&version.to_le_bytes(), extra].concat();
+        let mut other_version = None;
+        let mut successor = None;
         loop {
             if state.is_handshake_finished() {
+                if let Some(version) = other_version {
+                    return Err(NoiseStreamError::ProtocolVersion(version));
+                }
                 let transport = state.into_transport_mode()?;
+                // Back to the pool first, so the stream can reuse them
+                drop((message, payload));
                 return Ok(Self {
-                    inner: stream,
-                    transport,
-                    read_state: ReadState::Idle,
-                    write_state: WriteState::Idle,
-                    write_clean_waker: None,
-                    read_message_buffer: vec![0; MAX_MESSAGE_LEN],
-                    read_payload_buffer: vec![0; MAX_MESSAGE_LEN],
-                    write_message_buffer: vec![0; LENGTH_FIELD_LEN + MAX_MESSAGE_LEN],
+                    successor,
+                    ..Self::new(stream, transport)
                 });
             }
 
-            let mut message = vec![0; MAX_MESSAGE_LEN];
-            let mut payload = vec![0; MAX_MESSAGE_LEN];
-
             if state.is_my_turn() {
-                let len = state.write_message(&[], &mut message)?;
+                let len = state.write_message(&sent, &mut message)?;
+                sent.truncate(size_of::<u16>());
                 let prefix = (len as u16).to_be_bytes();
                 stream.write_all(&prefix).await?;
                 stream.write_all(&message[..len]).await?;
                 stream.flush().await?;
             } else {
-                let mut len_buf = [0; 2];
-                stream.read_exact(&mut len_buf).await?;
-                let len = u16::from_be_bytes(len_buf) as usize;
-                stream.read_exact(&mut message[..len]).await?;
-                state.read_message(&message[..len], &mut payload)?;
+                let len = match first.take() {
+                    Some(first) => state.read_message(first, &mut payload)?,
+                    None => {
+                        let len = read_message_len(&mut stream).await?;
+                        // The refusal of a responder requiring another cipher
+                        if len == 0 && state.is_initiator() {
+                            let mut refusal = [0; 2];
+                            stream.read_exact(&mut refusal).await?;
+                            return Err(NoiseStreamError::CipherMismatch {
+                                proposed: cipher_of_suite(refusal[0])?,
+                                required: cipher_of_suite(refusal[1])?,
+                            });
+                        }
+                        stream.read_exact(&mut message[..len]).await?;
+                        state.read_message(&message[..len], &mut payload)?
+                    }
+                };
+                let (other, extra) = parse_payload(&payload[..len]);
+                if other != version {
+                    other_version = Some(other);
+                }
+                if state.is_initiator() {
+                    successor = extra.try_into().ok();
+                }
             }
         }
     }
 }
 
+/// Byte the initiator proposes `cipher` with
+fn suite(cipher: Cipher) -> u8 {
+    SUITES.iter().position(|&c| c == cipher).unwrap() as u8
+}
+
+fn cipher_of_suite(suite: u8) -> Result<Cipher, NoiseStreamError> {
+    SUITES
+        .get(suite as usize)
+        .copied()
+        .ok_or(NoiseStreamError::UnsupportedCipher(suite))
+}
+
+/// Length of the public keys of the handshake, also the ephemeral key that
+/// starts the first message
+const DH_LEN: usize = size_of::<PublicKey>();
+
+async fn read_message_len(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<usize> {
+    let mut len_buf = [0; 2];
+    stream.read_exact(&mut len_buf).await?;
+    Ok(u16::from_be_bytes(len_buf) as usize)
+}
+
+/// Splits a handshake payload into the protocol version of the sender and
+/// the extra it sent along
+fn parse_payload(payload: &[u8]) -> (u16, &[u8]) {
+    match payload {
+        [low, high, extra @ ..] => (u16::from_le_bytes([*low, *high]), extra),
+        // Peers from before the version was sent send no payload
+        _ => (1, &[]),
+    }
+}
+
+/// A byte stream like the one it wraps, a write sends at most one Noise frame
+/// and returns how much of `buf` it took, so `write_all` may take several.
+/// Frames arent messages, layers on top frame their own like yamux does
 impl<T> AsyncWrite for NoiseStream<T>
 where
     T: AsyncWrite,
@@ -321,9 +1708,15 @@ where
                     *state = WriteState::WritingMessage(0, payload_len);
                 }
                 WriteState::WritingMessage(start, payload_len) => {
+                    // The frame is sealed already, so a write left pending has
+                    // to be retried with the same `buf`
+                    debug_assert!(buf.len() >= *payload_len);
                     let n = ready!(
                         Pin::new(&mut inner).poll_write(cx, &write_message_buffer[*start..])
                     )?;
+                    if n == 0 {
+                        return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+                    }
                     *start += n;
 
                     if *start == write_message_buffer.len() {
@@ -378,6 +1771,7 @@ where
         let state = this.read_state;
         let transport = this.transport;
 
+        let read_ahead = this.read_ahead;
         let read_message_buffer = this.read_message_buffer;
         let read_payload_buffer = this.read_payload_buffer;
 
@@ -397,7 +1791,11 @@ where
                         read_message_buffer.resize(message_len as usize, 0);
                         *state = ReadState::ReadingMessage(0);
                     } else {
-                        let n = ready!(inner.as_mut().poll_read(cx, &mut buf[*read_len..],))?;
+                        let n = ready!(read_ahead.poll_read_through(
+                            inner.as_mut(),
+                            cx,
+                            &mut buf[*read_len..]
+                        ))?;
 
                         if n == 0 {
                             *state = ReadState::ShuttingDown;
@@ -417,11 +1815,11 @@ where
                         read_payload_buffer.truncate(n);
                         *state = ReadState::ServingPayload(0);
                     } else {
-                        let n = ready!(
-                            inner
-                                .as_mut()
-                                .poll_read(cx, &mut read_message_buffer[*start..])
-                        )?;
+                        let n = ready!(read_ahead.poll_read_through(
+                            inner.as_mut(),
+                            cx,
+                            &mut read_message_buffer[*start..]
+                        ))?;
 
                         if n == 0 {
                             *state = ReadState::ShuttingDown;
@@ -433,6 +1831,11 @@ where
 
                 ReadState::ServingPayload(start) => {
                     let available = read_payload_buffer.len() - *start;
+                    if available == 0 {
+                        // Empty message, returning 0 here would read as EOF
+                        *state = ReadState::Idle;
+                        continue;
+                    }
                     let to_copy = available.min(out.len());
 
                     out[..to_copy].copy_from_slice(&read_payload_buffer[*start..*start + to_copy]);
@@ -455,18 +1858,91 @@ where
 pub enum NoiseStreamError {
     Io(io::Error),
     Crypto(snow::Error),
+    #[display("Peer is unreachable")]
+    #[from(skip)]
+    PeerUnreachable(io::Error),
+    /// Suite byte of a cipher this build doesnt know, the peer is likely newer
+    #[display("Peer proposed an unsupported cipher suite: {_0}")]
+    #[from(skip)]
+    UnsupportedCipher(#[error(not(source))] u8),
+    /// The responder requires another cipher with `--cipher`
+    #[display(
+        "Initiator proposed {proposed} but the responder requires {required}, set the same `--cipher` on both"
+    )]
+    #[from(skip)]
+    CipherMismatch {
+        proposed: Cipher,
+        required: Cipher,
+    },
+    #[display(
+        "Peer speaks protocol version {_0} and this daemon {PROTOCOL_VERSION}, both need the same rdir version"
+    )]
+    #[from(skip)]
+    ProtocolVersion(#[error(not(source))] u16),
+}
+
+impl NoiseStreamError {
+    pub fn category(&self) -> ConnectionErrorCategory {
+        match self {
+            Self::PeerUnreachable(_) => ConnectionErrorCategory::Unreachable,
+            Self::Crypto(_)
+            | Self::UnsupportedCipher(_)
+            | Self::CipherMismatch { .. }
+            | Self::ProtocolVersion(_) => ConnectionErrorCategory::HandshakeFailed,
+            Self::Io(err) => match err.kind() {
+                ErrorKind::TimedOut => ConnectionErrorCategory::Timeout,
+                ErrorKind::InvalidData | ErrorKind::UnexpectedEof => {
+                    ConnectionErrorCategory::Protocol
+                }
+                _ => ConnectionErrorCategory::Io,
+            },
+        }
+    }
+
+    /// Whether the peer closed the connection, as opposed to it failing
+    pub fn is_peer_closed(&self) -> bool {
+        matches!(
+            self,
+            Self::Io(err) if matches!(
+                err.kind(),
+                ErrorKind::UnexpectedEof
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::BrokenPipe
+            )
+        )
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use async_broadcast::broadcast;
+    use bitcode::decode;
+    use futures::FutureExt as _;
     use smol::{
         block_on,
-        net::{TcpListener, TcpStream},
+        net::{TcpListener, TcpStream, unix::UnixStream},
         spawn,
     };
     use snow::Builder;
 
     use super::*;
+    use crate::{
+        server::{
+            ConnectToRemoteShareError, JoinedShare,
+            state::{Peer, Share, State},
+        },
+        test_dir::TestDir,
+    };
+
+    impl<T> NoiseStream<T>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        async fn handshake(stream: T, state: HandshakeState) -> Result<Self, NoiseStreamError> {
+            Self::handshake_as(stream, state, PROTOCOL_VERSION, None, &[]).await
+        }
+    }
 
     #[test]
     fn tcp() {
@@ -572,6 +2048,759 @@ mod tests {
         block_on(result).unwrap();
     }
 
+    #[test]
+    fn reconnecting_reuses_buffers() {
+        let connect = async || {
+            let (local, remote) = UnixStream::pair()?;
+            let params = noise_params(Cipher::AesGcm);
+            let key = &static_key().private;
+            let initiator = Builder::new(params.clone())
+                .local_private_key(key)?
+                .build_initiator()?;
+            let responder = Builder::new(params)
+                .local_private_key(key)?
+                .build_responder()?;
+            let (a, b) = smol::future::zip(
+                NoiseStream::handshake(local, initiator),
+                NoiseStream::handshake(remote, responder),
+            )
+            .await;
+            drop((a?, b?));
+            anyhow::Ok(())
+        };
+        block_on(async {
+            connect().await.unwrap();
+            let hits = pool::hits();
+            for _ in 0..10 {
+                connect().await.unwrap();
+            }
+            // Every cycle takes 5 buffers per side, all of them from the pool
+            assert_eq!(pool::hits() - hits, 10 * 2 * 5);
+        });
+    }
+
+    #[test]
+    fn peers_of_other_protocol_versions_are_refused() {
+        let result = async {
+            let (local, remote) = UnixStream::pair()?;
+            let params = noise_params(Cipher::AesGcm);
+            let key = &static_key().private;
+            let initiator = Builder::new(params.clone())
+                .local_private_key(key)?
+                .build_initiator()?;
+            let responder = Builder::new(params)
+                .local_private_key(key)?
+                .build_responder()?;
+            let (a, b) = smol::future::zip(
+                NoiseStream::handshake(local, initiator),
+                NoiseStream::handshake_as(remote, responder, PROTOCOL_VERSION + 1, None, &[]),
+            )
+            .await;
+            anyhow::Ok((a.err(), b.err()))
+        };
+        let (initiator, responder) = block_on(result).unwrap();
+        assert!(matches!(
+            initiator,
+            Some(NoiseStreamError::ProtocolVersion(v)) if v == PROTOCOL_VERSION + 1
+        ));
+        assert!(matches!(
+            responder,
+            Some(NoiseStreamError::ProtocolVersion(v)) if v == PROTOCOL_VERSION
+        ));
+    }
+
+    #[test]
+    fn both_ciphers_handshake() {
+        for cipher in SUITES {
+            let result = async {
+                let (local, remote) = UnixStream::pair()?;
+                let (initiator, responder) = smol::future::zip(
+                    NoiseStream::initiate(local, cipher, None),
+                    NoiseStream::respond(remote, Some(cipher)),
+                )
+                .await;
+                let (mut initiator, mut responder) = (initiator?, responder?);
+                initiator.write_all(b"hello").await?;
+                initiator.flush().await?;
+                let mut buf = [0; 5];
+                responder.read_exact(&mut buf).await?;
+                assert_eq!(&buf, b"hello");
+                anyhow::Ok(())
+            };
+            block_on(result).unwrap_or_else(|err| panic!("{cipher} failed: {err}"));
+        }
+    }
+
+    #[test]
+    fn cipher_mismatch_is_reported_on_both_sides() {
+        let (initiator, responder) = block_on(async {
+            let (local, remote) = UnixStream::pair().unwrap();
+            smol::future::zip(
+                NoiseStream::initiate(local, Cipher::AesGcm, None),
+                NoiseStream::respond(remote, Some(Cipher::ChaChaPoly)),
+            )
+            .await
+        });
+        for err in [initiator.err().unwrap(), responder.err().unwrap()] {
+            assert!(matches!(
+                err,
+                NoiseStreamError::CipherMismatch {
+                    proposed: Cipher::AesGcm,
+                    required: Cipher::ChaChaPoly,
+                }
+            ));
+            assert_eq!(err.category(), ConnectionErrorCategory::HandshakeFailed);
+        }
+    }
+
+    #[test]
+    fn peers_learn_each_others_static_key() {
+        let dir = TestDir::new("key");
+        let path = dir.join("key");
+        let keypair = read_or_create_keypair(&path).unwrap();
+        assert_eq!(
+            read_or_create_keypair(&path).unwrap().public,
+            keypair.public
+        );
+        std::fs::remove_file(&path).unwrap();
+        load_static_key(&path).unwrap();
+
+        block_on(async {
+            let (local, remote) = UnixStream::pair().unwrap();
+            let (initiator, responder) = smol::future::zip(
+                NoiseStream::initiate(local, Cipher::ChaChaPoly, None),
+                NoiseStream::respond(remote, None),
+            )
+            .await;
+            let own_key = static_key().public.as_slice();
+            assert_eq!(initiator.unwrap().remote_static().unwrap(), own_key);
+            assert_eq!(responder.unwrap().remote_static().unwrap(), own_key);
+        });
+    }
+
+    #[test]
+    fn retired_key_is_presented_during_the_grace_period() {
+        let (current, old) = (generate_keypair(), generate_keypair());
+        let current_key: PublicKey = current.public.as_slice().try_into().unwrap();
+        let old_key: PublicKey = old.public.as_slice().try_into().unwrap();
+        let mut retired = RetiredKey {
+            keypair: old,
+            valid_until: SystemTime::now() + Duration::from_secs(60),
+        };
+        let handshake = |pinned: Option<PublicKey>, retired: &RetiredKey| {
+            block_on(async {
+                let (local, remote) = UnixStream::pair().unwrap();
+                let (initiator, responder) = smol::future::zip(
+                    NoiseStream::initiate(local, Cipher::ChaChaPoly, pinned.as_ref()),
+                    NoiseStream::respond_with(remote, &current, Some(retired), None),
+                )
+                .await;
+                responder.unwrap();
+                let initiator = initiator.unwrap();
+                (initiator.remote_static().unwrap(), initiator.successor())
+            })
+        };
+
+        assert_eq!(
+            handshake(Some(old_key), &retired),
+            (old_key, Some(current_key))
+        );
+        assert_eq!(handshake(Some(current_key), &retired), (current_key, None));
+        assert_eq!(handshake(None, &retired), (current_key, None));
+        retired.valid_until = SystemTime::now();
+        assert_eq!(handshake(Some(old_key), &retired), (current_key, None));
+    }
+
+    #[test]
+    fn rotated_keys_are_kept_for_the_grace_period() {
+        let dir = TestDir::new("rotate-key");
+        let (path, retired_path) = (dir.join("key"), dir.join("key.old"));
+        let old = read_or_create_keypair(&path).unwrap();
+        let new = rotate_static_key(&path, &retired_path, Duration::from_secs(60)).unwrap();
+        assert_eq!(read_or_create_keypair(&path).unwrap().public, new);
+        let retired = read_retired_key(&retired_path).unwrap().unwrap();
+        assert_eq!(retired.keypair.public, old.public);
+        assert!(retired.is_valid());
+
+        rotate_static_key(&path, &retired_path, Duration::ZERO).unwrap();
+        assert!(read_retired_key(&retired_path).unwrap().is_none());
+    }
+
+    #[test]
+    fn other_users_authenticate_over_the_same_host_socket() {
+        block_on(async {
+            let (local, remote) = UnixStream::pair().unwrap();
+            let addr = SocketAddrV4::new([0, 0, 0, 0].into(), 1);
+            let buffers = BufferBudget::new(None);
+            let (initiator, conn) = smol::future::zip(
+                NoiseStream::initiate(local, Cipher::ChaChaPoly, None),
+                PeerConnection::accept_other_user(remote, addr, None, &buffers),
+            )
+            .await;
+            let own_key = static_key().public.as_slice();
+            assert_eq!(initiator.unwrap().remote_static().unwrap(), own_key);
+            let conn = conn.unwrap();
+            assert_eq!(conn.peer_key().unwrap(), own_key);
+            assert_eq!(conn.peer_addr(), addr);
+        });
+    }
+
+    #[test]
+    fn unknown_cipher_is_reported() {
+        block_on(async {
+            let (mut local, remote) = UnixStream::pair().unwrap();
+            local.write_all(&[SUITES.len() as u8]).await.unwrap();
+            let Err(err) = NoiseStream::respond(remote, None).await else {
+                panic!("unknown cipher was accepted");
+            };
+            assert!(matches!(err, NoiseStreamError::UnsupportedCipher(2)));
+            assert_eq!(err.category(), ConnectionErrorCategory::HandshakeFailed);
+        });
+    }
+
+    #[test]
+    fn idle_stream_gets_closed() {
+        let result = async {
+            let idle_timeout = Duration::from_millis(50);
+            let (local, mut remote) = UnixStream::pair()?;
+            let start = Instant::now();
+            let serve = serve_until_idle(local, idle_timeout, async |_| {
+                smol::future::pending::<()>().await
+            });
+            let read = async {
+                let mut buf = [0; 1];
+                remote.read(&mut buf).timeout(Duration::from_secs(1)).await
+            };
+
+            let ((), n) = smol::future::zip(serve, read).await;
+            assert_eq!(n.expect("Idle stream wasnt closed")?, 0);
+            assert!(start.elapsed() >= idle_timeout);
+            anyhow::Ok(())
+        };
+        block_on(result).unwrap();
+    }
+
+    #[test]
+    fn abusive_peer_is_cut_off() {
+        let result = async {
+            let traffic = || {
+                Rc::new(PeerTraffic::new(
+                    5.try_into().unwrap(),
+                    1024.try_into().unwrap(),
+                ))
+            };
+            let (abusive, compliant) = (traffic(), traffic());
+            let (local, mut remote) = UnixStream::pair()?;
+            let (ok_local, mut ok_remote) = UnixStream::pair()?;
+            let timeout = Duration::from_millis(200);
+            let serve = smol::future::zip(
+                handle_new_channel(local, None, abusive.clone(), timeout, timeout),
+                handle_new_channel(ok_local, None, compliant.clone(), timeout, timeout),
+            );
+            let send = async {
+                for _ in 0..10 {
+                    if FramedStream::new(&mut remote).write(b"ping").await.is_err() {
+                        break;
+                    }
+                }
+                for _ in 0..2 {
+                    FramedStream::new(&mut ok_remote).write(b"ping").await?;
+                }
+                // Closed with unread requests left, which can show up as a reset
+                let mut buf = [0; 1];
+                assert!(matches!(remote.read(&mut buf).await, Ok(0) | Err(_)));
+                anyhow::Ok(())
+            };
+            let (_, sent) = smol::future::zip(serve, send).await;
+            sent?;
+
+            assert_eq!(abusive.exceeded().await, TrafficExceededError::Requests(5));
+            assert!(compliant.exceeded().now_or_never().is_none());
+            anyhow::Ok(())
+        };
+        block_on(result).unwrap();
+    }
+
+    #[test]
+    fn joined_peers_read_files_over_streams() {
+        let dir = TestDir::new("read-file");
+        std::fs::create_dir_all(dir.join("shared")).unwrap();
+        std::fs::write(dir.join("secret"), "private").unwrap();
+        let contents: Vec<u8> = (0..=255).cycle().take(300_000).collect();
+        std::fs::write(dir.join("shared/data.bin"), &contents).unwrap();
+        let server = Server::in_memory(clap::Parser::parse_from(["rdir", "ls"]));
+        let share = Share::new("A".parse().unwrap(), dir.join("shared"));
+        server.state.borrow_mut().add_share(share).unwrap();
+        let peer_id = match server.join_share("1.1.1.1:1".parse().unwrap(), "A".parse().unwrap()) {
+            Ok(JoinedShare { peer_id, .. }) => peer_id,
+            _ => panic!("the peer has to join"),
+        };
+
+        let (local, remote) = UnixStream::pair().unwrap();
+        let traffic = Rc::new(PeerTraffic::new(NonZeroU32::MAX, NonZeroU64::MAX));
+        let timeout = Duration::from_secs(1);
+        let responder = ChannelResponder::new(server.clone(), peer_id, None);
+        let serve = handle_new_channel(local, Some(responder), traffic, timeout, timeout);
+        let read = async {
+            let mut stream = FramedStream::new(remote);
+            let mut request = async |rel_path: &str, offset, len| {
+                let message = PeerMessage::ReadFile {
+                    share: "A".parse().unwrap(),
+                    rel_path: rel_path.to_string(),
+                    offset,
+                    len,
+                };
+                stream.write(&encode(&message)).await.unwrap();
+                decode::<PeerResponse>(&stream.read().await.unwrap()).unwrap()
+            };
+            let mut read = Vec::new();
+            while read.len() < contents.len() {
+                match request("data.bin", read.len() as u64, 100_000).await {
+                    PeerResponse::FileData(data) => read.extend(data),
+                    response => panic!("expected data, got {response:?}"),
+                }
+            }
+            assert_eq!(read, contents);
+            assert!(matches!(
+                request("../secret", 0, 10).await,
+                PeerResponse::Err(PeerResponseError::PermissionDenied)
+            ));
+        };
+        block_on(server.ex.run(read.or(async {
+            serve.await;
+            panic!("the stream closed early");
+        })));
+    }
+
+    #[test]
+    fn drip_feeding_peer_gets_aborted() {
+        let result = async {
+            let idle_timeout = Duration::from_millis(50);
+            let request_timeout = Duration::from_millis(100);
+            let (local, mut remote) = UnixStream::pair()?;
+            let start = Instant::now();
+            let traffic = Rc::new(PeerTraffic::new(NonZeroU32::MAX, NonZeroU64::MAX));
+            let handler = handle_new_channel(local, None, traffic, idle_timeout, request_timeout);
+            let drip = async {
+                // Announces a long message, then sends it one byte at a time
+                for byte in u16::MAX.to_be_bytes().into_iter().chain([0; 100]) {
+                    if remote.write_all(&[byte]).await.is_err() {
+                        break;
+                    }
+                    Timer::after(Duration::from_millis(10)).await;
+                }
+            };
+
+            smol::future::zip(handler, drip).await;
+            assert!(start.elapsed() >= request_timeout);
+            assert!(start.elapsed() < Duration::from_millis(900));
+            anyhow::Ok(())
+        };
+        block_on(result).unwrap();
+    }
+
+    #[test]
+    fn concurrent_reads_are_capped() {
+        /// Tracks how many reads run at once
+        #[derive(Default)]
+        struct Gauge {
+            running: Cell<usize>,
+            peak: Cell<usize>,
+        }
+
+        impl Gauge {
+            fn enter(&self) {
+                self.running.set(self.running.get() + 1);
+                self.peak.set(self.peak.get().max(self.running.get()));
+            }
+
+            fn exit(&self) {
+                self.running.set(self.running.get() - 1);
+            }
+        }
+
+        let mut state = State::default();
+        let name: CommonShareName = "A".parse().unwrap();
+        state
+            .add_share(Share::new(name.clone(), "/".into()))
+            .unwrap();
+        let peer_ids = [1, 2].map(|i| {
+            let address = SocketAddrV4::new([i; 4].into(), 1);
+            let peer = Peer::new(address, unbounded().0, unbounded().0);
+            state
+                .new_peer_connected_to_share(peer, name.clone())
+                .unwrap()
+        });
+
+        let limiter = ReadLimiter::new(3.try_into().unwrap(), 2.try_into().unwrap());
+        let total = Gauge::default();
+        let by_peer = [Gauge::default(), Gauge::default()];
+        let reads = (0..10).map(|i| {
+            let (total, peer) = (&total, &by_peer[i % 2]);
+            limiter.run(peer_ids[i % 2], async move {
+                total.enter();
+                peer.enter();
+                Timer::after(Duration::from_millis(5)).await;
+                peer.exit();
+                total.exit();
+            })
+        });
+        block_on(futures::future::join_all(reads));
+
+        assert_eq!(total.peak.get(), 3);
+        assert!(by_peer.iter().all(|peer| peer.peak.get() == 2));
+        assert_eq!(total.running.get(), 0);
+        assert!(limiter.per_peer.borrow().is_empty());
+    }
+
+    #[test]
+    fn sequential_reads_reuse_the_file() {
+        let name: CommonShareName = "A".parse().unwrap();
+        let dir = TestDir::new("handles");
+        let path = dir.join("file");
+        let content: Vec<u8> = (0..100).collect();
+        std::fs::write(&path, &content).unwrap();
+        let rel_path = Path::new("file");
+
+        let handles = FileHandles::default();
+        let mut read = Vec::new();
+        for offset in (0..100).step_by(30) {
+            read.extend(block_on(handles.read(&name, rel_path, &path, offset, 30)).unwrap());
+        }
+        assert_eq!(read, content);
+        // Jumping back seeks in the open file
+        assert_eq!(
+            block_on(handles.read(&name, rel_path, &path, 10, 2)).unwrap(),
+            [10, 11]
+        );
+        assert_eq!(handles.opens.get(), 1);
+
+        handles.forget_share(&name);
+        block_on(handles.read(&name, rel_path, &path, 0, 1)).unwrap();
+        assert_eq!(handles.opens.get(), 2);
+    }
+
+    #[test]
+    fn in_flight_request_sees_share_removal() {
+        let mut state = State::default();
+        let name: CommonShareName = "A".parse().unwrap();
+        state
+            .add_share(Share::new(name.clone(), "/".into()))
+            .unwrap();
+        let removal_signal = state.get_shares()[&name].removal_signal();
+        let (shutdown_tx, _shutdown_rx) = broadcast(1);
+
+        let result = async {
+            let (mut local, remote) = UnixStream::pair()?;
+            let respond =
+                respond_unless_removed(&mut local, removal_signal, smol::future::pending());
+            let remove = async { state.remove_share(&name, &shutdown_tx).unwrap() };
+            let (result, ()) = smol::future::zip(respond, remove).await;
+            result?;
+            drop(local);
+
+            let response: PeerResponse = decode(&FramedStream::new(remote).read().await?)?;
+            assert!(matches!(
+                response,
+                PeerResponse::Err(PeerResponseError::ShareRemoved)
+            ));
+            anyhow::Ok(())
+        };
+        block_on(result).unwrap();
+    }
+
+    #[test]
+    fn refused_connection_fails_fast() {
+        let result = async {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let SocketAddr::V4(addr) = listener.local_addr()? else {
+                unreachable!()
+            };
+            drop(listener);
+
+            let start = Instant::now();
+            let err = PeerConnection::connect(
+                addr,
+                Cipher::ChaChaPoly,
+                &BufferBudget::new(None),
+                FRAMED_TCP_CONNECT_TIMEOUT,
+            )
+            .await
+            .err()
+            .unwrap();
+            assert!(err.is_peer_unreachable());
+            assert!(start.elapsed() < FRAMED_TCP_CONNECT_TIMEOUT);
+            anyhow::Ok(())
+        };
+        block_on(result).unwrap();
+    }
+
+    #[test]
+    fn peer_closing_after_handshake_is_reported() {
+        let result = async {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let SocketAddr::V4(addr) = listener.local_addr()? else {
+                unreachable!()
+            };
+            let peer = async {
+                let (stream, _) = listener.accept().await?;
+                // Handshake, then go away without answering
+                drop(PeerConnection::accept(stream, None, &BufferBudget::new(None)).await?);
+                anyhow::Ok(())
+            };
+            let client = async {
+                let mut conn = PeerConnection::connect(
+                    addr,
+                    Cipher::ChaChaPoly,
+                    &BufferBudget::new(None),
+                    FRAMED_TCP_CONNECT_TIMEOUT,
+                )
+                .await?;
+                anyhow::Ok(conn.request(b"connect").await)
+            };
+            let (peer, response) = futures::join!(peer, client);
+            peer?;
+            response
+        };
+
+        let err = block_on(result).unwrap().unwrap_err();
+        assert!(
+            ConnectToRemoteShareError::from_first_request(err).is_peer_closed_during_handshake()
+        );
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        assert!(
+            ConnectToRemoteShareError::from_first_request(refused.into()).is_io(),
+            "only a peer closing the connection is reported as such"
+        );
+    }
+
+    #[test]
+    fn repeatable_requests_are_retried_on_a_new_stream() {
+        let result = async {
+            let (local, remote) = UnixStream::pair()?;
+            let addr = SocketAddrV4::new([127, 0, 0, 1].into(), 0);
+            let mut client = PeerConnection::new(local, addr, yamux::Mode::Client);
+            let mut peer = PeerConnection::new(remote, addr, yamux::Mode::Server);
+            let flaky_peer = async {
+                // The stream of the first request is dropped unanswered
+                let (stream, _) = peer.next_request().await?;
+                drop(stream);
+                let (stream, request) = peer.next_request().await?;
+                peer.reply(stream, &request).await?;
+                // Keeps the connection going until the reply got through
+                Err(anyhow::Error::from(peer.drive().await))
+            };
+            let request = async { Ok(client.request_repeatable(b"read").await?) };
+            request.or(flaky_peer).await
+        };
+        assert_eq!(block_on(result).unwrap(), b"read");
+    }
+
+    /// Reader that counts how many times it was polled
+    struct CountingReader<R> {
+        inner: R,
+        reads: usize,
+    }
+
+    impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<R> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            self.reads += 1;
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    fn transport_pair() -> (TransportState, TransportState) {
+        let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+        let mut initiator = Builder::new(params.clone()).build_initiator().unwrap();
+        let mut responder = Builder::new(params).build_responder().unwrap();
+        let (mut buf, mut message) = ([0; 1024], [0; 1024]);
+        let len = initiator.write_message(&[], &mut message).unwrap();
+        responder.read_message(&message[..len], &mut buf).unwrap();
+        let len = responder.write_message(&[], &mut message).unwrap();
+        initiator.read_message(&message[..len], &mut buf).unwrap();
+        (
+            initiator.into_transport_mode().unwrap(),
+            responder.into_transport_mode().unwrap(),
+        )
+    }
+
+    /// Counts the reads of the inner stream needed to receive 100 small frames
+    fn reads_for_small_frames(read_ahead: usize) -> usize {
+        let (initiator, responder) = transport_pair();
+        block_on(async {
+            let mut writer = NoiseStream::new(Vec::new(), initiator);
+            for i in 0..100 {
+                writer.write_all(&[i; 10]).await.unwrap();
+            }
+
+            let reader = CountingReader {
+                inner: writer.inner.as_slice(),
+                reads: 0,
+            };
+            let mut reader = NoiseStream::new(reader, responder)
+                .with_budgeted_read_ahead(read_ahead, &BufferBudget::new(None));
+            let mut payload = vec![0; 1000];
+            reader.read_exact(&mut payload).await.unwrap();
+            for (i, chunk) in payload.chunks(10).enumerate() {
+                assert_eq!(chunk, [i as u8; 10]);
+            }
+            reader.inner.reads
+        })
+    }
+
+    #[test]
+    fn read_ahead_reduces_reads() {
+        let without = reads_for_small_frames(0);
+        let with = reads_for_small_frames(0x10000);
+        assert_eq!(without, 200);
+        assert!(with * 10 < without, "{with} reads with read ahead");
+        // a buffer smaller than a frame still has to reassemble correctly
+        reads_for_small_frames(7);
+    }
+
+    #[test]
+    fn read_ahead_is_held_until_freed() {
+        let budget = BufferBudget::new(Some(READ_AHEAD_LEN + READ_AHEAD_LEN / 2));
+        let stream = || {
+            let (_, responder) = transport_pair();
+            NoiseStream::new(io::empty(), responder)
+                .with_budgeted_read_ahead(READ_AHEAD_LEN, &budget)
+        };
+
+        let first = stream();
+        assert_eq!(first.read_ahead.buf.len(), READ_AHEAD_LEN);
+        let second = stream();
+        assert!(second.read_ahead.buf.is_empty());
+        assert_eq!(first.read_ahead.buf.len(), READ_AHEAD_LEN);
+        assert_eq!(budget.used(), READ_AHEAD_LEN);
+
+        drop(first);
+        assert_eq!(budget.used(), 0);
+        assert_eq!(stream().read_ahead.buf.len(), READ_AHEAD_LEN);
+        drop(second);
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[test]
+    fn payload_served_byte_by_byte() {
+        let (initiator, responder) = transport_pair();
+        block_on(async {
+            let mut writer = NoiseStream::new(Vec::new(), initiator);
+            let payload: Vec<u8> = (0..100).collect();
+            writer.write_all(&payload).await.unwrap();
+
+            let mut reader = NoiseStream::new(writer.inner.as_slice(), responder);
+            for (i, expected) in payload.iter().enumerate() {
+                let mut byte = [0];
+                assert_eq!(reader.read(&mut byte).await.unwrap(), 1);
+                assert_eq!(byte[0], *expected);
+                let last = i == payload.len() - 1;
+                assert_eq!(matches!(reader.read_state, ReadState::Idle), last);
+            }
+        });
+    }
+
+    /// Takes at most `max` bytes per write, pending every other call
+    struct TrickleWriter {
+        written: Vec<u8>,
+        max: usize,
+        pending: bool,
+    }
+
+    impl AsyncWrite for TrickleWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.pending = !self.pending;
+            if self.pending {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            let n = buf.len().min(self.max);
+            self.written.extend_from_slice(&buf[..n]);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn payloads_span_frames() {
+        const FULL: usize = MAX_MESSAGE_LEN - TAG_LEN;
+        for len in [FULL - 1, FULL, FULL + 1, 3 * FULL + 7] {
+            let (initiator, responder) = transport_pair();
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            block_on(async {
+                let writer = TrickleWriter {
+                    written: Vec::new(),
+                    max: 1000,
+                    pending: false,
+                };
+                let mut writer = NoiseStream::new(writer, initiator);
+                writer.write_all(&payload).await.unwrap();
+
+                let written = &writer.inner.written;
+                let frames = len.div_ceil(FULL);
+                assert_eq!(written.len(), len + frames * (LENGTH_FIELD_LEN + TAG_LEN));
+                // A full frame is exactly as long as the length field allows
+                let first_len = u16::from_le_bytes([written[0], written[1]]) as usize;
+                assert_eq!(first_len, len.min(FULL) + TAG_LEN);
+
+                let mut reader = NoiseStream::new(written.as_slice(), responder);
+                let mut read = Vec::new();
+                reader.read_to_end(&mut read).await.unwrap();
+                assert!(read == payload, "{len} bytes came back different");
+            });
+        }
+    }
+
+    #[test]
+    fn empty_message_isnt_eof() {
+        let (initiator, responder) = transport_pair();
+        block_on(async {
+            let mut writer = NoiseStream::new(Vec::new(), initiator);
+            assert_eq!(writer.write(&[]).await.unwrap(), 0);
+            writer.write_all(&[7]).await.unwrap();
+
+            let mut reader = NoiseStream::new(writer.inner.as_slice(), responder);
+            let mut byte = [0];
+            assert_eq!(reader.read(&mut byte).await.unwrap(), 1);
+            assert_eq!(byte, [7]);
+        });
+    }
+
+    #[test]
+    fn forward_secrecy_classification() {
+        let params = |name: &str| name.parse::<NoiseParams>().unwrap();
+        assert!(
+            SUITES
+                .iter()
+                .all(|&cipher| has_forward_secrecy(&noise_params(cipher)))
+        );
+        assert!(has_forward_secrecy(&params(
+            "Noise_XX_25519_AESGCM_BLAKE2b"
+        )));
+        assert!(!has_forward_secrecy(&params(
+            "Noise_K_25519_AESGCM_BLAKE2b"
+        )));
+        assert!(!has_forward_secrecy(&params(
+            "Noise_N_25519_ChaChaPoly_BLAKE2s"
+        )));
+        assert!(check_forward_secrecy(Cipher::ChaChaPoly, true).is_ok());
+    }
+
     #[test]
     fn snow() -> Result<(), Box<dyn std::error::Error>> {
         static PATTERN: &str = "Noise_NN_25519_ChaChaPoly_BLAKE2s";
@@ -601,4 +2830,55 @@ mod tests {
         let _responder = responder.into_transport_mode().unwrap();
         Ok(())
     }
+
+    #[test]
+    fn draining_delivers_responses_in_flight() {
+        let result = async {
+            let (local, remote) = UnixStream::pair()?;
+            let mut server = yamux::Connection::new(local, Default::default(), yamux::Mode::Server);
+            let mut client =
+                yamux::Connection::new(remote, Default::default(), yamux::Mode::Client);
+            let mut outbound = poll_fn(|cx| client.poll_new_outbound(cx)).await?;
+
+            let serve = async {
+                let mut stream = poll_fn(|cx| server.poll_next_inbound(cx)).await.unwrap()?;
+                let in_flight = InFlight::new();
+                let respond = in_flight.track(async move {
+                    let mut request = [0; 4];
+                    stream.read_exact(&mut request).await?;
+                    // The connection starts failing while this is being prepared
+                    Timer::after(Duration::from_millis(50)).await;
+                    stream.write_all(&[7; 100_000]).await?;
+                    stream.close().await
+                });
+                let close = async {
+                    drain(&mut server, in_flight, Duration::from_secs(5)).await;
+                    let _ = poll_fn(|cx| server.poll_close(cx)).await;
+                };
+                let (responded, ()) = smol::future::zip(respond, close).await;
+                responded?;
+                anyhow::Ok(())
+            };
+            let request = async {
+                outbound.write_all(b"ping").await?;
+                let mut response = Vec::new();
+                outbound.read_to_end(&mut response).await?;
+                io::Result::Ok(response)
+            };
+            let drive_client = async {
+                while let Some(Ok(_)) = poll_fn(|cx| client.poll_next_inbound(cx)).await {}
+                smol::future::pending().await
+            };
+            let (served, response) = smol::future::zip(serve, request.or(drive_client)).await;
+            served?;
+            assert_eq!(response?.len(), 100_000);
+
+            assert!(is_hard_disconnect(&yamux::ConnectionError::Io(
+                ErrorKind::ConnectionReset.into()
+            )));
+            assert!(!is_hard_disconnect(&yamux::ConnectionError::TooManyStreams));
+            anyhow::Ok(())
+        };
+        block_on(result).unwrap();
+    }
 }
@@ -0,0 +1,88 @@
+use std::{
+    cell::{Cell, RefCell},
+    ops::{Deref, DerefMut},
+};
+
+/// Most buffers kept around per thread, anything above that is freed
+const MAX_POOLED: usize = 16;
+
+thread_local! {
+    static FREE: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+    static HITS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Zeroed buffer of `len` bytes, reusing a previously dropped one if possible
+pub fn take(len: usize) -> PooledBuffer {
+    let mut buf = match FREE.with_borrow_mut(Vec::pop) {
+        Some(buf) => {
+            HITS.set(HITS.get() + 1);
+            buf
+        }
+        None => Vec::new(),
+    };
+    buf.resize(len, 0);
+    PooledBuffer(buf)
+}
+
+/// Number of times [`take`] reused a buffer on this thread
+#[cfg(test)]
+pub fn hits() -> usize {
+    HITS.get()
+}
+
+/// Buffer that goes back to the pool when dropped. Contents are cleared first,
+/// so data of one connection is never visible to the next one
+#[derive(Debug)]
+pub struct PooledBuffer(Vec<u8>);
+
+impl Deref for PooledBuffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let mut buf = std::mem::take(&mut self.0);
+        buf.clear();
+        FREE.with_borrow_mut(|free| {
+            if free.len() < MAX_POOLED {
+                free.push(buf);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dropped_buffer_is_reused() {
+        let mut buf = take(16);
+        buf.copy_from_slice(&[1; 16]);
+        let ptr = buf.as_ptr();
+        drop(buf);
+
+        let hits = hits();
+        let buf = take(8);
+        assert_eq!(super::hits(), hits + 1);
+        assert_eq!(buf.as_ptr(), ptr);
+        assert_eq!(buf.as_slice(), &[0; 8]);
+    }
+
+    #[test]
+    fn pool_is_capped() {
+        let bufs: Vec<_> = (0..MAX_POOLED * 2).map(|_| take(1)).collect();
+        drop(bufs);
+        assert_eq!(FREE.with_borrow(Vec::len), MAX_POOLED);
+    }
+}
@@ -0,0 +1,91 @@
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::{Component, Path, PathBuf},
+};
+
+/// Resolves a path requested by a peer to a path inside the share at `root`.
+/// Only plain names are followed, and symlinks leading out of the share are
+/// refused, so the result never escapes it.
+///
+/// When `case_insensitive`, a name without an exact match matches an entry of
+/// its dir that differs only in case, the real on-disk name is returned. An
+/// exact match always wins
+pub fn resolve(root: &Path, requested: &Path, case_insensitive: bool) -> io::Result<PathBuf> {
+    let root = fs::canonicalize(root)?;
+    let mut path = root.to_path_buf();
+    for component in requested.components() {
+        match component {
+            Component::RootDir | Component::CurDir => {}
+            Component::Normal(name) => {
+                let exact = path.join(name);
+                path = match case_insensitive && fs::symlink_metadata(&exact).is_err() {
+                    true => find_ignoring_case(&path, name.to_string_lossy().as_ref())?,
+                    false => exact,
+                };
+            }
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    "Path leads outside of the share",
+                ));
+            }
+        }
+    }
+
+    let resolved = fs::canonicalize(&path)?;
+    if !resolved.starts_with(&root) {
+        return Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            "Path leads outside of the share",
+        ));
+    }
+    Ok(path)
+}
+
+/// Reads the whole dir, the smallest matching name is picked if several differ
+/// only in case so that the result doesnt depend on the dir order
+fn find_ignoring_case(dir: &Path, name: &str) -> io::Result<PathBuf> {
+    let name = name.to_lowercase();
+    let mut found = None;
+    for entry in fs::read_dir(dir)? {
+        let entry_name = entry?.file_name();
+        if entry_name.to_string_lossy().to_lowercase() == name
+            && found.as_ref().is_none_or(|found| entry_name < *found)
+        {
+            found = Some(entry_name);
+        }
+    }
+    found
+        .map(|found| dir.join(found))
+        .ok_or_else(|| ErrorKind::NotFound.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn resolves_ignoring_case() {
+        let root = TestDir::new("resolve");
+        fs::create_dir_all(root.join("Photos")).unwrap();
+        fs::write(root.join("Photos/photo.jpg"), []).unwrap();
+        fs::write(root.join("Photos/PHOTO.png"), []).unwrap();
+        fs::write(root.join("Photos/photo.png"), []).unwrap();
+        let canonical = fs::canonicalize(&root).unwrap();
+
+        let resolved = resolve(&root, Path::new("photos/PHOTO.JPG"), true).unwrap();
+        assert_eq!(resolved, canonical.join("Photos/photo.jpg"));
+        assert!(resolve(&root, Path::new("photos/PHOTO.JPG"), false).is_err());
+        // exact matches win over ones differing in case
+        let resolved = resolve(&root, Path::new("/Photos/photo.png"), true).unwrap();
+        assert_eq!(resolved, canonical.join("Photos/photo.png"));
+
+        assert!(resolve(&root, Path::new("Photos/../../etc"), true).is_err());
+        symlink("/etc", root.join("Photos/Outside")).unwrap();
+        assert!(resolve(&root, Path::new("photos/outside"), true).is_err());
+    }
+}
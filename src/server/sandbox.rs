@@ -0,0 +1,68 @@
+use std::path::Path;
+
+use landlock::{
+    ABI, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr, RulesetError, RulesetStatus,
+    path_beneath_rules,
+};
+
+/// Newest Landlock features used, older kernels enforce what they support
+const LANDLOCK_ABI: ABI = ABI::V5;
+
+/// Limits the filesystem access of the calling thread, and the threads and
+/// processes it starts later, to reading `read_only` and to anything within
+/// `read_write`. Paths that dont exist are left out. Returns `false` when the
+/// kernel doesnt support Landlock and nothing got restricted
+pub fn restrict<R, W>(read_only: R, read_write: W) -> Result<bool, RulesetError>
+where
+    R: IntoIterator<Item: AsRef<Path>>,
+    W: IntoIterator<Item: AsRef<Path>>,
+{
+    let status = Ruleset::default()
+        .handle_access(AccessFs::from_all(LANDLOCK_ABI))?
+        .create()?
+        .add_rules(path_beneath_rules(
+            read_only,
+            AccessFs::from_read(LANDLOCK_ABI),
+        ))?
+        .add_rules(path_beneath_rules(
+            read_write,
+            AccessFs::from_all(LANDLOCK_ABI),
+        ))?
+        .restrict_self()?;
+    Ok(status.ruleset != RulesetStatus::NotEnforced)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::ErrorKind};
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn access_outside_the_shares_is_denied() {
+        let dir = TestDir::new("sandbox");
+        fs::create_dir_all(dir.join("share")).unwrap();
+        fs::create_dir_all(dir.join("tmp")).unwrap();
+        fs::write(dir.join("share/shared"), "yes").unwrap();
+        fs::write(dir.join("outside"), "no").unwrap();
+
+        // Landlock restricts only the thread applying it, not the whole test run
+        std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    if !restrict([dir.join("share")], [dir.join("tmp")]).unwrap() {
+                        // Landlock isnt supported by this kernel
+                        return;
+                    }
+                    assert_eq!(fs::read_to_string(dir.join("share/shared")).unwrap(), "yes");
+                    fs::write(dir.join("tmp/written"), "").unwrap();
+                    let denied = |err: std::io::Error| err.kind() == ErrorKind::PermissionDenied;
+                    assert!(fs::read(dir.join("outside")).is_err_and(denied));
+                    assert!(fs::write(dir.join("share/written"), "").is_err_and(denied));
+                })
+                .join()
+                .unwrap()
+        });
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    fs,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result as AnyResult, bail};
+use clap::Parser;
+use smol::{future::FutureExt, net::TcpListener};
+use smol_timeout::TimeoutExt;
+
+use crate::{
+    args::Args,
+    common::shares::{FullShareName, ShareName},
+    server::{
+        Server,
+        messages::{PeerMessage, PeerResponse},
+        state::Share,
+    },
+};
+
+/// Dir under the tmp dir the selftest works in, removed afterwards
+const SELFTEST_DIR: &str = "selftest";
+const SHARE_NAME: &str = "selftest";
+/// The one file of the share, listed and read back through the mount
+const FILE_NAME: &str = "hello.txt";
+const FILE_CONTENTS: &[u8] = b"Hello from rdir selftest";
+/// A selftest hanging for longer has failed as well
+const SELFTEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Step of the selftest along with how long it took
+pub type Timing = (&'static str, Duration);
+
+/// Runs `rdir selftest mount`, printing how long each step took
+pub fn run(tmp_dir: &Path) -> AnyResult<()> {
+    let timings = mount(tmp_dir)?;
+    for (step, elapsed) in &timings {
+        println!("{step}: {}ms", elapsed.as_millis());
+    }
+    println!("Selftest passed");
+    Ok(())
+}
+
+/// Mounts a share of one server in this process from another one over
+/// loopback TCP, Noise and yamux like any remote peer would. Then lists the
+/// root of the share and reads its file through the mount. Both servers keep
+/// their files in a dir of their own under `tmp_dir`
+pub fn mount(tmp_dir: &Path) -> AnyResult<Vec<Timing>> {
+    let dir = tmp_dir.join(SELFTEST_DIR);
+    // Left behind by a selftest that got killed
+    let _ = fs::remove_dir_all(&dir);
+    let result = mount_in(&dir);
+    let _ = fs::remove_dir_all(&dir);
+    result
+}
+
+fn mount_in(dir: &Path) -> AnyResult<Vec<Timing>> {
+    let (share_path, mount_path) = (dir.join("share"), dir.join("mnt"));
+    let server = |name: &str| {
+        let tmp_dir = dir.join(name).to_string_lossy().to_string();
+        Server::in_memory(Args::parse_from(["rdir", "--tmpdir", &tmp_dir, "ls"]))
+    };
+    let (owner, mounter) = (server("owner"), server("mounter"));
+    for path in [
+        &share_path,
+        &mount_path,
+        &owner.args.tmp_dir,
+        &mounter.args.tmp_dir,
+    ] {
+        fs::create_dir_all(path)
+            .with_context(|| format!("Failed to create {}", path.to_string_lossy()))?;
+    }
+    fs::write(share_path.join(FILE_NAME), FILE_CONTENTS)?;
+    let share = Share::new(SHARE_NAME.parse()?, share_path);
+    owner.state.borrow_mut().add_share(share)?;
+
+    let mut timings = Vec::new();
+    // Mounting takes a large future, too large for the stack of a test thread
+    let result = Box::pin(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let accept = async {
+            loop {
+                let (stream, _) = listener.accept().await?;
+                owner.ex.spawn(owner.clone().handle_peer(stream)).detach();
+            }
+        };
+        let run = async {
+            let name: FullShareName = format!("{addr}/{SHARE_NAME}").parse()?;
+            let mount =
+                mounter.connect_to_remote_share(name.clone(), Some(mount_path), Default::default());
+            timed(&mut timings, "mount", mount).await?;
+            let (name, owner_id) = mounter
+                .state
+                .borrow()
+                .resolve_remote_share(&ShareName::Full(name))?
+                .context("Selftest lost its mount")?;
+
+            let list = PeerMessage::ReadDir {
+                share: name.name.clone(),
+                rel_path: String::new(),
+            };
+            let list = mounter.request_peer(owner_id, &list);
+            match timed(&mut timings, "list the share", list).await? {
+                PeerResponse::DirEntries(entries)
+                    if entries.iter().any(|entry| entry.name == FILE_NAME) => {}
+                response => bail!("Selftest listed the share as {response:?}"),
+            }
+
+            let read = PeerMessage::ReadFile {
+                share: name.name,
+                rel_path: FILE_NAME.to_string(),
+                offset: 0,
+                len: FILE_CONTENTS.len() as u32,
+            };
+            let read = mounter.request_peer(owner_id, &read);
+            match timed(&mut timings, "read a file", read).await? {
+                PeerResponse::FileData(data) if data == FILE_CONTENTS => {}
+                response => bail!("Selftest read {response:?} from its file"),
+            }
+            mounter.remove_peer(owner_id);
+            anyhow::Ok(())
+        };
+        run.or(accept).await
+    });
+    let run = owner
+        .ex
+        .run(mounter.ex.run(result.timeout(SELFTEST_TIMEOUT)));
+    smol::block_on(run).context("Selftest timed out")??;
+    Ok(timings)
+}
+
+/// Awaits `fut`, noting how long the `step` took when it succeeds
+async fn timed<T, E>(
+    timings: &mut Vec<Timing>,
+    step: &'static str,
+    fut: impl Future<Output = Result<T, E>>,
+) -> AnyResult<T>
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let start = Instant::now();
+    let value = fut
+        .await
+        .with_context(|| format!("Selftest failed to {step}"))?;
+    timings.push((step, start.elapsed()));
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn selftest_mounts_over_loopback() {
+        let dir = TestDir::new("selftest");
+        let timings = mount(&dir).unwrap();
+        let steps: Vec<_> = timings.iter().map(|(step, _)| *step).collect();
+        assert_eq!(steps, ["mount", "list the share", "read a file"]);
+        assert!(!dir.join(SELFTEST_DIR).exists());
+    }
+}
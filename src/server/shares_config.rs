@@ -0,0 +1,208 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result as AnyResult, bail};
+use derive_more::Display;
+
+use crate::{
+    common::shares::{CommonShareName, CommonShareNameParseError},
+    server::state::{Share, State},
+};
+
+/// Reads the shares of a peer only server. Each line is `NAME=PATH`, blank lines
+/// and `#` comments are ignored. Paths are resolved right away, relative ones
+/// wouldnt survive the daemon changing its working dir
+pub fn load(path: &Path) -> AnyResult<Vec<Share>> {
+    let content = fs::read_to_string(path).context(format!(
+        "Failed to read the shares config at: {}",
+        path.to_string_lossy()
+    ))?;
+    parse(&content, path)
+}
+
+fn parse(content: &str, path: &Path) -> AnyResult<Vec<Share>> {
+    let (shares, problems) = check(content);
+    match problems.into_iter().find(ConfigProblem::is_fatal) {
+        Some(problem) => bail!("{problem} of {}", path.to_string_lossy()),
+        None => Ok(shares),
+    }
+}
+
+/// Something wrong with a line of a shares config
+#[derive(Debug, Display)]
+pub enum ConfigProblem {
+    #[display("Expected NAME=PATH on line {line}")]
+    Syntax { line: usize },
+    #[display("{err} on line {line}")]
+    InvalidName {
+        line: usize,
+        err: CommonShareNameParseError,
+    },
+    #[display("Cant resolve {path}: {err} on line {line}")]
+    BadPath {
+        line: usize,
+        path: String,
+        err: io::Error,
+    },
+    #[display("Share {name} is already defined on line {first} but again on line {line}")]
+    DuplicateName {
+        line: usize,
+        first: usize,
+        name: CommonShareName,
+    },
+    #[display("{} is already shared on line {first} but again on line {line}", path.to_string_lossy())]
+    DuplicatePath {
+        line: usize,
+        first: usize,
+        path: PathBuf,
+    },
+    #[display("{} overlaps the share on line {other} on line {line}", path.to_string_lossy())]
+    Overlap {
+        line: usize,
+        other: usize,
+        path: PathBuf,
+    },
+}
+
+impl ConfigProblem {
+    /// Overlapping shares only get a warning from `share share`, so they dont
+    /// stop the server either
+    pub fn is_fatal(&self) -> bool {
+        !matches!(self, Self::Overlap { .. })
+    }
+}
+
+/// Goes through the whole config instead of stopping at the first problem,
+/// returns the shares that are fine along with every problem found
+pub fn check(content: &str) -> (Vec<Share>, Vec<ConfigProblem>) {
+    let mut state = State::default();
+    let mut lines = BTreeMap::new();
+    let mut problems = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line_no = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((name, share_path)) = line.split_once('=') else {
+            problems.push(ConfigProblem::Syntax { line: line_no });
+            continue;
+        };
+        let name = CommonShareName::local(name.trim());
+        let share_path =
+            fs::canonicalize(share_path.trim()).map_err(|err| ConfigProblem::BadPath {
+                line: line_no,
+                path: share_path.trim().to_string(),
+                err,
+            });
+        let (name, share_path) = match (name, share_path) {
+            (Ok(name), Ok(share_path)) => (name, share_path),
+            (name, share_path) => {
+                if let Err(err) = name {
+                    problems.push(ConfigProblem::InvalidName { line: line_no, err });
+                }
+                if let Err(problem) = share_path {
+                    problems.push(problem);
+                }
+                continue;
+            }
+        };
+
+        if let Some(&first) = lines.get(&name) {
+            problems.push(ConfigProblem::DuplicateName {
+                line: line_no,
+                first,
+                name,
+            });
+            continue;
+        }
+        let mut overlapping = state.overlapping_shares(&share_path);
+        overlapping.sort_by_key(|other| lines[other]);
+        if let Some(other) = overlapping.first() {
+            let first = lines[other];
+            match state
+                .get_shares()
+                .get(other)
+                .is_some_and(|other| other.path == share_path)
+            {
+                true => {
+                    problems.push(ConfigProblem::DuplicatePath {
+                        line: line_no,
+                        first,
+                        path: share_path,
+                    });
+                    continue;
+                }
+                false => problems.push(ConfigProblem::Overlap {
+                    line: line_no,
+                    other: first,
+                    path: share_path.clone(),
+                }),
+            }
+        }
+        lines.insert(name.clone(), line_no);
+        state
+            .add_share(Share::new(name, share_path))
+            .expect("duplicate names are checked above");
+    }
+    let mut shares: Vec<_> = state.into_shares().collect();
+    shares.sort_by_key(|share| lines[&share.name]);
+    (shares, problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_shares() {
+        let content = "# served to everyone\n\nphotos = /\n  docs=/tmp  \n";
+        let shares = parse(content, Path::new("shares.conf")).unwrap();
+        let shares: Vec<_> = shares
+            .iter()
+            .map(|share| (share.name.to_string(), share.path.clone()))
+            .collect();
+        assert_eq!(
+            shares,
+            [
+                ("photos".to_string(), "/".into()),
+                ("docs".to_string(), fs::canonicalize("/tmp").unwrap())
+            ]
+        );
+
+        assert!(parse("photos", Path::new("shares.conf")).is_err());
+        assert!(parse("photos=/does/not/exist", Path::new("shares.conf")).is_err());
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let long_name = "x".repeat(300);
+        let content =
+            format!("photos=/tmp\nphotos=/usr\n{long_name}=/tmp\nbin=/usr/bin\nusr=/usr\n");
+        let (shares, problems) = check(&content);
+        assert!(
+            matches!(
+                problems[..],
+                [
+                    ConfigProblem::DuplicateName {
+                        line: 2,
+                        first: 1,
+                        ..
+                    },
+                    ConfigProblem::InvalidName { line: 3, .. },
+                    ConfigProblem::Overlap {
+                        line: 5,
+                        other: 4,
+                        ..
+                    },
+                ]
+            ),
+            "{problems:?}"
+        );
+        assert_eq!(shares.len(), 3);
+        assert!(parse(&content, Path::new("shares.conf")).is_err());
+    }
+}
@@ -1,16 +1,31 @@
 use std::{
     collections::{BTreeMap, BTreeSet, btree_map::Entry},
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, ErrorKind, Write},
     net::SocketAddrV4,
-    path::PathBuf,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
+    time::Instant,
 };
 
 use bitcode::{Decode, Encode};
 use derive_more::{Display, Eq, Error, From, IsVariant, PartialEq};
-use smol::channel::Sender;
-
-use crate::common::{
-    PeersDto, RemoteShareDto, RemoteSharesDto, ShareDto, SharesDto,
-    shares::{CommonShareName, FullShareName, RemotePeerAddr},
+use smol::channel::{Receiver, Sender, bounded};
+use tracing::warn;
+
+use crate::{
+    common::{
+        DirEntryDto, MountOptions, PeersDto, RemoteShareDto, RemoteSharesDto, ShareDto,
+        ShareOptions, SharesDto, ShutdownReason,
+        shares::{CommonShareName, FullShareName, ShareName},
+    },
+    server::{
+        content_hash::{ContentHash, HashCache},
+        filter::PathFilter,
+        messages::FileAttrs,
+        resolve,
+    },
 };
 
 #[derive(Debug, Default)]
@@ -22,6 +37,15 @@ pub struct State {
     remote_shares: BTreeMap<FullShareName, RemoteShare>,
 }
 
+/// Part of a share that outlives the server, peers and expiry dont
+#[derive(Encode, Decode)]
+struct PersistedShare {
+    name: CommonShareName,
+    /// Raw bytes, so paths that arent UTF-8 come back unchanged
+    path: Vec<u8>,
+    options: ShareOptions,
+}
+
 /// Helper macro to generate a new PeerId
 /// Sometimes I want to create a new PeerId while already holding a ref mut to
 /// another field of the State, hence another method does not work
@@ -39,6 +63,63 @@ macro_rules! new_peer_id {
 }
 
 impl State {
+    /// Writes the shares to `path`, except expiring ones. The file is replaced
+    /// atomically, so a crash leaves either the old or the new one
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let shares: Vec<_> = self
+            .shares
+            .values()
+            .filter(|share| share.expires_at.is_none())
+            .map(|share| PersistedShare {
+                name: share.name.clone(),
+                path: share.path.as_os_str().as_bytes().to_vec(),
+                options: share.options.clone(),
+            })
+            .collect();
+        let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(".tmp");
+        let tmp_path = path.with_file_name(tmp_name);
+
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&bitcode::encode(&shares))?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        // Makes the rename itself durable
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// State with the shares saved at `path`. A missing file starts fresh, and
+    /// so does a corrupt one, with a warning
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err),
+        };
+        let shares: Vec<PersistedShare> = match bitcode::decode(&bytes) {
+            Ok(shares) => shares,
+            Err(err) => {
+                warn!(
+                    "Ignoring the corrupt state file {}: {err}",
+                    path.to_string_lossy()
+                );
+                return Ok(Self::default());
+            }
+        };
+        let mut state = Self::default();
+        for persisted in shares {
+            let path = OsString::from_vec(persisted.path).into();
+            let mut share = Share::new(persisted.name, path);
+            share.set_options(persisted.options);
+            // Names were unique when saved
+            let _ = state.add_share(share);
+        }
+        Ok(state)
+    }
+
     pub fn get_peers(&self) -> &BTreeMap<PeerId, Peer> {
         &self.peers
     }
@@ -51,6 +132,10 @@ impl State {
         &self.shares
     }
 
+    pub fn into_shares(self) -> impl Iterator<Item = Share> {
+        self.shares.into_values()
+    }
+
     pub fn get_remote_shares(&self) -> &BTreeMap<FullShareName, RemoteShare> {
         &self.remote_shares
     }
@@ -82,7 +167,37 @@ impl State {
     }
 
     pub fn shares_dto(&self) -> SharesDto {
-        SharesDto(self.shares.values().map(ShareDto::from).collect())
+        self.shares_dto_where(|_| true)
+    }
+
+    pub fn shares_dto_where(&self, predicate: impl Fn(&Share) -> bool) -> SharesDto {
+        SharesDto(
+            self.shares
+                .values()
+                .filter(|share| predicate(share))
+                .map(|share| ShareDto {
+                    reexports: self.reexported_remote(share).cloned(),
+                    ..ShareDto::from(share)
+                })
+                .collect(),
+        )
+    }
+
+    /// Mounted remote share the share lies in, sharing it passes the remote on
+    pub fn reexported_remote(&self, share: &Share) -> Option<&FullShareName> {
+        self.remote_shares
+            .iter()
+            .find(|(_, remote)| share.path.starts_with(&remote.mount_path))
+            .map(|(name, _)| name)
+    }
+
+    /// Shares nobody is connected to
+    pub fn idle_shares(&self) -> Vec<&CommonShareName> {
+        self.shares
+            .values()
+            .filter(|share| share.participants.is_empty())
+            .map(|share| &share.name)
+            .collect()
     }
 
     pub fn new_peer_connected_to_share(
@@ -111,22 +226,21 @@ impl State {
         Ok(peer_id)
     }
 
-    /// Must not be called after peer was dropped
     pub fn peer_connected_to_share(
         &mut self,
         peer_id: PeerId,
         share_name: CommonShareName,
     ) -> Result<(), PeerConnectedToShareError> {
+        let peer = self.peers.get_mut(&peer_id).ok_or(PeerDoesntExistError)?;
         let share = match self.shares.get_mut(&share_name) {
             Some(val) => val,
             None => return Err(ShareDoesntExistError.into()),
         };
+        if share.participants.contains(&peer_id) {
+            return Err(RepeatedPeerError.into());
+        }
 
-        self.peers
-            .get_mut(&peer_id)
-            .unwrap()
-            .used_shares
-            .insert(share_name);
+        peer.used_shares.insert(share_name);
         let res = share.participants.insert(peer_id);
         debug_assert!(res);
         Ok(())
@@ -152,30 +266,68 @@ impl State {
         Ok(())
     }
 
-    pub fn kick_peer_from_share(
+    /// Replaces a peer that reconnected with `new_peer`, joined to `shares`, in one
+    /// step so the old entry never lingers next to the new one. Mounted remote
+    /// shares of the old peer move over too
+    pub fn re_attach_peer(
         &mut self,
-        peer_id: PeerId,
-        share_name: CommonShareName,
-    ) -> Result<(), KickPeerFromShareError> {
-        let peer = self.peers.get_mut(&peer_id).unwrap();
-        let share = self
-            .shares
-            .get_mut(&share_name)
-            .ok_or(ShareDoesntExistError)?;
-        if !share.participants.remove(&peer_id) {
-            return Err(PeerNotUsingShareError.into());
+        old: PeerId,
+        mut new_peer: Peer,
+        shares: &[CommonShareName],
+    ) -> Result<PeerId, ReAttachPeerError> {
+        let old_address = self.peers.get(&old).ok_or(PeerDoesntExistError)?.address;
+        if new_peer.address != old_address && self.peers_by_socket.contains_key(&new_peer.address) {
+            return Err(RepeatedPeerError.into());
         }
-        let res = peer.used_shares.remove(&share_name);
-        debug_assert!(res);
-        peer.notification_tx
-            .try_send(StateNotification::KickedFromShare(share_name))
-            .unwrap();
-        self.try_drop_peer(peer_id);
-        Ok(())
+        if shares.iter().any(|name| !self.shares.contains_key(name)) {
+            return Err(ShareDoesntExistError.into());
+        }
+
+        // all checks passed, now modifying
+        let old_peer = self.peers.remove(&old).unwrap();
+        self.peers_by_socket.remove(&old_peer.address);
+        for name in &old_peer.used_shares {
+            let res = self.shares.get_mut(name).unwrap().participants.remove(&old);
+            debug_assert!(res);
+        }
+        let _ = old_peer.shutdown_tx.try_send(());
+
+        let peer_id = new_peer_id!(self);
+        for name in shares {
+            self.shares
+                .get_mut(name)
+                .unwrap()
+                .participants
+                .insert(peer_id);
+            new_peer.used_shares.insert(name.clone());
+        }
+        for name in &old_peer.used_remote_shares {
+            self.remote_shares.get_mut(name).unwrap().owner = peer_id;
+        }
+        new_peer.used_remote_shares = old_peer.used_remote_shares;
+        self.peers_by_socket.insert(new_peer.address, peer_id);
+        self.peers.insert(peer_id, new_peer);
+        Ok(peer_id)
     }
 
-    pub fn remove_peer(&mut self, peer_id: PeerId) -> Result<(), KickPeerFromShareError> {
-        todo!()
+    /// Drops a peer along with everything it still uses, returns the shares
+    /// it was connected to
+    pub fn remove_peer(
+        &mut self,
+        peer_id: PeerId,
+    ) -> Result<BTreeSet<CommonShareName>, PeerDoesntExistError> {
+        let peer = self.peers.remove(&peer_id).ok_or(PeerDoesntExistError)?;
+        self.peers_by_socket.remove(&peer.address);
+        for name in &peer.used_shares {
+            if let Some(share) = self.shares.get_mut(name) {
+                share.participants.remove(&peer_id);
+            }
+        }
+        for name in &peer.used_remote_shares {
+            self.remote_shares.remove(name);
+        }
+        let _ = peer.shutdown_tx.try_send(());
+        Ok(peer.used_shares)
     }
 
     /// removes a peer if it can
@@ -187,7 +339,9 @@ impl State {
             Entry::Occupied(entry) => {
                 let peer = entry.get();
                 if peer.used_shares.len() + peer.used_remote_shares.len() == 0 {
-                    let _ = entry.remove_entry().1.shutdown_tx.try_send(());
+                    let peer = entry.remove();
+                    self.peers_by_socket.remove(&peer.address);
+                    let _ = peer.shutdown_tx.try_send(());
                     true
                 } else {
                     false
@@ -196,6 +350,16 @@ impl State {
         }
     }
 
+    /// Shares whose dir lies inside `path` or contains it, peers could reach the
+    /// same files through each of them
+    pub fn overlapping_shares(&self, path: &Path) -> Vec<CommonShareName> {
+        self.shares
+            .values()
+            .filter(|share| share.path.starts_with(path) || path.starts_with(&share.path))
+            .map(|share| share.name.clone())
+            .collect()
+    }
+
     pub fn add_share(&mut self, share: Share) -> Result<(), RepeatedShare> {
         let common_name = share.name.clone();
         let entry = self.shares.entry(common_name);
@@ -208,10 +372,30 @@ impl State {
         }
     }
 
+    /// Points a share at a new dir, returning the previous one. With
+    /// `expected_current`, only updates if the share still points there
+    pub fn update_share_path(
+        &mut self,
+        name: &CommonShareName,
+        path: PathBuf,
+        expected_current: Option<&Path>,
+    ) -> Result<PathBuf, UpdateSharePathError> {
+        let share = self.shares.get_mut(name).ok_or(ShareDoesntExistError)?;
+        if let Some(expected) = expected_current
+            && share.path != expected
+        {
+            return Err(PreconditionFailedError {
+                actual: share.path.to_string_lossy().to_string(),
+            }
+            .into());
+        }
+        Ok(std::mem::replace(&mut share.path, path))
+    }
+
     pub fn remove_share(
         &mut self,
         name: &CommonShareName,
-        shutdown_tx: &async_broadcast::Sender<()>,
+        shutdown_tx: &async_broadcast::Sender<ShutdownReason>,
     ) -> Result<(), ShareDoesntExistError> {
         let (name, share) = self
             .shares
@@ -219,12 +403,16 @@ impl State {
             .ok_or(ShareDoesntExistError)?;
 
         for participant_id in share.participants {
-            let peer = self.peers.get_mut(&participant_id).unwrap();
-            let res = peer.used_shares.remove(&name);
-            assert!(res);
-            peer.notification_tx
-                .try_send(StateNotification::KickedFromShare(name.clone()))
-                .unwrap();
+            // The peer could have been dropped on another path while this share
+            // still listed it, there is nobody left to kick then
+            let Some(peer) = self.peers.get_mut(&participant_id) else {
+                continue;
+            };
+            peer.used_shares.remove(&name);
+            // A peer whose handler already quit doesnt need to be told
+            let _ = peer
+                .notification_tx
+                .try_send(StateNotification::KickedFromShare(name.clone()));
             self.try_drop_peer(participant_id);
         }
 
@@ -237,6 +425,7 @@ impl State {
         mut peer: Peer,
         name: FullShareName,
         mount_path: PathBuf,
+        options: MountOptions,
     ) -> Result<PeerId, RepeatedRemoteShareError> {
         debug_assert!(!self.peers_by_socket.contains_key(&peer.address));
         let Entry::Vacant(entry) = self.remote_shares.entry(name) else {
@@ -247,8 +436,9 @@ impl State {
         let name = entry.key().clone();
         let remote_share = RemoteShare {
             owner: peer_id,
-            name: name.name.clone(),
+            name: name.name.without_namespace(),
             mount_path,
+            options,
         };
         entry.insert(remote_share);
 
@@ -263,6 +453,7 @@ impl State {
         peer_id: PeerId,
         name: FullShareName,
         mount_path: PathBuf,
+        options: MountOptions,
     ) -> Result<(), RepeatedRemoteShareError> {
         let Entry::Vacant(entry) = self.remote_shares.entry(name) else {
             return Err(RepeatedRemoteShareError);
@@ -271,8 +462,9 @@ impl State {
         let name = entry.key().clone();
         let remote_share = RemoteShare {
             owner: peer_id,
-            name: name.name.clone(),
+            name: name.name.without_namespace(),
             mount_path,
+            options,
         };
         entry.insert(remote_share);
 
@@ -284,12 +476,42 @@ impl State {
         Ok(())
     }
 
+    /// Mounted remote share called `name` and the peer it is mounted from. A
+    /// common name has to match a single mount
+    pub fn resolve_remote_share(
+        &self,
+        name: &ShareName,
+    ) -> Result<Option<(FullShareName, PeerId)>, AmbiguousShareNameError> {
+        let name = match name {
+            ShareName::Full(name) => {
+                return Ok(self
+                    .remote_shares
+                    .get(name)
+                    .map(|remote_share| (name.clone(), remote_share.owner)));
+            }
+            ShareName::Common(name) => name,
+        };
+        let mut matching = self
+            .remote_shares
+            .iter()
+            .filter(|(full_name, remote_share)| {
+                remote_share.name == *name || full_name.name == *name
+            });
+        match (matching.next(), matching.next()) {
+            (Some((full_name, remote_share)), None) => {
+                Ok(Some((full_name.clone(), remote_share.owner)))
+            }
+            (None, _) => Ok(None),
+            (Some(_), Some(_)) => Err(AmbiguousShareNameError { name: name.clone() }),
+        }
+    }
+
     /// Return whether server should shut down
     pub fn exit_remote_share(
         &mut self,
         peer_id: PeerId,
         remote_share_name: FullShareName,
-        shutdown_tx: &async_broadcast::Sender<()>,
+        shutdown_tx: &async_broadcast::Sender<ShutdownReason>,
     ) -> Result<(), ExitPeerShareError> {
         let peer = self.peers.get_mut(&peer_id).unwrap();
         if !peer.used_remote_shares.remove(&remote_share_name) {
@@ -302,9 +524,9 @@ impl State {
         Ok(())
     }
 
-    pub fn should_server_close(&self, shutdown_tx: &async_broadcast::Sender<()>) {
+    pub fn should_server_close(&self, shutdown_tx: &async_broadcast::Sender<ShutdownReason>) {
         if self.peers.is_empty() && self.shares.is_empty() {
-            let _ = shutdown_tx.try_broadcast(());
+            let _ = shutdown_tx.try_broadcast(ShutdownReason::Idle);
         }
     }
 }
@@ -333,6 +555,13 @@ pub struct RepeatedRemoteShareError;
 #[display("Specified remote share doesnt exist")]
 pub struct NoSuchRemoteShareError;
 
+#[derive(Encode, Decode, Clone, Debug, Display, Error, PartialEq, Eq)]
+#[display("Several mounted shares are named {name}, specify it as <IP>/<NAME>")]
+pub struct AmbiguousShareNameError {
+    #[error(ignore)]
+    pub name: CommonShareName,
+}
+
 #[derive(Encode, Decode, Clone, Debug, Display, Error, From, PartialEq, Eq, IsVariant)]
 #[display("New peer failed to connect to a share")]
 pub enum NewPeerConnectedToShareError {
@@ -344,19 +573,21 @@ pub enum NewPeerConnectedToShareError {
 #[display("Peer failed to connect to a share")]
 pub enum PeerConnectedToShareError {
     PeerDoesntExist(PeerDoesntExistError),
+    RepeatedPeer(RepeatedPeerError),
     ShareDoesntExist(ShareDoesntExistError),
 }
 
 #[derive(Encode, Decode, Clone, Debug, Display, Error, From, PartialEq, Eq, IsVariant)]
-#[display("Couldnt disconnect peer from a share")]
-pub enum PeerDisconnectedFromShareError {
-    PeerNotUsingShare(PeerNotUsingShareError),
+#[display("Failed to re-attach a reconnected peer")]
+pub enum ReAttachPeerError {
+    PeerDoesntExist(PeerDoesntExistError),
+    RepeatedPeer(RepeatedPeerError),
     ShareDoesntExist(ShareDoesntExistError),
 }
 
 #[derive(Encode, Decode, Clone, Debug, Display, Error, From, PartialEq, Eq, IsVariant)]
-#[display("Failed to kick a peer")]
-pub enum KickPeerFromShareError {
+#[display("Couldnt disconnect peer from a share")]
+pub enum PeerDisconnectedFromShareError {
     PeerNotUsingShare(PeerNotUsingShareError),
     ShareDoesntExist(ShareDoesntExistError),
 }
@@ -365,6 +596,35 @@ pub enum KickPeerFromShareError {
 #[display("Share with this name already exists")]
 pub struct RepeatedShare;
 
+#[derive(Encode, Decode, Clone, Debug, Display, Error, PartialEq, Eq)]
+#[display("Share path lies inside or around the shares: {}", join_names(shares))]
+pub struct SharePathOverlapError {
+    #[error(ignore)]
+    pub shares: Vec<CommonShareName>,
+}
+
+fn join_names(names: &[CommonShareName]) -> String {
+    names
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[derive(Encode, Decode, Clone, Debug, Display, Error, PartialEq, Eq)]
+#[display("Share path changed concurrently, it is now {actual}")]
+pub struct PreconditionFailedError {
+    #[error(ignore)]
+    pub actual: String,
+}
+
+#[derive(Encode, Decode, Clone, Debug, Display, Error, From, PartialEq, Eq, IsVariant)]
+#[display("Failed to update the share path")]
+pub enum UpdateSharePathError {
+    ShareDoesntExist(ShareDoesntExistError),
+    PreconditionFailed(PreconditionFailedError),
+}
+
 #[derive(Encode, Decode, Clone, Debug, Display, Error, From, PartialEq, Eq, IsVariant)]
 #[display("Failed to disconnect from a remote share")]
 pub enum ExitPeerShareError {
@@ -398,6 +658,16 @@ impl Peer {
             notification_tx,
         }
     }
+
+    /// Remote shares mounted over the connection to this peer
+    pub fn used_remote_shares(&self) -> &BTreeSet<FullShareName> {
+        &self.used_remote_shares
+    }
+
+    /// Local shares the peer joined
+    pub fn used_shares(&self) -> &BTreeSet<CommonShareName> {
+        &self.used_shares
+    }
 }
 
 #[derive(Debug)]
@@ -405,14 +675,141 @@ pub struct Share {
     pub name: CommonShareName,
     pub path: PathBuf,
     pub participants: BTreeSet<PeerId>,
+    pub options: ShareOptions,
+    filter: PathFilter,
+    pub expires_at: Option<Instant>,
+    /// Never sent on, dropping the share closes the channel
+    _removal_tx: Sender<()>,
+    removal_rx: Receiver<()>,
+    hashes: HashCache,
 }
 
 impl Share {
     pub fn new(name: CommonShareName, path: PathBuf) -> Self {
+        let (removal_tx, removal_rx) = bounded(1);
         Self {
             name,
             path,
             participants: Default::default(),
+            options: Default::default(),
+            filter: Default::default(),
+            expires_at: None,
+            _removal_tx: removal_tx,
+            removal_rx,
+            hashes: Default::default(),
+        }
+    }
+
+    /// Returns a receiver that resolves once this share is removed from the state
+    pub fn removal_signal(&self) -> Receiver<()> {
+        self.removal_rx.clone()
+    }
+
+    /// Whether the shared directory still exists
+    pub fn is_available(&self) -> bool {
+        self.path.is_dir()
+    }
+
+    pub fn set_options(&mut self, options: ShareOptions) {
+        self.filter = PathFilter::new(&options.include, &options.exclude);
+        self.options = options;
+    }
+
+    /// Path of a file in this share requested by a peer. Paths hidden by
+    /// `--include`/`--exclude` are reported as not found
+    pub fn resolve(&self, requested: &Path) -> io::Result<PathBuf> {
+        if !self.filter.is_visible(requested) {
+            return Err(ErrorKind::NotFound.into());
+        }
+        let path = resolve::resolve(&self.path, requested, self.options.case_insensitive)?;
+        // Matching ignoring case could have found a hidden name
+        let relative = path.strip_prefix(fs::canonicalize(&self.path)?);
+        if relative.is_ok_and(|relative| !self.filter.is_visible(relative)) {
+            return Err(ErrorKind::NotFound.into());
+        }
+        Ok(path)
+    }
+
+    /// Names in a dir of this share requested by a peer, without hidden ones
+    pub fn read_dir(&self, requested: &Path) -> io::Result<Vec<OsString>> {
+        let dir = self.resolve(requested)?;
+        let mut names = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name();
+            if self.filter.is_visible(&requested.join(&name)) {
+                names.push(name);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Entries of a dir of this share requested by a peer, sorted by name.
+    /// Symlinks are listed as such, not followed
+    pub fn dir_entries(&self, requested: &Path) -> io::Result<Vec<DirEntryDto>> {
+        self.entries(requested, self.read_dir(requested)?)
+    }
+
+    /// Entries of `names` in a dir of this share, see [`Self::dir_entries`].
+    /// Names removed since they were listed are left out
+    pub fn entries(&self, requested: &Path, names: Vec<OsString>) -> io::Result<Vec<DirEntryDto>> {
+        let dir = self.resolve(requested)?;
+        let mut entries = Vec::with_capacity(names.len());
+        for name in names {
+            let metadata = match fs::symlink_metadata(dir.join(&name)) {
+                Ok(metadata) => metadata,
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            let attrs = FileAttrs::new(&metadata, &self.options);
+            entries.push(DirEntryDto {
+                name: name.to_string_lossy().into_owned(),
+                kind: attrs.kind,
+                size: attrs.size,
+                mtime: attrs.mtime,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Attrs of a file of this share requested by a peer, symlinks arent followed
+    pub fn attrs(&self, requested: &Path) -> io::Result<FileAttrs> {
+        let path = self.resolve(requested)?;
+        Ok(FileAttrs::new(&fs::symlink_metadata(path)?, &self.options))
+    }
+
+    /// Value of an extended attribute of a file in this share. Without
+    /// `--xattrs`, or where they arent supported, files have none
+    pub fn xattr(&self, requested: &Path, name: &str) -> io::Result<Option<Vec<u8>>> {
+        let path = self.resolve(requested)?;
+        if !self.options.xattrs || !xattr::SUPPORTED_PLATFORM {
+            return Ok(None);
+        }
+        match xattr::get(path, name) {
+            Err(err) if err.kind() == ErrorKind::Unsupported => Ok(None),
+            result => result,
+        }
+    }
+
+    /// Content hash of a file in this share, only with `--dedup`
+    pub fn content_hash(&self, requested: &Path) -> io::Result<Option<ContentHash>> {
+        let path = self.resolve(requested)?;
+        if !self.options.dedup {
+            return Ok(None);
+        }
+        self.hashes.get(&path).map(Some)
+    }
+
+    /// Names of the extended attributes of a file in this share, see [`Self::xattr`]
+    pub fn xattr_names(&self, requested: &Path) -> io::Result<Vec<OsString>> {
+        let path = self.resolve(requested)?;
+        if !self.options.xattrs || !xattr::SUPPORTED_PLATFORM {
+            return Ok(Vec::new());
+        }
+        match xattr::list(path) {
+            Ok(names) => Ok(names.collect()),
+            Err(err) if err.kind() == ErrorKind::Unsupported => Ok(Vec::new()),
+            Err(err) => Err(err),
         }
     }
 }
@@ -422,6 +819,14 @@ pub struct RemoteShare {
     owner: PeerId,
     pub name: CommonShareName,
     pub mount_path: PathBuf,
+    pub options: MountOptions,
+}
+
+impl RemoteShare {
+    /// Peer the share is mounted from
+    pub fn owner(&self) -> PeerId {
+        self.owner
+    }
 }
 
 #[derive(Encode, Decode, Clone, Debug, Display, From, IsVariant, PartialEq, Eq)]
@@ -434,7 +839,7 @@ mod tests {
     use async_broadcast::broadcast;
     use smol::channel::{Receiver, unbounded};
 
-    use crate::server::NETWORK_PORT;
+    use crate::{common::ShareAvailability, server::NETWORK_PORT, test_dir::TestDir};
 
     use super::*;
 
@@ -466,6 +871,12 @@ mod tests {
                 let peer = self.peers.get(&connection.owner).unwrap();
                 assert!(peer.used_remote_shares.contains(connection_name));
             }
+
+            // 4. validate sockets
+            assert_eq!(self.peers_by_socket.len(), self.peers.len());
+            for (address, peer_id) in &self.peers_by_socket {
+                assert_eq!(&self.peers.get(peer_id).unwrap().address, address);
+            }
         }
     }
 
@@ -505,6 +916,136 @@ mod tests {
         state.integrity_check();
     }
 
+    #[test]
+    fn filter_shares_by_availability() {
+        let mut state = State::default();
+        let a_name: CommonShareName = "A".parse().unwrap();
+        let b_name: CommonShareName = "B".parse().unwrap();
+        let c_name: CommonShareName = "C".parse().unwrap();
+        state
+            .add_share(Share::new(a_name.clone(), PathBuf::from("/")))
+            .unwrap();
+        state
+            .add_share(Share::new(b_name.clone(), PathBuf::from("/rdir/missing")))
+            .unwrap();
+        state
+            .add_share(Share::new(c_name.clone(), std::env::temp_dir()))
+            .unwrap();
+
+        let names = |availability: ShareAvailability| {
+            state
+                .shares_dto_where(|share| availability.matches(share))
+                .0
+                .into_iter()
+                .map(|share| share.name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(ShareAvailability::Available), [a_name, c_name]);
+        assert_eq!(names(ShareAvailability::Unavailable), [b_name]);
+        assert_eq!(state.shares_dto().0.len(), 3);
+    }
+
+    #[test]
+    fn overlapping_share_paths() {
+        let mut state = State::default();
+        state
+            .add_share(Share::new("A".parse().unwrap(), "/data/sub".into()))
+            .unwrap();
+        let names = |path: &str| {
+            state
+                .overlapping_shares(Path::new(path))
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+        };
+
+        // ancestor, descendant and the same dir
+        assert_eq!(names("/data"), ["A"]);
+        assert_eq!(names("/data/sub/deeper"), ["A"]);
+        assert_eq!(names("/data/sub"), ["A"]);
+        // only whole components count
+        assert!(names("/data/subway").is_empty());
+        assert!(names("/other").is_empty());
+    }
+
+    #[test]
+    fn update_share_path_precondition() {
+        let mut state = State::default();
+        let name: CommonShareName = "A".parse().unwrap();
+        state
+            .add_share(Share::new(name.clone(), "/a".into()))
+            .unwrap();
+
+        let previous = state
+            .update_share_path(&name, "/b".into(), Some(Path::new("/a")))
+            .unwrap();
+        assert_eq!(previous, PathBuf::from("/a"));
+
+        // Someone else already moved it
+        let err = state
+            .update_share_path(&name, "/c".into(), Some(Path::new("/a")))
+            .unwrap_err();
+        assert_eq!(
+            err,
+            PreconditionFailedError {
+                actual: "/b".to_string()
+            }
+            .into()
+        );
+        assert_eq!(state.get_shares()[&name].path, PathBuf::from("/b"));
+
+        state.update_share_path(&name, "/c".into(), None).unwrap();
+        assert_eq!(state.get_shares()[&name].path, PathBuf::from("/c"));
+    }
+
+    #[test]
+    fn share_inside_a_mounted_remote() {
+        let mut state = State::default();
+        let remote_name: FullShareName = "1.1.1.1:29284/R".parse().unwrap();
+        let (peer, _, _) = new_peer(1);
+        let _ = state
+            .join_remote_share_new(
+                peer,
+                remote_name.clone(),
+                PathBuf::from("/mnt/remote"),
+                Default::default(),
+            )
+            .unwrap();
+        let inside: CommonShareName = "A".parse().unwrap();
+        let outside: CommonShareName = "B".parse().unwrap();
+        state
+            .add_share(Share::new(inside.clone(), "/mnt/remote/photos".into()))
+            .unwrap();
+        state
+            .add_share(Share::new(outside.clone(), "/mnt/remote2".into()))
+            .unwrap();
+
+        let shares = state.shares_dto().0;
+        assert_eq!(shares[0].name, inside);
+        assert_eq!(shares[0].reexports, Some(remote_name));
+        assert_eq!(shares[1].name, outside);
+        assert_eq!(shares[1].reexports, None);
+    }
+
+    #[test]
+    fn idle_shares() {
+        let mut state = State::default();
+        let a_name: CommonShareName = "A".parse().unwrap();
+        let b_name: CommonShareName = "B".parse().unwrap();
+        state
+            .add_share(Share::new(a_name.clone(), PathBuf::from("/")))
+            .unwrap();
+        state
+            .add_share(Share::new(b_name.clone(), PathBuf::from("/")))
+            .unwrap();
+        let (peer, _, _) = new_peer(1);
+        let _ = state
+            .new_peer_connected_to_share(peer, a_name.clone())
+            .unwrap();
+
+        assert_eq!(state.idle_shares(), [&b_name]);
+    }
+
     #[test]
     fn connect_and_disconnect_peer_to_share() {
         let mut state = State::default();
@@ -532,11 +1073,16 @@ mod tests {
         assert_eq!(peer_ref.used_shares.len(), 2);
         state.integrity_check();
         // Now peer uses 2 shares
+        assert_eq!(
+            state.peer_connected_to_share(peer_id, share_name2.clone()),
+            Err(RepeatedPeerError.into())
+        );
+        state.integrity_check();
 
         state
             .peer_disconnected_from_share(peer_id, share_name1.clone())
             .unwrap();
-        assert!(state.peers.get(&peer_id).is_some());
+        assert!(state.peers.contains_key(&peer_id));
         state.integrity_check();
 
         state
@@ -548,11 +1094,72 @@ mod tests {
         state
             .peer_disconnected_from_share(peer_id, share_name2.clone())
             .unwrap();
-        assert!(state.peers.get(&peer_id).is_none());
+        assert!(!state.peers.contains_key(&peer_id));
         assert!(shutdown_rx.try_recv().is_ok());
         state.integrity_check();
     }
 
+    #[test]
+    fn re_attach_peer_keeps_membership() {
+        let mut state = State::default();
+        let a_name: CommonShareName = "A".parse().unwrap();
+        let b_name: CommonShareName = "B".parse().unwrap();
+        state
+            .add_share(Share::new(a_name.clone(), PathBuf::from("/")))
+            .unwrap();
+        state
+            .add_share(Share::new(b_name.clone(), PathBuf::from("/")))
+            .unwrap();
+        let remote_name: FullShareName = "1.1.1.1:29284/R".parse().unwrap();
+        let (peer, old_shutdown_rx, _) = new_peer(1);
+        let old_id = state
+            .new_peer_connected_to_share(peer, a_name.clone())
+            .unwrap();
+        state
+            .peer_connected_to_share(old_id, b_name.clone())
+            .unwrap();
+        state
+            .join_remote_share(
+                old_id,
+                remote_name.clone(),
+                "/mnt/r".into(),
+                Default::default(),
+            )
+            .unwrap();
+        let (other, _, _) = new_peer(2);
+        let _ = state
+            .new_peer_connected_to_share(other, a_name.clone())
+            .unwrap();
+        state.integrity_check();
+
+        let (taken, _, _) = new_peer(2);
+        let err = state.re_attach_peer(old_id, taken, std::slice::from_ref(&a_name));
+        assert_eq!(err, Err(RepeatedPeerError.into()));
+        let (reconnected, _, _) = new_peer(1);
+        let err = state.re_attach_peer(old_id, reconnected, &["C".parse().unwrap()]);
+        assert_eq!(err, Err(ShareDoesntExistError.into()));
+        assert!(state.peers.contains_key(&old_id));
+        state.integrity_check();
+
+        let (reconnected, _, _) = new_peer(1);
+        let new_id = state
+            .re_attach_peer(old_id, reconnected, &[a_name.clone(), b_name.clone()])
+            .unwrap();
+        state.integrity_check();
+        assert_ne!(new_id, old_id);
+        assert!(!state.peers.contains_key(&old_id));
+        assert!(old_shutdown_rx.try_recv().is_ok());
+        assert_eq!(state.peers_by_socket[&state.peers[&new_id].address], new_id);
+        assert_eq!(state.shares[&a_name].participants.len(), 2);
+        assert_eq!(state.shares[&b_name].participants.len(), 1);
+        assert!(state.shares[&b_name].participants.contains(&new_id));
+        assert_eq!(state.remote_shares[&remote_name].owner, new_id);
+
+        let (reconnected, _, _) = new_peer(3);
+        let err = state.re_attach_peer(old_id, reconnected, &[]);
+        assert_eq!(err, Err(PeerDoesntExistError.into()));
+    }
+
     #[test]
     fn remove_share() {
         let mut state = State::default();
@@ -579,7 +1186,7 @@ mod tests {
             .unwrap();
         state.integrity_check();
         assert!(server_shutdown_rx.try_recv().is_err());
-        assert!(state.peers.get(&peer_id).is_some());
+        assert!(state.peers.contains_key(&peer_id));
         assert!(notification_rx.try_recv().unwrap().is_kicked_from_share());
         assert!(shutdown_rx.try_recv().is_err());
 
@@ -588,8 +1195,99 @@ mod tests {
             .unwrap();
         state.integrity_check();
         assert!(server_shutdown_rx.try_recv().is_ok());
-        assert!(state.peers.get(&peer_id).is_none());
+        assert!(!state.peers.contains_key(&peer_id));
         assert!(notification_rx.try_recv().unwrap().is_kicked_from_share());
         assert!(shutdown_rx.try_recv().is_ok());
     }
+
+    #[test]
+    fn remove_share_skips_missing_participants() {
+        let mut state = State::default();
+        let (server_shutdown_tx, _server_shutdown_rx) = broadcast(1);
+        let name: CommonShareName = "A".parse().unwrap();
+        state
+            .add_share(Share::new(name.clone(), PathBuf::from("/")))
+            .unwrap();
+        let (gone, _, _) = new_peer(1);
+        let gone_id = state
+            .new_peer_connected_to_share(gone, name.clone())
+            .unwrap();
+        let (quit, _, notification_rx) = new_peer(2);
+        let quit_id = state
+            .new_peer_connected_to_share(quit, name.clone())
+            .unwrap();
+        // One peer dropped without leaving the share, the other one stopped
+        // listening for notifications
+        let gone = state.peers.remove(&gone_id).unwrap();
+        state.peers_by_socket.remove(&gone.address);
+        drop(notification_rx);
+
+        state.remove_share(&name, &server_shutdown_tx).unwrap();
+        assert!(!state.peers.contains_key(&quit_id));
+        state.integrity_check();
+    }
+
+    #[test]
+    fn saved_state_round_trips() {
+        let dir = TestDir::new("state");
+        let path = dir.join("shares.state");
+
+        let mut state = State::default();
+        let mut share = Share::new("A".parse().unwrap(), "/srv/a".into());
+        share.set_options(ShareOptions {
+            hide_mtime: true,
+            ..Default::default()
+        });
+        state.add_share(share).unwrap();
+        state.save(&path).unwrap();
+        let loaded = State::load(&path).unwrap();
+        let share = &loaded.get_shares()[&"A".parse().unwrap()];
+        assert_eq!(share.path, Path::new("/srv/a"));
+        assert!(share.options.hide_mtime);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        let mut state = State::default();
+        let path_bytes = b"/srv/caf\xe9".to_vec();
+        let share = Share::new(
+            "B".parse().unwrap(),
+            OsString::from_vec(path_bytes.clone()).into(),
+        );
+        state.add_share(share).unwrap();
+        state.save(&path).unwrap();
+        let loaded = State::load(&path).unwrap();
+        let share = &loaded.get_shares()[&"B".parse().unwrap()];
+        assert_eq!(share.path.as_os_str().as_bytes(), path_bytes);
+
+        // Like a crash in the middle of a write without the rename
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() / 2]).unwrap();
+        assert!(State::load(&path).unwrap().get_shares().is_empty());
+        assert!(
+            State::load(&dir.join("missing"))
+                .unwrap()
+                .get_shares()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn excluded_paths_are_invisible() {
+        let root = TestDir::new("exclude");
+        fs::create_dir_all(root.join(".git")).unwrap();
+        fs::write(root.join(".git/config"), []).unwrap();
+        fs::write(root.join("main.rs"), []).unwrap();
+
+        let mut share = Share::new("A".parse().unwrap(), root.to_path_buf());
+        share.set_options(ShareOptions {
+            case_insensitive: true,
+            exclude: vec![".git".to_string()],
+            ..Default::default()
+        });
+        assert_eq!(share.read_dir(Path::new("/")).unwrap(), ["main.rs"]);
+        for hidden in [".git", ".git/config", ".GIT/config"] {
+            let err = share.resolve(Path::new(hidden)).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::NotFound, "{hidden}");
+        }
+        assert!(share.resolve(Path::new("main.rs")).is_ok());
+    }
 }
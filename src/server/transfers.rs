@@ -0,0 +1,156 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    net::SocketAddrV4,
+    time::{Duration, Instant},
+};
+
+use tracing::info;
+
+use crate::common::TransferDto;
+
+/// File transfers in progress, for `rdir transfers`
+#[derive(Debug, Default)]
+pub struct Transfers {
+    next_id: Cell<u64>,
+    active: RefCell<BTreeMap<u64, Transfer>>,
+}
+
+#[derive(Debug)]
+struct Transfer {
+    share: String,
+    path: String,
+    peer: SocketAddrV4,
+    done: u64,
+    total: u64,
+    started: Instant,
+}
+
+impl Transfers {
+    /// Registers a transfer of `total` bytes, it is listed until the returned
+    /// guard is dropped
+    pub fn start(
+        &self,
+        share: impl ToString,
+        path: impl ToString,
+        peer: SocketAddrV4,
+        total: u64,
+    ) -> TransferGuard<'_> {
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        self.active.borrow_mut().insert(
+            id,
+            Transfer {
+                share: share.to_string(),
+                path: path.to_string(),
+                peer,
+                done: 0,
+                total,
+                started: Instant::now(),
+            },
+        );
+        TransferGuard {
+            transfers: self,
+            id,
+        }
+    }
+
+    pub fn dtos(&self) -> Vec<TransferDto> {
+        self.active
+            .borrow()
+            .values()
+            .map(|transfer| TransferDto {
+                share: transfer.share.clone(),
+                path: transfer.path.clone(),
+                peer: transfer.peer,
+                done: transfer.done,
+                total: transfer.total,
+                rate: transfer.rate(Instant::now()),
+            })
+            .collect()
+    }
+
+    /// Logs the progress of every transfer that has been running for at least
+    /// `threshold` at `now`, quick ones would only spam the log
+    pub fn log_progress(&self, now: Instant, threshold: Duration) {
+        for transfer in self.active.borrow().values() {
+            if now.saturating_duration_since(transfer.started) >= threshold {
+                info!(
+                    "Transfer of {}/{} with {} still running: {}/{} bytes, {} B/s",
+                    transfer.share,
+                    transfer.path,
+                    transfer.peer,
+                    transfer.done,
+                    transfer.total,
+                    transfer.rate(now)
+                );
+            }
+        }
+    }
+}
+
+impl Transfer {
+    /// Average bytes per second since the transfer started
+    fn rate(&self, now: Instant) -> u64 {
+        let secs = now.saturating_duration_since(self.started).as_secs_f64();
+        match secs > 0.0 {
+            true => (self.done as f64 / secs) as u64,
+            false => 0,
+        }
+    }
+}
+
+/// Keeps a transfer listed, dropping it marks the transfer as finished
+pub struct TransferGuard<'a> {
+    transfers: &'a Transfers,
+    id: u64,
+}
+
+impl TransferGuard<'_> {
+    /// Records `bytes` more sent or received
+    pub fn advance(&self, bytes: u64) {
+        if let Some(transfer) = self.transfers.active.borrow_mut().get_mut(&self.id) {
+            transfer.done = (transfer.done + bytes).min(transfer.total);
+        }
+    }
+}
+
+impl Drop for TransferGuard<'_> {
+    fn drop(&mut self) {
+        self.transfers.active.borrow_mut().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use tracing::level_filters::LevelFilter;
+
+    use super::*;
+    use crate::server::logs::{self, tests::Captured};
+
+    #[test]
+    fn long_transfers_log_their_progress() {
+        let log = Captured::default();
+        let (subscriber, _handle) = {
+            let log = log.clone();
+            logs::subscriber(LevelFilter::DEBUG, move || log.clone(), io::sink)
+        };
+        let transfers = Transfers::default();
+        let peer = "10.0.0.2:4242".parse().unwrap();
+        let transfer = transfers.start("photos", "cat.jpg", peer, 1000);
+        transfer.advance(600);
+
+        let start = Instant::now();
+        let threshold = Duration::from_secs(30);
+        tracing::subscriber::with_default(subscriber, || {
+            transfers.log_progress(start + Duration::from_secs(1), threshold);
+            assert!(!log.contents().contains("cat.jpg"));
+            transfers.log_progress(start + Duration::from_secs(60), threshold);
+        });
+        let log = log.contents();
+        assert!(log.contains("photos/cat.jpg with 10.0.0.2:4242"), "{log}");
+        assert!(log.contains("600/1000 bytes"), "{log}");
+    }
+}
@@ -0,0 +1,90 @@
+use std::{fs, num::NonZeroUsize, path::PathBuf};
+
+use futures::stream::FuturesUnordered;
+use smol::{io, stream::StreamExt, unblock};
+
+/// Sums the sizes of all regular files under `root`, reading up to
+/// `concurrency` directories at once. Symlinks are not followed.
+pub async fn dir_size(root: PathBuf, concurrency: NonZeroUsize) -> io::Result<u64> {
+    let mut pending = vec![root];
+    let mut in_flight = FuturesUnordered::new();
+    let mut total = 0;
+
+    loop {
+        while in_flight.len() < concurrency.get()
+            && let Some(dir) = pending.pop()
+        {
+            in_flight.push(unblock(move || read_dir_shallow(dir)));
+        }
+
+        match in_flight.next().await {
+            Some(result) => {
+                let (size, subdirs) = result?;
+                total += size;
+                pending.extend(subdirs);
+            }
+            None => return Ok(total),
+        }
+    }
+}
+
+/// Returns the total size of the files directly in `dir` and its subdirectories
+fn read_dir_shallow(dir: PathBuf) -> io::Result<(u64, Vec<PathBuf>)> {
+    let mut size = 0;
+    let mut subdirs = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            subdirs.push(entry.path());
+        } else if file_type.is_file() {
+            size += entry.metadata()?.len();
+        }
+    }
+    Ok((size, subdirs))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use smol::block_on;
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    fn reference_size(dir: &Path) -> u64 {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                match entry.file_type().unwrap().is_dir() {
+                    true => reference_size(&entry.path()),
+                    false => entry.metadata().unwrap().len(),
+                }
+            })
+            .sum()
+    }
+
+    #[test]
+    fn concurrent_walk_matches_reference() {
+        let root = TestDir::new("walk");
+        let mut dir = root.to_path_buf();
+        for depth in 0..6 {
+            fs::create_dir_all(&dir).unwrap();
+            for i in 0..4 {
+                fs::write(dir.join(format!("file{i}")), vec![0; depth * 100 + i]).unwrap();
+                fs::create_dir_all(dir.join(format!("sub{i}"))).unwrap();
+                fs::write(dir.join(format!("sub{i}/leaf")), vec![0; i * 7]).unwrap();
+            }
+            dir.push("deeper");
+        }
+
+        let expected = reference_size(&root);
+        for concurrency in [1, 2, 8] {
+            let concurrency = NonZeroUsize::new(concurrency).unwrap();
+            let size = block_on(dir_size(root.to_path_buf(), concurrency)).unwrap();
+            assert_eq!(size, expected);
+        }
+    }
+}
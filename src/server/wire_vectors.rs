@@ -0,0 +1,351 @@
+//! Golden encodings of the messages sent between processes. A failure here means
+//! the wire format changed and old clients or peers wont understand new ones.
+//! Once that is intended, bump [`PROTOCOL_VERSION`] so they are refused instead
+//! and bless the new vectors with `RDIR_BLESS_WIRE_VECTORS=1 cargo test wire_vectors`.
+//! New vectors can be added without a bump
+
+use std::{collections::BTreeMap, fmt::Write, fs};
+
+use bitcode::{DecodeOwned, Encode, decode, encode};
+
+use crate::{
+    common::{
+        ClientEnvelope, ClientMessage, ConfigDto, ConnectMessage, DirEntryDto, DirEntryKind,
+        DiscoveredDto, DiscoveredPeerDto, MountOptions, PeerStatusDto, ServerErrorDto,
+        ServerResponse, ShareMessage, ShareOptions,
+        version::{BuildInfo, PROTOCOL_VERSION},
+    },
+    server::{
+        introduction::Introduction,
+        messages::{
+            FileAttrs, PeerInitConnectToShareResponse, PeerInitListSharesRosponse, PeerInitMessage,
+            PeerInitReadDirResponse, PeerInitStatusResponse, PeerMessage, PeerResponse,
+            PeerResponseError,
+        },
+        state::{RepeatedPeerError, ShareDoesntExistError},
+    },
+};
+
+const VECTORS_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/wire_vectors.txt");
+
+struct Vector {
+    name: &'static str,
+    bytes: Vec<u8>,
+    roundtrip: fn(&[u8]) -> Option<Vec<u8>>,
+}
+
+fn vector<T: Encode + DecodeOwned>(name: &'static str, value: T) -> Vector {
+    Vector {
+        name,
+        bytes: encode(&value),
+        roundtrip: |bytes| decode::<T>(bytes).ok().map(|value| encode(&value)),
+    }
+}
+
+fn vectors() -> Vec<Vector> {
+    let name = || "photos".parse().unwrap();
+    let client = |message: ClientMessage| ClientEnvelope::from(&message);
+    vec![
+        vector(
+            "peer_init_connect_to_share",
+            PeerInitMessage::ConnectToShare { name: name() },
+        ),
+        vector("peer_init_list_shares", PeerInitMessage::ListShares),
+        vector("peer_init_status", PeerInitMessage::Status),
+        vector(
+            "peer_init_status_response",
+            PeerInitStatusResponse::Ok(PeerStatusDto {
+                shares: vec![name()],
+                peers: Some(2),
+            }),
+        ),
+        vector(
+            "peer_init_connect_ok",
+            PeerInitConnectToShareResponse::Ok {
+                suggested_mount: Some("photos".to_string()),
+                banner: Some("Ask ana@example.com for access".to_string()),
+            },
+        ),
+        vector(
+            "peer_init_connect_err",
+            PeerInitConnectToShareResponse::Err(RepeatedPeerError.into()),
+        ),
+        vector(
+            "peer_init_list_shares_response",
+            PeerInitListSharesRosponse {
+                shares: vec![name()],
+            },
+        ),
+        vector(
+            "peer_init_read_dir",
+            PeerInitMessage::ReadDir {
+                name: name(),
+                rel_path: "2024/summer".to_string(),
+            },
+        ),
+        vector(
+            "peer_init_read_dir_response",
+            PeerInitReadDirResponse::Ok(vec![DirEntryDto {
+                name: "cat.jpg".to_string(),
+                kind: DirEntryKind::File,
+                size: 1 << 20,
+                mtime: 1_700_000_000,
+            }]),
+        ),
+        vector(
+            "peer_response_share_removed",
+            PeerResponse::Err(PeerResponseError::ShareRemoved),
+        ),
+        vector(
+            "peer_read_file",
+            PeerMessage::ReadFile {
+                share: name(),
+                rel_path: "2024/cat.jpg".to_string(),
+                offset: 1 << 20,
+                len: 64 * 1024,
+            },
+        ),
+        vector("peer_file_data", PeerResponse::FileData(b"jpeg".to_vec())),
+        vector(
+            "peer_file_hash",
+            PeerMessage::FileHash {
+                share: name(),
+                rel_path: "2024/cat.jpg".to_string(),
+            },
+        ),
+        vector(
+            "peer_file_hash_response",
+            PeerResponse::FileHash(Some([7; 32])),
+        ),
+        vector(
+            "peer_read_dir",
+            PeerMessage::ReadDir {
+                share: name(),
+                rel_path: "2024".to_string(),
+            },
+        ),
+        vector(
+            "peer_dir_entries",
+            PeerResponse::DirEntries(vec![DirEntryDto {
+                name: "cat.jpg".to_string(),
+                kind: DirEntryKind::File,
+                size: 4096,
+                mtime: 1_700_000_000,
+            }]),
+        ),
+        vector(
+            "peer_stat",
+            PeerMessage::Stat {
+                share: name(),
+                rel_path: "2024/cat.jpg".to_string(),
+            },
+        ),
+        vector(
+            "peer_attrs",
+            PeerResponse::Attrs(FileAttrs {
+                kind: DirEntryKind::File,
+                size: 4096,
+                mtime: 1_700_000_000,
+                mode: 0o644,
+                uid: 1000,
+                gid: 1000,
+            }),
+        ),
+        vector(
+            "peer_read_dir_page",
+            PeerMessage::ReadDirPage {
+                share: name(),
+                rel_path: "2024".to_string(),
+                cursor: None,
+                limit: 256,
+            },
+        ),
+        vector(
+            "peer_dir_page",
+            PeerResponse::DirPage {
+                entries: vec![DirEntryDto {
+                    name: "cat.jpg".to_string(),
+                    kind: DirEntryKind::File,
+                    size: 4096,
+                    mtime: 1_700_000_000,
+                }],
+                next: None,
+            },
+        ),
+        vector(
+            "peer_connect_to_another_share",
+            PeerMessage::ConnectToShare { share: name() },
+        ),
+        vector(
+            "peer_leave_share",
+            PeerMessage::LeaveShare { share: name() },
+        ),
+        vector("peer_left", PeerResponse::Left),
+        vector(
+            "peer_introduce",
+            PeerMessage::Introduce {
+                introduction: Introduction::sign(
+                    &[1; 32],
+                    "10.0.0.2".parse().unwrap(),
+                    [7; 32],
+                    60,
+                ),
+            },
+        ),
+        vector("peer_introduced", PeerResponse::Introduced),
+        vector("client_ping", client(ClientMessage::Ping)),
+        vector(
+            "client_connect_mount",
+            client(ClientMessage::Connect(ConnectMessage::Mount {
+                path: Some("/mnt/photos".to_string()),
+                name: "127.0.0.1:29284/photos".parse().unwrap(),
+                options: MountOptions {
+                    map_uid: Some(1000),
+                    map_gid: None,
+                    pin_key: Some([7; 32]),
+                },
+            })),
+        ),
+        vector(
+            "client_share",
+            client(ClientMessage::Share(ShareMessage::Share {
+                path: "/home/user/photos".to_string(),
+                name: Some(name()),
+                options: ShareOptions {
+                    suggested_mount: Some("photos".to_string()),
+                    expires_in: Some(60),
+                    case_insensitive: true,
+                    hide_mtime: true,
+                    hide_mode: false,
+                    on_connect: Some("notify-send \"$1\"".to_string()),
+                    on_disconnect: None,
+                    include: vec![],
+                    exclude: vec![".git".to_string()],
+                    xattrs: true,
+                    dedup: true,
+                    banner: None,
+                },
+                no_overlap: true,
+                force: false,
+            })),
+        ),
+        vector("server_ok", ServerResponse::Ok),
+        vector(
+            "server_share_size",
+            ServerResponse::ShareSize {
+                name: name(),
+                bytes: 1 << 40,
+            },
+        ),
+        vector(
+            "server_discovered",
+            ServerResponse::Discovered(DiscoveredDto {
+                peers: vec![DiscoveredPeerDto {
+                    addr: "192.168.1.10:4242".parse().unwrap(),
+                    rtt_ms: 3,
+                    shares: vec![name()],
+                }],
+            }),
+        ),
+        vector(
+            "server_err",
+            ServerResponse::Err(ServerErrorDto::ShareDoesntExit(ShareDoesntExistError)),
+        ),
+        vector(
+            "server_version",
+            ServerResponse::Version(BuildInfo {
+                version: "0.1.0".to_string(),
+                git_commit: "0123456".to_string(),
+                rustc_version: "rustc 1.95.0".to_string(),
+                features: vec![],
+            }),
+        ),
+        vector(
+            "server_config",
+            ServerResponse::Config(ConfigDto {
+                settings: vec![("request-timeout".to_string(), "5s".to_string())],
+            }),
+        ),
+    ]
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+fn from_hex(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+/// Version the golden vectors were blessed for, on the first line
+const VERSION_KEY: &str = "protocol_version";
+
+fn read_golden() -> BTreeMap<String, String> {
+    let content = fs::read_to_string(VECTORS_PATH).unwrap_or_default();
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        // messages without any data have an empty encoding
+        .map(|line| line.trim().split_once(' ').unwrap_or((line.trim(), "")))
+        .map(|(name, hex)| (name.to_string(), hex.to_string()))
+        .collect()
+}
+
+#[test]
+fn wire_vectors() {
+    let vectors = vectors();
+    let golden = read_golden();
+    let golden_version = golden.get(VERSION_KEY).map(|v| v.parse::<u16>().unwrap());
+    let changed = vectors.iter().find(|vector| {
+        golden
+            .get(vector.name)
+            .is_some_and(|hex| from_hex(hex) != vector.bytes)
+    });
+    if std::env::var_os("RDIR_BLESS_WIRE_VECTORS").is_some() {
+        if let Some(vector) = changed
+            && golden_version == Some(PROTOCOL_VERSION)
+        {
+            panic!(
+                "Encoding of {} changed, bump PROTOCOL_VERSION before blessing",
+                vector.name
+            );
+        }
+        let mut content = format!("{VERSION_KEY} {PROTOCOL_VERSION}\n");
+        for vector in &vectors {
+            let line = format!("{} {}", vector.name, to_hex(&vector.bytes));
+            let _ = writeln!(content, "{}", line.trim_end());
+        }
+        fs::write(VECTORS_PATH, content).unwrap();
+        return;
+    }
+
+    assert_eq!(
+        golden_version,
+        Some(PROTOCOL_VERSION),
+        "Golden vectors are of another protocol version, bless them"
+    );
+    if let Some(vector) = changed {
+        panic!(
+            "Encoding of {} changed, this breaks the wire format. Bump PROTOCOL_VERSION and bless the vectors",
+            vector.name
+        );
+    }
+    for vector in vectors {
+        let Some(hex) = golden.get(vector.name) else {
+            panic!("No golden vector for {}", vector.name);
+        };
+        let bytes = from_hex(hex);
+        assert_eq!(
+            (vector.roundtrip)(&bytes),
+            Some(bytes),
+            "Golden vector of {} no longer decodes",
+            vector.name
+        );
+    }
+}
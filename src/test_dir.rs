@@ -0,0 +1,40 @@
+use std::{
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+/// Fresh dir for a test, removed with everything in it once dropped, so it
+/// goes away even when an assert fails
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    /// Unique to `name` and the test process, whatever an earlier run left is
+    /// cleared first
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("rdir-test-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
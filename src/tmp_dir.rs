@@ -0,0 +1,150 @@
+use std::{
+    fs::{self, DirBuilder, Permissions},
+    io::ErrorKind,
+    os::unix::{
+        fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+        net::UnixListener,
+    },
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::{Context, Result as AnyResult, bail};
+use backoff::{ExponentialBackoffBuilder, backoff::Backoff};
+use nix::libc;
+
+/// Binds of the IPC socket tried before giving up on a dir that keeps vanishing
+const BIND_ATTEMPTS: usize = 5;
+
+/// Creates the tmp dir, or makes sure an existing one is safe to use. Anyone can
+/// create dirs in `/tmp`, so a dir planted there by another user or a symlink
+/// could be used to intercept the IPC socket.
+pub fn prepare(path: &Path) -> AnyResult<()> {
+    // SAFETY: getuid has no preconditions and cant fail
+    prepare_for(path, unsafe { libc::getuid() })
+}
+
+/// Like [`prepare`], but leaves a missing dir alone, commands that only talk to
+/// a running server have no use for it. Returns whether the dir exists
+pub fn prepare_existing(path: &Path) -> AnyResult<bool> {
+    match fs::symlink_metadata(path) {
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+        _ => prepare(path).map(|()| true),
+    }
+}
+
+/// Binds the IPC socket `name` in the prepared tmp dir at `dir`. An instance
+/// cleaning up can remove the dir right before the bind, so a missing dir is
+/// prepared again and the bind retried after a short randomized wait
+pub fn bind_socket(dir: &Path, name: &str) -> AnyResult<UnixListener> {
+    let path = dir.join(name);
+    let context = || {
+        format!(
+            "Failed to create a unix socket at: {}",
+            path.to_string_lossy()
+        )
+    };
+    let mut backoff = ExponentialBackoffBuilder::new()
+        .with_initial_interval(Duration::from_millis(10))
+        .with_max_elapsed_time(None)
+        .build();
+    for _ in 1..BIND_ATTEMPTS {
+        match UnixListener::bind(&path) {
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            result => return result.with_context(context),
+        }
+        std::thread::sleep(backoff.next_backoff().unwrap_or_default());
+        prepare(dir)?;
+    }
+    UnixListener::bind(&path).with_context(context)
+}
+
+fn prepare_for(path: &Path, uid: u32) -> AnyResult<()> {
+    match DirBuilder::new().mode(0o700).create(path) {
+        Ok(()) => return Ok(()),
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => {}
+        Err(err) => {
+            return Err(err).context(format!(
+                "Failed to create the tmp dir at: {}",
+                path.to_string_lossy()
+            ));
+        }
+    }
+
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.is_symlink() {
+        bail!(
+            "Refusing to use {}, it is a symlink",
+            path.to_string_lossy()
+        );
+    }
+    if !metadata.is_dir() {
+        bail!("Refusing to use {}, it isnt a dir", path.to_string_lossy());
+    }
+    if metadata.uid() != uid {
+        bail!(
+            "Refusing to use {}, it is owned by uid {} instead of {uid}",
+            path.to_string_lossy(),
+            metadata.uid()
+        );
+    }
+    if metadata.mode() & 0o077 != 0 {
+        fs::set_permissions(path, Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn creates_private_dir() {
+        let dir = TestDir::new("create");
+        let path = dir.join("rdir");
+        prepare(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().mode() & 0o777, 0o700);
+        // an existing dir of ours is fine
+        fs::set_permissions(&path, Permissions::from_mode(0o755)).unwrap();
+        prepare(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().mode() & 0o777, 0o700);
+    }
+
+    #[test]
+    fn missing_dir_is_left_alone() {
+        let dir = TestDir::new("missing");
+        let path = dir.join("rdir");
+        assert!(!prepare_existing(&path).unwrap());
+        assert!(!path.exists());
+        prepare(&path).unwrap();
+        assert!(prepare_existing(&path).unwrap());
+    }
+
+    #[test]
+    fn bind_recreates_a_vanished_dir() {
+        let dir = TestDir::new("bind");
+        let path = dir.join("rdir");
+        prepare(&path).unwrap();
+        // Another instance cleaning up between preparing and binding
+        fs::remove_dir(&path).unwrap();
+        bind_socket(&path, "rdir.sock").unwrap();
+        assert!(path.join("rdir.sock").exists());
+        assert_eq!(fs::metadata(&path).unwrap().mode() & 0o777, 0o700);
+    }
+
+    #[test]
+    fn refuses_unsafe_dirs() {
+        let dir = TestDir::new("refuse");
+        let target = dir.join("target");
+        fs::create_dir(&target).unwrap();
+        let link = dir.join("link");
+        symlink(&target, &link).unwrap();
+        assert!(prepare(&link).is_err());
+
+        let uid = fs::metadata(&target).unwrap().uid();
+        assert!(prepare_for(&target, uid + 1).is_err());
+    }
+}